@@ -1,6 +1,12 @@
-use crate::{utils, Error, Result};
+use crate::{database::appservice::RegistrationInfo, utils, Error, Result};
 use bytes::BytesMut;
-use ruma::api::{IncomingResponse, OutgoingRequest, SendAccessToken};
+use http::header::CONTENT_TYPE;
+use ruma::{
+    api::{IncomingResponse, OutgoingRequest, SendAccessToken},
+    events::AnyRoomEvent,
+    serde::Raw,
+};
+use serde_json::value::RawValue as RawJsonValue;
 use std::{
     convert::{TryFrom, TryInto},
     fmt::Debug,
@@ -11,14 +17,14 @@ use tracing::warn;
 
 pub(crate) async fn send_request<T: OutgoingRequest>(
     globals: &crate::database::globals::Globals,
-    registration: serde_yaml::Value,
+    registration: RegistrationInfo,
     request: T,
 ) -> Result<T::IncomingResponse>
 where
     T: Debug,
 {
-    let destination = registration.get("url").unwrap().as_str().unwrap();
-    let hs_token = registration.get("hs_token").unwrap().as_str().unwrap();
+    let destination = registration.url.as_str();
+    let hs_token = registration.hs_token.as_str();
 
     let mut http_request = request
         .try_into_http_request::<BytesMut>(destination, SendAccessToken::IfRequired(""))
@@ -92,3 +98,54 @@ where
         Error::BadServerResponse("Server returned bad response.")
     })
 }
+
+/// Sends a transaction that also contains ephemeral events (MSC2409). Ruma's transaction request
+/// has no field for them, so the request is built by hand.
+pub(crate) async fn send_ephemeral_transaction(
+    globals: &crate::database::globals::Globals,
+    registration: RegistrationInfo,
+    txn_id: &str,
+    events: &[Raw<AnyRoomEvent>],
+    ephemeral: &[Box<RawJsonValue>],
+) -> Result<()> {
+    let destination = registration.url.as_str();
+    let hs_token = registration.hs_token.as_str();
+
+    let url = format!(
+        "{}/_matrix/app/v1/transactions/{}?access_token={}",
+        destination.trim_end_matches('/'),
+        txn_id,
+        hs_token
+    );
+
+    let body = serde_json::to_vec(&serde_json::json!({
+        "events": events,
+        "de.sorunome.msc2409.ephemeral": ephemeral,
+    }))
+    .expect("transaction can be serialized");
+
+    let response = globals
+        .reqwest_client()?
+        .build()?
+        .put(&url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body)
+        .timeout(Duration::from_secs(30))
+        .send()
+        .await?;
+
+    let status = response.status();
+    if status != 200 {
+        warn!(
+            "Appservice returned bad response {} {}\n{:?}",
+            destination,
+            status,
+            response.text().await
+        );
+        return Err(Error::BadServerResponse(
+            "Appservice rejected the transaction.",
+        ));
+    }
+
+    Ok(())
+}
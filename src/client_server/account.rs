@@ -1,11 +1,22 @@
 use std::{
     collections::BTreeMap,
     convert::{TryFrom, TryInto},
+    net::IpAddr,
     sync::Arc,
 };
 
-use super::{DEVICE_ID_LENGTH, SESSION_ID_LENGTH, TOKEN_LENGTH};
-use crate::{database::DatabaseGuard, pdu::PduBuilder, utils, ConduitResult, Error, Ruma};
+use super::{
+    issue_refresh_token, wants_refresh_token, DEVICE_ID_LENGTH, SESSION_ID_LENGTH, TOKEN_LENGTH,
+};
+use crate::{
+    database::{
+        terms::{Terms, TERMS_AUTH_TYPE},
+        threepids::ValidationPurpose,
+        DatabaseGuard,
+    },
+    pdu::PduBuilder,
+    utils, ConduitResult, Error, Result, Ruma, RumaResponseWithFields,
+};
 use ruma::{
     api::client::{
         error::ErrorKind,
@@ -34,7 +45,10 @@ use ruma::{
         EventType,
     },
     identifiers::RoomName,
-    push, RoomAliasId, RoomId, RoomVersionId, UserId,
+    push,
+    signatures::CanonicalJsonValue,
+    thirdparty::ThirdPartyIdentifierInit,
+    RoomAliasId, RoomId, RoomVersionId, UserId,
 };
 use serde_json::value::to_raw_value;
 use tracing::info;
@@ -44,6 +58,7 @@ use register::RegistrationKind;
 use rocket::{get, post};
 
 const GUEST_NAME_LENGTH: usize = 10;
+const EMAIL_IDENTITY_AUTH_TYPE: &str = "m.login.email.identity";
 
 /// # `GET /_matrix/client/r0/register/available`
 ///
@@ -83,7 +98,12 @@ pub async fn get_register_available_route(
         ));
     }
 
-    // TODO add check for appservice namespaces
+    if db.appservice.exclusive_user_owner(&user_id)?.is_some() {
+        return Err(Error::BadRequest(
+            ErrorKind::Exclusive,
+            "Desired user ID is reserved by an appservice.",
+        ));
+    }
 
     // If no if check is true we have an username that's available to be used.
     Ok(get_username_availability::Response { available: true }.into())
@@ -102,6 +122,7 @@ pub async fn get_register_available_route(
 /// - If type is not guest and no username is given: Always fails after UIAA check
 /// - Creates a new account and populates it with default account data
 /// - If `inhibit_login` is false: Creates a device and returns device id and access_token
+/// - If the client sets `refresh_token`: the access token expires and a refresh token is returned
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/register", data = "<body>")
@@ -109,8 +130,9 @@ pub async fn get_register_available_route(
 #[tracing::instrument(skip(db, body))]
 pub async fn register_route(
     db: DatabaseGuard,
+    client_ip: Option<IpAddr>,
     body: Ruma<register::Request<'_>>,
-) -> ConduitResult<register::Response> {
+) -> Result<RumaResponseWithFields<register::Response>> {
     if !db.globals.allow_registration() && !body.from_appservice {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
@@ -153,28 +175,93 @@ pub async fn register_route(
         ));
     }
 
+    // Appservices can only register users in their namespace, and only the appservice can
+    // register users in its exclusive namespaces
+    if !is_guest && !missing_username {
+        match &body.appservice_id {
+            Some(appservice_id) => {
+                let registration =
+                    db.appservice
+                        .get_registration(appservice_id)?
+                        .ok_or(Error::BadRequest(
+                            ErrorKind::Forbidden,
+                            "The appservice of this request is not registered.",
+                        ))?;
+                if !registration.is_user_allowed(&user_id, db.globals.server_name()) {
+                    return Err(Error::BadRequest(
+                        ErrorKind::Exclusive,
+                        "Desired user ID is not in the namespace of the appservice.",
+                    ));
+                }
+            }
+            None => {
+                if db.appservice.exclusive_user_owner(&user_id)?.is_some() {
+                    return Err(Error::BadRequest(
+                        ErrorKind::Exclusive,
+                        "Desired user ID is reserved by an appservice.",
+                    ));
+                }
+            }
+        }
+    }
+
     // UIAA
-    let mut uiaainfo = UiaaInfo {
-        flows: vec![AuthFlow {
-            stages: vec![AuthType::Dummy],
-        }],
-        completed: Vec::new(),
-        params: Default::default(),
-        session: None,
-        auth_error: None,
+    let mut uiaainfo = match db.globals.terms() {
+        Some(terms) => UiaaInfo {
+            flows: vec![AuthFlow {
+                stages: vec![AuthType::from(TERMS_AUTH_TYPE)],
+            }],
+            completed: Vec::new(),
+            params: Terms::uiaa_params(terms),
+            session: None,
+            auth_error: None,
+        },
+        None => UiaaInfo {
+            flows: vec![AuthFlow {
+                stages: vec![AuthType::Dummy],
+            }],
+            completed: Vec::new(),
+            params: Default::default(),
+            session: None,
+            auth_error: None,
+        },
+    };
+
+    // Ruma doesn't know about the terms stage yet
+    let terms_session = match &body.json_body {
+        Some(CanonicalJsonValue::Object(json_body)) => match json_body.get("auth") {
+            Some(CanonicalJsonValue::Object(auth))
+                if auth.get("type").and_then(|t| t.as_str()) == Some(TERMS_AUTH_TYPE) =>
+            {
+                auth.get("session").and_then(|s| s.as_str())
+            }
+            _ => None,
+        },
+        _ => None,
     };
 
     if !body.from_appservice {
         if let Some(auth) = &body.auth {
-            let (worked, uiaainfo) = db.uiaa.try_auth(
-                &UserId::parse_with_server_name("", db.globals.server_name())
-                    .expect("we know this is valid"),
-                "".into(),
-                auth,
-                &uiaainfo,
-                &db.users,
-                &db.globals,
-            )?;
+            let (worked, uiaainfo) = if let Some(session) = terms_session {
+                db.uiaa.complete_stage(
+                    &UserId::parse_with_server_name("", db.globals.server_name())
+                        .expect("we know this is valid"),
+                    "".into(),
+                    session,
+                    AuthType::from(TERMS_AUTH_TYPE),
+                )?
+            } else {
+                db.uiaa.try_auth(
+                    &UserId::parse_with_server_name("", db.globals.server_name())
+                        .expect("we know this is valid"),
+                    "".into(),
+                    auth,
+                    &uiaainfo,
+                    &db.users,
+                    &db.globals,
+                    client_ip,
+                )?
+            };
             if !worked {
                 return Err(Error::Uiaa(uiaainfo));
             }
@@ -210,6 +297,13 @@ pub async fn register_route(
     // Create user
     db.users.create(&user_id, password)?;
 
+    // The user accepted the policies in the terms stage
+    if let Some(terms) = db.globals.terms() {
+        if !body.from_appservice {
+            db.terms.accept(&user_id, terms)?;
+        }
+    }
+
     // Default to pretty displayname
     let displayname = format!("{} ⚡️", user_id.localpart());
     db.users
@@ -523,16 +617,24 @@ pub async fn register_route(
         )?;
     }
 
+    let fields = if wants_refresh_token(&body.json_body) {
+        issue_refresh_token(&db, &user_id, &device_id, &token)?
+    } else {
+        serde_json::Map::new()
+    };
+
     info!("{} registered on this server", user_id);
 
     db.flush()?;
 
-    Ok(register::Response {
-        access_token: Some(token),
-        user_id,
-        device_id: Some(device_id),
-    }
-    .into())
+    Ok(RumaResponseWithFields {
+        response: register::Response {
+            access_token: Some(token),
+            user_id,
+            device_id: Some(device_id),
+        },
+        fields,
+    })
 }
 
 /// # `POST /_matrix/client/r0/account/password`
@@ -544,6 +646,9 @@ pub async fn register_route(
 /// - The password hash is calculated using argon2 with 32 character salt, the plain password is
 /// not saved
 ///
+/// Users who forgot their password can call this without an access token. They authenticate with
+/// `m.login.email.identity` and the credentials of a validated password reset session instead.
+///
 /// If logout_devices is true it does the following for each device except the sender device:
 /// - Invalidates access token
 /// - Deletes device metadata (device id, device display name, last seen ip, last seen ts)
@@ -556,55 +661,105 @@ pub async fn register_route(
 #[tracing::instrument(skip(db, body))]
 pub async fn change_password_route(
     db: DatabaseGuard,
+    client_ip: Option<IpAddr>,
     body: Ruma<change_password::Request<'_>>,
 ) -> ConduitResult<change_password::Response> {
-    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
-    let sender_device = body.sender_device.as_ref().expect("user is authenticated");
-
-    let mut uiaainfo = UiaaInfo {
-        flows: vec![AuthFlow {
-            stages: vec![AuthType::Password],
-        }],
-        completed: Vec::new(),
-        params: Default::default(),
-        session: None,
-        auth_error: None,
+    // Ruma doesn't know about the threepid credentials of this stage
+    let email_identity_creds = match &body.json_body {
+        Some(CanonicalJsonValue::Object(json_body)) => match json_body.get("auth") {
+            Some(CanonicalJsonValue::Object(auth))
+                if auth.get("type").and_then(|t| t.as_str()) == Some(EMAIL_IDENTITY_AUTH_TYPE) =>
+            {
+                match auth
+                    .get("threepid_creds")
+                    .or_else(|| auth.get("threepidCreds"))
+                {
+                    Some(CanonicalJsonValue::Object(creds)) => creds
+                        .get("sid")
+                        .and_then(|sid| sid.as_str())
+                        .zip(creds.get("client_secret").and_then(|s| s.as_str())),
+                    _ => None,
+                }
+            }
+            _ => None,
+        },
+        _ => None,
     };
 
-    if let Some(auth) = &body.auth {
-        let (worked, uiaainfo) = db.uiaa.try_auth(
-            sender_user,
-            sender_device,
-            auth,
-            &uiaainfo,
-            &db.users,
-            &db.globals,
-        )?;
-        if !worked {
-            return Err(Error::Uiaa(uiaainfo));
+    let sender_user = if let Some((sid, client_secret)) = email_identity_creds {
+        let session = db
+            .threepids
+            .take_validated(sid, client_secret, ValidationPurpose::PasswordReset)?
+            .ok_or(Error::BadRequest(
+                ErrorKind::ThreepidAuthFailed,
+                "The email address was not validated.",
+            ))?;
+
+        match db.threepids.find_user(&session.medium, &session.address)? {
+            // Setting a password would reactivate the account
+            Some(user_id) if !db.users.is_deactivated(&user_id)? => user_id,
+            _ => {
+                return Err(Error::BadRequest(
+                    ErrorKind::ThreepidNotFound,
+                    "Email address is not bound to an account.",
+                ))
+            }
         }
-    // Success!
-    } else if let Some(json) = body.json_body {
-        uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
-        db.uiaa
-            .create(sender_user, sender_device, &uiaainfo, &json)?;
-        return Err(Error::Uiaa(uiaainfo));
     } else {
-        return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
-    }
+        let sender_user = body.sender_user.as_ref().ok_or(Error::BadRequest(
+            ErrorKind::MissingToken,
+            "Missing access token.",
+        ))?;
+        let sender_device = body.sender_device.as_ref().expect("user is authenticated");
+
+        let mut uiaainfo = UiaaInfo {
+            flows: vec![AuthFlow {
+                stages: vec![AuthType::Password],
+            }],
+            completed: Vec::new(),
+            params: Default::default(),
+            session: None,
+            auth_error: None,
+        };
+
+        if let Some(auth) = &body.auth {
+            let (worked, uiaainfo) = db.uiaa.try_auth(
+                sender_user,
+                sender_device,
+                auth,
+                &uiaainfo,
+                &db.users,
+                &db.globals,
+                client_ip,
+            )?;
+            if !worked {
+                return Err(Error::Uiaa(uiaainfo));
+            }
+        // Success!
+        } else if let Some(json) = &body.json_body {
+            uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
+            db.uiaa
+                .create(sender_user, sender_device, &uiaainfo, json)?;
+            return Err(Error::Uiaa(uiaainfo));
+        } else {
+            return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
+        }
+
+        sender_user.clone()
+    };
 
     db.users
-        .set_password(sender_user, Some(&body.new_password))?;
+        .set_password(&sender_user, Some(&body.new_password))?;
 
     if body.logout_devices {
         // Logout all devices except the current one
         for id in db
             .users
-            .all_device_ids(sender_user)
+            .all_device_ids(&sender_user)
             .filter_map(|id| id.ok())
-            .filter(|id| id != sender_device)
+            .filter(|id| Some(id) != body.sender_device.as_ref())
         {
-            db.users.remove_device(sender_user, &id)?;
+            db.users.remove_device(&sender_user, &id)?;
         }
     }
 
@@ -648,6 +803,7 @@ pub async fn whoami_route(body: Ruma<whoami::Request>) -> ConduitResult<whoami::
 #[tracing::instrument(skip(db, body))]
 pub async fn deactivate_route(
     db: DatabaseGuard,
+    client_ip: Option<IpAddr>,
     body: Ruma<deactivate::Request<'_>>,
 ) -> ConduitResult<deactivate::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
@@ -671,6 +827,7 @@ pub async fn deactivate_route(
             &uiaainfo,
             &db.users,
             &db.globals,
+            client_ip,
         )?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
@@ -737,6 +894,9 @@ pub async fn deactivate_route(
     // Remove devices and mark account as deactivated
     db.users.deactivate_account(sender_user)?;
 
+    // Other accounts can use the email addresses now
+    db.threepids.remove_all(sender_user)?;
+
     info!("{} deactivated their account", sender_user);
 
     db.flush()?;
@@ -751,15 +911,34 @@ pub async fn deactivate_route(
 ///
 /// Get a list of third party identifiers associated with this account.
 ///
-/// - Currently always returns empty list
+/// - Only contains the addresses validated on this server, identity servers are not asked
+/// - Phone numbers (`msisdn`) are not supported, so only email addresses are listed
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/account/3pid", data = "<body>")
 )]
+#[tracing::instrument(skip(db, body))]
 pub async fn third_party_route(
+    db: DatabaseGuard,
     body: Ruma<get_contacts::Request>,
 ) -> ConduitResult<get_contacts::Response> {
-    let _sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let threepids = db
+        .threepids
+        .threepids(sender_user)
+        .map(|threepid| {
+            threepid.map(|threepid| {
+                ThirdPartyIdentifierInit {
+                    address: threepid.address,
+                    medium: threepid.medium.as_str().into(),
+                    added_at: threepid.added_at,
+                    validated_at: threepid.validated_at,
+                }
+                .into()
+            })
+        })
+        .collect::<Result<_>>()?;
 
-    Ok(get_contacts::Response::new(Vec::new()).into())
+    Ok(get_contacts::Response::new(threepids).into())
 }
@@ -1,5 +1,4 @@
 use crate::{database::DatabaseGuard, ConduitResult, Database, Error, Ruma};
-use regex::Regex;
 use ruma::{
     api::{
         appservice,
@@ -38,6 +37,15 @@ pub async fn create_alias_route(
         return Err(Error::Conflict("Alias already exists."));
     }
 
+    if let Some(owner) = db.appservice.exclusive_alias_owner(&body.room_alias)? {
+        if body.appservice_id.as_ref() != Some(&owner) {
+            return Err(Error::BadRequest(
+                ErrorKind::Exclusive,
+                "Alias is reserved by an appservice.",
+            ));
+        }
+    }
+
     db.rooms
         .set_alias(&body.room_alias, Some(&body.room_id), &db.globals)?;
 
@@ -116,20 +124,7 @@ pub(crate) async fn get_alias_helper(
         Some(r) => room_id = Some(r),
         None => {
             for (_id, registration) in db.appservice.all()? {
-                let aliases = registration
-                    .get("namespaces")
-                    .and_then(|ns| ns.get("aliases"))
-                    .and_then(|aliases| aliases.as_sequence())
-                    .map_or_else(Vec::new, |aliases| {
-                        aliases
-                            .iter()
-                            .filter_map(|aliases| Regex::new(aliases.get("regex")?.as_str()?).ok())
-                            .collect::<Vec<_>>()
-                    });
-
-                if aliases
-                    .iter()
-                    .any(|aliases| aliases.is_match(room_alias.as_str()))
+                if registration.namespaces.is_alias_match(room_alias)
                     && db
                         .sending
                         .send_appservice_request(
@@ -1,4 +1,4 @@
-use crate::{database::DatabaseGuard, ConduitResult, Error, Ruma};
+use crate::{database::DatabaseGuard, ConduitResult, Error, Result, Ruma};
 use ruma::api::client::{error::ErrorKind, r0::context::get_context};
 use std::convert::TryFrom;
 
@@ -39,14 +39,15 @@ pub async fn get_context_route(
 
     let base_token = db.rooms.pdu_count(&base_pdu_id)?;
 
-    let base_event = db
+    let mut base_event = db
         .rooms
         .get_pdu_from_id(&base_pdu_id)?
         .ok_or(Error::BadRequest(
             ErrorKind::NotFound,
             "Base event not found.",
-        ))?
-        .to_room_event();
+        ))?;
+    db.rooms
+        .add_bundled_aggregations(sender_user, &mut base_event)?;
 
     let events_before: Vec<_> = db
         .rooms
@@ -65,10 +66,13 @@ pub async fn get_context_route(
         .and_then(|(pdu_id, _)| db.rooms.pdu_count(pdu_id).ok())
         .map(|count| count.to_string());
 
-    let events_before: Vec<_> = events_before
+    let events_before = events_before
         .into_iter()
-        .map(|(_, pdu)| pdu.to_room_event())
-        .collect();
+        .map(|(_, mut pdu)| {
+            db.rooms.add_bundled_aggregations(sender_user, &mut pdu)?;
+            Ok(pdu.to_room_event())
+        })
+        .collect::<Result<_>>()?;
 
     let events_after: Vec<_> = db
         .rooms
@@ -87,16 +91,19 @@ pub async fn get_context_route(
         .and_then(|(pdu_id, _)| db.rooms.pdu_count(pdu_id).ok())
         .map(|count| count.to_string());
 
-    let events_after: Vec<_> = events_after
+    let events_after = events_after
         .into_iter()
-        .map(|(_, pdu)| pdu.to_room_event())
-        .collect();
+        .map(|(_, mut pdu)| {
+            db.rooms.add_bundled_aggregations(sender_user, &mut pdu)?;
+            Ok(pdu.to_room_event())
+        })
+        .collect::<Result<_>>()?;
 
     let mut resp = get_context::Response::new();
     resp.start = start_token;
     resp.end = end_token;
     resp.events_before = events_before;
-    resp.event = Some(base_event);
+    resp.event = Some(base_event.to_room_event());
     resp.events_after = events_after;
     resp.state = db // TODO: State at event
         .rooms
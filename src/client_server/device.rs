@@ -6,6 +6,7 @@ use ruma::api::client::{
         uiaa::{AuthFlow, AuthType, UiaaInfo},
     },
 };
+use std::net::IpAddr;
 
 use super::SESSION_ID_LENGTH;
 #[cfg(feature = "conduit_bin")]
@@ -101,6 +102,7 @@ pub async fn update_device_route(
 #[tracing::instrument(skip(db, body))]
 pub async fn delete_device_route(
     db: DatabaseGuard,
+    client_ip: Option<IpAddr>,
     body: Ruma<delete_device::Request<'_>>,
 ) -> ConduitResult<delete_device::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
@@ -125,6 +127,7 @@ pub async fn delete_device_route(
             &uiaainfo,
             &db.users,
             &db.globals,
+            client_ip,
         )?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
@@ -164,6 +167,7 @@ pub async fn delete_device_route(
 #[tracing::instrument(skip(db, body))]
 pub async fn delete_devices_route(
     db: DatabaseGuard,
+    client_ip: Option<IpAddr>,
     body: Ruma<delete_devices::Request<'_>>,
 ) -> ConduitResult<delete_devices::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
@@ -188,6 +192,7 @@ pub async fn delete_devices_route(
             &uiaainfo,
             &db.users,
             &db.globals,
+            client_ip,
         )?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
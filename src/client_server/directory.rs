@@ -226,6 +226,12 @@ pub(crate) async fn get_public_rooms_filtered_helper(
     let mut all_rooms: Vec<_> = db
         .rooms
         .public_rooms()
+        // Rooms banned by a policy list are never advertised
+        .filter(|room_id| {
+            room_id
+                .as_ref()
+                .map_or(true, |room_id| !db.policies.is_room_banned(room_id))
+        })
         .map(|room_id| {
             let room_id = room_id?;
 
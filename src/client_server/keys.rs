@@ -19,7 +19,10 @@ use ruma::{
     DeviceId, DeviceKeyAlgorithm, UserId,
 };
 use serde_json::json;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::IpAddr,
+};
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, post};
@@ -140,6 +143,7 @@ pub async fn claim_keys_route(
 #[tracing::instrument(skip(db, body))]
 pub async fn upload_signing_keys_route(
     db: DatabaseGuard,
+    client_ip: Option<IpAddr>,
     body: Ruma<upload_signing_keys::Request<'_>>,
 ) -> ConduitResult<upload_signing_keys::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
@@ -164,6 +168,7 @@ pub async fn upload_signing_keys_route(
             &uiaainfo,
             &db.users,
             &db.globals,
+            client_ip,
         )?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
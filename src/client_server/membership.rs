@@ -526,6 +526,20 @@ async fn join_room_by_id_helper(
 ) -> ConduitResult<join_room_by_id::Response> {
     let sender_user = sender_user.expect("user is authenticated");
 
+    if db.policies.is_user_banned(sender_user) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You are banned by a policy list of this server.",
+        ));
+    }
+
+    if db.policies.is_room_banned(room_id) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This room is banned by a policy list of this server.",
+        ));
+    }
+
     let mutex_state = Arc::clone(
         db.globals
             .roomid_mutex_state
@@ -849,6 +863,25 @@ pub(crate) async fn invite_helper<'a>(
     db: &Database,
     is_direct: bool,
 ) -> Result<()> {
+    if db.policies.is_user_banned(sender_user) || db.policies.is_user_banned(user_id) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This user is banned by a policy list of this server.",
+        ));
+    }
+
+    if db.policies.is_room_banned(room_id) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This room is banned by a policy list of this server.",
+        ));
+    }
+
+    // Shadow-banned users should not notice that their invites are thrown away
+    if db.users.is_shadow_banned(sender_user)? {
+        return Ok(());
+    }
+
     if user_id.server_name() != db.globals.server_name() {
         let (room_version_id, pdu_json, invite_room_state) = {
             let mutex_state = Arc::clone(
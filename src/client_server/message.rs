@@ -1,4 +1,4 @@
-use crate::{database::DatabaseGuard, pdu::PduBuilder, utils, ConduitResult, Error, Ruma};
+use crate::{database::DatabaseGuard, pdu::PduBuilder, utils, ConduitResult, Error, Result, Ruma};
 use ruma::{
     api::client::{
         error::ErrorKind,
@@ -75,6 +75,24 @@ pub async fn send_message_event_route(
         return Ok(send_message_event::Response { event_id }.into());
     }
 
+    // Shadow-banned users should not notice that their events are thrown away
+    if db.users.is_shadow_banned(sender_user)? {
+        let event_id = utils::random_event_id();
+
+        db.transaction_ids.add_txnid(
+            sender_user,
+            sender_device,
+            &body.txn_id,
+            event_id.as_bytes(),
+        )?;
+
+        drop(state_lock);
+
+        db.flush()?;
+
+        return Ok(send_message_event::Response { event_id }.into());
+    }
+
     let mut unsigned = BTreeMap::new();
     unsigned.insert("transaction_id".to_owned(), body.txn_id.clone().into());
 
@@ -160,10 +178,13 @@ pub async fn get_message_events_route(
 
             let end_token = events_after.last().map(|(count, _)| count.to_string());
 
-            let events_after: Vec<_> = events_after
+            let events_after = events_after
                 .into_iter()
-                .map(|(_, pdu)| pdu.to_room_event())
-                .collect();
+                .map(|(_, mut pdu)| {
+                    db.rooms.add_bundled_aggregations(sender_user, &mut pdu)?;
+                    Ok(pdu.to_room_event())
+                })
+                .collect::<Result<_>>()?;
 
             let mut resp = get_message_events::Response::new();
             resp.start = Some(body.from.to_owned());
@@ -190,10 +211,13 @@ pub async fn get_message_events_route(
 
             let start_token = events_before.last().map(|(count, _)| count.to_string());
 
-            let events_before: Vec<_> = events_before
+            let events_before = events_before
                 .into_iter()
-                .map(|(_, pdu)| pdu.to_room_event())
-                .collect();
+                .map(|(_, mut pdu)| {
+                    db.rooms.add_bundled_aggregations(sender_user, &mut pdu)?;
+                    Ok(pdu.to_room_event())
+                })
+                .collect::<Result<_>>()?;
 
             let mut resp = get_message_events::Response::new();
             resp.start = Some(body.from.to_owned());
@@ -16,14 +16,19 @@ mod profile;
 mod push;
 mod read_marker;
 mod redact;
+mod relations;
 mod report;
 mod room;
 mod search;
 mod session;
+mod sso;
 mod state;
 mod sync;
 mod tag;
+mod terms;
 mod thirdparty;
+mod threads;
+mod threepid;
 mod to_device;
 mod typing;
 mod unversioned;
@@ -48,14 +53,19 @@ pub use profile::*;
 pub use push::*;
 pub use read_marker::*;
 pub use redact::*;
+pub use relations::*;
 pub use report::*;
 pub use room::*;
 pub use search::*;
 pub use session::*;
+pub use sso::*;
 pub use state::*;
 pub use sync::*;
 pub use tag::*;
+pub use terms::*;
 pub use thirdparty::*;
+pub use threads::*;
+pub use threepid::*;
 pub use to_device::*;
 pub use typing::*;
 pub use unversioned::*;
@@ -1,6 +1,12 @@
-use crate::{database::DatabaseGuard, utils, ConduitResult, Ruma};
-use ruma::api::client::r0::presence::{get_presence, set_presence};
-use std::{convert::TryInto, time::Duration};
+use crate::{
+    database::{rooms::RoomEdus, DatabaseGuard},
+    utils, ConduitResult, Error, Ruma,
+};
+use ruma::api::client::{
+    error::ErrorKind,
+    r0::presence::{get_presence, set_presence},
+};
+use std::time::Duration;
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, put};
@@ -8,6 +14,8 @@ use rocket::{get, put};
 /// # `PUT /_matrix/client/r0/presence/{userId}/status`
 ///
 /// Sets the presence state of the sender user.
+///
+/// - Does nothing if presence is disabled on this server
 #[cfg_attr(
     feature = "conduit_bin",
     put("/_matrix/client/r0/presence/<_>/status", data = "<body>")
@@ -19,31 +27,21 @@ pub async fn set_presence_route(
 ) -> ConduitResult<set_presence::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    for room_id in db.rooms.rooms_joined(sender_user) {
-        let room_id = room_id?;
-
-        db.rooms.edus.update_presence(
-            sender_user,
-            &room_id,
-            ruma::events::presence::PresenceEvent {
-                content: ruma::events::presence::PresenceEventContent {
-                    avatar_url: db.users.avatar_url(sender_user)?,
-                    currently_active: None,
-                    displayname: db.users.displayname(sender_user)?,
-                    last_active_ago: Some(
-                        utils::millis_since_unix_epoch()
-                            .try_into()
-                            .expect("time is valid"),
-                    ),
-                    presence: body.presence.clone(),
-                    status_msg: body.status_msg.clone(),
-                },
-                sender: sender_user.clone(),
-            },
-            &db.globals,
-        )?;
+    if sender_user != &body.user_id {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You cannot set the presence of other users.",
+        ));
     }
 
+    RoomEdus::set_presence(
+        &db,
+        sender_user,
+        body.presence.clone(),
+        body.status_msg.clone(),
+        utils::millis_since_unix_epoch(),
+    )?;
+
     db.flush()?;
 
     Ok(set_presence::Response {}.into())
@@ -65,37 +63,32 @@ pub async fn get_presence_route(
 ) -> ConduitResult<get_presence::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    let mut presence_event = None;
-
-    for room_id in db
-        .rooms
-        .get_shared_rooms(vec![sender_user.clone(), body.user_id.clone()])?
-    {
-        let room_id = room_id?;
-
-        if let Some(presence) = db
+    let shares_room = sender_user == &body.user_id
+        || db
             .rooms
-            .edus
-            .get_last_presence_event(sender_user, &room_id)?
-        {
-            presence_event = Some(presence);
-            break;
-        }
-    }
+            .get_shared_rooms(vec![sender_user.clone(), body.user_id.clone()])?
+            .next()
+            .is_some();
 
-    if let Some(presence) = presence_event {
-        Ok(get_presence::Response {
-            // TODO: Should ruma just use the presenceeventcontent type here?
-            status_msg: presence.content.status_msg,
-            currently_active: presence.content.currently_active,
-            last_active_ago: presence
-                .content
-                .last_active_ago
-                .map(|millis| Duration::from_millis(millis.into())),
-            presence: presence.content.presence,
-        }
-        .into())
+    let presence = if shares_room {
+        db.rooms.edus.get_presence_event(&body.user_id)?
     } else {
-        todo!();
+        None
+    }
+    .ok_or(Error::BadRequest(
+        ErrorKind::NotFound,
+        "Presence state for this user was not found.",
+    ))?;
+
+    Ok(get_presence::Response {
+        // TODO: Should ruma just use the presenceeventcontent type here?
+        status_msg: presence.content.status_msg,
+        currently_active: presence.content.currently_active,
+        last_active_ago: presence
+            .content
+            .last_active_ago
+            .map(|millis| Duration::from_millis(millis.into())),
+        presence: presence.content.presence,
     }
+    .into())
 }
@@ -1,4 +1,8 @@
-use crate::{database::DatabaseGuard, pdu::PduBuilder, utils, ConduitResult, Error, Ruma};
+use crate::{
+    database::{rooms::RoomEdus, DatabaseGuard},
+    pdu::PduBuilder,
+    utils, ConduitResult, Database, Error, Result, Ruma,
+};
 use ruma::{
     api::{
         client::{
@@ -10,9 +14,11 @@ use ruma::{
         federation::{self, query::get_profile_information::v1::ProfileField},
     },
     events::{room::member::RoomMemberEventContent, EventType},
+    presence::PresenceState,
+    UserId,
 };
 use serde_json::value::to_raw_value;
-use std::{convert::TryInto, sync::Arc};
+use std::sync::Arc;
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, put};
@@ -90,30 +96,11 @@ pub async fn set_displayname_route(
         let _ = db
             .rooms
             .build_and_append_pdu(pdu_builder, sender_user, &room_id, &db, &state_lock);
-
-        // Presence update
-        db.rooms.edus.update_presence(
-            sender_user,
-            &room_id,
-            ruma::events::presence::PresenceEvent {
-                content: ruma::events::presence::PresenceEventContent {
-                    avatar_url: db.users.avatar_url(sender_user)?,
-                    currently_active: None,
-                    displayname: db.users.displayname(sender_user)?,
-                    last_active_ago: Some(
-                        utils::millis_since_unix_epoch()
-                            .try_into()
-                            .expect("time is valid"),
-                    ),
-                    presence: ruma::presence::PresenceState::Online,
-                    status_msg: None,
-                },
-                sender: sender_user.clone(),
-            },
-            &db.globals,
-        )?;
     }
 
+    // Presence update
+    update_profile_presence(&db, sender_user)?;
+
     db.flush()?;
 
     Ok(set_display_name::Response {}.into())
@@ -233,30 +220,11 @@ pub async fn set_avatar_url_route(
         let _ = db
             .rooms
             .build_and_append_pdu(pdu_builder, sender_user, &room_id, &db, &state_lock);
-
-        // Presence update
-        db.rooms.edus.update_presence(
-            sender_user,
-            &room_id,
-            ruma::events::presence::PresenceEvent {
-                content: ruma::events::presence::PresenceEventContent {
-                    avatar_url: db.users.avatar_url(sender_user)?,
-                    currently_active: None,
-                    displayname: db.users.displayname(sender_user)?,
-                    last_active_ago: Some(
-                        utils::millis_since_unix_epoch()
-                            .try_into()
-                            .expect("time is valid"),
-                    ),
-                    presence: ruma::presence::PresenceState::Online,
-                    status_msg: None,
-                },
-                sender: sender_user.clone(),
-            },
-            &db.globals,
-        )?;
     }
 
+    // Presence update
+    update_profile_presence(&db, sender_user)?;
+
     db.flush()?;
 
     Ok(set_avatar_url::Response {}.into())
@@ -353,3 +321,18 @@ pub async fn get_profile_route(
     }
     .into())
 }
+
+/// Sends the new profile of the user to others with a presence update.
+fn update_profile_presence(db: &Database, user_id: &UserId) -> Result<()> {
+    let current = db.rooms.edus.get_presence_event(user_id)?;
+
+    RoomEdus::set_presence(
+        db,
+        user_id,
+        current.as_ref().map_or(PresenceState::Online, |current| {
+            current.content.presence.clone()
+        }),
+        current.and_then(|current| current.content.status_msg),
+        utils::millis_since_unix_epoch(),
+    )
+}
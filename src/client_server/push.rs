@@ -1,16 +1,18 @@
-use crate::{database::DatabaseGuard, ConduitResult, Error, Ruma};
+use crate::{database::DatabaseGuard, ConduitResult, Error, Result, Ruma};
+use rocket::{http::RawStr, response::content::Html};
 use ruma::{
     api::client::{
         error::ErrorKind,
         r0::push::{
-            delete_pushrule, get_pushers, get_pushrule, get_pushrule_actions, get_pushrule_enabled,
-            get_pushrules_all, set_pusher, set_pushrule, set_pushrule_actions,
-            set_pushrule_enabled, RuleKind,
+            delete_pushrule, get_notifications, get_pushers, get_pushrule, get_pushrule_actions,
+            get_pushrule_enabled, get_pushrules_all, set_pusher, set_pushrule,
+            set_pushrule_actions, set_pushrule_enabled, PusherKind, RuleKind,
         },
     },
     events::{push_rules::PushRulesEvent, EventType},
     push::{ConditionalPushRuleInit, PatternedPushRuleInit, SimplePushRuleInit},
 };
+use std::convert::TryInto;
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{delete, get, post, put};
@@ -567,6 +569,7 @@ pub async fn get_pushers_route(
 ///
 /// Adds a pusher for the sender user.
 ///
+/// - Email pushers can only send to email addresses bound to the account of the user
 /// - TODO: Handle `append`
 #[cfg_attr(
     feature = "conduit_bin",
@@ -580,9 +583,159 @@ pub async fn set_pushers_route(
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
     let pusher = body.pusher.clone();
 
+    if pusher.kind == Some(PusherKind::Email)
+        && db.threepids.find_user("email", &pusher.pushkey)?.as_ref() != Some(sender_user)
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "The pushkey of email pushers must be an email address of your account.",
+        ));
+    }
+
     db.pusher.set_pusher(sender_user, pusher)?;
 
     db.flush()?;
 
     Ok(set_pusher::Response::default().into())
 }
+
+/// # `GET /_matrix/client/r0/notifications`
+///
+/// Paginates backwards through the events that notified the sender user.
+///
+/// - `only=highlight` only returns notifications that highlighted the user
+/// - A notification is read if the read receipt of the user is at or after the event
+/// - Notifications of events that can't be found anymore are skipped
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/notifications", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn get_notifications_route(
+    db: DatabaseGuard,
+    body: Ruma<get_notifications::Request<'_>>,
+) -> ConduitResult<get_notifications::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let from = body
+        .from
+        .as_ref()
+        .map(|from| from.parse())
+        .transpose()
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid from."))?
+        .unwrap_or(u64::MAX);
+
+    let limit = body
+        .limit
+        .map_or(10, u64::from)
+        .min(100)
+        .try_into()
+        .expect("limit is at most 100");
+
+    let only_highlight = body.only.as_deref() == Some("highlight");
+
+    let mut notifications = Vec::new();
+    let mut next_token = None;
+
+    for notification in db.rooms.notifications_until(sender_user, from) {
+        let (count, notification) = notification?;
+
+        if only_highlight && !notification.highlight {
+            continue;
+        }
+
+        if notifications.len() == limit {
+            next_token = Some((count + 1).to_string());
+            break;
+        }
+
+        let pdu = match db.rooms.get_pdu(&notification.event_id)? {
+            Some(pdu) => pdu,
+            None => continue,
+        };
+
+        let read = db
+            .rooms
+            .edus
+            .private_read_get(&notification.room_id, sender_user)?
+            .map_or(false, |read_count| read_count >= count);
+
+        notifications.push(get_notifications::Notification {
+            actions: notification.actions,
+            event: pdu.to_sync_room_event(),
+            profile_tag: None,
+            read,
+            room_id: notification.room_id,
+            ts: notification.ts,
+        });
+    }
+
+    Ok(get_notifications::Response {
+        next_token,
+        notifications,
+    }
+    .into())
+}
+
+/// # `GET /_conduit/client/email/unsubscribe`
+///
+/// Shows a button to remove the email pusher the unsubscribe link of a notification email
+/// belongs to.
+///
+/// - Nothing is removed here, because mail clients and scanners fetch links on their own
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_conduit/client/email/unsubscribe?<token>")
+)]
+#[tracing::instrument(skip(db, token))]
+pub async fn email_unsubscribe_form_route(
+    db: DatabaseGuard,
+    token: String,
+) -> Result<Html<String>> {
+    db.email
+        .senderkey_from_unsubscribe_token(&token)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Unknown unsubscribe link.",
+        ))?;
+
+    Ok(Html(format!(
+        "<!DOCTYPE html>\n\
+        <html>\n\
+        <body>\n\
+        <p>Do you want to stop receiving notification emails from {}?</p>\n\
+        <form method=\"post\" action=\"unsubscribe?token={}\">\n\
+        <button type=\"submit\">Unsubscribe</button>\n\
+        </form>\n\
+        </body>\n\
+        </html>\n",
+        db.globals.server_name(),
+        RawStr::new(&token).html_escape()
+    )))
+}
+
+/// # `POST /_conduit/client/email/unsubscribe`
+///
+/// Removes the email pusher the unsubscribe link of a notification email belongs to.
+///
+/// - Mail clients that support one-click unsubscribing (RFC 8058) post here directly
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_conduit/client/email/unsubscribe?<token>")
+)]
+#[tracing::instrument(skip(db, token))]
+pub async fn email_unsubscribe_route(db: DatabaseGuard, token: String) -> Result<&'static str> {
+    let senderkey = db
+        .email
+        .take_unsubscribe_token(&token)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Unknown unsubscribe link.",
+        ))?;
+
+    db.pusher.remove_pusher(&senderkey)?;
+
+    db.flush()?;
+
+    Ok("You will no longer receive notification emails.")
+}
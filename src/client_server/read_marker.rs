@@ -1,14 +1,18 @@
-use crate::{database::DatabaseGuard, ConduitResult, Error, Ruma};
+use crate::{
+    database::{rooms, DatabaseGuard},
+    ConduitResult, Error, Result, Ruma,
+};
 use ruma::{
     api::client::{
         error::ErrorKind,
         r0::{read_marker::set_read_marker, receipt::create_receipt},
     },
-    events::{AnyEphemeralRoomEvent, EventType},
-    receipt::ReceiptType,
-    MilliSecondsSinceUnixEpoch,
+    events::EventType,
+    signatures::CanonicalJsonValue,
+    EventId, MilliSecondsSinceUnixEpoch, RoomId, UserId,
 };
-use std::collections::BTreeMap;
+use serde_json::json;
+use std::convert::TryFrom;
 
 #[cfg(feature = "conduit_bin")]
 use rocket::post;
@@ -19,6 +23,7 @@ use rocket::post;
 ///
 /// - Updates fully-read account data event to `fully_read`
 /// - If `read_receipt` is set: Update private marker and public read receipt EDU
+/// - If `m.read.private` is set: Update private marker and private read receipt
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/rooms/<_>/read_markers", data = "<body>")
@@ -44,40 +49,27 @@ pub async fn set_read_marker_route(
     )?;
 
     if let Some(event) = &body.read_receipt {
-        db.rooms.edus.private_read_set(
-            &body.room_id,
-            sender_user,
-            db.rooms.get_pdu_count(event)?.ok_or(Error::BadRequest(
-                ErrorKind::InvalidParam,
-                "Event does not exist.",
-            ))?,
-            &db.globals,
-        )?;
-        db.rooms
-            .reset_notification_counts(sender_user, &body.room_id)?;
-
-        let mut user_receipts = BTreeMap::new();
-        user_receipts.insert(
-            sender_user.clone(),
-            ruma::events::receipt::Receipt {
-                ts: Some(MilliSecondsSinceUnixEpoch::now()),
-            },
-        );
-
-        let mut receipts = BTreeMap::new();
-        receipts.insert(ReceiptType::Read, user_receipts);
+        set_receipt(&db, sender_user, &body.room_id, event, "m.read", None)?;
+    }
 
-        let mut receipt_content = BTreeMap::new();
-        receipt_content.insert(event.to_owned(), receipts);
+    // Ruma doesn't know about private read receipts yet
+    let private_receipt = match &body.json_body {
+        Some(CanonicalJsonValue::Object(json_body)) => json_body
+            .get("m.read.private")
+            .or_else(|| json_body.get("org.matrix.msc2285.read.private")),
+        _ => None,
+    };
 
-        db.rooms.edus.readreceipt_update(
+    if let Some(CanonicalJsonValue::String(event)) = private_receipt {
+        let event = EventId::try_from(event.as_str())
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid event id."))?;
+        set_receipt(
+            &db,
             sender_user,
             &body.room_id,
-            AnyEphemeralRoomEvent::Receipt(ruma::events::receipt::ReceiptEvent {
-                content: ruma::events::receipt::ReceiptEventContent(receipt_content),
-                room_id: body.room_id.clone(),
-            }),
-            &db.globals,
+            &event,
+            "m.read.private",
+            None,
         )?;
     }
 
@@ -88,7 +80,11 @@ pub async fn set_read_marker_route(
 
 /// # `POST /_matrix/client/r0/rooms/{roomId}/receipt/{receiptType}/{eventId}`
 ///
-/// Sets private read marker and public read receipt EDU.
+/// Sets private read marker and a read receipt.
+///
+/// - `m.read` receipts are sent to other users and servers
+/// - `m.read.private` receipts are only visible to the sender
+/// - A `thread_id` in the body only marks that thread (or the main timeline for `main`) as read
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/rooms/<_>/receipt/<_>/<_>", data = "<body>")
@@ -100,44 +96,134 @@ pub async fn create_receipt_route(
 ) -> ConduitResult<create_receipt::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    db.rooms.edus.private_read_set(
-        &body.room_id,
-        sender_user,
-        db.rooms
-            .get_pdu_count(&body.event_id)?
-            .ok_or(Error::BadRequest(
-                ErrorKind::InvalidParam,
-                "Event does not exist.",
-            ))?,
-        &db.globals,
-    )?;
-    db.rooms
-        .reset_notification_counts(sender_user, &body.room_id)?;
-
-    let mut user_receipts = BTreeMap::new();
-    user_receipts.insert(
-        sender_user.clone(),
-        ruma::events::receipt::Receipt {
-            ts: Some(MilliSecondsSinceUnixEpoch::now()),
+    // Ruma doesn't know about threaded read receipts yet
+    let thread_id = match &body.json_body {
+        Some(CanonicalJsonValue::Object(json_body)) => match json_body.get("thread_id") {
+            Some(CanonicalJsonValue::String(thread_id)) => Some(thread_id.as_str()),
+            Some(_) => {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "thread_id has to be a string.",
+                ))
+            }
+            None => None,
         },
-    );
-    let mut receipts = BTreeMap::new();
-    receipts.insert(ReceiptType::Read, user_receipts);
-
-    let mut receipt_content = BTreeMap::new();
-    receipt_content.insert(body.event_id.to_owned(), receipts);
+        _ => None,
+    };
 
-    db.rooms.edus.readreceipt_update(
+    set_receipt(
+        &db,
         sender_user,
         &body.room_id,
-        AnyEphemeralRoomEvent::Receipt(ruma::events::receipt::ReceiptEvent {
-            content: ruma::events::receipt::ReceiptEventContent(receipt_content),
-            room_id: body.room_id.clone(),
-        }),
-        &db.globals,
+        &body.event_id,
+        body.receipt_type.as_ref(),
+        thread_id,
     )?;
 
     db.flush()?;
 
     Ok(create_receipt::Response {}.into())
 }
+
+/// Marks the event as read and stores the receipt.
+fn set_receipt(
+    db: &DatabaseGuard,
+    sender_user: &UserId,
+    room_id: &RoomId,
+    event_id: &EventId,
+    receipt_type: &str,
+    thread_id: Option<&str>,
+) -> Result<()> {
+    let receipt_type = match receipt_type {
+        "m.read" => "m.read",
+        "m.read.private" | "org.matrix.msc2285.read.private" => "m.read.private",
+        _ => {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Unsupported receipt type.",
+            ))
+        }
+    };
+
+    let pdu = db
+        .rooms
+        .get_pdu(event_id)?
+        .filter(|pdu| &pdu.room_id == room_id)
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event does not exist.",
+        ))?;
+    if let Some(thread_id) = thread_id {
+        if !rooms::is_in_thread(&pdu, thread_id) {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Event is not in this thread.",
+            ));
+        }
+    }
+
+    let count = db.rooms.get_pdu_count(event_id)?.ok_or(Error::BadRequest(
+        ErrorKind::InvalidParam,
+        "Event does not exist.",
+    ))?;
+
+    match thread_id {
+        None => {
+            db.rooms
+                .edus
+                .private_read_set(room_id, sender_user, count, &db.globals)?;
+            db.rooms.reset_notification_counts(sender_user, room_id)?;
+        }
+        Some("main") => {
+            db.rooms
+                .edus
+                .private_read_set(room_id, sender_user, count, &db.globals)?;
+            db.rooms
+                .reset_thread_notification_counts(sender_user, room_id, None)?;
+        }
+        Some(thread_root) => {
+            let thread_root = EventId::try_from(thread_root)
+                .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid thread_id."))?;
+            db.rooms
+                .edus
+                .private_read_touch(room_id, sender_user, &db.globals)?;
+            db.rooms
+                .reset_thread_notification_counts(sender_user, room_id, Some(&thread_root))?;
+        }
+    }
+
+    let mut receipt = json!({ "ts": MilliSecondsSinceUnixEpoch::now() });
+    if let Some(thread_id) = thread_id {
+        receipt["thread_id"] = json!(thread_id);
+    }
+
+    let event = json!({
+        "type": "m.receipt",
+        "room_id": room_id,
+        "content": {
+            event_id.as_str(): {
+                receipt_type: {
+                    sender_user.as_str(): receipt,
+                },
+            },
+        },
+    });
+
+    if receipt_type == "m.read.private" {
+        db.rooms.edus.private_receipt_update(
+            sender_user,
+            room_id,
+            thread_id,
+            &event,
+            &db.globals,
+        )?;
+    } else {
+        db.sending
+            .send_edu_appservices(&[room_id.clone()], &event, db)?;
+        db.rooms
+            .edus
+            .readreceipt_update(sender_user, room_id, thread_id, &event, &db.globals)?;
+    }
+
+    Ok(())
+}
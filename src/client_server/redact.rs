@@ -1,6 +1,6 @@
 use std::sync::Arc;
 
-use crate::{database::DatabaseGuard, pdu::PduBuilder, ConduitResult, Ruma};
+use crate::{database::DatabaseGuard, pdu::PduBuilder, utils, ConduitResult, Ruma};
 use ruma::{
     api::client::r0::redact::redact_event,
     events::{room::redaction::RoomRedactionEventContent, EventType},
@@ -26,6 +26,14 @@ pub async fn redact_event_route(
 ) -> ConduitResult<redact_event::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    // Shadow-banned users should not notice that their redactions are thrown away
+    if db.users.is_shadow_banned(sender_user)? {
+        return Ok(redact_event::Response {
+            event_id: utils::random_event_id(),
+        }
+        .into());
+    }
+
     let mutex_state = Arc::clone(
         db.globals
             .roomid_mutex_state
@@ -0,0 +1,372 @@
+use crate::{
+    database::{rooms::Relation, DatabaseGuard},
+    ConduitResult, Error, Result, Ruma,
+};
+use ruma::{
+    api::client::{error::ErrorKind, r0::message::get_message_events::Direction},
+    events::{AnyRoomEvent, EventType},
+    serde::Raw,
+    EventId, RoomId, UInt, UserId,
+};
+use std::convert::TryInto;
+
+#[cfg(feature = "conduit_bin")]
+use rocket::get;
+
+/// The endpoints of MSC2675, which are not part of our ruma version yet. They only differ in the
+/// filters in their path.
+pub mod get_relating_events {
+    use ruma::{api::ruma_api, events::AnyRoomEvent, serde::Raw, EventId, RoomId, UInt};
+
+    ruma_api! {
+        metadata: {
+            description: "Get the child events for a given parent event.",
+            method: GET,
+            name: "get_relating_events",
+            path: "/_matrix/client/unstable/rooms/:room_id/relations/:event_id",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            #[ruma_api(path)]
+            pub room_id: &'a RoomId,
+
+            #[ruma_api(path)]
+            pub event_id: &'a EventId,
+
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub from: Option<&'a str>,
+
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub to: Option<&'a str>,
+
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub limit: Option<UInt>,
+
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub dir: Option<super::Direction>,
+        }
+
+        response: {
+            pub chunk: Vec<Raw<AnyRoomEvent>>,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub next_batch: Option<String>,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub prev_batch: Option<String>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+pub mod get_relating_events_with_rel_type {
+    use ruma::{api::ruma_api, events::AnyRoomEvent, serde::Raw, EventId, RoomId, UInt};
+
+    ruma_api! {
+        metadata: {
+            description: "Get the child events for a given parent event, with a given relType.",
+            method: GET,
+            name: "get_relating_events_with_rel_type",
+            path: "/_matrix/client/unstable/rooms/:room_id/relations/:event_id/:rel_type",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            #[ruma_api(path)]
+            pub room_id: &'a RoomId,
+
+            #[ruma_api(path)]
+            pub event_id: &'a EventId,
+
+            #[ruma_api(path)]
+            pub rel_type: &'a str,
+
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub from: Option<&'a str>,
+
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub to: Option<&'a str>,
+
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub limit: Option<UInt>,
+
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub dir: Option<super::Direction>,
+        }
+
+        response: {
+            pub chunk: Vec<Raw<AnyRoomEvent>>,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub next_batch: Option<String>,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub prev_batch: Option<String>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+pub mod get_relating_events_with_rel_type_and_event_type {
+    use ruma::{api::ruma_api, events::AnyRoomEvent, serde::Raw, EventId, RoomId, UInt};
+
+    ruma_api! {
+        metadata: {
+            description: "Get the child events for a given parent event, with a given relType and eventType.",
+            method: GET,
+            name: "get_relating_events_with_rel_type_and_event_type",
+            path: "/_matrix/client/unstable/rooms/:room_id/relations/:event_id/:rel_type/:event_type",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            #[ruma_api(path)]
+            pub room_id: &'a RoomId,
+
+            #[ruma_api(path)]
+            pub event_id: &'a EventId,
+
+            #[ruma_api(path)]
+            pub rel_type: &'a str,
+
+            #[ruma_api(path)]
+            pub event_type: &'a str,
+
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub from: Option<&'a str>,
+
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub to: Option<&'a str>,
+
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub limit: Option<UInt>,
+
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub dir: Option<super::Direction>,
+        }
+
+        response: {
+            pub chunk: Vec<Raw<AnyRoomEvent>>,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub next_batch: Option<String>,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub prev_batch: Option<String>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+/// # `GET /_matrix/client/unstable/rooms/{roomId}/relations/{eventId}`
+///
+/// Paginates through the events that relate to an event.
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/unstable/rooms/<_>/relations/<_>", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn get_relating_events_route(
+    db: DatabaseGuard,
+    body: Ruma<get_relating_events::Request<'_>>,
+) -> ConduitResult<get_relating_events::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let (chunk, next_batch) = relations_helper(
+        &db,
+        sender_user,
+        &body.room_id,
+        &body.event_id,
+        None,
+        None,
+        body.from.as_deref(),
+        body.to.as_deref(),
+        body.limit,
+        body.dir.as_ref(),
+    )?;
+
+    Ok(get_relating_events::Response {
+        chunk,
+        next_batch,
+        prev_batch: body.from.clone(),
+    }
+    .into())
+}
+
+/// # `GET /_matrix/client/unstable/rooms/{roomId}/relations/{eventId}/{relType}`
+///
+/// Paginates through the events that relate to an event with the given relation type.
+#[cfg_attr(
+    feature = "conduit_bin",
+    get(
+        "/_matrix/client/unstable/rooms/<_>/relations/<_>/<_>",
+        data = "<body>"
+    )
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn get_relating_events_with_rel_type_route(
+    db: DatabaseGuard,
+    body: Ruma<get_relating_events_with_rel_type::Request<'_>>,
+) -> ConduitResult<get_relating_events_with_rel_type::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let (chunk, next_batch) = relations_helper(
+        &db,
+        sender_user,
+        &body.room_id,
+        &body.event_id,
+        Some(body.rel_type.as_str()),
+        None,
+        body.from.as_deref(),
+        body.to.as_deref(),
+        body.limit,
+        body.dir.as_ref(),
+    )?;
+
+    Ok(get_relating_events_with_rel_type::Response {
+        chunk,
+        next_batch,
+        prev_batch: body.from.clone(),
+    }
+    .into())
+}
+
+/// # `GET /_matrix/client/unstable/rooms/{roomId}/relations/{eventId}/{relType}/{eventType}`
+///
+/// Paginates through the events of a type that relate to an event with the given relation type.
+#[cfg_attr(
+    feature = "conduit_bin",
+    get(
+        "/_matrix/client/unstable/rooms/<_>/relations/<_>/<_>/<_>",
+        data = "<body>"
+    )
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn get_relating_events_with_rel_type_and_event_type_route(
+    db: DatabaseGuard,
+    body: Ruma<get_relating_events_with_rel_type_and_event_type::Request<'_>>,
+) -> ConduitResult<get_relating_events_with_rel_type_and_event_type::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let (chunk, next_batch) = relations_helper(
+        &db,
+        sender_user,
+        &body.room_id,
+        &body.event_id,
+        Some(body.rel_type.as_str()),
+        Some(&EventType::from(&*body.event_type)),
+        body.from.as_deref(),
+        body.to.as_deref(),
+        body.limit,
+        body.dir.as_ref(),
+    )?;
+
+    Ok(get_relating_events_with_rel_type_and_event_type::Response {
+        chunk,
+        next_batch,
+        prev_batch: body.from.clone(),
+    }
+    .into())
+}
+
+/// Returns a page of events relating to the event and the token for the next page.
+///
+/// - Only works if the user is joined
+/// - Newest events come first, unless `dir` is forward
+#[allow(clippy::too_many_arguments)]
+fn relations_helper(
+    db: &DatabaseGuard,
+    sender_user: &UserId,
+    room_id: &RoomId,
+    event_id: &EventId,
+    rel_type: Option<&str>,
+    event_type: Option<&EventType>,
+    from: Option<&str>,
+    to: Option<&str>,
+    limit: Option<UInt>,
+    dir: Option<&Direction>,
+) -> Result<(Vec<Raw<AnyRoomEvent>>, Option<String>)> {
+    if !db.rooms.is_joined(sender_user, room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You don't have permission to view this room.",
+        ));
+    }
+
+    if db
+        .rooms
+        .get_pdu(event_id)?
+        .filter(|pdu| &pdu.room_id == room_id)
+        .is_none()
+    {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Event not found."));
+    }
+
+    let from = from
+        .map(|from| from.parse())
+        .transpose()
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `from` value."))?;
+
+    let to = to
+        .map(|to| to.parse::<u64>())
+        .transpose()
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `to` value."))?;
+
+    // Use limit or else 5, but at most 100
+    let limit = limit
+        .and_then(|limit| limit.try_into().ok())
+        .map_or(5_usize, |limit: u32| limit as usize)
+        .min(100);
+
+    let backwards = !matches!(dir, Some(Direction::Forward));
+
+    let mut relations = db
+        .rooms
+        .relations(sender_user, room_id, event_id, from, backwards)
+        .filter_map(|r| r.ok()) // Filter out buggy events
+        .take_while(|&(count, _)| Some(count) != to) // Stop at `to`
+        .filter(|(_, pdu)| {
+            Relation::from_pdu(pdu).map_or(false, |relation| {
+                rel_type.map_or(true, |rel_type| relation.rel_type == rel_type)
+            }) && event_type.map_or(true, |event_type| &pdu.kind == event_type)
+        })
+        .take(limit + 1)
+        .collect::<Vec<_>>();
+
+    // We asked for one more event to find out if there is a next page
+    let next_batch = if relations.len() > limit {
+        relations.truncate(limit);
+        relations.last().map(|(count, _)| count.to_string())
+    } else {
+        None
+    };
+
+    let chunk = relations
+        .into_iter()
+        .map(|(_, mut pdu)| {
+            db.rooms.add_bundled_aggregations(sender_user, &mut pdu)?;
+            Ok(pdu.to_room_event())
+        })
+        .collect::<Result<_>>()?;
+
+    Ok((chunk, next_batch))
+}
@@ -12,6 +12,8 @@ use rocket::{http::RawStr, post};
 ///
 /// Reports an inappropriate event to homeserver admins
 ///
+/// - Stores the report with a snapshot of the event, so admins can manage it later
+/// - Notifies the admin room
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/rooms/<_>/report/<_>", data = "<body>")
@@ -47,31 +49,55 @@ pub async fn report_event_route(
         ));
     };
 
+    let event = db
+        .rooms
+        .get_pdu_json(&body.event_id)?
+        .ok_or_else(|| Error::bad_database("Event in db has no json."))?;
+
+    let report_id = db.reports.create(
+        sender_user,
+        &pdu.room_id,
+        &pdu.event_id,
+        &pdu.sender,
+        body.score,
+        &body.reason,
+        event,
+        &db.globals,
+    )?;
+
     db.admin.send(AdminCommand::SendMessage(
         message::RoomMessageEventContent::text_html(
             format!(
-                "Report received from: {}\n\n\
+                "Report {} received from: {}\n\n\
                 Event ID: {}\n\
                 Room ID: {}\n\
                 Sent By: {}\n\n\
                 Report Score: {}\n\
-                Report Reason: {}",
-                sender_user, pdu.event_id, pdu.room_id, pdu.sender, body.score, body.reason
+                Report Reason: {}\n\n\
+                Use show_report {0} to see the event.",
+                report_id,
+                sender_user,
+                pdu.event_id,
+                pdu.room_id,
+                pdu.sender,
+                body.score,
+                body.reason
             )
             .to_owned(),
             format!(
-                "<details><summary>Report received from: <a href=\"https://matrix.to/#/{0}\">{0}\
+                "<details><summary>Report {6} received from: <a href=\"https://matrix.to/#/{0}\">{0}\
                 </a></summary><ul><li>Event Info<ul><li>Event ID: <code>{1}</code>\
                 <a href=\"https://matrix.to/#/{2}/{1}\">🔗</a></li><li>Room ID: <code>{2}</code>\
                 </li><li>Sent By: <a href=\"https://matrix.to/#/{3}\">{3}</a></li></ul></li><li>\
                 Report Info<ul><li>Report Score: {4}</li><li>Report Reason: {5}</li></ul></li>\
-                </ul></details>",
+                </ul><p>Use <code>show_report {6}</code> to see the event.</p></details>",
                 sender_user,
                 pdu.event_id,
                 pdu.room_id,
                 pdu.sender,
                 body.score,
-                RawStr::new(&body.reason).html_escape()
+                RawStr::new(&body.reason).html_escape(),
+                report_id
             )
             .to_owned(),
         ),
@@ -441,12 +441,15 @@ pub async fn get_room_event_route(
         ));
     }
 
+    let mut event = (*db
+        .rooms
+        .get_pdu(&body.event_id)?
+        .ok_or(Error::BadRequest(ErrorKind::NotFound, "Event not found."))?)
+    .clone();
+    db.rooms.add_bundled_aggregations(sender_user, &mut event)?;
+
     Ok(get_room_event::Response {
-        event: db
-            .rooms
-            .get_pdu(&body.event_id)?
-            .ok_or(Error::BadRequest(ErrorKind::NotFound, "Event not found."))?
-            .to_room_event(),
+        event: event.to_room_event(),
     }
     .into())
 }
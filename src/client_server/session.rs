@@ -1,5 +1,7 @@
 use super::{DEVICE_ID_LENGTH, TOKEN_LENGTH};
-use crate::{database::DatabaseGuard, utils, ConduitResult, Error, Ruma};
+use crate::{
+    database::DatabaseGuard, utils, ConduitResult, Error, Result, Ruma, RumaResponseWithFields,
+};
 use ruma::{
     api::client::{
         error::ErrorKind,
@@ -8,10 +10,13 @@ use ruma::{
             uiaa::IncomingUserIdentifier,
         },
     },
-    UserId,
+    signatures::CanonicalJsonValue,
+    DeviceId, UInt, UserId,
 };
 use serde::Deserialize;
-use tracing::info;
+use serde_json::{Map as JsonMap, Value as JsonValue};
+use std::{convert::TryFrom, net::IpAddr};
+use tracing::{info, warn};
 
 #[derive(Debug, Deserialize)]
 struct Claims {
@@ -22,29 +27,102 @@ struct Claims {
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, post};
 
+/// The refresh endpoint of MSC2918, which is not part of our ruma version yet.
+pub mod refresh {
+    use ruma::{api::ruma_api, UInt};
+
+    ruma_api! {
+        metadata: {
+            description: "Refresh an access token.",
+            method: POST,
+            name: "refresh",
+            path: "/_matrix/client/v1/refresh",
+            rate_limited: true,
+            authentication: None,
+        }
+
+        request: {
+            pub refresh_token: &'a str,
+        }
+
+        response: {
+            pub access_token: String,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub refresh_token: Option<String>,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub expires_in_ms: Option<UInt>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+/// The unstable path of the [refresh endpoint](refresh/index.html).
+pub mod refresh_unstable {
+    use ruma::{api::ruma_api, UInt};
+
+    ruma_api! {
+        metadata: {
+            description: "Refresh an access token.",
+            method: POST,
+            name: "refresh_unstable",
+            path: "/_matrix/client/unstable/org.matrix.msc2918.refresh_token/refresh",
+            rate_limited: true,
+            authentication: None,
+        }
+
+        request: {
+            pub refresh_token: &'a str,
+        }
+
+        response: {
+            pub access_token: String,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub refresh_token: Option<String>,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub expires_in_ms: Option<UInt>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
 /// # `GET /_matrix/client/r0/login`
 ///
 /// Get the supported login types of this server. One of these should be used as the `type` field
 /// when logging in.
 #[cfg_attr(feature = "conduit_bin", get("/_matrix/client/r0/login"))]
-#[tracing::instrument]
-pub async fn get_login_types_route() -> ConduitResult<get_login_types::Response> {
-    Ok(
-        get_login_types::Response::new(vec![get_login_types::LoginType::Password(
-            Default::default(),
-        )])
-        .into(),
-    )
+#[tracing::instrument(skip(db))]
+pub async fn get_login_types_route(db: DatabaseGuard) -> ConduitResult<get_login_types::Response> {
+    let mut flows = vec![get_login_types::LoginType::Password(Default::default())];
+
+    if db.globals.oidc().is_some() {
+        flows.push(get_login_types::LoginType::Sso(Default::default()));
+    }
+
+    if db.globals.oidc().is_some() || db.globals.jwt_decoding_key().is_some() {
+        flows.push(get_login_types::LoginType::Token(Default::default()));
+    }
+
+    Ok(get_login_types::Response::new(flows).into())
 }
 
 /// # `POST /_matrix/client/r0/login`
 ///
 /// Authenticates the user and returns an access token it can use in subsequent requests.
 ///
-/// - The user needs to authenticate using their password (or if enabled using a json web token)
+/// - The user needs to authenticate using their password (or if enabled using a json web token or
+/// a login token from an SSO login)
+/// - Failed password logins of a user or IP address lead to increasing delays and finally a
+/// temporary lockout
 /// - If `device_id` is known: invalidates old access token of that device
 /// - If `device_id` is unknown: creates a new device
 /// - Returns access token that is associated with the user and device
+/// - If the client sets `refresh_token`: the access token expires and a refresh token is returned
 ///
 /// Note: You can use [`GET /_matrix/client/r0/login`](fn.get_supported_versions_route.html) to see
 /// supported login types.
@@ -55,8 +133,9 @@ pub async fn get_login_types_route() -> ConduitResult<get_login_types::Response>
 #[tracing::instrument(skip(db, body))]
 pub async fn login_route(
     db: DatabaseGuard,
+    client_ip: Option<IpAddr>,
     body: Ruma<login::Request<'_>>,
-) -> ConduitResult<login::Response> {
+) -> Result<RumaResponseWithFields<login::Response>> {
     // Validate login method
     // TODO: Other login methods
     let user_id = match &body.login_info {
@@ -74,10 +153,28 @@ pub async fn login_route(
                     .map_err(|_| {
                         Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid.")
                     })?;
-            let hash = db.users.password_hash(&user_id)?.ok_or(Error::BadRequest(
-                ErrorKind::Forbidden,
-                "Wrong username or password.",
-            ))?;
+
+            if let Err(retry_after) = db.globals.login_lockout.check(Some(&user_id), client_ip) {
+                return Err(Error::BadRequest(
+                    ErrorKind::LimitExceeded {
+                        retry_after_ms: Some(retry_after),
+                    },
+                    "Too many failed login attempts.",
+                ));
+            }
+
+            let hash = match db.users.password_hash(&user_id)? {
+                Some(hash) => hash,
+                None => {
+                    db.globals
+                        .login_lockout
+                        .record_failure(Some(&user_id), client_ip);
+                    return Err(Error::BadRequest(
+                        ErrorKind::Forbidden,
+                        "Wrong username or password.",
+                    ));
+                }
+            };
 
             if hash.is_empty() {
                 return Err(Error::BadRequest(
@@ -89,16 +186,24 @@ pub async fn login_route(
             let hash_matches = argon2::verify_encoded(&hash, password.as_bytes()).unwrap_or(false);
 
             if !hash_matches {
+                warn!("Failed login for {} from {:?}", user_id, client_ip);
+                db.globals
+                    .login_lockout
+                    .record_failure(Some(&user_id), client_ip);
                 return Err(Error::BadRequest(
                     ErrorKind::Forbidden,
                     "Wrong username or password.",
                 ));
             }
 
+            db.globals.login_lockout.unlock_user(&user_id);
+
             user_id
         }
         login::IncomingLoginInfo::Token(login::IncomingToken { token }) => {
-            if let Some(jwt_decoding_key) = db.globals.jwt_decoding_key() {
+            if let Some(user_id) = db.sso.redeem_login_token(token)? {
+                user_id
+            } else if let Some(jwt_decoding_key) = db.globals.jwt_decoding_key() {
                 let token = jsonwebtoken::decode::<Claims>(
                     token,
                     jwt_decoding_key,
@@ -109,6 +214,11 @@ pub async fn login_route(
                 UserId::parse_with_server_name(username, db.globals.server_name()).map_err(
                     |_| Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid."),
                 )?
+            } else if db.globals.oidc().is_some() {
+                return Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "Login token is invalid or expired.",
+                ));
             } else {
                 return Err(Error::BadRequest(
                     ErrorKind::Unknown,
@@ -151,20 +261,159 @@ pub async fn login_route(
         )?;
     }
 
+    let fields = if wants_refresh_token(&body.json_body) {
+        issue_refresh_token(&db, &user_id, &device_id, &token)?
+    } else {
+        JsonMap::new()
+    };
+
     info!("{} logged in", user_id);
 
     db.flush()?;
 
-    Ok(login::Response {
-        user_id,
-        access_token: token,
-        home_server: Some(db.globals.server_name().to_owned()),
-        device_id,
-        well_known: None,
+    Ok(RumaResponseWithFields {
+        response: login::Response {
+            user_id,
+            access_token: token,
+            home_server: Some(db.globals.server_name().to_owned()),
+            device_id,
+            well_known: None,
+        },
+        fields,
+    })
+}
+
+/// # `POST /_matrix/client/v1/refresh`
+///
+/// Exchanges a refresh token for a new access token and a new refresh token.
+///
+/// - The old access token and refresh token stop working
+/// - If a refresh token is used again after it was exchanged, the token was probably stolen, so
+/// the device is logged out
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/v1/refresh", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn refresh_route(
+    db: DatabaseGuard,
+    body: Ruma<refresh::Request<'_>>,
+) -> ConduitResult<refresh::Response> {
+    let (access_token, refresh_token, expires_in_ms) = refresh(&db, body.refresh_token)?;
+
+    Ok(refresh::Response {
+        access_token,
+        refresh_token: Some(refresh_token),
+        expires_in_ms: Some(expires_in_ms),
     }
     .into())
 }
 
+/// # `POST /_matrix/client/unstable/org.matrix.msc2918.refresh_token/refresh`
+///
+/// Unstable version of [`POST /_matrix/client/v1/refresh`](fn.refresh_route.html).
+#[cfg_attr(
+    feature = "conduit_bin",
+    post(
+        "/_matrix/client/unstable/org.matrix.msc2918.refresh_token/refresh",
+        data = "<body>"
+    )
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn refresh_unstable_route(
+    db: DatabaseGuard,
+    body: Ruma<refresh_unstable::Request<'_>>,
+) -> ConduitResult<refresh_unstable::Response> {
+    let (access_token, refresh_token, expires_in_ms) = refresh(&db, body.refresh_token)?;
+
+    Ok(refresh_unstable::Response {
+        access_token,
+        refresh_token: Some(refresh_token),
+        expires_in_ms: Some(expires_in_ms),
+    }
+    .into())
+}
+
+/// Rotates the tokens of the device the refresh token belongs to. Returns the new access token,
+/// the new refresh token and the lifetime of the access token.
+fn refresh(db: &DatabaseGuard, refresh_token: &str) -> Result<(String, String, UInt)> {
+    let (user_id, device_id) = match db.users.find_from_refresh_token(refresh_token)? {
+        Some(device) => device,
+        None => {
+            if let Some((user_id, device_id)) =
+                db.users.find_from_previous_refresh_token(refresh_token)?
+            {
+                warn!(
+                    "Rotated refresh token of {} {} was used again, logging out the device",
+                    user_id, device_id
+                );
+                db.users.remove_device(&user_id, &device_id)?;
+                db.flush()?;
+            }
+
+            return Err(Error::BadRequest(
+                ErrorKind::UnknownToken { soft_logout: false },
+                "Unknown refresh token.",
+            ));
+        }
+    };
+
+    let token = utils::random_string(TOKEN_LENGTH);
+    let refresh_token = utils::random_string(TOKEN_LENGTH);
+    let lifetime = db.globals.access_token_lifetime();
+
+    db.users
+        .rotate_refresh_token(&user_id, &device_id, &token, &refresh_token, lifetime)?;
+
+    db.flush()?;
+
+    Ok((
+        token,
+        refresh_token,
+        UInt::try_from(lifetime.as_millis()).unwrap_or(UInt::MAX),
+    ))
+}
+
+/// Clients opt into refresh tokens by setting `refresh_token` in the login or registration
+/// request.
+pub(super) fn wants_refresh_token(json_body: &Option<CanonicalJsonValue>) -> bool {
+    match json_body {
+        Some(CanonicalJsonValue::Object(body)) => {
+            matches!(
+                body.get("refresh_token"),
+                Some(CanonicalJsonValue::Bool(true))
+            )
+        }
+        _ => false,
+    }
+}
+
+/// Makes the access token of the device expire and returns the response fields with the new
+/// refresh token.
+pub(super) fn issue_refresh_token(
+    db: &DatabaseGuard,
+    user_id: &UserId,
+    device_id: &DeviceId,
+    token: &str,
+) -> Result<JsonMap<String, JsonValue>> {
+    let refresh_token = utils::random_string(TOKEN_LENGTH);
+    let lifetime = db.globals.access_token_lifetime();
+
+    db.users
+        .set_refreshable_token(user_id, device_id, token, &refresh_token, lifetime)?;
+
+    let mut fields = JsonMap::new();
+    fields.insert("refresh_token".to_owned(), refresh_token.into());
+    fields.insert(
+        "expires_in_ms".to_owned(),
+        u64::try_from(lifetime.as_millis())
+            .unwrap_or(u64::MAX)
+            .into(),
+    );
+
+    Ok(fields)
+}
+
 /// # `POST /_matrix/client/r0/logout`
 ///
 /// Log out the current device.
@@ -0,0 +1,474 @@
+use crate::{
+    database::{sso::OidcConfig, DatabaseGuard},
+    utils, Error, Result, Ruma,
+};
+use reqwest::Url;
+use rocket::{
+    http::{Cookie, CookieJar, RawStr, SameSite},
+    response::{content::Html, Redirect},
+};
+use ruma::{
+    api::client::{error::ErrorKind, r0::session::sso_login},
+    events::EventType,
+    push, UserId,
+};
+use serde::Deserialize;
+use serde_json::{Map as JsonMap, Value as JsonValue};
+use tracing::{info, warn};
+
+#[cfg(feature = "conduit_bin")]
+use rocket::get;
+
+/// Length of the random password of users that were created by an SSO login. Nobody knows this
+/// password, so these users can only log in through SSO.
+const SSO_PASSWORD_LENGTH: usize = 64;
+/// The cookie that ties the state of an SSO login to the browser that started it, so nobody can
+/// log a victim in to the attacker's account by sending them a callback link.
+const OIDC_STATE_COOKIE: &str = "conduit_oidc_state";
+
+#[derive(Deserialize)]
+struct OidcTokenResponse {
+    access_token: String,
+}
+
+/// # `GET /_matrix/client/r0/login/sso/redirect`
+///
+/// Redirects the user to the OpenID Connect identity provider to log in.
+///
+/// - After logging in, the identity provider sends the user to the
+/// [callback](fn.oidc_callback_route.html), which in turn asks the user to confirm that they
+/// want to log in to `redirectUrl` and links there with a `loginToken` the client can use with
+/// `m.login.token`
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/login/sso/redirect", data = "<body>")
+)]
+#[tracing::instrument(skip(db, cookies, body))]
+pub async fn sso_login_route(
+    db: DatabaseGuard,
+    cookies: &CookieJar<'_>,
+    body: Ruma<sso_login::Request<'_>>,
+) -> Result<Redirect> {
+    let config = db.globals.oidc().ok_or(Error::BadRequest(
+        ErrorKind::Unrecognized,
+        "SSO login is not enabled on this server.",
+    ))?;
+
+    Url::parse(&body.redirect_url)
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid redirectUrl."))?;
+
+    let state = db.sso.create_oidc_state(&body.redirect_url)?;
+
+    let authorization_url = Url::parse_with_params(
+        &config.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", &config.client_id),
+            ("redirect_uri", &config.callback_url),
+            ("scope", &config.scopes.join(" ")),
+            ("state", &state),
+        ],
+    )
+    .map_err(|_| Error::bad_config("Invalid OIDC authorization endpoint."))?;
+
+    // The identity provider sends the browser back with a top-level GET, which keeps lax cookies
+    cookies.add(
+        Cookie::build(OIDC_STATE_COOKIE, state)
+            .path("/_conduit/client/oidc")
+            .http_only(true)
+            .secure(config.callback_url.starts_with("https://"))
+            .same_site(SameSite::Lax)
+            .finish(),
+    );
+
+    db.flush()?;
+
+    Ok(Redirect::to(authorization_url.to_string()))
+}
+
+/// # `GET /_conduit/client/oidc/callback`
+///
+/// The identity provider redirects the user here after a successful login.
+///
+/// - Only accepts the state in the browser that started the login
+/// - Exchanges the authorization code for an access token and fetches the claims of the user
+/// - Identity provider users are mapped to the same local user on every login. On the first login,
+/// the localpart is taken from the configured claim and the user is created if needed
+/// - Shows a page that names the host of the `redirectUrl` and links there with a `loginToken`.
+/// The user has to confirm, otherwise anyone could send them a login link that hands their
+/// login token to a host of the attacker
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_conduit/client/oidc/callback?<code>&<state>")
+)]
+#[tracing::instrument(skip(db, cookies, code, state))]
+pub async fn oidc_callback_route(
+    db: DatabaseGuard,
+    cookies: &CookieJar<'_>,
+    code: String,
+    state: String,
+) -> Result<Html<String>> {
+    let config = db.globals.oidc().ok_or(Error::BadRequest(
+        ErrorKind::Unrecognized,
+        "SSO login is not enabled on this server.",
+    ))?;
+
+    if cookies.get(OIDC_STATE_COOKIE).map(|cookie| cookie.value()) != Some(state.as_str()) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This SSO login was started in a different browser.",
+        ));
+    }
+    cookies.remove(
+        Cookie::build(OIDC_STATE_COOKIE, "")
+            .path("/_conduit/client/oidc")
+            .finish(),
+    );
+
+    let redirect_url = db.sso.take_oidc_state(&state)?.ok_or(Error::BadRequest(
+        ErrorKind::Forbidden,
+        "Unknown or expired SSO login.",
+    ))?;
+
+    let client = db.globals.reqwest_client()?.build()?;
+    let claims = fetch_claims(&client, config, &code).await?;
+
+    let subject =
+        claims
+            .get("sub")
+            .and_then(|sub| sub.as_str())
+            .ok_or(Error::BadServerResponse(
+                "User info from identity provider has no subject.",
+            ))?;
+
+    let user_id = match db.sso.user_for_subject(subject)? {
+        Some(user_id) => user_id,
+        None => {
+            let localpart = claims
+                .get(&config.localpart_claim)
+                .and_then(|localpart| localpart.as_str())
+                .map(localpart_from_claim)
+                .filter(|localpart| !localpart.is_empty())
+                .ok_or(Error::BadServerResponse(
+                    "User info from identity provider has no usable localpart claim.",
+                ))?;
+
+            let user_id = UserId::parse_with_server_name(localpart, db.globals.server_name())
+                .map_err(|_| {
+                    Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid.")
+                })?;
+
+            if db.users.exists(&user_id)? {
+                if !config.link_existing_users {
+                    return Err(Error::BadRequest(
+                        ErrorKind::UserInUse,
+                        "A local user with this username already exists.",
+                    ));
+                }
+            } else {
+                let displayname = claims
+                    .get(&config.displayname_claim)
+                    .and_then(|displayname| displayname.as_str())
+                    .map(ToOwned::to_owned);
+
+                create_sso_user(&db, &user_id, displayname)?;
+            }
+
+            db.sso.set_user_for_subject(subject, &user_id)?;
+
+            user_id
+        }
+    };
+
+    if db.users.is_deactivated(&user_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::UserDeactivated,
+            "The user has been deactivated",
+        ));
+    }
+
+    let login_token = db.sso.create_login_token(&user_id)?;
+
+    info!("{} logged in at the identity provider", user_id);
+
+    db.flush()?;
+
+    let mut continue_url = Url::parse(&redirect_url)
+        .map_err(|_| Error::bad_database("Invalid redirect url in oidcstate_redirecturl."))?;
+    continue_url
+        .query_pairs_mut()
+        .append_pair("loginToken", &login_token);
+
+    let target = continue_url
+        .host_str()
+        .unwrap_or_else(|| continue_url.scheme())
+        .to_owned();
+
+    Ok(Html(format!(
+        "<!DOCTYPE html>\n\
+        <html>\n\
+        <body>\n\
+        <p>You are about to log in to <strong>{}</strong> as {}.</p>\n\
+        <p>Only continue if you trust this application. It will get full access to your account.</p>\n\
+        <p><a href=\"{}\">Continue to {}</a></p>\n\
+        </body>\n\
+        </html>\n",
+        RawStr::new(&target).html_escape(),
+        RawStr::new(user_id.as_str()).html_escape(),
+        RawStr::new(continue_url.as_str()).html_escape(),
+        RawStr::new(&target).html_escape()
+    )))
+}
+
+/// Exchanges the authorization code for an access token at the token endpoint of the identity
+/// provider and returns the claims of the userinfo endpoint.
+async fn fetch_claims(
+    client: &reqwest::Client,
+    config: &OidcConfig,
+    code: &str,
+) -> Result<JsonMap<String, JsonValue>> {
+    let token_response = client
+        .post(&config.token_endpoint)
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &config.callback_url),
+            ("client_id", &config.client_id),
+            ("client_secret", &config.client_secret),
+        ])
+        .send()
+        .await?;
+
+    if !token_response.status().is_success() {
+        warn!(
+            "OIDC token endpoint returned {}: {:?}",
+            token_response.status(),
+            token_response.text().await
+        );
+        return Err(Error::BadServerResponse(
+            "Identity provider did not accept the authorization code.",
+        ));
+    }
+
+    let token_response: OidcTokenResponse = serde_json::from_slice(&token_response.bytes().await?)
+        .map_err(|_| Error::BadServerResponse("Invalid token response from identity provider."))?;
+
+    let userinfo_response = client
+        .get(&config.userinfo_endpoint)
+        .bearer_auth(&token_response.access_token)
+        .send()
+        .await?;
+
+    if !userinfo_response.status().is_success() {
+        warn!(
+            "OIDC userinfo endpoint returned {}",
+            userinfo_response.status()
+        );
+        return Err(Error::BadServerResponse(
+            "Could not get user info from identity provider.",
+        ));
+    }
+
+    let claims: JsonMap<String, JsonValue> =
+        serde_json::from_slice(&userinfo_response.bytes().await?)
+            .map_err(|_| Error::BadServerResponse("Invalid user info from identity provider."))?;
+
+    Ok(claims)
+}
+
+/// Turns a claim into a valid localpart by lowercasing it and replacing unsupported characters.
+fn localpart_from_claim(claim: &str) -> String {
+    claim
+        .to_lowercase()
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '.' | '_' | '=' | '-' | '/' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+fn create_sso_user(
+    db: &DatabaseGuard,
+    user_id: &UserId,
+    displayname: Option<String>,
+) -> Result<()> {
+    db.users
+        .create(user_id, Some(&utils::random_string(SSO_PASSWORD_LENGTH)))?;
+
+    let displayname = displayname.unwrap_or_else(|| format!("{} ⚡️", user_id.localpart()));
+    db.users.set_displayname(user_id, Some(displayname))?;
+
+    // Initial account data
+    db.account_data.update(
+        None,
+        user_id,
+        EventType::PushRules,
+        &ruma::events::push_rules::PushRulesEvent {
+            content: ruma::events::push_rules::PushRulesEventContent {
+                global: push::Ruleset::server_default(user_id),
+            },
+        },
+        &db.globals,
+    )?;
+
+    info!("New user {} registered through SSO", user_id);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fetch_claims, localpart_from_claim};
+    use crate::database::sso::OidcConfig;
+    use serde_json::{json, Value as JsonValue};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        task::JoinHandle,
+    };
+
+    /// A request the mock identity provider received.
+    struct ReceivedRequest {
+        head: String,
+        body: String,
+    }
+
+    /// Runs an identity provider on a local port that answers two requests. The token endpoint
+    /// only accepts the code `the_code`, the userinfo endpoint returns `claims` for the access
+    /// token it handed out.
+    async fn mock_idp(claims: JsonValue) -> (OidcConfig, JoinHandle<Vec<ReceivedRequest>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let config = OidcConfig {
+            authorization_endpoint: format!("{}/authorize", base_url),
+            token_endpoint: format!("{}/token", base_url),
+            userinfo_endpoint: format!("{}/userinfo", base_url),
+            client_id: "conduit".to_owned(),
+            client_secret: "secret".to_owned(),
+            callback_url: "https://matrix.example.com/_conduit/client/oidc/callback".to_owned(),
+            scopes: vec!["openid".to_owned()],
+            localpart_claim: "preferred_username".to_owned(),
+            displayname_claim: "name".to_owned(),
+            link_existing_users: false,
+        };
+
+        let handle = tokio::spawn(async move {
+            let mut received = Vec::new();
+
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (reader, mut writer) = stream.into_split();
+                let mut reader = BufReader::new(reader);
+
+                let mut head = String::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).await.unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    head.push_str(&line);
+                }
+                let head = head.to_lowercase();
+
+                let content_length = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .map_or(0, |length| length.trim().parse().unwrap());
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).await.unwrap();
+                let body = String::from_utf8(body).unwrap();
+
+                let (status, response) =
+                    if head.starts_with("post /token ") && body.contains("code=the_code") {
+                        (
+                            "200 OK",
+                            json!({ "access_token": "idp_token", "token_type": "Bearer" }),
+                        )
+                    } else if head.starts_with("get /userinfo ")
+                        && head.contains("authorization: bearer idp_token")
+                    {
+                        ("200 OK", claims.clone())
+                    } else {
+                        ("400 Bad Request", json!({ "error": "invalid_grant" }))
+                    };
+                let response = response.to_string();
+
+                // Closing the connection makes the client use a new one for the next request
+                writer
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 {}\r\n\
+                             Content-Type: application/json\r\n\
+                             Content-Length: {}\r\n\
+                             Connection: close\r\n\r\n{}",
+                            status,
+                            response.len(),
+                            response
+                        )
+                        .as_bytes(),
+                    )
+                    .await
+                    .unwrap();
+
+                received.push(ReceivedRequest { head, body });
+            }
+
+            received
+        });
+
+        (config, handle)
+    }
+
+    fn client() -> reqwest::Client {
+        reqwest::Client::builder().no_proxy().build().unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn claims_are_fetched_with_the_token_of_the_code() {
+        let (config, idp) = mock_idp(json!({ "sub": "123", "preferred_username": "Alice" })).await;
+
+        let claims = fetch_claims(&client(), &config, "the_code").await.unwrap();
+        let received = idp.await.unwrap();
+
+        assert_eq!(claims["sub"], "123");
+        assert_eq!(claims["preferred_username"], "Alice");
+        assert!(received[0].head.starts_with("post /token "));
+        assert!(received[0].body.contains("grant_type=authorization_code"));
+        assert!(received[0].body.contains("client_secret=secret"));
+        assert!(received[1].head.starts_with("get /userinfo "));
+    }
+
+    #[rocket::async_test]
+    async fn rejected_codes_are_an_error() {
+        let (config, _idp) = mock_idp(json!({ "sub": "123" })).await;
+
+        assert!(fetch_claims(&client(), &config, "wrong_code")
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn localpart_from_claim_keeps_allowed_characters() {
+        assert_eq!(
+            localpart_from_claim("alice.smith_1=a-b/c"),
+            "alice.smith_1=a-b/c"
+        );
+    }
+
+    #[test]
+    fn localpart_from_claim_lowercases() {
+        assert_eq!(localpart_from_claim("Alice"), "alice");
+    }
+
+    #[test]
+    fn localpart_from_claim_replaces_other_characters() {
+        assert_eq!(
+            localpart_from_claim("alice smith@example.org"),
+            "alice_smith_example.org"
+        );
+        assert_eq!(localpart_from_claim("jörg"), "j_rg");
+        assert_eq!(localpart_from_claim(""), "");
+    }
+}
@@ -1,7 +1,7 @@
 use std::sync::Arc;
 
 use crate::{
-    database::DatabaseGuard, pdu::PduBuilder, ConduitResult, Database, Error, Result, Ruma,
+    database::DatabaseGuard, pdu::PduBuilder, utils, ConduitResult, Database, Error, Result, Ruma,
 };
 use ruma::{
     api::client::{
@@ -270,6 +270,11 @@ async fn send_state_event_for_key_helper(
 ) -> Result<EventId> {
     let sender_user = sender;
 
+    // Shadow-banned users should not notice that their events are thrown away
+    if db.users.is_shadow_banned(sender_user)? {
+        return Ok(utils::random_event_id());
+    }
+
     // TODO: Review this check, error if event is unparsable, use event type, allow alias if it
     // previously existed
     if let Ok(canonical_alias) =
@@ -1,4 +1,7 @@
-use crate::{database::DatabaseGuard, ConduitResult, Database, Error, Result, Ruma, RumaResponse};
+use crate::{
+    database::{rooms::RoomEdus, DatabaseGuard},
+    Database, Error, Result, Ruma, RumaResponse, RumaResponseWithFields,
+};
 use ruma::{
     api::client::r0::{sync::sync_events, uiaa::UiaaResponse},
     events::{
@@ -8,6 +11,7 @@ use ruma::{
     serde::Raw,
     DeviceId, RoomId, UserId,
 };
+use serde_json::{json, Map as JsonMap};
 use std::{
     collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
     convert::{TryFrom, TryInto},
@@ -52,18 +56,36 @@ use rocket::{get, tokio};
 ///
 /// - Sync is handled in an async task, multiple requests from the same device with the same
 /// `since` will be cached
+/// - Notifications in threads only get their own counts if the timeline filter sets
+/// `unread_thread_notifications` (MSC3773), otherwise they count towards the room
 #[cfg_attr(
     feature = "conduit_bin",
-    get("/_matrix/client/r0/sync", data = "<body>")
+    get("/_matrix/client/r0/sync?<filter>", data = "<body>")
 )]
 #[tracing::instrument(skip(db, body))]
 pub async fn sync_events_route(
     db: DatabaseGuard,
+    filter: Option<String>,
     body: Ruma<sync_events::Request<'_>>,
-) -> Result<RumaResponse<sync_events::Response>, RumaResponse<UiaaResponse>> {
+) -> Result<RumaResponseWithFields<sync_events::Response>, RumaResponse<UiaaResponse>> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
     let sender_device = body.sender_device.as_ref().expect("user is authenticated");
 
+    // Our ruma version drops unknown filter fields, so the opt-in is read from the raw filter
+    let unread_thread_notifications = filter
+        .as_deref()
+        .map_or(false, wants_unread_thread_notifications);
+
+    RoomEdus::ping_presence(&db, sender_user, &body.set_presence)?;
+
+    // Users who can only read until they accept the current policies get the link with a notice
+    if let Some(terms) = db.globals.terms() {
+        if !body.from_appservice && db.terms.needs_consent(sender_user, terms)? {
+            db.terms
+                .notify_missing_consent(sender_user, terms, &db.server_notices)?;
+        }
+    }
+
     let arc_db = Arc::new(db);
 
     let mut rx = match arc_db
@@ -83,6 +105,7 @@ pub async fn sync_events_route(
                 body.since.clone(),
                 body.full_state,
                 body.timeout,
+                unread_thread_notifications,
                 tx,
             ));
 
@@ -99,6 +122,7 @@ pub async fn sync_events_route(
                     body.since.clone(),
                     body.full_state,
                     body.timeout,
+                    unread_thread_notifications,
                     tx,
                 ));
 
@@ -130,6 +154,22 @@ pub async fn sync_events_route(
     result
 }
 
+/// Whether the timeline filter of a sync asks for separate thread notification counts. Filter
+/// ids are not stored, so only filters that are sent along can opt in.
+fn wants_unread_thread_notifications(filter: &str) -> bool {
+    let timeline = match serde_json::from_str::<serde_json::Value>(filter) {
+        Ok(filter) => filter["room"]["timeline"].clone(),
+        Err(_) => return false,
+    };
+
+    [
+        "unread_thread_notifications",
+        "org.matrix.msc3773.unread_thread_notifications",
+    ]
+    .iter()
+    .any(|field| timeline[field] == true)
+}
+
 async fn sync_helper_wrapper(
     db: Arc<DatabaseGuard>,
     sender_user: UserId,
@@ -137,7 +177,8 @@ async fn sync_helper_wrapper(
     since: Option<String>,
     full_state: bool,
     timeout: Option<Duration>,
-    tx: Sender<Option<ConduitResult<sync_events::Response>>>,
+    unread_thread_notifications: bool,
+    tx: Sender<Option<Result<RumaResponseWithFields<sync_events::Response>>>>,
 ) {
     let r = sync_helper(
         Arc::clone(&db),
@@ -146,10 +187,11 @@ async fn sync_helper_wrapper(
         since.clone(),
         full_state,
         timeout,
+        unread_thread_notifications,
     )
     .await;
 
-    if let Ok((_, caching_allowed)) = r {
+    if let Ok((_, _, caching_allowed)) = r {
         if !caching_allowed {
             match db
                 .globals
@@ -171,7 +213,9 @@ async fn sync_helper_wrapper(
 
     drop(db);
 
-    let _ = tx.send(Some(r.map(|(r, _)| r.into())));
+    let _ = tx.send(Some(r.map(|(response, fields, _)| {
+        RumaResponseWithFields { response, fields }
+    })));
 }
 
 async fn sync_helper(
@@ -181,11 +225,9 @@ async fn sync_helper(
     since: Option<String>,
     full_state: bool,
     timeout: Option<Duration>,
-    // bool = caching allowed
-) -> Result<(sync_events::Response, bool), Error> {
-    // TODO: match body.set_presence {
-    db.rooms.edus.ping_presence(&sender_user)?;
-
+    unread_thread_notifications: bool,
+    // Map = additional json fields, bool = caching allowed
+) -> Result<(sync_events::Response, JsonMap, bool), Error> {
     // Setup watchers, so if there's no response, we can wait for them
     let watcher = db.watch(&sender_user, &sender_device);
 
@@ -193,6 +235,7 @@ async fn sync_helper(
     let next_batch_string = next_batch.to_string();
 
     let mut joined_rooms = BTreeMap::new();
+    let mut joined_room_fields = JsonMap::new();
     let since = since
         .clone()
         .and_then(|string| string.parse().ok())
@@ -494,10 +537,19 @@ async fn sync_helper(
                 .filter_map(|r| r.ok()),
         );
 
+        // The counts of the room include its threads, unless the client shows them separately
+        let thread_counts = if send_notification_counts && unread_thread_notifications {
+            db.rooms
+                .thread_notification_counts(&sender_user, &room_id)?
+        } else {
+            BTreeMap::new()
+        };
+
         let notification_count = if send_notification_counts {
             Some(
                 db.rooms
                     .notification_count(&sender_user, &room_id)?
+                    .saturating_sub(thread_counts.values().map(|(n, _)| n).sum())
                     .try_into()
                     .expect("notification count can't go that high"),
             )
@@ -509,6 +561,7 @@ async fn sync_helper(
             Some(
                 db.rooms
                     .highlight_count(&sender_user, &room_id)?
+                    .saturating_sub(thread_counts.values().map(|(_, h)| h).sum())
                     .try_into()
                     .expect("highlight count can't go that high"),
             )
@@ -522,10 +575,14 @@ async fn sync_helper(
                 Ok(Some(db.rooms.pdu_count(pdu_id)?.to_string()))
             })?;
 
-        let room_events: Vec<_> = timeline_pdus
+        let room_events = timeline_pdus
             .iter()
-            .map(|(_, pdu)| pdu.to_sync_room_event())
-            .collect();
+            .map(|(_, pdu)| {
+                let mut pdu = pdu.clone();
+                db.rooms.add_bundled_aggregations(&sender_user, &mut pdu)?;
+                Ok(pdu.to_sync_room_event())
+            })
+            .collect::<Result<Vec<_>>>()?;
 
         let mut edus: Vec<_> = db
             .rooms
@@ -535,6 +592,13 @@ async fn sync_helper(
             .map(|(_, _, v)| v)
             .collect();
 
+        // Private read receipts are only sent to their sender
+        edus.extend(
+            db.rooms
+                .edus
+                .private_receipts_since(&room_id, &sender_user, since)?,
+        );
+
         if db.rooms.edus.last_typing_update(&room_id, &db.globals)? > since {
             edus.push(
                 serde_json::from_str(
@@ -589,43 +653,39 @@ async fn sync_helper(
 
         if !joined_room.is_empty() {
             joined_rooms.insert(room_id.clone(), joined_room);
-        }
 
-        // Take presence updates from this room
-        for (user_id, presence) in
-            db.rooms
-                .edus
-                .presence_since(&room_id, since, &db.rooms, &db.globals)?
-        {
-            match presence_updates.entry(user_id) {
-                Entry::Vacant(v) => {
-                    v.insert(presence);
-                }
-                Entry::Occupied(mut o) => {
-                    let p = o.get_mut();
+            if send_notification_counts && unread_thread_notifications {
+                let thread_counts = thread_counts
+                    .into_iter()
+                    .map(|(thread_root, (notification_count, highlight_count))| {
+                        (
+                            thread_root.to_string(),
+                            json!({
+                                "notification_count": notification_count,
+                                "highlight_count": highlight_count,
+                            }),
+                        )
+                    })
+                    .collect::<JsonMap>();
 
-                    // Update existing presence event with more info
-                    p.content.presence = presence.content.presence;
-                    if let Some(status_msg) = presence.content.status_msg {
-                        p.content.status_msg = Some(status_msg);
-                    }
-                    if let Some(last_active_ago) = presence.content.last_active_ago {
-                        p.content.last_active_ago = Some(last_active_ago);
-                    }
-                    if let Some(displayname) = presence.content.displayname {
-                        p.content.displayname = Some(displayname);
-                    }
-                    if let Some(avatar_url) = presence.content.avatar_url {
-                        p.content.avatar_url = Some(avatar_url);
-                    }
-                    if let Some(currently_active) = presence.content.currently_active {
-                        p.content.currently_active = Some(currently_active);
-                    }
-                }
+                joined_room_fields.insert(
+                    room_id.to_string(),
+                    json!({ "unread_thread_notifications": thread_counts }),
+                );
             }
         }
     }
 
+    // Take presence updates of users that share a room with us
+    for room_id in db.rooms.rooms_joined(&sender_user) {
+        for (user_id, _, presence) in db.rooms.edus.presence_since(&room_id?, since)? {
+            presence_updates.insert(user_id, presence);
+        }
+    }
+    if let Some(presence) = db.rooms.edus.presence_of_user_since(&sender_user, since)? {
+        presence_updates.insert(sender_user.clone(), presence);
+    }
+
     let mut left_rooms = BTreeMap::new();
     let all_left_rooms: Vec<_> = db.rooms.rooms_left(&sender_user).collect();
     for result in all_left_rooms {
@@ -763,6 +823,11 @@ async fn sync_helper(
         },
     };
 
+    let mut fields = JsonMap::new();
+    if !joined_room_fields.is_empty() {
+        fields.insert("rooms".to_owned(), json!({ "join": joined_room_fields }));
+    }
+
     // TODO: Retry the endpoint instead of returning (waiting for #118)
     if !full_state
         && response.rooms.is_empty()
@@ -778,9 +843,9 @@ async fn sync_helper(
             duration = Duration::from_secs(30);
         }
         let _ = tokio::time::timeout(duration, watcher).await;
-        Ok((response, false))
+        Ok((response, fields, false))
     } else {
-        Ok((response, since != next_batch)) // Only cache if we made progress
+        Ok((response, fields, since != next_batch)) // Only cache if we made progress
     }
 }
 
@@ -0,0 +1,81 @@
+use crate::{database::DatabaseGuard, Error, Result};
+use rocket::{http::RawStr, response::content::Html};
+use ruma::api::client::error::ErrorKind;
+
+#[cfg(feature = "conduit_bin")]
+use rocket::{get, post};
+
+/// # `GET /_conduit/client/consent`
+///
+/// Shows the current policy documents and a button to accept them.
+///
+/// - The token is the one of the consent link in the server notice
+#[cfg_attr(feature = "conduit_bin", get("/_conduit/client/consent?<token>"))]
+#[tracing::instrument(skip(db, token))]
+pub async fn consent_form_route(db: DatabaseGuard, token: String) -> Result<Html<String>> {
+    let terms = db.globals.terms().ok_or(Error::BadRequest(
+        ErrorKind::NotFound,
+        "This server has no policies.",
+    ))?;
+
+    let user_id = db
+        .terms
+        .user_from_consent_token(&token)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Unknown consent link.",
+        ))?;
+
+    let mut documents = String::new();
+    for document in terms.policies.values() {
+        documents += &format!(
+            "<li><a href=\"{}\">{}</a> (version {})</li>\n",
+            RawStr::new(&document.url).html_escape(),
+            RawStr::new(&document.name).html_escape(),
+            RawStr::new(&document.version).html_escape()
+        );
+    }
+
+    Ok(Html(format!(
+        "<!DOCTYPE html>\n\
+        <html>\n\
+        <body>\n\
+        <p>Please read the policies of {}:</p>\n\
+        <ul>\n{}</ul>\n\
+        <form method=\"post\" action=\"consent?token={}\">\n\
+        <button type=\"submit\">I accept these policies as {}</button>\n\
+        </form>\n\
+        </body>\n\
+        </html>\n",
+        db.globals.server_name(),
+        documents,
+        RawStr::new(&token).html_escape(),
+        RawStr::new(user_id.as_str()).html_escape()
+    )))
+}
+
+/// # `POST /_conduit/client/consent`
+///
+/// Records that the user accepted the current version of every policy document.
+#[cfg_attr(feature = "conduit_bin", post("/_conduit/client/consent?<token>"))]
+#[tracing::instrument(skip(db, token))]
+pub async fn consent_route(db: DatabaseGuard, token: String) -> Result<&'static str> {
+    let terms = db.globals.terms().ok_or(Error::BadRequest(
+        ErrorKind::NotFound,
+        "This server has no policies.",
+    ))?;
+
+    let user_id = db
+        .terms
+        .user_from_consent_token(&token)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Unknown consent link.",
+        ))?;
+
+    db.terms.accept(&user_id, terms)?;
+
+    db.flush()?;
+
+    Ok("Thank you, you can use your account again.")
+}
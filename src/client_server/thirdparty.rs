@@ -1,22 +1,422 @@
-use crate::ConduitResult;
-use ruma::api::client::r0::thirdparty::get_protocols;
+use crate::{
+    appservice_server,
+    database::{appservice::RegistrationInfo, DatabaseGuard},
+    ConduitResult, Error, Result, Ruma,
+};
+use rocket::futures::{stream::FuturesUnordered, Future, StreamExt};
+use ruma::{
+    api::{
+        appservice,
+        client::{
+            error::ErrorKind,
+            r0::thirdparty::{
+                get_location_for_protocol, get_location_for_room_alias, get_protocol,
+                get_protocols, get_user_for_protocol, get_user_for_user_id,
+            },
+        },
+    },
+    thirdparty::Protocol,
+};
+use tracing::warn;
 
 #[cfg(feature = "conduit_bin")]
 use rocket::get;
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, time::Duration};
+
+/// How long an appservice may take to answer a lookup before it is left out of the response.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// # `GET /_matrix/client/r0/thirdparty/protocols`
 ///
-/// TODO: Fetches all metadata about protocols supported by the homeserver.
+/// Fetches all metadata about protocols supported by the homeserver.
+///
+/// - Asks every appservice that lists protocols in its registration
+/// - Instances of protocols that are bridged by multiple appservices are merged
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/thirdparty/protocols", data = "<_body>")
+)]
+#[tracing::instrument(skip(db, _body))]
+pub async fn get_protocols_route(
+    db: DatabaseGuard,
+    _body: Ruma<get_protocols::Request>,
+) -> ConduitResult<get_protocols::Response> {
+    let mut protocols = BTreeMap::<String, Protocol>::new();
+
+    let lookups = protocol_appservices(&db)?
+        .into_iter()
+        .map(|(protocol, registration)| {
+            let db = &db;
+            async move {
+                let response = in_time(
+                    LOOKUP_TIMEOUT,
+                    appservice_server::send_request(
+                        &db.globals,
+                        registration,
+                        appservice::thirdparty::get_protocol::v1::Request {
+                            protocol: &protocol,
+                        },
+                    ),
+                )
+                .await;
+                (protocol, response)
+            }
+        });
+
+    for (protocol, response) in fan_out(lookups).await {
+        match response {
+            Ok(response) => merge_protocol(&mut protocols, protocol, response.protocol),
+            Err(e) => warn!("Appservice failed to describe protocol {}: {}", protocol, e),
+        }
+    }
+
+    Ok(get_protocols::Response { protocols }.into())
+}
+
+/// # `GET /_matrix/client/r0/thirdparty/protocol/{protocol}`
+///
+/// Fetches the metadata of a protocol from the appservices that bridge it.
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/thirdparty/protocol/<_>", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn get_protocol_route(
+    db: DatabaseGuard,
+    body: Ruma<get_protocol::Request<'_>>,
+) -> ConduitResult<get_protocol::Response> {
+    let lookups = appservices_for_protocol(&db, &body.protocol)?
+        .into_iter()
+        .map(|registration| {
+            in_time(
+                LOOKUP_TIMEOUT,
+                appservice_server::send_request(
+                    &db.globals,
+                    registration,
+                    appservice::thirdparty::get_protocol::v1::Request {
+                        protocol: &body.protocol,
+                    },
+                ),
+            )
+        });
+
+    let mut protocol: Option<Protocol> = None;
+
+    for response in fan_out(lookups).await {
+        match (response, &mut protocol) {
+            (Ok(response), Some(existing)) => {
+                existing.instances.extend(response.protocol.instances)
+            }
+            (Ok(response), None) => protocol = Some(response.protocol),
+            (Err(e), _) => warn!(
+                "Appservice failed to describe protocol {}: {}",
+                body.protocol, e
+            ),
+        }
+    }
+
+    let protocol = protocol.ok_or(Error::BadRequest(
+        ErrorKind::NotFound,
+        "Protocol is not bridged by this server.",
+    ))?;
+
+    Ok(get_protocol::Response { protocol }.into())
+}
+
+/// # `GET /_matrix/client/r0/thirdparty/location/{protocol}`
+///
+/// Looks up third party locations of a protocol at the appservices that bridge it.
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/thirdparty/location/<_>", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn get_location_for_protocol_route(
+    db: DatabaseGuard,
+    body: Ruma<get_location_for_protocol::Request<'_>>,
+) -> ConduitResult<get_location_for_protocol::Response> {
+    let lookups = appservices_for_protocol(&db, &body.protocol)?
+        .into_iter()
+        .map(|registration| {
+            in_time(
+                LOOKUP_TIMEOUT,
+                appservice_server::send_request(
+                    &db.globals,
+                    registration,
+                    appservice::thirdparty::get_location_for_protocol::v1::Request {
+                        protocol: &body.protocol,
+                        fields: body.fields.clone(),
+                    },
+                ),
+            )
+        });
+
+    let mut locations = Vec::new();
+    for response in fan_out(lookups).await {
+        match response {
+            Ok(response) => locations.extend(response.locations),
+            Err(e) => warn!("Appservice failed to look up locations: {}", e),
+        }
+    }
+
+    Ok(get_location_for_protocol::Response { locations }.into())
+}
+
+/// # `GET /_matrix/client/r0/thirdparty/location`
+///
+/// Looks up the third party locations of a room alias at all appservices that bridge protocols.
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/thirdparty/location", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn get_location_for_room_alias_route(
+    db: DatabaseGuard,
+    body: Ruma<get_location_for_room_alias::Request<'_>>,
+) -> ConduitResult<get_location_for_room_alias::Response> {
+    let lookups = protocol_appservices(&db)?
+        .into_iter()
+        .map(|(_, registration)| registration)
+        .collect::<Vec<_>>();
+    let lookups = dedup_registrations(lookups)
+        .into_iter()
+        .map(|registration| {
+            in_time(
+                LOOKUP_TIMEOUT,
+                appservice_server::send_request(
+                    &db.globals,
+                    registration,
+                    appservice::thirdparty::get_location_for_room_alias::v1::Request {
+                        alias: &body.alias,
+                    },
+                ),
+            )
+        });
+
+    let mut locations = Vec::new();
+    for response in fan_out(lookups).await {
+        match response {
+            Ok(response) => locations.extend(response.locations),
+            Err(e) => warn!("Appservice failed to look up locations: {}", e),
+        }
+    }
+
+    Ok(get_location_for_room_alias::Response { locations }.into())
+}
+
+/// # `GET /_matrix/client/r0/thirdparty/user/{protocol}`
+///
+/// Looks up third party users of a protocol at the appservices that bridge it.
 #[cfg_attr(
     feature = "conduit_bin",
-    get("/_matrix/client/r0/thirdparty/protocols")
+    get("/_matrix/client/r0/thirdparty/user/<_>", data = "<body>")
 )]
-#[tracing::instrument]
-pub async fn get_protocols_route() -> ConduitResult<get_protocols::Response> {
-    // TODO
-    Ok(get_protocols::Response {
-        protocols: BTreeMap::new(),
+#[tracing::instrument(skip(db, body))]
+pub async fn get_user_for_protocol_route(
+    db: DatabaseGuard,
+    body: Ruma<get_user_for_protocol::Request<'_>>,
+) -> ConduitResult<get_user_for_protocol::Response> {
+    let lookups = appservices_for_protocol(&db, &body.protocol)?
+        .into_iter()
+        .map(|registration| {
+            in_time(
+                LOOKUP_TIMEOUT,
+                appservice_server::send_request(
+                    &db.globals,
+                    registration,
+                    appservice::thirdparty::get_user_for_protocol::v1::Request {
+                        protocol: &body.protocol,
+                        fields: body.fields.clone(),
+                    },
+                ),
+            )
+        });
+
+    let mut users = Vec::new();
+    for response in fan_out(lookups).await {
+        match response {
+            Ok(response) => users.extend(response.users),
+            Err(e) => warn!("Appservice failed to look up users: {}", e),
+        }
+    }
+
+    Ok(get_user_for_protocol::Response { users }.into())
+}
+
+/// # `GET /_matrix/client/r0/thirdparty/user`
+///
+/// Looks up the third party users of a Matrix user at all appservices that bridge protocols.
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/thirdparty/user", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn get_user_for_user_id_route(
+    db: DatabaseGuard,
+    body: Ruma<get_user_for_user_id::Request<'_>>,
+) -> ConduitResult<get_user_for_user_id::Response> {
+    let lookups = protocol_appservices(&db)?
+        .into_iter()
+        .map(|(_, registration)| registration)
+        .collect::<Vec<_>>();
+    let lookups = dedup_registrations(lookups)
+        .into_iter()
+        .map(|registration| {
+            in_time(
+                LOOKUP_TIMEOUT,
+                appservice_server::send_request(
+                    &db.globals,
+                    registration,
+                    appservice::thirdparty::get_user_for_user_id::v1::Request {
+                        userid: &body.userid,
+                    },
+                ),
+            )
+        });
+
+    let mut users = Vec::new();
+    for response in fan_out(lookups).await {
+        match response {
+            Ok(response) => users.extend(response.users),
+            Err(e) => warn!("Appservice failed to look up users: {}", e),
+        }
+    }
+
+    Ok(get_user_for_user_id::Response { users }.into())
+}
+
+/// Returns every protocol together with the registration of an appservice that bridges it.
+fn protocol_appservices(db: &DatabaseGuard) -> Result<Vec<(String, RegistrationInfo)>> {
+    Ok(db
+        .appservice
+        .all()?
+        .into_iter()
+        .flat_map(|(_, registration)| {
+            registration
+                .protocols
+                .clone()
+                .into_iter()
+                .map(move |protocol| (protocol, registration.clone()))
+                .collect::<Vec<_>>()
+        })
+        .collect())
+}
+
+/// Returns the registrations of all appservices that bridge the protocol.
+fn appservices_for_protocol(db: &DatabaseGuard, protocol: &str) -> Result<Vec<RegistrationInfo>> {
+    Ok(db
+        .appservice
+        .all()?
+        .into_iter()
+        .map(|(_, registration)| registration)
+        .filter(|registration| registration.protocols.iter().any(|p| p == protocol))
+        .collect())
+}
+
+/// Appservices that bridge multiple protocols only need to be asked once.
+fn dedup_registrations(registrations: Vec<RegistrationInfo>) -> Vec<RegistrationInfo> {
+    let mut unique = Vec::<RegistrationInfo>::new();
+    for registration in registrations {
+        if !unique.iter().any(|other| other.id == registration.id) {
+            unique.push(registration);
+        }
+    }
+    unique
+}
+
+/// Adds the instances of a protocol to the ones of other appservices that bridge it.
+fn merge_protocol(protocols: &mut BTreeMap<String, Protocol>, name: String, protocol: Protocol) {
+    match protocols.get_mut(&name) {
+        Some(existing) => existing.instances.extend(protocol.instances),
+        None => {
+            protocols.insert(name, protocol);
+        }
+    }
+}
+
+/// Fails the lookup if the appservice does not answer within `timeout`.
+async fn in_time<T>(timeout: Duration, lookup: impl Future<Output = Result<T>>) -> Result<T> {
+    tokio::time::timeout(timeout, lookup)
+        .await
+        .unwrap_or(Err(Error::BadServerResponse(
+            "Appservice did not answer in time.",
+        )))
+}
+
+/// Sends all lookups at the same time and collects the responses.
+async fn fan_out<F: Future>(lookups: impl Iterator<Item = F>) -> Vec<F::Output> {
+    let mut futures: FuturesUnordered<_> = lookups.collect();
+    let mut responses = Vec::new();
+
+    while let Some(response) = futures.next().await {
+        responses.push(response);
+    }
+
+    responses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{in_time, merge_protocol};
+    use ruma::thirdparty::Protocol;
+    use serde_json::json;
+    use std::{collections::BTreeMap, time::Duration};
+
+    fn protocol(network_ids: &[&str]) -> Protocol {
+        serde_json::from_value(json!({
+            "user_fields": ["username"],
+            "location_fields": ["channel"],
+            "icon": "mxc://example.com/icon",
+            "field_types": {},
+            "instances": network_ids
+                .iter()
+                .map(|network_id| json!({
+                    "desc": network_id,
+                    "fields": {},
+                    "network_id": network_id,
+                }))
+                .collect::<Vec<_>>(),
+        }))
+        .unwrap()
+    }
+
+    fn network_ids(protocol: &Protocol) -> Vec<&str> {
+        protocol
+            .instances
+            .iter()
+            .map(|instance| instance.network_id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn instances_of_appservices_are_merged() {
+        let mut protocols = BTreeMap::new();
+
+        merge_protocol(&mut protocols, "irc".to_owned(), protocol(&["libera"]));
+        merge_protocol(
+            &mut protocols,
+            "irc".to_owned(),
+            protocol(&["oftc", "hackint"]),
+        );
+        merge_protocol(&mut protocols, "gitter".to_owned(), protocol(&["gitter"]));
+
+        assert_eq!(protocols.len(), 2);
+        assert_eq!(
+            network_ids(&protocols["irc"]),
+            ["libera", "oftc", "hackint"]
+        );
+        assert_eq!(network_ids(&protocols["gitter"]), ["gitter"]);
+    }
+
+    #[rocket::async_test]
+    async fn slow_lookups_fail() {
+        let slow = in_time(
+            Duration::from_millis(10),
+            std::future::pending::<crate::Result<()>>(),
+        );
+        assert!(slow.await.is_err());
+
+        let fast = in_time(Duration::from_millis(10), async { Ok(1) });
+        assert_eq!(fast.await.unwrap(), 1);
     }
-    .into())
 }
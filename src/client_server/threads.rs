@@ -0,0 +1,229 @@
+use crate::{database::DatabaseGuard, ConduitResult, Database, Error, Result, Ruma};
+use ruma::{api::client::error::ErrorKind, events::AnyRoomEvent, serde::Raw, RoomId, UInt, UserId};
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+
+#[cfg(feature = "conduit_bin")]
+use rocket::get;
+
+/// Which threads to return.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IncludeThreads {
+    /// All threads in the room.
+    All,
+    /// Only threads the user sent the root or an event in.
+    Participated,
+}
+
+impl Default for IncludeThreads {
+    fn default() -> Self {
+        IncludeThreads::All
+    }
+}
+
+/// The threads endpoint of MSC3856, which is not part of our ruma version yet.
+pub mod get_threads {
+    use ruma::{api::ruma_api, events::AnyRoomEvent, serde::Raw, RoomId, UInt};
+
+    ruma_api! {
+        metadata: {
+            description: "Retrieve a list of threads in a room, with optional filters.",
+            method: GET,
+            name: "get_threads",
+            path: "/_matrix/client/v1/rooms/:room_id/threads",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            #[ruma_api(path)]
+            pub room_id: &'a RoomId,
+
+            #[ruma_api(query)]
+            #[serde(default, skip_serializing_if = "ruma::serde::is_default")]
+            pub include: super::IncludeThreads,
+
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub from: Option<&'a str>,
+
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub limit: Option<UInt>,
+        }
+
+        response: {
+            pub chunk: Vec<Raw<AnyRoomEvent>>,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub next_batch: Option<String>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+/// The unstable path of the [threads endpoint](get_threads/index.html).
+pub mod get_threads_unstable {
+    use ruma::{api::ruma_api, events::AnyRoomEvent, serde::Raw, RoomId, UInt};
+
+    ruma_api! {
+        metadata: {
+            description: "Retrieve a list of threads in a room, with optional filters.",
+            method: GET,
+            name: "get_threads_unstable",
+            path: "/_matrix/client/unstable/org.matrix.msc3856/rooms/:room_id/threads",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            #[ruma_api(path)]
+            pub room_id: &'a RoomId,
+
+            #[ruma_api(query)]
+            #[serde(default, skip_serializing_if = "ruma::serde::is_default")]
+            pub include: super::IncludeThreads,
+
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub from: Option<&'a str>,
+
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub limit: Option<UInt>,
+        }
+
+        response: {
+            pub chunk: Vec<Raw<AnyRoomEvent>>,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub next_batch: Option<String>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+/// # `GET /_matrix/client/v1/rooms/{roomId}/threads`
+///
+/// Lists the thread roots of a room, the most recently active thread first.
+///
+/// - Only works if the user is joined
+/// - With `include=participated`, only threads the user took part in are returned
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/v1/rooms/<_>/threads", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn get_threads_route(
+    db: DatabaseGuard,
+    body: Ruma<get_threads::Request<'_>>,
+) -> ConduitResult<get_threads::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let (chunk, next_batch) = threads(
+        &db,
+        sender_user,
+        &body.room_id,
+        &body.include,
+        body.from.as_deref(),
+        body.limit,
+    )?;
+
+    Ok(get_threads::Response { chunk, next_batch }.into())
+}
+
+/// # `GET /_matrix/client/unstable/org.matrix.msc3856/rooms/{roomId}/threads`
+///
+/// Unstable version of [`GET /_matrix/client/v1/rooms/{roomId}/threads`](fn.get_threads_route.html).
+#[cfg_attr(
+    feature = "conduit_bin",
+    get(
+        "/_matrix/client/unstable/org.matrix.msc3856/rooms/<_>/threads",
+        data = "<body>"
+    )
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn get_threads_unstable_route(
+    db: DatabaseGuard,
+    body: Ruma<get_threads_unstable::Request<'_>>,
+) -> ConduitResult<get_threads_unstable::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let (chunk, next_batch) = threads(
+        &db,
+        sender_user,
+        &body.room_id,
+        &body.include,
+        body.from.as_deref(),
+        body.limit,
+    )?;
+
+    Ok(get_threads_unstable::Response { chunk, next_batch }.into())
+}
+
+/// Returns a page of thread roots and the token of the next page.
+fn threads(
+    db: &Database,
+    sender_user: &UserId,
+    room_id: &RoomId,
+    include: &IncludeThreads,
+    from: Option<&str>,
+    limit: Option<UInt>,
+) -> Result<(Vec<Raw<AnyRoomEvent>>, Option<String>)> {
+    if !db.rooms.is_joined(sender_user, room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You don't have permission to view this room.",
+        ));
+    }
+
+    let from = from
+        .map(|from| from.parse())
+        .transpose()
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `from` value."))?
+        .unwrap_or(u64::MAX);
+
+    // Use limit or else 10, but at most 100
+    let limit = limit
+        .and_then(|limit| limit.try_into().ok())
+        .map_or(10_usize, |limit: u32| limit as usize)
+        .min(100);
+
+    let mut threads = db
+        .rooms
+        .threads_until(room_id, from)?
+        .filter_map(|r| r.ok()) // Filter out buggy threads
+        .filter(|(_, thread_root)| {
+            *include == IncludeThreads::All
+                || db
+                    .rooms
+                    .participated_in_thread(sender_user, thread_root)
+                    .unwrap_or(false)
+        })
+        .filter_map(|(count, thread_root)| Some((count, db.rooms.get_pdu(&thread_root).ok()??)))
+        .filter(|(_, pdu)| pdu.room_id == *room_id)
+        .take(limit + 1)
+        .collect::<Vec<_>>();
+
+    // We asked for one more thread to find out if there is a next page
+    let next_batch = if threads.len() > limit {
+        threads.truncate(limit);
+        threads.last().map(|(count, _)| count.to_string())
+    } else {
+        None
+    };
+
+    let chunk = threads
+        .into_iter()
+        .map(|(_, pdu)| {
+            let mut pdu = (*pdu).clone();
+            db.rooms.add_bundled_aggregations(sender_user, &mut pdu)?;
+            Ok(pdu.to_room_event())
+        })
+        .collect::<Result<_>>()?;
+
+    Ok((chunk, next_batch))
+}
@@ -0,0 +1,527 @@
+use super::SESSION_ID_LENGTH;
+use crate::{
+    database::{email, threepids::ValidationPurpose, DatabaseGuard},
+    utils, ConduitResult, Error, Result, Ruma,
+};
+use rocket::{http::RawStr, response::content::Html};
+use ruma::{
+    api::client::{
+        error::ErrorKind,
+        r0::{
+            account::ThirdPartyIdRemovalStatus,
+            uiaa::{AuthFlow, AuthType, UiaaInfo},
+        },
+    },
+    UInt,
+};
+use std::net::IpAddr;
+
+#[cfg(feature = "conduit_bin")]
+use rocket::{get, post};
+
+const EMAIL_MEDIUM: &str = "email";
+const MAX_CLIENT_SECRET_LENGTH: usize = 255;
+
+/// Requests a link to validate an email address that will be added to the account.
+pub mod request_3pid_management_token_via_email {
+    use ruma::{api::ruma_api, UInt};
+
+    ruma_api! {
+        metadata: {
+            description: "Request a validation link for an email address that will be added to the account.",
+            method: POST,
+            name: "request_3pid_management_token_via_email",
+            path: "/_matrix/client/r0/account/3pid/email/requestToken",
+            rate_limited: true,
+            authentication: None,
+        }
+
+        request: {
+            pub client_secret: &'a str,
+
+            pub email: &'a str,
+
+            pub send_attempt: UInt,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub next_link: Option<&'a str>,
+        }
+
+        response: {
+            pub sid: String,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub submit_url: Option<String>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+/// Requests a link to an email address of an account to reset its password.
+pub mod request_password_change_token_via_email {
+    use ruma::{api::ruma_api, UInt};
+
+    ruma_api! {
+        metadata: {
+            description: "Request a validation link for an email address of an account to reset its password.",
+            method: POST,
+            name: "request_password_change_token_via_email",
+            path: "/_matrix/client/r0/account/password/email/requestToken",
+            rate_limited: true,
+            authentication: None,
+        }
+
+        request: {
+            pub client_secret: &'a str,
+
+            pub email: &'a str,
+
+            pub send_attempt: UInt,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub next_link: Option<&'a str>,
+        }
+
+        response: {
+            pub sid: String,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub submit_url: Option<String>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+/// Adds a validated email address to the account.
+pub mod add_3pid {
+    use ruma::api::{
+        client::r0::uiaa::{AuthData, IncomingAuthData},
+        ruma_api,
+    };
+
+    ruma_api! {
+        metadata: {
+            description: "Add a validated third party identifier to the account.",
+            method: POST,
+            name: "add_3pid",
+            path: "/_matrix/client/r0/account/3pid/add",
+            rate_limited: true,
+            authentication: AccessToken,
+        }
+
+        request: {
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub auth: Option<AuthData<'a>>,
+
+            pub client_secret: &'a str,
+
+            pub sid: &'a str,
+        }
+
+        response: {}
+
+        error: ruma::api::client::Error
+    }
+}
+
+/// Removes a third party identifier from the account.
+pub mod delete_3pid {
+    use ruma::api::{client::r0::account::ThirdPartyIdRemovalStatus, ruma_api};
+
+    ruma_api! {
+        metadata: {
+            description: "Remove a third party identifier from the account.",
+            method: POST,
+            name: "delete_3pid",
+            path: "/_matrix/client/r0/account/3pid/delete",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            pub medium: &'a str,
+
+            pub address: &'a str,
+
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub id_server: Option<&'a str>,
+        }
+
+        response: {
+            pub id_server_unbind_result: ThirdPartyIdRemovalStatus,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+/// # `POST /_matrix/client/r0/account/3pid/email/requestToken`
+///
+/// Sends a link to the email address that proves the user owns it.
+///
+/// - Fails if the address already belongs to an account
+/// - Only sends the email again if `send_attempt` increased
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/account/3pid/email/requestToken", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn request_3pid_email_token_route(
+    db: DatabaseGuard,
+    body: Ruma<request_3pid_management_token_via_email::Request<'_>>,
+) -> ConduitResult<request_3pid_management_token_via_email::Response> {
+    let sid = request_email_token(
+        db,
+        ValidationPurpose::AddThreepid,
+        &body.client_secret,
+        &body.email,
+        body.send_attempt,
+    )
+    .await?;
+
+    Ok(request_3pid_management_token_via_email::Response {
+        sid,
+        submit_url: None,
+    }
+    .into())
+}
+
+/// # `POST /_matrix/client/r0/account/password/email/requestToken`
+///
+/// Sends a link to an email address of an account. After the user opened it, the client can set
+/// a new password with an `m.login.email.identity` auth.
+///
+/// - Fails if the address does not belong to an active account
+/// - Only sends the email again if `send_attempt` increased
+#[cfg_attr(
+    feature = "conduit_bin",
+    post(
+        "/_matrix/client/r0/account/password/email/requestToken",
+        data = "<body>"
+    )
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn request_password_email_token_route(
+    db: DatabaseGuard,
+    body: Ruma<request_password_change_token_via_email::Request<'_>>,
+) -> ConduitResult<request_password_change_token_via_email::Response> {
+    let sid = request_email_token(
+        db,
+        ValidationPurpose::PasswordReset,
+        &body.client_secret,
+        &body.email,
+        body.send_attempt,
+    )
+    .await?;
+
+    Ok(request_password_change_token_via_email::Response {
+        sid,
+        submit_url: None,
+    }
+    .into())
+}
+
+/// # `GET /_conduit/client/email/validate`
+///
+/// The link in validation emails. Shows a button that validates the address, so that mail
+/// clients which open links in advance don't validate it without the user.
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_conduit/client/email/validate?<sid>&<client_secret>&<token>")
+)]
+#[tracing::instrument(skip(db, client_secret, token))]
+pub async fn validate_email_form_route(
+    db: DatabaseGuard,
+    sid: String,
+    client_secret: String,
+    token: String,
+) -> Result<Html<String>> {
+    let purpose = db
+        .threepids
+        .purpose(&sid, &client_secret, &token)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Unknown or expired validation link.",
+        ))?;
+
+    let action = match purpose {
+        ValidationPurpose::AddThreepid => "add this email address to your account",
+        ValidationPurpose::PasswordReset => "reset your password",
+    };
+    let query = format!(
+        "sid={}&client_secret={}&token={}",
+        sid, client_secret, token
+    );
+
+    Ok(Html(format!(
+        "<!DOCTYPE html>\n\
+        <html>\n\
+        <body>\n\
+        <p>Do you want to {} on {}?</p>\n\
+        <form method=\"post\" action=\"validate?{}\">\n\
+        <button type=\"submit\">Continue</button>\n\
+        </form>\n\
+        </body>\n\
+        </html>\n",
+        action,
+        db.globals.server_name(),
+        RawStr::new(&query).html_escape()
+    )))
+}
+
+/// # `POST /_conduit/client/email/validate`
+///
+/// Marks the address as validated, so the client can continue.
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_conduit/client/email/validate?<sid>&<client_secret>&<token>")
+)]
+#[tracing::instrument(skip(db, client_secret, token))]
+pub async fn validate_email_route(
+    db: DatabaseGuard,
+    sid: String,
+    client_secret: String,
+    token: String,
+) -> Result<&'static str> {
+    let purpose = db
+        .threepids
+        .validate(&sid, &client_secret, &token)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Unknown or expired validation link.",
+        ))?;
+
+    db.flush()?;
+
+    Ok(match purpose {
+        ValidationPurpose::AddThreepid => {
+            "Your email address has been validated, you can go back to your client."
+        }
+        ValidationPurpose::PasswordReset => {
+            "Your email address has been validated, you can go back to your client to set a new \
+             password."
+        }
+    })
+}
+
+/// # `POST /_matrix/client/r0/account/3pid/add`
+///
+/// Adds an email address that was validated through `requestToken` to the account.
+///
+/// - Requires UIAA to verify password
+/// - Users who log in through SSO don't know their password, so they can't add addresses until
+///   there is a UIAA fallback for SSO
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/account/3pid/add", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn add_3pid_route(
+    db: DatabaseGuard,
+    client_ip: Option<IpAddr>,
+    body: Ruma<add_3pid::Request<'_>>,
+) -> ConduitResult<add_3pid::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_device = body.sender_device.as_ref().expect("user is authenticated");
+
+    let mut uiaainfo = UiaaInfo {
+        flows: vec![AuthFlow {
+            stages: vec![AuthType::Password],
+        }],
+        completed: Vec::new(),
+        params: Default::default(),
+        session: None,
+        auth_error: None,
+    };
+
+    if let Some(auth) = &body.auth {
+        let (worked, uiaainfo) = db.uiaa.try_auth(
+            sender_user,
+            sender_device,
+            auth,
+            &uiaainfo,
+            &db.users,
+            &db.globals,
+            client_ip,
+        )?;
+        if !worked {
+            return Err(Error::Uiaa(uiaainfo));
+        }
+    // Success!
+    } else if let Some(json) = &body.json_body {
+        uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
+        db.uiaa
+            .create(sender_user, sender_device, &uiaainfo, json)?;
+        return Err(Error::Uiaa(uiaainfo));
+    } else {
+        return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
+    }
+
+    let session = db
+        .threepids
+        .take_validated(
+            &body.sid,
+            &body.client_secret,
+            ValidationPurpose::AddThreepid,
+        )?
+        .ok_or(Error::BadRequest(
+            ErrorKind::ThreepidAuthFailed,
+            "The email address was not validated.",
+        ))?;
+
+    // Someone else might have added the address since the link was sent
+    if db
+        .threepids
+        .find_user(&session.medium, &session.address)?
+        .is_some()
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidInUse,
+            "Email address is already in use.",
+        ));
+    }
+
+    db.threepids.add(
+        sender_user,
+        &session.medium,
+        &session.address,
+        session
+            .validated_at
+            .expect("take_validated only returns validated sessions"),
+    )?;
+
+    db.flush()?;
+
+    Ok(add_3pid::Response {}.into())
+}
+
+/// # `POST /_matrix/client/r0/account/3pid/delete`
+///
+/// Removes a third party identifier from the account.
+///
+/// - Identity servers are not involved, so unbinding there is not supported
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/account/3pid/delete", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn delete_3pid_route(
+    db: DatabaseGuard,
+    body: Ruma<delete_3pid::Request<'_>>,
+) -> ConduitResult<delete_3pid::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let address = normalize_address(&body.medium, &body.address);
+    if !db.threepids.remove(sender_user, &body.medium, &address)? {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidNotFound,
+            "This third party identifier does not belong to your account.",
+        ));
+    }
+
+    db.flush()?;
+
+    Ok(delete_3pid::Response {
+        id_server_unbind_result: ThirdPartyIdRemovalStatus::NoSupport,
+    }
+    .into())
+}
+
+async fn request_email_token(
+    db: DatabaseGuard,
+    purpose: ValidationPurpose,
+    client_secret: &str,
+    email: &str,
+    send_attempt: UInt,
+) -> Result<String> {
+    let config = db
+        .globals
+        .email()
+        .ok_or(Error::BadRequest(
+            ErrorKind::ThreepidDenied,
+            "This server does not send emails.",
+        ))?
+        .clone();
+
+    // The client secret is part of the link, so it may only contain URL safe characters
+    if client_secret.is_empty()
+        || client_secret.len() > MAX_CLIENT_SECRET_LENGTH
+        || !client_secret
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ".=_-".contains(c))
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Invalid client secret.",
+        ));
+    }
+
+    let address = normalize_address(EMAIL_MEDIUM, email);
+    if !is_valid_email(&address) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Invalid email address.",
+        ));
+    }
+
+    let user_id = db.threepids.find_user(EMAIL_MEDIUM, &address)?;
+    match purpose {
+        ValidationPurpose::AddThreepid if user_id.is_some() => {
+            return Err(Error::BadRequest(
+                ErrorKind::ThreepidInUse,
+                "Email address is already in use.",
+            ));
+        }
+        ValidationPurpose::PasswordReset => match user_id {
+            Some(user_id) if !db.users.is_deactivated(&user_id)? => {}
+            _ => {
+                return Err(Error::BadRequest(
+                    ErrorKind::ThreepidNotFound,
+                    "Email address is not bound to an account.",
+                ));
+            }
+        },
+        _ => {}
+    }
+
+    let (sid, session) =
+        db.threepids
+            .request_token(purpose, client_secret, EMAIL_MEDIUM, &address, send_attempt)?;
+
+    db.flush()?;
+
+    let server_name = db.globals.server_name().to_owned();
+
+    // The database lock must not be held while talking to the SMTP server
+    drop(db);
+
+    if let Some(session) = session {
+        email::send_validation_email(&config, &server_name, &sid, &session).await?;
+    }
+
+    Ok(sid)
+}
+
+/// Email addresses are case insensitive, so they are stored in lowercase.
+fn normalize_address(medium: &str, address: &str) -> String {
+    if medium == EMAIL_MEDIUM {
+        address.trim().to_lowercase()
+    } else {
+        address.to_owned()
+    }
+}
+
+fn is_valid_email(address: &str) -> bool {
+    let mut parts = address.splitn(2, '@');
+
+    matches!(
+        (parts.next(), parts.next()),
+        (Some(local), Some(domain))
+            if !local.is_empty()
+                && !domain.is_empty()
+                && !domain.contains('@')
+                && !address.chars().any(|c| c.is_whitespace() || c.is_control())
+    )
+}
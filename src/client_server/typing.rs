@@ -32,5 +32,7 @@ pub fn create_typing_event_route(
             .typing_remove(sender_user, &body.room_id, &db.globals)?;
     }
 
+    db.sending.send_typing_appservices(&body.room_id, &db)?;
+
     Ok(create_typing_event::Response {}.into())
 }
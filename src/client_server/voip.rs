@@ -1,18 +1,16 @@
 use crate::{database::DatabaseGuard, ConduitResult, Ruma};
-use hmac::{Hmac, Mac, NewMac};
 use ruma::api::client::r0::voip::get_turn_server_info;
-use ruma::SecondsSinceUnixEpoch;
-use sha1::Sha1;
-use std::time::{Duration, SystemTime};
-
-type HmacSha1 = Hmac<Sha1>;
+use std::time::Duration;
 
 #[cfg(feature = "conduit_bin")]
 use rocket::get;
 
 /// # `GET /_matrix/client/r0/voip/turnServer`
 ///
-/// TODO: Returns information about the recommended turn server.
+/// Returns credentials for one of the configured TURN server groups.
+///
+/// - Credentials are created with the shared secret of the group if it has one
+/// - Returns no uris if no TURN servers are configured
 #[cfg_attr(
     feature = "conduit_bin",
     get("/_matrix/client/r0/voip/turnServer", data = "<body>")
@@ -24,35 +22,25 @@ pub async fn turn_server_route(
 ) -> ConduitResult<get_turn_server_info::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    let turn_secret = db.globals.turn_secret();
-
-    let (username, password) = if turn_secret != "" {
-        let expiry = SecondsSinceUnixEpoch::from_system_time(
-            SystemTime::now() + Duration::from_secs(db.globals.turn_ttl()),
-        )
-        .expect("time is valid");
-
-        let username: String = format!("{}:{}", expiry.get(), sender_user);
-
-        let mut mac = HmacSha1::new_from_slice(turn_secret.as_bytes())
-            .expect("HMAC can take key of any size");
-        mac.update(username.as_bytes());
-
-        let password: String = base64::encode_config(mac.finalize().into_bytes(), base64::STANDARD);
-
-        (username, password)
-    } else {
-        (
-            db.globals.turn_username().clone(),
-            db.globals.turn_password().clone(),
-        )
-    };
-
-    Ok(get_turn_server_info::Response {
-        username: username,
-        password: password,
-        uris: db.globals.turn_uris().to_vec(),
-        ttl: Duration::from_secs(db.globals.turn_ttl()),
+    let credentials = db
+        .globals
+        .turn_servers
+        .credentials(sender_user, Duration::from_secs(db.globals.turn_ttl()));
+
+    Ok(match credentials {
+        Some(credentials) => get_turn_server_info::Response {
+            username: credentials.username,
+            password: credentials.password,
+            uris: credentials.uris,
+            ttl: credentials.ttl,
+        },
+        // Clients treat empty uris as "no TURN servers available"
+        None => get_turn_server_info::Response {
+            username: String::new(),
+            password: String::new(),
+            uris: Vec::new(),
+            ttl: Duration::from_secs(0),
+        },
     }
     .into())
 }
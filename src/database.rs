@@ -3,18 +3,27 @@ pub mod abstraction;
 pub mod account_data;
 pub mod admin;
 pub mod appservice;
+pub mod email;
 pub mod globals;
 pub mod key_backups;
 pub mod media;
+pub mod policies;
 pub mod proxy;
 pub mod pusher;
+pub mod ratelimit;
+pub mod reports;
 pub mod rooms;
 pub mod sending;
+pub mod server_notices;
+pub mod sso;
+pub mod terms;
+pub mod threepids;
 pub mod transaction_ids;
+pub mod turn;
 pub mod uiaa;
 pub mod users;
 
-use crate::{utils, Error, Result};
+use crate::{utils, Error, PduEvent, Result};
 use abstraction::DatabaseEngine;
 use directories::ProjectDirs;
 use lru_cache::LruCache;
@@ -35,11 +44,19 @@ use std::{
     ops::Deref,
     path::Path,
     sync::{Arc, Mutex, RwLock},
+    time::Instant,
 };
 use tokio::sync::{OwnedRwLockReadGuard, RwLock as TokioRwLock, Semaphore};
 use tracing::{debug, error, warn};
 
-use self::proxy::ProxyConfig;
+use self::{
+    email::EmailConfig,
+    proxy::ProxyConfig,
+    ratelimit::{LoginLockoutConfig, RateLimitConfig},
+    sso::OidcConfig,
+    terms::TermsConfig,
+    turn::TurnConfig,
+};
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
@@ -53,6 +70,8 @@ pub struct Config {
     sqlite_wal_clean_second_interval: u32,
     #[serde(default = "default_max_request_size")]
     max_request_size: u32,
+    #[serde(default = "default_max_account_data_size")]
+    max_account_data_size: u64,
     #[serde(default = "default_max_concurrent_requests")]
     max_concurrent_requests: u16,
     #[serde(default = "false_fn")]
@@ -63,6 +82,8 @@ pub struct Config {
     allow_federation: bool,
     #[serde(default = "true_fn")]
     allow_room_creation: bool,
+    #[serde(default = "true_fn")]
+    allow_presence: bool,
     #[serde(default = "false_fn")]
     pub allow_jaeger: bool,
     #[serde(default = "false_fn")]
@@ -70,8 +91,17 @@ pub struct Config {
     #[serde(default)]
     proxy: ProxyConfig,
     jwt_secret: Option<String>,
+    oidc: Option<OidcConfig>,
+    email: Option<EmailConfig>,
+    terms: Option<TermsConfig>,
+    #[serde(default = "default_access_token_lifetime_secs")]
+    access_token_lifetime_secs: u64,
     #[serde(default = "Vec::new")]
     trusted_servers: Vec<Box<ServerName>>,
+    #[serde(default = "Vec::new")]
+    appservice_registration_files: Vec<String>,
+    #[serde(default = "Vec::new")]
+    policy_rooms: Vec<RoomId>,
     #[serde(default = "default_log")]
     pub log: String,
     #[serde(default)]
@@ -84,6 +114,12 @@ pub struct Config {
     turn_secret: String,
     #[serde(default = "default_turn_ttl")]
     turn_ttl: u64,
+    #[serde(default)]
+    turn: TurnConfig,
+    #[serde(default)]
+    rate_limit: RateLimitConfig,
+    #[serde(default)]
+    login_lockout: LoginLockoutConfig,
 
     #[serde(flatten)]
     catchall: BTreeMap<String, IgnoredAny>,
@@ -133,6 +169,10 @@ fn default_max_request_size() -> u32 {
     20 * 1024 * 1024 // Default to 20 MB
 }
 
+fn default_max_account_data_size() -> u64 {
+    1024 * 1024 // Default to 1 MB per user
+}
+
 fn default_max_concurrent_requests() -> u16 {
     100
 }
@@ -145,6 +185,10 @@ fn default_turn_ttl() -> u64 {
     60 * 60 * 24
 }
 
+fn default_access_token_lifetime_secs() -> u64 {
+    60 * 60
+}
+
 #[cfg(feature = "sled")]
 pub type Engine = abstraction::sled::Engine;
 
@@ -171,6 +215,13 @@ pub struct Database {
     pub admin: admin::Admin,
     pub appservice: appservice::Appservice,
     pub pusher: pusher::PushData,
+    pub sso: sso::Sso,
+    pub email: email::Email,
+    pub reports: reports::Reports,
+    pub policies: policies::Policies,
+    pub server_notices: server_notices::ServerNotices,
+    pub terms: terms::Terms,
+    pub threepids: threepids::Threepids,
 }
 
 impl Database {
@@ -230,6 +281,7 @@ impl Database {
 
         let (admin_sender, admin_receiver) = mpsc::unbounded();
         let (sending_sender, sending_receiver) = mpsc::unbounded();
+        let (server_notices_sender, server_notices_receiver) = mpsc::unbounded();
 
         let db = Arc::new(TokioRwLock::from(Self {
             _db: builder.clone(),
@@ -242,6 +294,13 @@ impl Database {
                 userdeviceid_metadata: builder.open_tree("userdeviceid_metadata")?,
                 userid_devicelistversion: builder.open_tree("userid_devicelistversion")?,
                 token_userdeviceid: builder.open_tree("token_userdeviceid")?,
+                token_expiresat: builder.open_tree("token_expiresat")?,
+                userdeviceid_refreshtoken: builder.open_tree("userdeviceid_refreshtoken")?,
+                refreshtoken_userdeviceid: builder.open_tree("refreshtoken_userdeviceid")?,
+                userdeviceid_previousrefreshtoken: builder
+                    .open_tree("userdeviceid_previousrefreshtoken")?,
+                previousrefreshtoken_userdeviceid: builder
+                    .open_tree("previousrefreshtoken_userdeviceid")?,
                 onetimekeyid_onetimekeys: builder.open_tree("onetimekeyid_onetimekeys")?,
                 userid_lastonetimekeyupdate: builder.open_tree("userid_lastonetimekeyupdate")?,
                 keychangeid_userid: builder.open_tree("keychangeid_userid")?,
@@ -250,6 +309,8 @@ impl Database {
                 userid_selfsigningkeyid: builder.open_tree("userid_selfsigningkeyid")?,
                 userid_usersigningkeyid: builder.open_tree("userid_usersigningkeyid")?,
                 todeviceid_events: builder.open_tree("todeviceid_events")?,
+                userid_shadowbanned: builder.open_tree("userid_shadowbanned")?,
+                userid_suspended: builder.open_tree("userid_suspended")?,
             },
             uiaa: uiaa::Uiaa {
                 userdevicesessionid_uiaainfo: builder.open_tree("userdevicesessionid_uiaainfo")?,
@@ -262,9 +323,13 @@ impl Database {
                     roomuserid_privateread: builder.open_tree("roomuserid_privateread")?, // "Private" read receipt
                     roomuserid_lastprivatereadupdate: builder
                         .open_tree("roomuserid_lastprivatereadupdate")?,
+                    roomuserthreadid_privatereceipt: builder
+                        .open_tree("roomuserthreadid_privatereceipt")?,
                     typingid_userid: builder.open_tree("typingid_userid")?,
                     roomid_lasttypingupdate: builder.open_tree("roomid_lasttypingupdate")?,
                     presenceid_presence: builder.open_tree("presenceid_presence")?,
+                    userid_presenceid: builder.open_tree("userid_presenceid")?,
+                    roompresenceids: builder.open_tree("roompresenceids")?,
                     userid_lastpresenceupdate: builder.open_tree("userid_lastpresenceupdate")?,
                 },
                 pduid_pdu: builder.open_tree("pduid_pdu")?,
@@ -291,6 +356,11 @@ impl Database {
 
                 userroomid_notificationcount: builder.open_tree("userroomid_notificationcount")?,
                 userroomid_highlightcount: builder.open_tree("userroomid_highlightcount")?,
+                userroomthreadid_notificationcount: builder
+                    .open_tree("userroomthreadid_notificationcount")?,
+                userroomthreadid_highlightcount: builder
+                    .open_tree("userroomthreadid_highlightcount")?,
+                useridcount_notification: builder.open_tree("useridcount_notification")?,
 
                 statekey_shortstatekey: builder.open_tree("statekey_shortstatekey")?,
                 shortstatekey_statekey: builder.open_tree("shortstatekey_statekey")?,
@@ -311,6 +381,10 @@ impl Database {
                 softfailedeventids: builder.open_tree("softfailedeventids")?,
 
                 referencedevents: builder.open_tree("referencedevents")?,
+                relationid_pduid: builder.open_tree("relationid_pduid")?,
+                roomcount_threadroot: builder.open_tree("roomcount_threadroot")?,
+                threadroot_roomcount: builder.open_tree("threadroot_roomcount")?,
+                threadrootuserids: builder.open_tree("threadrootuserids")?,
                 pdu_cache: Mutex::new(LruCache::new(
                     config
                         .pdu_cache_capacity
@@ -324,11 +398,15 @@ impl Database {
                 statekeyshort_cache: Mutex::new(LruCache::new(1_000_000)),
                 our_real_users_cache: RwLock::new(HashMap::new()),
                 appservice_in_room_cache: RwLock::new(HashMap::new()),
+                pushcontext_cache: Mutex::new(LruCache::new(1000)),
                 stateinfo_cache: Mutex::new(LruCache::new(1000)),
             },
             account_data: account_data::AccountData {
                 roomuserdataid_accountdata: builder.open_tree("roomuserdataid_accountdata")?,
                 roomusertype_roomuserdataid: builder.open_tree("roomusertype_roomuserdataid")?,
+                userroomtype_roomusertype: builder.open_tree("userroomtype_roomusertype")?,
+                userid_accountdatasize: builder.open_tree("userid_accountdatasize")?,
+                userid_mutex: RwLock::new(HashMap::new()),
             },
             media: media::Media {
                 mediaid_file: builder.open_tree("mediaid_file")?,
@@ -354,13 +432,50 @@ impl Database {
             appservice: appservice::Appservice {
                 cached_registrations: Arc::new(RwLock::new(HashMap::new())),
                 id_appserviceregistrations: builder.open_tree("id_appserviceregistrations")?,
+                id_registrationfile: builder.open_tree("id_registrationfile")?,
             },
             pusher: pusher::PushData {
                 senderkey_pusher: builder.open_tree("senderkey_pusher")?,
+                senderkey_pusherfailure: builder.open_tree("senderkey_pusherfailure")?,
+            },
+            sso: sso::Sso {
+                oidcstate_redirecturl: builder.open_tree("oidcstate_redirecturl")?,
+                logintoken_userid: builder.open_tree("logintoken_userid")?,
+                oidcsubject_userid: builder.open_tree("oidcsubject_userid")?,
+                last_cleanup: Mutex::new(Instant::now()),
+            },
+            email: email::Email {
+                senderkeycount_eventid: builder.open_tree("senderkeycount_eventid")?,
+                unsubscribetoken_senderkey: builder.open_tree("unsubscribetoken_senderkey")?,
+                senderkey_unsubscribetoken: builder.open_tree("senderkey_unsubscribetoken")?,
+            },
+            reports: reports::Reports {
+                reportid_report: builder.open_tree("reportid_report")?,
+            },
+            policies: policies::Policies {
+                rules: RwLock::new(policies::PolicyRules::default()),
+            },
+            server_notices: server_notices::ServerNotices {
+                userid_noticeroomid: builder.open_tree("userid_noticeroomid")?,
+                sender: server_notices_sender,
+            },
+            terms: terms::Terms {
+                useridpolicyid_consent: builder.open_tree("useridpolicyid_consent")?,
+                userid_consentnotice: builder.open_tree("userid_consentnotice")?,
+                consenttoken_userid: builder.open_tree("consenttoken_userid")?,
+                userid_consenttoken: builder.open_tree("userid_consenttoken")?,
+            },
+            threepids: threepids::Threepids {
+                sid_validationsession: builder.open_tree("sid_validationsession")?,
+                clientsecretaddress_sid: builder.open_tree("clientsecretaddress_sid")?,
+                useridaddress_threepid: builder.open_tree("useridaddress_threepid")?,
+                address_userid: builder.open_tree("address_userid")?,
+                last_cleanup: Mutex::new(Instant::now()),
             },
             globals: globals::Globals::load(
                 builder.open_tree("global")?,
                 builder.open_tree("server_signingkeys")?,
+                builder.open_tree("server_signedkeys")?,
                 config.clone(),
             )?,
         }));
@@ -760,17 +875,118 @@ impl Database {
 
                 println!("Migration: 9 -> 10 finished");
             }
+
+            if db.globals.database_version()? < 11 {
+                // Presence is now stored per user instead of per room
+                db.rooms.edus.presenceid_presence.clear()?;
+                db.rooms.edus.userid_lastpresenceupdate.clear()?;
+
+                db.globals.bump_database_version(11)?;
+
+                println!("Migration: 10 -> 11 finished");
+            }
+
+            if db.globals.database_version()? < 12 {
+                // Index relations of existing events
+                for (pdu_id, pdu) in db.rooms.pduid_pdu.iter() {
+                    let pdu = serde_json::from_slice::<PduEvent>(&pdu)
+                        .map_err(|_| Error::bad_database("PDU in db is invalid."))?;
+
+                    if let Some(relation) = rooms::Relation::from_pdu(&pdu).filter(|relation| {
+                        db.rooms
+                            .is_relation_in_room(relation, &pdu.room_id)
+                            .unwrap_or(false)
+                    }) {
+                        let mut relation_id = relation.event_id.as_bytes().to_vec();
+                        relation_id.push(0xff);
+                        relation_id.extend_from_slice(&pdu_id[pdu_id.len() - size_of::<u64>()..]);
+                        db.rooms.relationid_pduid.insert(&relation_id, &pdu_id)?;
+                    }
+                }
+
+                db.globals.bump_database_version(12)?;
+
+                println!("Migration: 11 -> 12 finished");
+            }
+
+            if db.globals.database_version()? < 13 {
+                // Build the thread lists from existing relations
+                db.rooms.index_threads()?;
+
+                db.globals.bump_database_version(13)?;
+
+                println!("Migration: 12 -> 13 finished");
+            }
+
+            if db.globals.database_version()? < 14 {
+                // Index account data by user and calculate the account data size of every user
+                let mut sizes = HashMap::<Vec<u8>, u64>::new();
+
+                for (key, roomuserdataid) in db.account_data.roomusertype_roomuserdataid.iter() {
+                    let mut parts = key.splitn(3, |&b| b == 0xff);
+                    let room_id = parts.next().unwrap();
+                    let user_id = parts.next().unwrap();
+                    let event_type = parts.next().unwrap();
+
+                    let mut userroomtype = user_id.to_vec();
+                    userroomtype.push(0xff);
+                    userroomtype.extend_from_slice(room_id);
+                    userroomtype.push(0xff);
+                    userroomtype.extend_from_slice(event_type);
+
+                    db.account_data
+                        .userroomtype_roomusertype
+                        .insert(&userroomtype, &key)?;
+
+                    *sizes.entry(user_id.to_vec()).or_default() += db
+                        .account_data
+                        .roomuserdataid_accountdata
+                        .get(&roomuserdataid)?
+                        .map_or(0, |data| data.len() as u64);
+                }
+
+                for (user_id, size) in sizes {
+                    db.account_data
+                        .userid_accountdatasize
+                        .insert(&user_id, &size.to_be_bytes())?;
+                }
+
+                db.globals.bump_database_version(14)?;
+
+                println!("Migration: 13 -> 14 finished");
+            }
         }
 
         let guard = db.read().await;
 
-        // This data is probably outdated
-        guard.rooms.edus.presenceid_presence.clear()?;
+        let summary = guard
+            .appservice
+            .load_registration_files(guard.globals.appservice_registration_files())?;
+        for error in summary.errors {
+            error!("Failed to load appservice registration {}", error);
+        }
+        if !summary.removed.is_empty() {
+            warn!(
+                "Removed appservices whose registration files are gone: {}",
+                summary.removed.join(", ")
+            );
+        }
+
+        guard.policies.reload(&guard.rooms, &guard.globals)?;
 
         guard.admin.start_handler(Arc::clone(&db), admin_receiver);
         guard
             .sending
             .start_handler(Arc::clone(&db), sending_receiver);
+        guard
+            .server_notices
+            .start_handler(Arc::clone(&db), server_notices_receiver);
+        guard.email.start_handler(Arc::clone(&db));
+        guard.account_data.start_handler(Arc::clone(&db));
+        guard.rooms.start_notification_log_handler(Arc::clone(&db));
+        if guard.globals.allow_presence() {
+            guard.rooms.edus.start_presence_handler(Arc::clone(&db));
+        }
 
         drop(guard);
 
@@ -831,6 +1047,16 @@ impl Database {
                 .userroomid_highlightcount
                 .watch_prefix(&userid_prefix),
         );
+        futures.push(
+            self.rooms
+                .userroomthreadid_notificationcount
+                .watch_prefix(&userid_prefix),
+        );
+        futures.push(
+            self.rooms
+                .userroomthreadid_highlightcount
+                .watch_prefix(&userid_prefix),
+        );
 
         // Events for rooms we are in
         for room_id in self.rooms.rooms_joined(user_id).filter_map(|r| r.ok()) {
@@ -877,6 +1103,14 @@ impl Database {
                     .roomusertype_roomuserdataid
                     .watch_prefix(&roomuser_prefix),
             );
+
+            // Private read receipts
+            futures.push(
+                self.rooms
+                    .edus
+                    .roomuserthreadid_privatereceipt
+                    .watch_prefix(&roomuser_prefix),
+            );
         }
 
         let mut globaluserdata_prefix = vec![0xff];
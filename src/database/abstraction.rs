@@ -15,6 +15,9 @@ pub mod heed;
 #[cfg(feature = "rocksdb")]
 pub mod rocksdb;
 
+#[cfg(test)]
+pub mod memory;
+
 pub trait DatabaseEngine: Sized {
     fn open(config: &Config) -> Result<Arc<Self>>;
     fn open_tree(self: &Arc<Self>, name: &'static str) -> Result<Arc<dyn Tree>>;
@@ -0,0 +1,96 @@
+use super::Tree;
+use crate::Result;
+use std::{collections::BTreeMap, future::Future, ops::Bound, pin::Pin, sync::RwLock};
+
+type TupleOfBytes = (Vec<u8>, Vec<u8>);
+
+/// A tree that only lives in memory, used by tests of the database services.
+///
+/// Iterators work on a snapshot, so the tree can be changed while iterating.
+#[derive(Default)]
+pub struct MemoryTree {
+    map: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl Tree for MemoryTree {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.map.read().unwrap().get(key).cloned())
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.map
+            .write()
+            .unwrap()
+            .insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn insert_batch(&self, iter: &mut dyn Iterator<Item = TupleOfBytes>) -> Result<()> {
+        self.map.write().unwrap().extend(iter);
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<()> {
+        self.map.write().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = TupleOfBytes> + 'a> {
+        let snapshot = self
+            .map
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<Vec<_>>();
+
+        Box::new(snapshot.into_iter())
+    }
+
+    fn iter_from<'a>(
+        &'a self,
+        from: &[u8],
+        backwards: bool,
+    ) -> Box<dyn Iterator<Item = TupleOfBytes> + 'a> {
+        let map = self.map.read().unwrap();
+        let snapshot = if backwards {
+            map.range::<[u8], _>((Bound::Unbounded, Bound::Included(from)))
+                .rev()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect::<Vec<_>>()
+        } else {
+            map.range::<[u8], _>((Bound::Included(from), Bound::Unbounded))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect::<Vec<_>>()
+        };
+
+        Box::new(snapshot.into_iter())
+    }
+
+    fn increment(&self, key: &[u8]) -> Result<Vec<u8>> {
+        let mut map = self.map.write().unwrap();
+        let new = crate::utils::increment(map.get(key).map(|old| &**old))
+            .expect("utils::increment always returns Some");
+        map.insert(key.to_vec(), new.clone());
+        Ok(new)
+    }
+
+    fn increment_batch(&self, iter: &mut dyn Iterator<Item = Vec<u8>>) -> Result<()> {
+        for key in iter {
+            self.increment(&key)?;
+        }
+        Ok(())
+    }
+
+    fn scan_prefix<'a>(&'a self, prefix: Vec<u8>) -> Box<dyn Iterator<Item = TupleOfBytes> + 'a> {
+        Box::new(
+            self.iter_from(&prefix, false)
+                .take_while(move |(key, _)| key.starts_with(&prefix)),
+        )
+    }
+
+    fn watch_prefix<'a>(&'a self, _prefix: &[u8]) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        // Tests don't wait for changes
+        Box::pin(std::future::pending())
+    }
+}
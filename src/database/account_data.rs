@@ -1,4 +1,4 @@
-use crate::{utils, Error, Result};
+use crate::{utils, Database, Error, Result};
 use ruma::{
     api::client::error::ErrorKind,
     events::{AnyEphemeralRoomEvent, EventType},
@@ -6,13 +6,34 @@ use ruma::{
     RoomId, UserId,
 };
 use serde::{de::DeserializeOwned, Serialize};
-use std::{collections::HashMap, convert::TryFrom, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    sync::{Arc, Mutex, RwLock as StdRwLock},
+    time::Duration,
+};
+use tokio::{sync::RwLock, time::interval};
+use tracing::{error, info};
 
 use super::abstraction::Tree;
 
+/// How often account data entries that were left behind by interrupted updates are removed.
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
 pub struct AccountData {
     pub(super) roomuserdataid_accountdata: Arc<dyn Tree>, // RoomUserDataId = Room + User + Count + Type
     pub(super) roomusertype_roomuserdataid: Arc<dyn Tree>, // RoomUserType = Room + User + Type
+    pub(super) userroomtype_roomusertype: Arc<dyn Tree>,  // UserRoomType = User + Room + Type
+    pub(super) userid_accountdatasize: Arc<dyn Tree>, // AccountDataSize = Bytes of all current entries
+    /// Updates of a user and the compaction of their entries must not interleave.
+    pub(super) userid_mutex: StdRwLock<HashMap<UserId, Arc<Mutex<()>>>>,
+}
+
+/// One account data entry of a user, as shown to admins.
+pub struct AccountDataEntry {
+    pub room_id: Option<RoomId>,
+    pub event_type: String,
+    pub size: u64,
 }
 
 impl AccountData {
@@ -26,6 +47,9 @@ impl AccountData {
         data: &T,
         globals: &super::globals::Globals,
     ) -> Result<()> {
+        let mutex = self.user_mutex(user_id);
+        let _lock = mutex.lock().unwrap();
+
         let mut prefix = room_id
             .map(|r| r.to_string())
             .unwrap_or_default()
@@ -43,6 +67,12 @@ impl AccountData {
         let mut key = prefix;
         key.extend_from_slice(event_type.as_bytes());
 
+        let mut userroomtype = user_id.as_bytes().to_vec();
+        userroomtype.push(0xff);
+        userroomtype.extend_from_slice(room_id.map(|r| r.as_bytes()).unwrap_or_default());
+        userroomtype.push(0xff);
+        userroomtype.extend_from_slice(event_type.as_bytes());
+
         let json = serde_json::to_value(data).expect("all types here can be serialized"); // TODO: maybe add error handling
         if json.get("type").is_none() || json.get("content").is_none() {
             return Err(Error::BadRequest(
@@ -51,12 +81,37 @@ impl AccountData {
             ));
         }
 
-        self.roomuserdataid_accountdata.insert(
-            &roomuserdataid,
-            &serde_json::to_vec(&json).expect("to_vec always works on json values"),
-        )?;
+        let json = serde_json::to_vec(&json).expect("to_vec always works on json values");
 
         let prev = self.roomusertype_roomuserdataid.get(&key)?;
+        let prev_size = match &prev {
+            Some(prev) => self
+                .roomuserdataid_accountdata
+                .get(prev)?
+                .map_or(0, |data| data.len() as u64),
+            None => 0,
+        };
+
+        let size = self.size(user_id)?.saturating_sub(prev_size) + json.len() as u64;
+        let max_size = globals.max_account_data_size();
+
+        // Users that are already over the limit can still make their account data smaller
+        if max_size != 0 && size > max_size && json.len() as u64 > prev_size {
+            return Err(Error::BadRequest(
+                ErrorKind::TooLarge,
+                "Account data of this user would exceed the size limit of the server.",
+            ));
+        }
+
+        self.roomuserdataid_accountdata
+            .insert(&roomuserdataid, &json)?;
+
+        // The size is updated before the switch to the new entry, so if this is interrupted,
+        // there is always an entry left behind and the compaction fixes the size
+        self.userid_accountdatasize
+            .insert(user_id.as_bytes(), &size.to_be_bytes())?;
+
+        self.userroomtype_roomusertype.insert(&userroomtype, &key)?;
 
         self.roomusertype_roomuserdataid
             .insert(&key, &roomuserdataid)?;
@@ -69,6 +124,146 @@ impl AccountData {
         Ok(())
     }
 
+    /// Returns the number of bytes of all account data of this user.
+    #[tracing::instrument(skip(self))]
+    pub fn size(&self, user_id: &UserId) -> Result<u64> {
+        self.userid_accountdatasize
+            .get(user_id.as_bytes())?
+            .map_or(Ok(0), |bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid account data size in db."))
+            })
+    }
+
+    /// Lists all current account data entries of a user, global and per room.
+    #[tracing::instrument(skip(self))]
+    pub fn entries(&self, user_id: &UserId) -> Result<Vec<AccountDataEntry>> {
+        let mut entries = Vec::new();
+
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        for (_, key) in self.userroomtype_roomusertype.scan_prefix(prefix) {
+            // The index entry is written before the first entry of the type
+            let roomuserdataid = match self.roomusertype_roomuserdataid.get(&key)? {
+                Some(roomuserdataid) => roomuserdataid,
+                None => continue,
+            };
+
+            let mut parts = key.splitn(3, |&b| b == 0xff);
+            let room_id = parts.next().expect("splitn always returns one element");
+            parts.next();
+            let event_type = parts
+                .next()
+                .ok_or_else(|| Error::bad_database("RoomUserType in db is invalid."))?;
+
+            let room_id = if room_id.is_empty() {
+                None
+            } else {
+                Some(
+                    RoomId::try_from(utils::string_from_bytes(room_id).map_err(|_| {
+                        Error::bad_database("Room ID in roomusertype_roomuserdataid is invalid.")
+                    })?)
+                    .map_err(|_| {
+                        Error::bad_database("Room ID in roomusertype_roomuserdataid is invalid.")
+                    })?,
+                )
+            };
+
+            entries.push(AccountDataEntry {
+                room_id,
+                event_type: utils::string_from_bytes(event_type)
+                    .map_err(|_| Error::bad_database("RoomUserType in db is invalid."))?,
+                size: self
+                    .roomuserdataid_accountdata
+                    .get(&roomuserdataid)?
+                    .map_or(0, |data| data.len() as u64),
+            });
+        }
+
+        Ok(entries)
+    }
+
+    /// Removes all entries that are no longer the current entry of their type, because an update
+    /// was interrupted, and recalculates the account data size of the users they belonged to.
+    ///
+    /// Only the user whose entry is checked is locked, so this can run next to updates.
+    ///
+    /// Returns how many entries were removed.
+    #[tracing::instrument(skip(self))]
+    pub fn compact(&self) -> Result<usize> {
+        let mut removed = 0;
+        let mut affected_users = HashSet::new();
+
+        let mut candidates = Vec::new();
+        for (roomuserdataid, _) in self.roomuserdataid_accountdata.iter() {
+            let (user_id, key) = roomusertype_key(&roomuserdataid)?;
+            if self.roomusertype_roomuserdataid.get(&key)?.as_deref() != Some(&*roomuserdataid) {
+                candidates.push((roomuserdataid, user_id, key));
+            }
+        }
+
+        for (roomuserdataid, user_id, key) in candidates {
+            let mutex = self.user_mutex(&user_id);
+            let _lock = mutex.lock().unwrap();
+
+            // The entry might have become the current one in the meantime
+            if self.roomusertype_roomuserdataid.get(&key)?.as_deref() != Some(&*roomuserdataid) {
+                self.roomuserdataid_accountdata.remove(&roomuserdataid)?;
+                removed += 1;
+                affected_users.insert(user_id);
+            }
+        }
+
+        // Sizes can only be wrong if an update was interrupted, which left an entry behind
+        for user_id in affected_users {
+            let mutex = self.user_mutex(&user_id);
+            let _lock = mutex.lock().unwrap();
+
+            let size = self
+                .entries(&user_id)?
+                .iter()
+                .map(|entry| entry.size)
+                .sum::<u64>();
+            self.userid_accountdatasize
+                .insert(user_id.as_bytes(), &size.to_be_bytes())?;
+        }
+
+        Ok(removed)
+    }
+
+    fn user_mutex(&self, user_id: &UserId) -> Arc<Mutex<()>> {
+        Arc::clone(
+            self.userid_mutex
+                .write()
+                .unwrap()
+                .entry(user_id.clone())
+                .or_default(),
+        )
+    }
+
+    /// Compacts the account data regularly in the background.
+    pub fn start_handler(&self, db: Arc<RwLock<Database>>) {
+        tokio::spawn(async move {
+            let mut i = interval(COMPACTION_INTERVAL);
+            // The first tick completes immediately, but startup is busy enough
+            i.tick().await;
+
+            loop {
+                i.tick().await;
+
+                let guard = db.read().await;
+                match guard.account_data.compact().and_then(|removed| {
+                    guard.flush()?;
+                    Ok(removed)
+                }) {
+                    Ok(removed) => info!("Removed {} stale account data entries", removed),
+                    Err(e) => error!("Failed to compact account data: {}", e),
+                }
+            }
+        });
+    }
+
     /// Searches the account data for a specific kind.
     #[tracing::instrument(skip(self, room_id, user_id, kind))]
     pub fn get<T: DeserializeOwned>(
@@ -151,3 +346,30 @@ impl AccountData {
         Ok(userdata)
     }
 }
+
+/// Returns the user and the RoomUserType key of an entry.
+fn roomusertype_key(roomuserdataid: &[u8]) -> Result<(UserId, Vec<u8>)> {
+    let mut parts = roomuserdataid.split(|&b| b == 0xff);
+    let room_id = parts.next().expect("split always returns one element");
+    let user_id = parts
+        .next()
+        .ok_or_else(|| Error::bad_database("RoomUserData ID in db is invalid."))?;
+    let event_type = roomuserdataid
+        .rsplit(|&b| b == 0xff)
+        .next()
+        .expect("rsplit always returns one element");
+
+    let mut key = room_id.to_vec();
+    key.push(0xff);
+    key.extend_from_slice(user_id);
+    key.push(0xff);
+    key.extend_from_slice(event_type);
+
+    let user_id = UserId::try_from(
+        utils::string_from_bytes(user_id)
+            .map_err(|_| Error::bad_database("User ID in roomuserdataid is invalid."))?,
+    )
+    .map_err(|_| Error::bad_database("User ID in roomuserdataid is invalid."))?;
+
+    Ok((user_id, key))
+}
@@ -3,20 +3,34 @@ use std::{
     sync::Arc,
 };
 
-use crate::{pdu::PduBuilder, Database};
+use crate::{pdu::PduBuilder, Database, Error, Result};
 use rocket::futures::{channel::mpsc, stream::StreamExt};
 use ruma::{
-    events::{room::message::RoomMessageEventContent, EventType},
-    UserId,
+    events::{
+        room::{
+            member::{MembershipState, RoomMemberEventContent},
+            message::RoomMessageEventContent,
+            redaction::RoomRedactionEventContent,
+        },
+        EventType,
+    },
+    RoomId, UserId,
 };
 use serde_json::value::to_raw_value;
 use tokio::sync::{MutexGuard, RwLock, RwLockReadGuard};
-use tracing::warn;
+use tracing::{info, warn};
+
+use super::reports::ReportAction;
 
 pub enum AdminCommand {
     RegisterAppservice(serde_yaml::Value),
     ListAppservices,
     SendMessage(RoomMessageEventContent),
+    ActOnReport {
+        report_id: u64,
+        action: ReportAction,
+        admin: UserId,
+    },
 }
 
 #[derive(Clone)]
@@ -85,6 +99,20 @@ impl Admin {
                 tokio::select! {
                     Some(event) = receiver.next() => {
                         let guard = db.read().await;
+
+                        // Acting on a report sends events into other rooms, which can't happen
+                        // while we hold the lock of the admin room
+                        let event = match event {
+                            AdminCommand::ActOnReport { report_id, action, admin } => {
+                                let message = match act_on_report(report_id, action, &admin, &guard).await {
+                                    Ok(message) => message,
+                                    Err(e) => format!("Failed to act on report {}: {}", report_id, e),
+                                };
+                                AdminCommand::SendMessage(RoomMessageEventContent::text_plain(message))
+                            }
+                            event => event,
+                        };
+
                         let mutex_state = Arc::clone(
                             guard.globals
                                 .roomid_mutex_state
@@ -97,7 +125,10 @@ impl Admin {
 
                         match event {
                             AdminCommand::RegisterAppservice(yaml) => {
-                                guard.appservice.register_appservice(yaml).unwrap(); // TODO handle error
+                                match guard.appservice.register_appservice(yaml) {
+                                    Ok(()) => send_message(RoomMessageEventContent::text_plain("Appservice registered."), guard, &state_lock),
+                                    Err(e) => send_message(RoomMessageEventContent::text_plain(format!("Failed to register appservice: {}", e)), guard, &state_lock),
+                                }
                             }
                             AdminCommand::ListAppservices => {
                                 if let Ok(appservices) = guard.appservice.iter_ids().map(|ids| ids.collect::<Vec<_>>()) {
@@ -115,6 +146,7 @@ impl Admin {
                             AdminCommand::SendMessage(message) => {
                                 send_message(message, guard, &state_lock);
                             }
+                            AdminCommand::ActOnReport { .. } => unreachable!("handled above"),
                         }
 
                         drop(state_lock);
@@ -128,3 +160,198 @@ impl Admin {
         self.sender.unbounded_send(command).unwrap();
     }
 }
+
+/// Redacts the reported event, kicks its sender or deactivates the sender's account and records
+/// this in the report. Events are sent in the name of the admin who asked for the action, so they
+/// need to be in the room with enough power.
+async fn act_on_report(
+    report_id: u64,
+    action: ReportAction,
+    admin: &UserId,
+    db: &Database,
+) -> Result<String> {
+    let report = match db.reports.get(report_id)? {
+        Some(report) => report,
+        None => return Ok(format!("Report {} does not exist.", report_id)),
+    };
+
+    if let Some(problem) = missing_power(action, admin, &report.room_id, &report.sender, db)? {
+        return Ok(problem);
+    }
+
+    let done = match action {
+        ReportAction::Redact => {
+            send_as_admin(
+                PduBuilder {
+                    event_type: EventType::RoomRedaction,
+                    content: to_raw_value(&RoomRedactionEventContent {
+                        reason: Some(format!("Removed because of report {}", report_id)),
+                    })
+                    .expect("event is valid, we just created it"),
+                    unsigned: None,
+                    state_key: None,
+                    redacts: Some(report.event_id.clone()),
+                },
+                admin,
+                &report.room_id,
+                db,
+            )
+            .await?;
+
+            format!("Redacted {}", report.event_id)
+        }
+        ReportAction::Kick => {
+            let member_event = match db.rooms.room_state_get(
+                &report.room_id,
+                &EventType::RoomMember,
+                report.sender.as_str(),
+            )? {
+                Some(member_event) => member_event,
+                None => {
+                    return Ok(format!(
+                        "{} is not a member of {}.",
+                        report.sender, report.room_id
+                    ))
+                }
+            };
+
+            let mut event: RoomMemberEventContent =
+                serde_json::from_str(member_event.content.get())
+                    .map_err(|_| Error::bad_database("Invalid member event in database."))?;
+
+            event.membership = MembershipState::Leave;
+            event.reason = Some(format!("Kicked because of report {}", report_id));
+
+            send_as_admin(
+                PduBuilder {
+                    event_type: EventType::RoomMember,
+                    content: to_raw_value(&event).expect("event is valid, we just created it"),
+                    unsigned: None,
+                    state_key: Some(report.sender.to_string()),
+                    redacts: None,
+                },
+                admin,
+                &report.room_id,
+                db,
+            )
+            .await?;
+
+            format!("Kicked {} from {}", report.sender, report.room_id)
+        }
+        ReportAction::Deactivate => {
+            if report.sender.server_name() != db.globals.server_name() {
+                return Ok(format!(
+                    "{} is not a local user and can't be deactivated.",
+                    report.sender
+                ));
+            }
+
+            let all_rooms = db
+                .rooms
+                .rooms_joined(&report.sender)
+                .chain(
+                    db.rooms
+                        .rooms_invited(&report.sender)
+                        .map(|t| t.map(|(r, _)| r)),
+                )
+                .collect::<Vec<_>>();
+
+            for room_id in all_rooms {
+                db.rooms.leave_room(&report.sender, &room_id?, db).await?;
+            }
+
+            db.users.deactivate_account(&report.sender)?;
+            db.threepids.remove_all(&report.sender)?;
+
+            info!(
+                "{} deactivated {} because of report {}",
+                admin, report.sender, report_id
+            );
+
+            format!("Deactivated {}", report.sender)
+        }
+    };
+
+    db.reports
+        .add_action(report_id, format!("{} by {}", done, admin))?;
+    db.flush()?;
+
+    Ok(format!(
+        "{}. Use resolve_report {} to close the report.",
+        done, report_id
+    ))
+}
+
+/// Explains why the admin can't redact or kick in the room of the report, if they can't.
+fn missing_power(
+    action: ReportAction,
+    admin: &UserId,
+    room_id: &RoomId,
+    sender: &UserId,
+    db: &Database,
+) -> Result<Option<String>> {
+    let push_context = db.rooms.push_context(room_id)?;
+    let power_levels = &push_context.power_levels;
+
+    let (needed, what) = match action {
+        ReportAction::Redact => (power_levels.redact, "redact"),
+        ReportAction::Kick => (power_levels.kick, "kick"),
+        ReportAction::Deactivate => return Ok(None),
+    };
+
+    if !db.rooms.is_joined(admin, room_id)? {
+        return Ok(Some(format!(
+            "You need to join {} to {} there.",
+            room_id, what
+        )));
+    }
+
+    let power_of = |user_id: &UserId| {
+        power_levels
+            .users
+            .get(user_id)
+            .copied()
+            .unwrap_or(power_levels.users_default)
+    };
+    let power = power_of(admin);
+
+    if admin != sender && power < needed {
+        return Ok(Some(format!(
+            "You need power level {} in {} to {} there, but you have {}.",
+            needed, room_id, what, power
+        )));
+    }
+
+    if let ReportAction::Kick = action {
+        if power <= power_of(sender) {
+            return Ok(Some(format!(
+                "You can't kick {} from {}, because their power level is not below yours.",
+                sender, room_id
+            )));
+        }
+    }
+
+    Ok(None)
+}
+
+async fn send_as_admin(
+    pdu_builder: PduBuilder,
+    admin: &UserId,
+    room_id: &RoomId,
+    db: &Database,
+) -> Result<()> {
+    let mutex_state = Arc::clone(
+        db.globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.clone())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    db.rooms
+        .build_and_append_pdu(pdu_builder, admin, room_id, db, &state_lock)?;
+
+    Ok(())
+}
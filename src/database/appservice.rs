@@ -1,52 +1,371 @@
 use crate::{utils, Error, Result};
+use regex::Regex;
+use ruma::{api::client::error::ErrorKind, RoomAliasId, ServerName, UserId};
 use std::{
     collections::HashMap,
+    fs,
     sync::{Arc, RwLock},
 };
 
 use super::abstraction::Tree;
 
+/// The user, alias and room namespaces of an appservice registration with compiled regexes.
+#[derive(Clone, Debug)]
+pub struct Namespaces {
+    users: Vec<Namespace>,
+    aliases: Vec<Namespace>,
+    rooms: Vec<Namespace>,
+}
+
+#[derive(Clone, Debug)]
+struct Namespace {
+    regex: Regex,
+    exclusive: bool,
+}
+
+impl Namespaces {
+    /// Compiles the namespaces of a registration. The regexes have to match the whole id.
+    fn from_registration(registration: &serde_yaml::Value) -> Result<Self> {
+        let namespace = |kind: &str| {
+            registration
+                .get("namespaces")
+                .and_then(|ns| ns.get(kind))
+                .and_then(|ns| ns.as_sequence())
+                .into_iter()
+                .flatten()
+                .map(|ns| {
+                    let regex = ns
+                        .get("regex")
+                        .and_then(|regex| regex.as_str())
+                        .and_then(|regex| Regex::new(&format!("^(?:{})$", regex)).ok())
+                        .ok_or(Error::BadRequest(
+                            ErrorKind::InvalidParam,
+                            "Appservice registration contains an invalid namespace regex.",
+                        ))?;
+
+                    Ok(Namespace {
+                        regex,
+                        exclusive: ns
+                            .get("exclusive")
+                            .and_then(|e| e.as_bool())
+                            .unwrap_or(false),
+                    })
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+
+        Ok(Self {
+            users: namespace("users")?,
+            aliases: namespace("aliases")?,
+            rooms: namespace("rooms")?,
+        })
+    }
+
+    pub fn is_user_match(&self, user_id: &UserId) -> bool {
+        self.users
+            .iter()
+            .any(|ns| ns.regex.is_match(user_id.as_str()))
+    }
+
+    pub fn is_exclusive_user_match(&self, user_id: &UserId) -> bool {
+        self.users
+            .iter()
+            .any(|ns| ns.exclusive && ns.regex.is_match(user_id.as_str()))
+    }
+
+    pub fn is_alias_match(&self, alias: &RoomAliasId) -> bool {
+        self.aliases
+            .iter()
+            .any(|ns| ns.regex.is_match(alias.as_str()))
+    }
+
+    pub fn is_exclusive_alias_match(&self, alias: &RoomAliasId) -> bool {
+        self.aliases
+            .iter()
+            .any(|ns| ns.exclusive && ns.regex.is_match(alias.as_str()))
+    }
+
+    pub fn is_room_match(&self, room_id: &str) -> bool {
+        self.rooms.iter().any(|ns| ns.regex.is_match(room_id))
+    }
+}
+
+/// An appservice registration, parsed once when it is registered or loaded.
+#[derive(Clone, Debug)]
+pub struct RegistrationInfo {
+    pub id: String,
+    pub url: String,
+    pub as_token: String,
+    pub hs_token: String,
+    pub sender_localpart: String,
+    pub namespaces: Namespaces,
+    /// Whether the appservice opted in to receiving ephemeral events like receipts and typing
+    /// notifications (MSC2409).
+    pub receive_ephemeral: bool,
+    pub protocols: Vec<String>,
+    /// The registration as the appservice sent it.
+    pub yaml: serde_yaml::Value,
+}
+
+impl RegistrationInfo {
+    /// Parses a registration. All required fields need to be strings and all namespace regexes
+    /// need to compile.
+    pub fn parse(yaml: serde_yaml::Value) -> Result<Self> {
+        let field = |name: &str| {
+            yaml.get(name)
+                .and_then(|value| value.as_str())
+                .map(ToOwned::to_owned)
+        };
+
+        let id = field("id").ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Appservice registration has no id.",
+        ))?;
+        let as_token = field("as_token").ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Appservice registration has no as_token.",
+        ))?;
+        let hs_token = field("hs_token").ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Appservice registration has no hs_token.",
+        ))?;
+        let (url, sender_localpart) = match (field("url"), field("sender_localpart")) {
+            (Some(url), Some(sender_localpart)) => (url, sender_localpart),
+            _ => {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Appservice registration needs a url and a sender_localpart.",
+                ))
+            }
+        };
+
+        let namespaces = Namespaces::from_registration(&yaml)?;
+
+        let receive_ephemeral = ["de.sorunome.msc2409.push_ephemeral", "receive_ephemeral"]
+            .iter()
+            .any(|key| {
+                yaml.get(*key)
+                    .and_then(|value| value.as_bool())
+                    .unwrap_or(false)
+            });
+
+        let protocols = yaml
+            .get("protocols")
+            .and_then(|protocols| protocols.as_sequence())
+            .into_iter()
+            .flatten()
+            .filter_map(|protocol| protocol.as_str())
+            .map(ToOwned::to_owned)
+            .collect();
+
+        Ok(Self {
+            id,
+            url,
+            as_token,
+            hs_token,
+            sender_localpart,
+            namespaces,
+            receive_ephemeral,
+            protocols,
+            yaml,
+        })
+    }
+
+    /// Returns the user the appservice acts as when it does not masquerade as another user.
+    pub fn sender_user(&self, server_name: &ServerName) -> Option<UserId> {
+        UserId::parse_with_server_name(self.sender_localpart.as_str(), server_name).ok()
+    }
+
+    /// Whether the appservice may act as this user: its sender or a user in its user namespace.
+    pub fn is_user_allowed(&self, user_id: &UserId, server_name: &ServerName) -> bool {
+        user_id.server_name() == server_name
+            && (self.sender_user(server_name).as_ref() == Some(user_id)
+                || self.namespaces.is_user_match(user_id))
+    }
+
+    /// Whether the user is one of the appservice's own users: its sender or a user in an
+    /// exclusive namespace. Users in non-exclusive namespaces can also be real users.
+    pub fn is_appservice_user(&self, user_id: &UserId, server_name: &ServerName) -> bool {
+        user_id.server_name() == server_name
+            && (self.sender_user(server_name).as_ref() == Some(user_id)
+                || self.namespaces.is_exclusive_user_match(user_id))
+    }
+}
+
 pub struct Appservice {
-    pub(super) cached_registrations: Arc<RwLock<HashMap<String, serde_yaml::Value>>>,
+    pub(super) cached_registrations: Arc<RwLock<HashMap<String, RegistrationInfo>>>,
     pub(super) id_appserviceregistrations: Arc<dyn Tree>,
+    pub(super) id_registrationfile: Arc<dyn Tree>, // Only for registrations from files
+}
+
+/// The outcome of loading the registration files.
+#[derive(Default)]
+pub struct ReloadSummary {
+    pub loaded: Vec<String>,
+    pub removed: Vec<String>,
+    pub errors: Vec<String>,
 }
 
 impl Appservice {
+    /// Validates and stores a new registration.
+    ///
+    /// - All required fields need to be strings and all namespace regexes need to compile
+    /// - The ID and both tokens can't be used by another appservice
     pub fn register_appservice(&self, yaml: serde_yaml::Value) -> Result<()> {
-        // TODO: Rumaify
-        let id = yaml.get("id").unwrap().as_str().unwrap();
+        let registration = RegistrationInfo::parse(yaml)?;
+        self.validate_registration(&registration, None)?;
+        self.store_registration(registration)
+    }
+
+    /// Removes a registration, no matter if it was registered in the admin room or from a file.
+    ///
+    /// Returns false if no appservice with this id exists.
+    pub fn unregister_appservice(&self, id: &str) -> Result<bool> {
+        let existed = self.get_registration(id)?.is_some();
+
+        self.id_appserviceregistrations.remove(id.as_bytes())?;
+        self.id_registrationfile.remove(id.as_bytes())?;
+        self.cached_registrations.write().unwrap().remove(id);
+
+        Ok(existed)
+    }
+
+    /// Loads the registration files and reconciles them with the stored registrations.
+    ///
+    /// - Registrations from files replace stored registrations with the same id
+    /// - Registrations that were loaded from a file before but are no longer in any file are
+    /// removed, unless their file could not be read this time
+    #[tracing::instrument(skip(self))]
+    pub fn load_registration_files(&self, paths: &[String]) -> Result<ReloadSummary> {
+        let mut summary = ReloadSummary::default();
+        let mut failed_paths = Vec::new();
+
+        for path in paths {
+            let yaml = match fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|content| {
+                    serde_yaml::from_str::<serde_yaml::Value>(&content).map_err(|e| e.to_string())
+                }) {
+                Ok(yaml) => yaml,
+                Err(e) => {
+                    summary.errors.push(format!("{}: {}", path, e));
+                    failed_paths.push(path.as_str());
+                    continue;
+                }
+            };
+
+            let id = match RegistrationInfo::parse(yaml).and_then(|registration| {
+                let id = registration.id.clone();
+                // A registration file replaces the stored registration with the same id
+                self.validate_registration(&registration, Some(&id))?;
+                self.store_registration(registration)?;
+                Ok(id)
+            }) {
+                Ok(id) => id,
+                Err(e) => {
+                    summary.errors.push(format!("{}: {}", path, e));
+                    failed_paths.push(path.as_str());
+                    continue;
+                }
+            };
+
+            self.id_registrationfile
+                .insert(id.as_bytes(), path.as_bytes())?;
+            summary.loaded.push(id);
+        }
+
+        let registration_files = self.id_registrationfile.iter().collect::<Vec<_>>();
+        for (id, path) in registration_files {
+            let id = utils::string_from_bytes(&id)
+                .map_err(|_| Error::bad_database("Invalid id bytes in id_registrationfile."))?;
+            let path = utils::string_from_bytes(&path)
+                .map_err(|_| Error::bad_database("Invalid path bytes in id_registrationfile."))?;
+
+            if !summary.loaded.contains(&id) && !failed_paths.contains(&path.as_str()) {
+                self.unregister_appservice(&id)?;
+                summary.removed.push(id);
+            }
+        }
+
+        Ok(summary)
+    }
+
+    /// Checks that the id and the tokens of a registration are not used by another appservice.
+    /// `replaces` is the id of a registration that will be overwritten by this one.
+    fn validate_registration(
+        &self,
+        registration: &RegistrationInfo,
+        replaces: Option<&str>,
+    ) -> Result<()> {
+        for (other_id, other) in self.all()? {
+            if Some(&*other_id) == replaces {
+                continue;
+            }
+
+            if other_id == registration.id {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "An appservice with this id is already registered.",
+                ));
+            }
+
+            let other_tokens = [&other.as_token, &other.hs_token];
+            if other_tokens.contains(&&registration.as_token)
+                || other_tokens.contains(&&registration.hs_token)
+            {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Another appservice already uses this token.",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn store_registration(&self, registration: RegistrationInfo) -> Result<()> {
         self.id_appserviceregistrations.insert(
-            id.as_bytes(),
-            serde_yaml::to_string(&yaml).unwrap().as_bytes(),
+            registration.id.as_bytes(),
+            serde_yaml::to_string(&registration.yaml)
+                .unwrap()
+                .as_bytes(),
         )?;
         self.cached_registrations
             .write()
             .unwrap()
-            .insert(id.to_owned(), yaml);
+            .insert(registration.id.clone(), registration);
 
         Ok(())
     }
 
-    pub fn get_registration(&self, id: &str) -> Result<Option<serde_yaml::Value>> {
-        self.cached_registrations
-            .read()
-            .unwrap()
-            .get(id)
-            .map_or_else(
-                || {
-                    self.id_appserviceregistrations
-                        .get(id.as_bytes())?
-                        .map(|bytes| {
-                            serde_yaml::from_slice(&bytes).map_err(|_| {
-                                Error::bad_database(
-                                    "Invalid registration bytes in id_appserviceregistrations.",
-                                )
-                            })
-                        })
-                        .transpose()
-                },
-                |r| Ok(Some(r.clone())),
-            )
+    pub fn get_registration(&self, id: &str) -> Result<Option<RegistrationInfo>> {
+        if let Some(registration) = self.cached_registrations.read().unwrap().get(id) {
+            return Ok(Some(registration.clone()));
+        }
+
+        let registration = self
+            .id_appserviceregistrations
+            .get(id.as_bytes())?
+            .map(|bytes| {
+                serde_yaml::from_slice(&bytes)
+                    .ok()
+                    .and_then(|yaml| RegistrationInfo::parse(yaml).ok())
+                    .ok_or_else(|| {
+                        Error::bad_database(
+                            "Invalid registration bytes in id_appserviceregistrations.",
+                        )
+                    })
+            })
+            .transpose()?;
+
+        if let Some(registration) = &registration {
+            self.cached_registrations
+                .write()
+                .unwrap()
+                .insert(id.to_owned(), registration.clone());
+        }
+
+        Ok(registration)
     }
 
     pub fn iter_ids(&self) -> Result<impl Iterator<Item = Result<String>> + '_> {
@@ -56,7 +375,7 @@ impl Appservice {
         }))
     }
 
-    pub fn all(&self) -> Result<Vec<(String, serde_yaml::Value)>> {
+    pub fn all(&self) -> Result<Vec<(String, RegistrationInfo)>> {
         self.iter_ids()?
             .filter_map(|id| id.ok())
             .map(move |id| {
@@ -68,4 +387,129 @@ impl Appservice {
             })
             .collect()
     }
+
+    /// Returns the id of the appservice that claims this user id exclusively.
+    pub fn exclusive_user_owner(&self, user_id: &UserId) -> Result<Option<String>> {
+        Ok(self.all()?.into_iter().find_map(|(id, registration)| {
+            registration
+                .namespaces
+                .is_exclusive_user_match(user_id)
+                .then(|| id)
+        }))
+    }
+
+    /// Returns the id of the appservice that claims this alias exclusively.
+    pub fn exclusive_alias_owner(&self, alias: &RoomAliasId) -> Result<Option<String>> {
+        Ok(self.all()?.into_iter().find_map(|(id, registration)| {
+            registration
+                .namespaces
+                .is_exclusive_alias_match(alias)
+                .then(|| id)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RegistrationInfo;
+    use ruma::{RoomAliasId, ServerName, UserId};
+    use std::convert::TryFrom;
+
+    fn registration(users_regex: &str) -> serde_yaml::Value {
+        serde_yaml::from_str(&format!(
+            r#"
+id: bridge
+url: "http://localhost:9000"
+as_token: as_secret
+hs_token: hs_secret
+sender_localpart: bridgebot
+namespaces:
+  users:
+    - exclusive: true
+      regex: '{}'
+  aliases:
+    - exclusive: false
+      regex: '#bridge_.*:example\.org'
+protocols:
+  - irc
+"#,
+            users_regex
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn namespace_regexes_match_the_whole_id() {
+        let registration =
+            RegistrationInfo::parse(registration(r"@bridge_.*:example\.org")).unwrap();
+        let namespaces = &registration.namespaces;
+
+        let inside = UserId::try_from("@bridge_alice:example.org").unwrap();
+        let prefixed = UserId::try_from("@evil_bridge_alice:example.org").unwrap();
+        let suffixed = UserId::try_from("@bridge_alice:example.org.evil.com").unwrap();
+
+        assert!(namespaces.is_exclusive_user_match(&inside));
+        assert!(!namespaces.is_user_match(&prefixed));
+        assert!(!namespaces.is_user_match(&suffixed));
+
+        assert!(namespaces.is_alias_match(&RoomAliasId::try_from("#bridge_x:example.org").unwrap()));
+        assert!(!namespaces
+            .is_exclusive_alias_match(&RoomAliasId::try_from("#bridge_x:example.org").unwrap()));
+        assert!(
+            !namespaces.is_alias_match(&RoomAliasId::try_from("#a_bridge_x:example.org").unwrap())
+        );
+    }
+
+    #[test]
+    fn registration_fields_are_parsed() {
+        let registration =
+            RegistrationInfo::parse(registration(r"@bridge_.*:example\.org")).unwrap();
+        let server_name = <&ServerName>::try_from("example.org").unwrap();
+
+        assert_eq!(registration.id, "bridge");
+        assert_eq!(registration.url, "http://localhost:9000");
+        assert_eq!(registration.protocols, vec!["irc".to_owned()]);
+        assert!(!registration.receive_ephemeral);
+
+        let sender = UserId::try_from("@bridgebot:example.org").unwrap();
+        assert_eq!(registration.sender_user(server_name), Some(sender.clone()));
+        assert!(registration.is_user_allowed(&sender, server_name));
+        assert!(!registration.is_user_allowed(
+            &UserId::try_from("@alice:example.org").unwrap(),
+            server_name
+        ));
+        assert!(!registration.is_user_allowed(
+            &UserId::try_from("@bridge_alice:other.org").unwrap(),
+            server_name
+        ));
+    }
+
+    #[test]
+    fn only_exclusive_namespaces_contain_appservice_users() {
+        let server_name = <&ServerName>::try_from("example.org").unwrap();
+        let alice = UserId::try_from("@alice:example.org").unwrap();
+        let sender = UserId::try_from("@bridgebot:example.org").unwrap();
+
+        let exclusive = RegistrationInfo::parse(registration(r"@.*:example\.org")).unwrap();
+        assert!(exclusive.is_appservice_user(&alice, server_name));
+
+        let mut yaml = registration(r"@.*:example\.org");
+        yaml["namespaces"]["users"][0]["exclusive"] = false.into();
+        let non_exclusive = RegistrationInfo::parse(yaml).unwrap();
+        assert!(non_exclusive.is_user_allowed(&alice, server_name));
+        assert!(!non_exclusive.is_appservice_user(&alice, server_name));
+        assert!(non_exclusive.is_appservice_user(&sender, server_name));
+    }
+
+    #[test]
+    fn invalid_registrations_are_rejected() {
+        assert!(RegistrationInfo::parse(registration("@bridge_(.*:example.org")).is_err());
+
+        let mut missing_token = registration(r"@bridge_.*:example\.org");
+        missing_token
+            .as_mapping_mut()
+            .unwrap()
+            .remove(&"hs_token".into());
+        assert!(RegistrationInfo::parse(missing_token).is_err());
+    }
 }
@@ -0,0 +1,854 @@
+use std::{
+    collections::BTreeMap, convert::TryFrom, fs, mem::size_of, path::Path, sync::Arc,
+    time::Duration,
+};
+
+use crate::{utils, Database, Error, Result};
+use ruma::{
+    events::{room::name::RoomNameEventContent, EventType},
+    EventId, ServerName, UserId,
+};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::RwLock,
+    time::interval,
+};
+use tracing::{error, info, warn};
+
+use super::{
+    abstraction::Tree,
+    threepids::{ValidationPurpose, ValidationSession},
+};
+
+const UNSUBSCRIBE_TOKEN_LENGTH: usize = 32;
+const MIME_BOUNDARY_LENGTH: usize = 32;
+/// How often the queue is checked for digests that are due.
+const DIGEST_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+const DEFAULT_TEXT_TEMPLATE: &str = "\
+You have {{count}} unread highlighted messages on {{server_name}}:
+
+{{messages}}
+
+To stop receiving these emails, open {{unsubscribe_link}}
+";
+
+const DEFAULT_HTML_TEMPLATE: &str = "\
+<!DOCTYPE html>
+<html>
+<body>
+<p>You have {{count}} unread highlighted messages on {{server_name}}:</p>
+<ul>
+{{messages}}
+</ul>
+<p><a href=\"{{unsubscribe_link}}\">Unsubscribe from these emails</a></p>
+</body>
+</html>
+";
+
+/// Email notifications and the links to validate email addresses are sent through an SMTP relay
+/// that accepts unauthenticated mail from Conduit, usually one running on the same machine.
+///
+/// ## Example:
+/// ```toml
+/// [global.email]
+/// smtp_host = "localhost"
+/// smtp_port = 25
+/// notif_from = "Conduit <noreply@example.com>"
+/// public_baseurl = "https://matrix.example.com"
+/// ```
+///
+/// `template_dir` can point to a directory with `notif_mail.txt` and `notif_mail.html` to replace
+/// the built-in templates. These can use `{{server_name}}`, `{{user_id}}`, `{{count}}`,
+/// `{{messages}}` and `{{unsubscribe_link}}`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct EmailConfig {
+    #[serde(default = "default_smtp_host")]
+    pub smtp_host: String,
+    #[serde(default = "default_smtp_port")]
+    pub smtp_port: u16,
+    pub notif_from: String,
+    /// The public URL of this server, used for unsubscribe and validation links.
+    pub public_baseurl: String,
+    /// How long highlights are collected before a digest email is sent.
+    #[serde(default = "default_digest_delay_secs")]
+    pub digest_delay_secs: u64,
+    pub template_dir: Option<String>,
+}
+
+fn default_smtp_host() -> String {
+    "localhost".to_owned()
+}
+
+fn default_smtp_port() -> u16 {
+    25
+}
+
+fn default_digest_delay_secs() -> u64 {
+    10 * 60
+}
+
+pub struct Email {
+    pub(super) senderkeycount_eventid: Arc<dyn Tree>, // SenderKeyCount = SenderKey + Count, EventId = QueuedAt + EventId
+    pub(super) unsubscribetoken_senderkey: Arc<dyn Tree>,
+    pub(super) senderkey_unsubscribetoken: Arc<dyn Tree>,
+}
+
+impl Email {
+    /// Remembers a highlight for the next digest email of an email pusher.
+    #[tracing::instrument(skip(self, senderkey, event_id, count))]
+    pub fn queue_notification(
+        &self,
+        senderkey: &[u8],
+        event_id: &EventId,
+        count: u64,
+    ) -> Result<()> {
+        let mut key = senderkey.to_vec();
+        key.push(0xff);
+        key.extend_from_slice(&count.to_be_bytes());
+
+        let mut value = utils::millis_since_unix_epoch().to_be_bytes().to_vec();
+        value.extend_from_slice(event_id.as_bytes());
+
+        self.senderkeycount_eventid.insert(&key, &value)
+    }
+
+    /// Returns the queued highlights of all email pushers whose oldest highlight waited longer
+    /// than `delay`.
+    #[tracing::instrument(skip(self))]
+    pub fn due_digests(
+        &self,
+        delay: Duration,
+    ) -> Result<BTreeMap<Vec<u8>, Vec<(Vec<u8>, EventId)>>> {
+        let now = utils::millis_since_unix_epoch();
+        let delay = u64::try_from(delay.as_millis()).unwrap_or(u64::MAX);
+
+        let mut digests: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        let mut due = BTreeMap::new();
+
+        for (key, value) in self.senderkeycount_eventid.iter() {
+            let senderkey = key
+                .len()
+                .checked_sub(size_of::<u64>() + 1)
+                .map(|len| key[..len].to_vec())
+                .ok_or_else(|| Error::bad_database("Invalid key in senderkeycount_eventid."))?;
+
+            if value.len() < size_of::<u64>() {
+                return Err(Error::bad_database(
+                    "Invalid value in senderkeycount_eventid.",
+                ));
+            }
+            let (queued_at, event_id) = value.split_at(size_of::<u64>());
+            let queued_at = utils::u64_from_bytes(queued_at)
+                .map_err(|_| Error::bad_database("Invalid timestamp in senderkeycount_eventid."))?;
+            let event_id = EventId::try_from(utils::string_from_bytes(event_id).map_err(|_| {
+                Error::bad_database("Event ID in senderkeycount_eventid is invalid unicode.")
+            })?)
+            .map_err(|_| Error::bad_database("Event ID in senderkeycount_eventid is invalid."))?;
+
+            // Entries are sorted by count, so the first entry of every pusher is the oldest
+            due.entry(senderkey.clone())
+                .or_insert_with(|| queued_at.saturating_add(delay) <= now);
+
+            digests.entry(senderkey).or_default().push((key, event_id));
+        }
+
+        digests.retain(|senderkey, _| due.get(senderkey) == Some(&true));
+
+        Ok(digests)
+    }
+
+    #[tracing::instrument(skip(self, keys))]
+    pub fn remove_queued(&self, keys: &[Vec<u8>]) -> Result<()> {
+        for key in keys {
+            self.senderkeycount_eventid.remove(key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the token for the unsubscribe link of an email pusher.
+    #[tracing::instrument(skip(self, senderkey))]
+    pub fn unsubscribe_token(&self, senderkey: &[u8]) -> Result<String> {
+        if let Some(token) = self.senderkey_unsubscribetoken.get(senderkey)? {
+            return utils::string_from_bytes(&token)
+                .map_err(|_| Error::bad_database("Invalid token in senderkey_unsubscribetoken."));
+        }
+
+        let token = utils::random_string(UNSUBSCRIBE_TOKEN_LENGTH);
+        self.senderkey_unsubscribetoken
+            .insert(senderkey, token.as_bytes())?;
+        self.unsubscribetoken_senderkey
+            .insert(token.as_bytes(), senderkey)?;
+
+        Ok(token)
+    }
+
+    /// Returns the email pusher of an unsubscribe token.
+    #[tracing::instrument(skip(self, token))]
+    pub fn senderkey_from_unsubscribe_token(&self, token: &str) -> Result<Option<Vec<u8>>> {
+        self.unsubscribetoken_senderkey.get(token.as_bytes())
+    }
+
+    /// Returns the email pusher of an unsubscribe token and forgets the token.
+    #[tracing::instrument(skip(self, token))]
+    pub fn take_unsubscribe_token(&self, token: &str) -> Result<Option<Vec<u8>>> {
+        let senderkey = match self.unsubscribetoken_senderkey.get(token.as_bytes())? {
+            Some(senderkey) => senderkey,
+            None => return Ok(None),
+        };
+
+        self.unsubscribetoken_senderkey.remove(token.as_bytes())?;
+        self.senderkey_unsubscribetoken.remove(&senderkey)?;
+
+        Ok(Some(senderkey))
+    }
+
+    pub fn start_handler(&self, db: Arc<RwLock<Database>>) {
+        tokio::spawn(async move {
+            let mut i = interval(DIGEST_CHECK_INTERVAL);
+
+            loop {
+                i.tick().await;
+
+                // The database lock must not be held while talking to the SMTP server
+                let (config, server_name, digests) = {
+                    let guard = db.read().await;
+                    let config = match guard.globals.email() {
+                        Some(config) => config.clone(),
+                        None => continue,
+                    };
+
+                    match prepare_due_digests(&guard, &config) {
+                        Ok(digests) => (config, guard.globals.server_name().to_owned(), digests),
+                        Err(e) => {
+                            error!("Failed to prepare email digests: {}", e);
+                            continue;
+                        }
+                    }
+                };
+
+                for digest in digests {
+                    if let Some(mail) = &digest.mail {
+                        if let Err(e) =
+                            send_mail(&config, &server_name, &mail.address, &mail.message).await
+                        {
+                            // The highlights stay queued and are retried with the next check
+                            warn!("Could not send email digest to {}: {}", digest.user_id, e);
+                            continue;
+                        }
+
+                        info!(
+                            "Sent email digest with {} highlights to {}",
+                            mail.count, digest.user_id
+                        );
+                    }
+
+                    let guard = db.read().await;
+                    if let Err(e) = guard
+                        .email
+                        .remove_queued(&digest.keys)
+                        .and_then(|()| guard.flush())
+                    {
+                        error!("Failed to remove sent email digest: {}", e);
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// The queued highlights of one email pusher and the email that has to be sent for them.
+struct Digest {
+    user_id: UserId,
+    keys: Vec<Vec<u8>>,
+    /// None if nothing has to be sent, e.g. because the user read all highlights already.
+    mail: Option<DigestMail>,
+}
+
+struct DigestMail {
+    address: String,
+    message: String,
+    count: usize,
+}
+
+#[tracing::instrument(skip(db, config))]
+fn prepare_due_digests(db: &Database, config: &EmailConfig) -> Result<Vec<Digest>> {
+    let digests = db
+        .email
+        .due_digests(Duration::from_secs(config.digest_delay_secs))?;
+
+    let mut prepared = Vec::new();
+
+    for (senderkey, queued) in digests {
+        let keys = queued
+            .iter()
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        let user_id = senderkey
+            .split(|&b| b == 0xff)
+            .next()
+            .and_then(|bytes| utils::string_from_bytes(bytes).ok())
+            .and_then(|user_id| UserId::try_from(user_id).ok())
+            .ok_or_else(|| Error::bad_database("Invalid user id in email queue."))?;
+
+        // The pusher might have been removed in the meantime and the address must still belong
+        // to the user
+        let mail = match db.pusher.get_pusher(&senderkey)? {
+            Some(pusher)
+                if db.threepids.find_user("email", &pusher.pushkey)?.as_ref() == Some(&user_id) =>
+            {
+                let events = queued
+                    .into_iter()
+                    .map(|(_, event_id)| event_id)
+                    .collect::<Vec<_>>();
+
+                build_digest(db, config, &user_id, &pusher.pushkey, &senderkey, &events)?
+            }
+            _ => None,
+        };
+
+        prepared.push(Digest {
+            user_id,
+            keys,
+            mail,
+        });
+    }
+
+    Ok(prepared)
+}
+
+#[tracing::instrument(skip(db, config, senderkey, events))]
+fn build_digest(
+    db: &Database,
+    config: &EmailConfig,
+    user_id: &UserId,
+    address: &str,
+    senderkey: &[u8],
+    events: &[EventId],
+) -> Result<Option<DigestMail>> {
+    let mut text_messages = Vec::new();
+    let mut html_messages = Vec::new();
+
+    for event_id in events {
+        let pdu = match db.rooms.get_pdu(event_id)? {
+            Some(pdu) => pdu,
+            None => continue,
+        };
+
+        // Highlights the user has read in the meantime are not included
+        let read_count = db.rooms.edus.private_read_get(&pdu.room_id, user_id)?;
+        let pdu_count = db.rooms.get_pdu_count(event_id)?;
+        if matches!((read_count, pdu_count), (Some(read), Some(count)) if count <= read) {
+            continue;
+        }
+
+        let room_name = db
+            .rooms
+            .room_state_get(&pdu.room_id, &EventType::RoomName, "")?
+            .and_then(|event| {
+                serde_json::from_str::<RoomNameEventContent>(event.content.get())
+                    .ok()?
+                    .name
+            })
+            .map_or_else(|| pdu.room_id.to_string(), |name| name.to_string());
+        let sender = db
+            .users
+            .displayname(&pdu.sender)?
+            .unwrap_or_else(|| pdu.sender.to_string());
+        let body = serde_json::from_str::<serde_json::Value>(pdu.content.get())
+            .ok()
+            .and_then(|content| content.get("body")?.as_str().map(ToOwned::to_owned))
+            .unwrap_or_else(|| "(message could not be displayed)".to_owned());
+
+        text_messages.push(format!("[{}] {}: {}", room_name, sender, body));
+        html_messages.push(format!(
+            "<li><b>{}</b> {}: {}</li>",
+            escape_html(&room_name),
+            escape_html(&sender),
+            escape_html(&body)
+        ));
+    }
+
+    if text_messages.is_empty() {
+        return Ok(None);
+    }
+
+    let unsubscribe_link = format!(
+        "{}/_conduit/client/email/unsubscribe?token={}",
+        config.public_baseurl.trim_end_matches('/'),
+        db.email.unsubscribe_token(senderkey)?
+    );
+
+    let highlights = text_messages.len();
+    let count = highlights.to_string();
+    let text_messages = text_messages.join("\n");
+    let html_messages = html_messages.join("\n");
+    let server_name = db.globals.server_name().as_str();
+
+    let (text_template, html_template) = load_templates(config)?;
+    let text = render(
+        &text_template,
+        &[
+            ("server_name", server_name),
+            ("user_id", user_id.as_str()),
+            ("count", &count),
+            ("messages", &text_messages),
+            ("unsubscribe_link", &unsubscribe_link),
+        ],
+    );
+    let html = render(
+        &html_template,
+        &[
+            ("server_name", &escape_html(server_name)),
+            ("user_id", &escape_html(user_id.as_str())),
+            ("count", &count),
+            ("messages", &html_messages),
+            ("unsubscribe_link", &escape_html(&unsubscribe_link)),
+        ],
+    );
+
+    let message = build_message(
+        &config.notif_from,
+        address,
+        &format!("{} unread highlights on {}", count, server_name),
+        Some(&unsubscribe_link),
+        &text,
+        &html,
+    );
+
+    Ok(Some(DigestMail {
+        address: address.to_owned(),
+        message,
+        count: highlights,
+    }))
+}
+
+/// Sends the link that proves that the user can read emails sent to the address of the session.
+#[tracing::instrument(skip(config, session))]
+pub async fn send_validation_email(
+    config: &EmailConfig,
+    server_name: &ServerName,
+    sid: &str,
+    session: &ValidationSession,
+) -> Result<()> {
+    let link = format!(
+        "{}/_conduit/client/email/validate?sid={}&client_secret={}&token={}",
+        config.public_baseurl.trim_end_matches('/'),
+        sid,
+        session.client_secret,
+        session.token
+    );
+
+    let (subject, action) = match session.purpose {
+        ValidationPurpose::AddThreepid => (
+            format!("Validate your email address on {}", server_name),
+            "add this email address to your account",
+        ),
+        ValidationPurpose::PasswordReset => (
+            format!("Reset your password on {}", server_name),
+            "reset your password",
+        ),
+    };
+
+    let text = format!(
+        "To {} on {}, open {}\n\n\
+         If you didn't ask for this, you can ignore this email.\n",
+        action, server_name, link
+    );
+    let html = format!(
+        "<!DOCTYPE html>\n\
+         <html>\n\
+         <body>\n\
+         <p>To {} on {}, <a href=\"{}\">click here</a>.</p>\n\
+         <p>If you didn't ask for this, you can ignore this email.</p>\n\
+         </body>\n\
+         </html>\n",
+        action,
+        escape_html(server_name.as_str()),
+        escape_html(&link)
+    );
+
+    let message = build_message(
+        &config.notif_from,
+        &session.address,
+        &subject,
+        None,
+        &text,
+        &html,
+    );
+
+    send_mail(config, server_name, &session.address, &message).await
+}
+
+fn load_templates(config: &EmailConfig) -> Result<(String, String)> {
+    match &config.template_dir {
+        Some(dir) => {
+            let dir = Path::new(dir);
+            Ok((
+                fs::read_to_string(dir.join("notif_mail.txt"))?,
+                fs::read_to_string(dir.join("notif_mail.html"))?,
+            ))
+        }
+        None => Ok((
+            DEFAULT_TEXT_TEMPLATE.to_owned(),
+            DEFAULT_HTML_TEMPLATE.to_owned(),
+        )),
+    }
+}
+
+/// Replaces all `{{name}}` placeholders of the template.
+fn render(template: &str, values: &[(&str, &str)]) -> String {
+    values
+        .iter()
+        .fold(template.to_owned(), |rendered, (name, value)| {
+            rendered.replace(&format!("{{{{{}}}}}", name), value)
+        })
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Builds a multipart email with a text and a html version.
+fn build_message(
+    from: &str,
+    to: &str,
+    subject: &str,
+    unsubscribe_link: Option<&str>,
+    text: &str,
+    html: &str,
+) -> String {
+    let boundary = utils::random_string(MIME_BOUNDARY_LENGTH);
+
+    let list_unsubscribe = unsubscribe_link
+        .map(|link| {
+            format!(
+                "List-Unsubscribe: <{}>\r\nList-Unsubscribe-Post: List-Unsubscribe=One-Click\r\n",
+                strip_newlines(link)
+            )
+        })
+        .unwrap_or_default();
+
+    let message = format!(
+        "From: {from}\r\n\
+         To: {to}\r\n\
+         Subject: {subject}\r\n\
+         {list_unsubscribe}\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/alternative; boundary=\"{boundary}\"\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         Content-Transfer-Encoding: 8bit\r\n\
+         \r\n\
+         {text}\r\n\
+         --{boundary}\r\n\
+         Content-Type: text/html; charset=utf-8\r\n\
+         Content-Transfer-Encoding: 8bit\r\n\
+         \r\n\
+         {html}\r\n\
+         --{boundary}--\r\n",
+        from = strip_newlines(from),
+        to = strip_newlines(to),
+        subject = strip_newlines(subject),
+        list_unsubscribe = list_unsubscribe,
+        boundary = boundary,
+        text = text,
+        html = html,
+    );
+
+    // SMTP needs CRLF line endings everywhere
+    message.replace("\r\n", "\n").replace('\n', "\r\n")
+}
+
+/// Prevents header injection through user controlled values.
+fn strip_newlines(s: &str) -> String {
+    s.replace(|c| c == '\r' || c == '\n', " ")
+}
+
+/// Returns the address part of `Name <address>`.
+fn mailbox_address(mailbox: &str) -> &str {
+    match (mailbox.rfind('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &mailbox[start + 1..end],
+        _ => mailbox.trim(),
+    }
+}
+
+/// Sends the message through the configured SMTP relay. Conduit introduces itself with its server
+/// name.
+#[tracing::instrument(skip(config, message))]
+async fn send_mail(
+    config: &EmailConfig,
+    server_name: &ServerName,
+    to: &str,
+    message: &str,
+) -> Result<()> {
+    let stream = TcpStream::connect((config.smtp_host.as_str(), config.smtp_port)).await?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    smtp_reply(&mut reader).await?;
+
+    for command in &[
+        format!("EHLO {}\r\n", server_name),
+        format!("MAIL FROM:<{}>\r\n", mailbox_address(&config.notif_from)),
+        format!("RCPT TO:<{}>\r\n", strip_newlines(mailbox_address(to))),
+        "DATA\r\n".to_owned(),
+    ] {
+        writer.write_all(command.as_bytes()).await?;
+        smtp_reply(&mut reader).await?;
+    }
+
+    // Lines starting with a dot need another dot, a single dot ends the message
+    let mut data = String::with_capacity(message.len());
+    for line in message.split("\r\n") {
+        if line.starts_with('.') {
+            data.push('.');
+        }
+        data.push_str(line);
+        data.push_str("\r\n");
+    }
+    data.push_str(".\r\n");
+
+    writer.write_all(data.as_bytes()).await?;
+    smtp_reply(&mut reader).await?;
+
+    writer.write_all(b"QUIT\r\n").await?;
+
+    Ok(())
+}
+
+/// Reads a (possibly multiline) SMTP reply and fails if it is not positive.
+async fn smtp_reply<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(Error::BadServerResponse(
+                "SMTP server closed the connection.",
+            ));
+        }
+
+        // The last line of a reply has a space after the code
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return match line.as_bytes().first() {
+                Some(b'2') | Some(b'3') => Ok(()),
+                _ => {
+                    warn!("SMTP server returned error: {}", line.trim_end());
+                    Err(Error::BadServerResponse("SMTP server rejected the email."))
+                }
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        build_message, mailbox_address, render, send_mail, send_validation_email, EmailConfig,
+    };
+    use crate::database::threepids::{ValidationPurpose, ValidationSession};
+    use ruma::{MilliSecondsSinceUnixEpoch, ServerName, UInt};
+    use std::convert::TryFrom;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        task::JoinHandle,
+    };
+
+    /// The commands and the message an SMTP client sent.
+    struct ReceivedMail {
+        commands: Vec<String>,
+        data: String,
+    }
+
+    fn config(listener: &TcpListener) -> EmailConfig {
+        EmailConfig {
+            smtp_host: "127.0.0.1".to_owned(),
+            smtp_port: listener.local_addr().unwrap().port(),
+            notif_from: "Conduit <noreply@example.com>".to_owned(),
+            public_baseurl: "https://matrix.example.com/".to_owned(),
+            digest_delay_secs: 0,
+            template_dir: None,
+        }
+    }
+
+    fn server_name() -> &'static ServerName {
+        <&ServerName>::try_from("example.com").unwrap()
+    }
+
+    /// Accepts one SMTP connection on a local port and records what the client sent.
+    async fn smtp_sink() -> (EmailConfig, JoinHandle<ReceivedMail>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = config(&listener);
+
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = BufReader::new(reader);
+
+            let mut received = ReceivedMail {
+                commands: Vec::new(),
+                data: String::new(),
+            };
+            let mut in_data = false;
+
+            writer.write_all(b"220 sink ready\r\n").await.unwrap();
+
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).await.unwrap() == 0 {
+                    break;
+                }
+
+                if in_data {
+                    if line == ".\r\n" {
+                        in_data = false;
+                        writer.write_all(b"250 queued\r\n").await.unwrap();
+                    } else {
+                        received.data.push_str(&line);
+                    }
+                    continue;
+                }
+
+                let command = line.trim_end().to_owned();
+                let reply: &[u8] = match command.as_str() {
+                    "DATA" => {
+                        in_data = true;
+                        b"354 end with .\r\n"
+                    }
+                    // Multiline reply like real servers send after EHLO
+                    c if c.starts_with("EHLO") => b"250-sink\r\n250 8BITMIME\r\n",
+                    "QUIT" => {
+                        received.commands.push(command);
+                        let _ = writer.write_all(b"221 bye\r\n").await;
+                        break;
+                    }
+                    _ => b"250 ok\r\n",
+                };
+                received.commands.push(command);
+                writer.write_all(reply).await.unwrap();
+            }
+
+            received
+        });
+
+        (config, handle)
+    }
+
+    #[test]
+    fn renders_all_placeholders() {
+        assert_eq!(
+            render(
+                "{{count}} new on {{server_name}}, {{count}}!",
+                &[("count", "3"), ("server_name", "example.com")]
+            ),
+            "3 new on example.com, 3!"
+        );
+    }
+
+    #[test]
+    fn message_has_no_injected_headers() {
+        let message = build_message(
+            "Conduit <noreply@example.com>",
+            "user@example.com\r\nBcc: other@example.com",
+            "subject",
+            Some("https://example.com/unsubscribe"),
+            "text\nbody",
+            "<p>html</p>",
+        );
+
+        assert!(!message.contains("\r\nBcc:"));
+        assert!(message.contains("text\r\nbody"));
+        assert_eq!(
+            mailbox_address("Conduit <noreply@example.com>"),
+            "noreply@example.com"
+        );
+    }
+
+    #[rocket::async_test]
+    async fn mail_is_delivered_to_smtp_server() {
+        let (config, sink) = smtp_sink().await;
+        let message = build_message(
+            &config.notif_from,
+            "user@example.com",
+            "subject",
+            None,
+            "first line\n.starts with a dot",
+            "<p>html</p>",
+        );
+
+        send_mail(&config, server_name(), "User <user@example.com>", &message)
+            .await
+            .unwrap();
+        let received = sink.await.unwrap();
+
+        assert_eq!(
+            received.commands,
+            vec![
+                "EHLO example.com",
+                "MAIL FROM:<noreply@example.com>",
+                "RCPT TO:<user@example.com>",
+                "DATA",
+                "QUIT",
+            ]
+        );
+        assert!(received.data.contains("Subject: subject\r\n"));
+        // Lines starting with a dot are escaped so they don't end the message early
+        assert!(received.data.contains("\r\n..starts with a dot\r\n"));
+    }
+
+    #[rocket::async_test]
+    async fn rejected_mail_is_an_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = config(&listener);
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"554 no service\r\n").await.unwrap();
+        });
+
+        assert!(
+            send_mail(&config, server_name(), "user@example.com", "message")
+                .await
+                .is_err()
+        );
+    }
+
+    #[rocket::async_test]
+    async fn validation_link_is_sent_to_the_address() {
+        let (config, sink) = smtp_sink().await;
+        let session = ValidationSession {
+            purpose: ValidationPurpose::PasswordReset,
+            client_secret: "secret".to_owned(),
+            medium: "email".to_owned(),
+            address: "alice@example.org".to_owned(),
+            send_attempt: UInt::from(1_u32),
+            token: "token".to_owned(),
+            created_at: MilliSecondsSinceUnixEpoch::now(),
+            validated_at: None,
+        };
+
+        send_validation_email(&config, server_name(), "sid", &session)
+            .await
+            .unwrap();
+        let received = sink.await.unwrap();
+
+        assert!(received
+            .commands
+            .contains(&"RCPT TO:<alice@example.org>".to_owned()));
+        assert!(received
+            .data
+            .contains("Subject: Reset your password on example.com\r\n"));
+        assert!(received.data.contains(
+            "https://matrix.example.com/_conduit/client/email/validate?sid=sid&client_secret=secret&token=token"
+        ));
+    }
+}
@@ -1,9 +1,12 @@
-use crate::{database::Config, server_server::FedDest, utils, ConduitResult, Error, Result};
+use crate::{
+    database::Config, server_server::FedDest, utils, Error, Result, RumaResponseWithFields,
+};
 use ruma::{
     api::{
         client::r0::sync::sync_events,
         federation::discovery::{ServerSigningKeys, VerifyKey},
     },
+    signatures::CanonicalJsonObject,
     DeviceId, EventId, MilliSecondsSinceUnixEpoch, RoomId, ServerName, ServerSigningKeyId, UserId,
 };
 use std::{
@@ -19,7 +22,14 @@ use tokio::sync::{broadcast, watch::Receiver, Mutex as TokioMutex, Semaphore};
 use tracing::error;
 use trust_dns_resolver::TokioAsyncResolver;
 
-use super::abstraction::Tree;
+use super::{
+    abstraction::Tree,
+    email::EmailConfig,
+    ratelimit::{LoginLockout, RateLimiter},
+    sso::OidcConfig,
+    terms::TermsConfig,
+    turn::{TurnServerGroup, TurnServers},
+};
 
 pub const COUNTER: &[u8] = b"c";
 
@@ -27,8 +37,8 @@ type WellKnownMap = HashMap<Box<ServerName>, (FedDest, String)>;
 type TlsNameMap = HashMap<String, (Vec<IpAddr>, u16)>;
 type RateLimitState = (Instant, u32); // Time if last failed try, number of failed tries
 type SyncHandle = (
-    Option<String>,                                         // since
-    Receiver<Option<ConduitResult<sync_events::Response>>>, // rx
+    Option<String>,                                                          // since
+    Receiver<Option<Result<RumaResponseWithFields<sync_events::Response>>>>, // rx
 );
 
 pub struct Globals {
@@ -40,9 +50,13 @@ pub struct Globals {
     dns_resolver: TokioAsyncResolver,
     jwt_decoding_key: Option<jsonwebtoken::DecodingKey<'static>>,
     pub(super) server_signingkeys: Arc<dyn Tree>,
+    pub(super) server_signedkeys: Arc<dyn Tree>, // ServerName = The key response as signed by the server
     pub bad_event_ratelimiter: Arc<RwLock<HashMap<EventId, RateLimitState>>>,
     pub bad_signature_ratelimiter: Arc<RwLock<HashMap<Vec<String>, RateLimitState>>>,
     pub servername_ratelimiter: Arc<RwLock<HashMap<Box<ServerName>, Arc<Semaphore>>>>,
+    pub client_ratelimiter: RateLimiter,
+    pub login_lockout: LoginLockout,
+    pub turn_servers: TurnServers,
     pub sync_receivers: RwLock<HashMap<(UserId, Box<DeviceId>), SyncHandle>>,
     pub roomid_mutex_insert: RwLock<HashMap<RoomId, Arc<Mutex<()>>>>,
     pub roomid_mutex_state: RwLock<HashMap<RoomId, Arc<TokioMutex<()>>>>,
@@ -84,6 +98,7 @@ impl Globals {
     pub fn load(
         globals: Arc<dyn Tree>,
         server_signingkeys: Arc<dyn Tree>,
+        server_signedkeys: Arc<dyn Tree>,
         config: Config,
     ) -> Result<Self> {
         let keypair_bytes = globals.get(b"keypair")?.map_or_else(
@@ -132,6 +147,20 @@ impl Globals {
             .as_ref()
             .map(|secret| jsonwebtoken::DecodingKey::from_secret(secret.as_bytes()).into_static());
 
+        let client_ratelimiter = RateLimiter::new(config.rate_limit.clone());
+        let login_lockout = LoginLockout::new(config.login_lockout.clone());
+        let turn_servers = TurnServers::new(
+            config.turn.clone(),
+            TurnServerGroup {
+                uris: config.turn_uris.clone(),
+                secret: Some(config.turn_secret.clone()).filter(|secret| !secret.is_empty()),
+                username: Some(config.turn_username.clone())
+                    .filter(|username| !username.is_empty()),
+                password: Some(config.turn_password.clone())
+                    .filter(|password| !password.is_empty()),
+            },
+        );
+
         let s = Self {
             globals,
             config,
@@ -142,10 +171,14 @@ impl Globals {
             actual_destination_cache: Arc::new(RwLock::new(WellKnownMap::new())),
             tls_name_override,
             server_signingkeys,
+            server_signedkeys,
             jwt_decoding_key,
             bad_event_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
             bad_signature_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
             servername_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
+            client_ratelimiter,
+            login_lockout,
+            turn_servers,
             roomid_mutex_state: RwLock::new(HashMap::new()),
             roomid_mutex_insert: RwLock::new(HashMap::new()),
             roomid_mutex_federation: RwLock::new(HashMap::new()),
@@ -198,6 +231,11 @@ impl Globals {
         self.config.max_request_size
     }
 
+    /// The maximum number of bytes of account data per user, 0 means unlimited.
+    pub fn max_account_data_size(&self) -> u64 {
+        self.config.max_account_data_size
+    }
+
     pub fn allow_registration(&self) -> bool {
         self.config.allow_registration
     }
@@ -214,10 +252,22 @@ impl Globals {
         self.config.allow_room_creation
     }
 
+    pub fn allow_presence(&self) -> bool {
+        self.config.allow_presence
+    }
+
     pub fn trusted_servers(&self) -> &[Box<ServerName>] {
         &self.config.trusted_servers
     }
 
+    pub fn appservice_registration_files(&self) -> &[String] {
+        &self.config.appservice_registration_files
+    }
+
+    pub fn policy_rooms(&self) -> &[RoomId] {
+        &self.config.policy_rooms
+    }
+
     pub fn dns_resolver(&self) -> &TokioAsyncResolver {
         &self.dns_resolver
     }
@@ -226,24 +276,25 @@ impl Globals {
         self.jwt_decoding_key.as_ref()
     }
 
-    pub fn turn_password(&self) -> &String {
-        &self.config.turn_password
+    pub fn oidc(&self) -> Option<&OidcConfig> {
+        self.config.oidc.as_ref()
     }
 
-    pub fn turn_ttl(&self) -> u64 {
-        self.config.turn_ttl
+    pub fn email(&self) -> Option<&EmailConfig> {
+        self.config.email.as_ref()
     }
 
-    pub fn turn_uris(&self) -> &[String] {
-        &self.config.turn_uris
+    pub fn terms(&self) -> Option<&TermsConfig> {
+        self.config.terms.as_ref()
     }
 
-    pub fn turn_username(&self) -> &String {
-        &self.config.turn_username
+    /// How long access tokens of clients that support refresh tokens are valid.
+    pub fn access_token_lifetime(&self) -> Duration {
+        Duration::from_secs(self.config.access_token_lifetime_secs)
     }
 
-    pub fn turn_secret(&self) -> &String {
-        &self.config.turn_secret
+    pub fn turn_ttl(&self) -> u64 {
+        self.config.turn_ttl
     }
 
     /// TODO: the key valid until timestamp is only honored in room version > 4
@@ -312,6 +363,30 @@ impl Globals {
         Ok(signingkeys)
     }
 
+    /// Remembers the key response of a server exactly as the server signed it, so notary requests
+    /// can be answered with the original signatures.
+    pub fn add_signed_server_keys(
+        &self,
+        origin: &ServerName,
+        server_keys: &CanonicalJsonObject,
+    ) -> Result<()> {
+        self.server_signedkeys.insert(
+            origin.as_bytes(),
+            &serde_json::to_vec(server_keys).expect("CanonicalJsonObject is valid JSON value"),
+        )
+    }
+
+    /// Returns the last key response of a server that had a valid signature of the server.
+    pub fn signed_server_keys(&self, origin: &ServerName) -> Result<Option<CanonicalJsonObject>> {
+        self.server_signedkeys
+            .get(origin.as_bytes())?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid signed server keys in db."))
+            })
+            .transpose()
+    }
+
     pub fn database_version(&self) -> Result<u64> {
         self.globals.get(b"version")?.map_or(Ok(0), |version| {
             utils::u64_from_bytes(&version)
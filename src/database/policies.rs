@@ -0,0 +1,330 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+};
+
+use crate::{PduEvent, Result};
+use regex::Regex;
+use ruma::{RoomId, ServerName, UserId};
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+
+use super::{globals::Globals, rooms::Rooms};
+
+/// The kinds of entities a policy rule can match.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PolicyKind {
+    User,
+    Server,
+    Room,
+}
+
+impl PolicyKind {
+    /// Returns the kind of rule the event type describes, including the legacy event types that
+    /// older moderation bots still send.
+    pub fn from_event_type(event_type: &str) -> Option<Self> {
+        match event_type {
+            "m.policy.rule.user" | "m.room.rule.user" | "org.matrix.mjolnir.rule.user" => {
+                Some(PolicyKind::User)
+            }
+            "m.policy.rule.server" | "m.room.rule.server" | "org.matrix.mjolnir.rule.server" => {
+                Some(PolicyKind::Server)
+            }
+            "m.policy.rule.room" | "m.room.rule.room" | "org.matrix.mjolnir.rule.room" => {
+                Some(PolicyKind::Room)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PolicyRuleEventContent {
+    entity: String,
+    recommendation: String,
+    #[serde(default)]
+    reason: String,
+}
+
+/// A ban recommended by a policy room.
+#[derive(Debug)]
+pub struct PolicyRule {
+    pub kind: PolicyKind,
+    /// The glob the entity has to match, `*` matches any number of characters and `?` matches
+    /// one character.
+    pub entity: String,
+    pub reason: String,
+    pattern: Regex,
+}
+
+impl PolicyRule {
+    fn matches(&self, entity: &str) -> bool {
+        self.pattern.is_match(entity)
+    }
+
+    fn is_glob(&self) -> bool {
+        self.entity.contains(|c| c == '*' || c == '?')
+    }
+}
+
+/// (PolicyRoomId, EventType, StateKey)
+type RuleKey = (RoomId, String, String);
+
+/// The rules of all policy rooms. Rules without wildcards are looked up by their entity, only
+/// globs have to be matched one by one.
+#[derive(Default)]
+pub struct PolicyRules {
+    rules: HashMap<RuleKey, PolicyRule>,
+    /// Kind -> Entity -> Number of rules that ban the entity
+    exact: HashMap<PolicyKind, HashMap<String, usize>>,
+    globs: HashSet<RuleKey>,
+}
+
+impl PolicyRules {
+    fn insert(&mut self, key: RuleKey, rule: PolicyRule) {
+        self.remove(&key);
+
+        if rule.is_glob() {
+            self.globs.insert(key.clone());
+        } else {
+            *self
+                .exact
+                .entry(rule.kind)
+                .or_default()
+                .entry(rule.entity.clone())
+                .or_default() += 1;
+        }
+
+        self.rules.insert(key, rule);
+    }
+
+    fn remove(&mut self, key: &RuleKey) -> Option<PolicyRule> {
+        let rule = self.rules.remove(key)?;
+
+        if !self.globs.remove(key) {
+            if let Some(entities) = self.exact.get_mut(&rule.kind) {
+                if let Some(count) = entities.get_mut(&rule.entity) {
+                    *count -= 1;
+                    if *count == 0 {
+                        entities.remove(&rule.entity);
+                    }
+                }
+            }
+        }
+
+        Some(rule)
+    }
+
+    fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    fn find(&self, kind: PolicyKind, entity: &str) -> bool {
+        self.exact
+            .get(&kind)
+            .map_or(false, |entities| entities.contains_key(entity))
+            || self.globs.iter().any(|key| {
+                self.rules
+                    .get(key)
+                    .map_or(false, |rule| rule.kind == kind && rule.matches(entity))
+            })
+    }
+}
+
+pub struct Policies {
+    pub(super) rules: RwLock<PolicyRules>,
+}
+
+impl Policies {
+    /// Reads all rules from the current state of the configured policy rooms.
+    #[tracing::instrument(skip(self, rooms, globals))]
+    pub fn reload(&self, rooms: &Rooms, globals: &Globals) -> Result<usize> {
+        let mut rules = PolicyRules::default();
+
+        for room_id in globals.policy_rooms() {
+            for ((event_type, state_key), pdu) in rooms.room_state_full(room_id)? {
+                if let Some(rule) = parse_rule(event_type.as_str(), &pdu) {
+                    debug!(
+                        "Policy room {} bans {}: {}",
+                        room_id, rule.entity, rule.reason
+                    );
+                    rules.insert((room_id.clone(), event_type.to_string(), state_key), rule);
+                }
+            }
+        }
+
+        let count = rules.len();
+        *self.rules.write().unwrap() = rules;
+
+        info!("Loaded {} policy rules", count);
+
+        Ok(count)
+    }
+
+    /// Adds, replaces or removes the rule of a new state event in a policy room.
+    #[tracing::instrument(skip(self, pdu, globals))]
+    pub fn update_rule(&self, pdu: &PduEvent, globals: &Globals) {
+        let state_key = match &pdu.state_key {
+            Some(state_key) if globals.policy_rooms().contains(&pdu.room_id) => state_key,
+            _ => return,
+        };
+
+        let event_type = pdu.kind.to_string();
+        if PolicyKind::from_event_type(&event_type).is_none() {
+            return;
+        }
+
+        let key = (pdu.room_id.clone(), event_type, state_key.clone());
+        let mut rules = self.rules.write().unwrap();
+
+        // Rules are removed by replacing them with an event without content
+        match parse_rule(&key.1, pdu) {
+            Some(rule) => {
+                info!(
+                    "Policy room {} bans {}: {}",
+                    pdu.room_id, rule.entity, rule.reason
+                );
+                rules.insert(key, rule);
+            }
+            None => {
+                if let Some(rule) = rules.remove(&key) {
+                    info!("Policy room {} unbans {}", pdu.room_id, rule.entity);
+                }
+            }
+        }
+    }
+
+    /// Checks if the user or their server is banned by a policy room.
+    pub fn is_user_banned(&self, user_id: &UserId) -> bool {
+        self.find(PolicyKind::User, user_id.as_str())
+            || self.is_server_banned(user_id.server_name())
+    }
+
+    pub fn is_server_banned(&self, server_name: &ServerName) -> bool {
+        self.find(PolicyKind::Server, server_name.as_str())
+    }
+
+    pub fn is_room_banned(&self, room_id: &RoomId) -> bool {
+        self.find(PolicyKind::Room, room_id.as_str())
+    }
+
+    fn find(&self, kind: PolicyKind, entity: &str) -> bool {
+        self.rules.read().unwrap().find(kind, entity)
+    }
+}
+
+fn parse_rule(event_type: &str, pdu: &PduEvent) -> Option<PolicyRule> {
+    let kind = PolicyKind::from_event_type(event_type)?;
+
+    // Redacted or removed rules don't have an entity anymore
+    let content = serde_json::from_str::<PolicyRuleEventContent>(pdu.content.get()).ok()?;
+
+    // Only bans are enforced, other recommendations are for humans
+    if content.recommendation != "m.ban" && content.recommendation != "org.matrix.mjolnir.ban" {
+        return None;
+    }
+
+    match glob_to_regex(&content.entity) {
+        Ok(pattern) => Some(PolicyRule {
+            kind,
+            entity: content.entity,
+            reason: content.reason,
+            pattern,
+        }),
+        Err(e) => {
+            warn!("Invalid policy rule {}: {}", pdu.event_id, e);
+            None
+        }
+    }
+}
+
+/// Turns a glob into a regex that matches the whole string.
+fn glob_to_regex(glob: &str) -> Result<Regex, regex::Error> {
+    let mut pattern = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+
+    Regex::new(&pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{glob_to_regex, PolicyKind, PolicyRule, PolicyRules};
+    use ruma::RoomId;
+    use std::convert::TryFrom;
+
+    fn rule(kind: PolicyKind, entity: &str) -> PolicyRule {
+        PolicyRule {
+            kind,
+            entity: entity.to_owned(),
+            reason: String::new(),
+            pattern: glob_to_regex(entity).unwrap(),
+        }
+    }
+
+    fn key(state_key: &str) -> (RoomId, String, String) {
+        (
+            RoomId::try_from("!policies:example.org").unwrap(),
+            "m.policy.rule.user".to_owned(),
+            state_key.to_owned(),
+        )
+    }
+
+    #[test]
+    fn exact_and_glob_rules_are_found() {
+        let mut rules = PolicyRules::default();
+        rules.insert(key("a"), rule(PolicyKind::User, "@spammer:example.org"));
+        rules.insert(key("b"), rule(PolicyKind::User, "@bot*:example.org"));
+
+        assert!(rules.find(PolicyKind::User, "@spammer:example.org"));
+        assert!(rules.find(PolicyKind::User, "@bot42:example.org"));
+        assert!(!rules.find(PolicyKind::User, "@alice:example.org"));
+        assert!(!rules.find(PolicyKind::Server, "@spammer:example.org"));
+    }
+
+    #[test]
+    fn replaced_and_removed_rules_are_forgotten() {
+        let mut rules = PolicyRules::default();
+        rules.insert(key("a"), rule(PolicyKind::User, "@spammer:example.org"));
+        rules.insert(key("b"), rule(PolicyKind::User, "@spammer:example.org"));
+        rules.insert(key("c"), rule(PolicyKind::User, "@bot*:example.org"));
+
+        // Another rule still bans the user
+        rules.remove(&key("a"));
+        assert!(rules.find(PolicyKind::User, "@spammer:example.org"));
+
+        rules.insert(key("b"), rule(PolicyKind::User, "@other:example.org"));
+        assert!(!rules.find(PolicyKind::User, "@spammer:example.org"));
+        assert!(rules.find(PolicyKind::User, "@other:example.org"));
+
+        rules.remove(&key("c"));
+        assert!(!rules.find(PolicyKind::User, "@bot42:example.org"));
+        assert_eq!(rules.len(), 1);
+    }
+
+    #[test]
+    fn glob_matches_whole_entity() {
+        let pattern = glob_to_regex("@spam*:example.org").unwrap();
+        assert!(pattern.is_match("@spam:example.org"));
+        assert!(pattern.is_match("@spammer:example.org"));
+        assert!(!pattern.is_match("@spammer:example.org.evil"));
+        assert!(!pattern.is_match("@alice:example.org"));
+    }
+
+    #[test]
+    fn glob_escapes_regex_characters() {
+        let pattern = glob_to_regex("*.example.org").unwrap();
+        assert!(pattern.is_match("matrix.example.org"));
+        assert!(!pattern.is_match("matrix-example.org"));
+
+        let pattern = glob_to_regex("server?.org").unwrap();
+        assert!(pattern.is_match("server1.org"));
+        assert!(!pattern.is_match("server12.org"));
+    }
+}
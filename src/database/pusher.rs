@@ -1,4 +1,4 @@
-use crate::{Database, Error, PduEvent, Result};
+use crate::{utils, Database, Error, PduEvent, Result};
 use bytes::BytesMut;
 use ruma::{
     api::{
@@ -10,22 +10,40 @@ use ruma::{
         IncomingResponse, OutgoingRequest, SendAccessToken,
     },
     events::{
-        room::{name::RoomNameEventContent, power_levels::RoomPowerLevelsEventContent},
+        room::{
+            member::RoomMemberEventContent, name::RoomNameEventContent,
+            power_levels::RoomPowerLevelsEventContent,
+        },
         AnySyncRoomEvent, EventType,
     },
     push::{Action, PushConditionRoomCtx, PushFormat, Ruleset, Tweak},
     serde::Raw,
-    uint, RoomId, UInt, UserId,
+    uint, MilliSecondsSinceUnixEpoch, RoomId, UInt, UserId,
 };
+use serde::{Deserialize, Serialize};
 use tracing::{error, info, warn};
 
 use std::{convert::TryFrom, fmt::Debug, mem, sync::Arc};
 
 use super::abstraction::Tree;
 
+/// After this many failed deliveries in a row that the gateway answered with a client error, the
+/// pusher is removed.
+const MAX_PUSHER_FAILURES: u32 = 5;
+
 pub struct PushData {
     /// UserId + pushkey -> Pusher
     pub(super) senderkey_pusher: Arc<dyn Tree>,
+    /// UserId + pushkey -> PusherFailure
+    pub(super) senderkey_pusherfailure: Arc<dyn Tree>,
+}
+
+/// Consecutive failed deliveries of a pusher.
+#[derive(Deserialize, Serialize)]
+pub struct PusherFailure {
+    pub failures: u32,
+    pub last_failure: MilliSecondsSinceUnixEpoch,
+    pub last_error: String,
 }
 
 impl PushData {
@@ -37,11 +55,7 @@ impl PushData {
 
         // There are 2 kinds of pushers but the spec says: null deletes the pusher.
         if pusher.kind.is_none() {
-            return self
-                .senderkey_pusher
-                .remove(&key)
-                .map(|_| ())
-                .map_err(Into::into);
+            return self.remove_pusher(&key);
         }
 
         self.senderkey_pusher.insert(
@@ -52,6 +66,75 @@ impl PushData {
         Ok(())
     }
 
+    #[tracing::instrument(skip(self, senderkey))]
+    pub fn remove_pusher(&self, senderkey: &[u8]) -> Result<()> {
+        self.senderkey_pusherfailure.remove(senderkey)?;
+        self.senderkey_pusher.remove(senderkey)
+    }
+
+    /// Remembers a failed delivery and returns the number of failures in a row.
+    #[tracing::instrument(skip(self, senderkey))]
+    pub fn record_failure(&self, senderkey: &[u8], error: &Error) -> Result<u32> {
+        let failures = self
+            .pusher_failure(senderkey)?
+            .map_or(0, |failure| failure.failures)
+            .saturating_add(1);
+
+        self.senderkey_pusherfailure.insert(
+            senderkey,
+            &serde_json::to_vec(&PusherFailure {
+                failures,
+                last_failure: MilliSecondsSinceUnixEpoch::now(),
+                last_error: error.to_string(),
+            })
+            .expect("PusherFailure is valid JSON value"),
+        )?;
+
+        Ok(failures)
+    }
+
+    #[tracing::instrument(skip(self, senderkey))]
+    pub fn reset_failures(&self, senderkey: &[u8]) -> Result<()> {
+        self.senderkey_pusherfailure.remove(senderkey)
+    }
+
+    #[tracing::instrument(skip(self, senderkey))]
+    pub fn pusher_failure(&self, senderkey: &[u8]) -> Result<Option<PusherFailure>> {
+        self.senderkey_pusherfailure
+            .get(senderkey)?
+            .map(|failure| {
+                serde_json::from_slice(&failure)
+                    .map_err(|_| Error::bad_database("Invalid PusherFailure in db."))
+            })
+            .transpose()
+    }
+
+    /// Returns all pushers whose last delivery failed.
+    #[tracing::instrument(skip(self))]
+    pub fn failing_pushers<'a>(
+        &'a self,
+    ) -> impl Iterator<Item = Result<(UserId, String, PusherFailure)>> + 'a {
+        self.senderkey_pusherfailure.iter().map(|(key, value)| {
+            let mut parts = key.splitn(2, |&b| b == 0xff);
+            let user_id = UserId::try_from(
+                utils::string_from_bytes(parts.next().expect("splitn always returns one element"))
+                    .map_err(|_| {
+                        Error::bad_database("Invalid user id in senderkey_pusherfailure.")
+                    })?,
+            )
+            .map_err(|_| Error::bad_database("Invalid user id in senderkey_pusherfailure."))?;
+            let pushkey =
+                utils::string_from_bytes(parts.next().ok_or_else(|| {
+                    Error::bad_database("Invalid key in senderkey_pusherfailure.")
+                })?)
+                .map_err(|_| Error::bad_database("Invalid pushkey in senderkey_pusherfailure."))?;
+            let failure = serde_json::from_slice(&value)
+                .map_err(|_| Error::bad_database("Invalid PusherFailure in db."))?;
+
+            Ok((user_id, pushkey, failure))
+        })
+    }
+
     #[tracing::instrument(skip(self, senderkey))]
     pub fn get_pusher(&self, senderkey: &[u8]) -> Result<Option<get_pushers::Pusher>> {
         self.senderkey_pusher
@@ -146,8 +229,9 @@ where
                     destination,
                     status,
                     url,
-                    crate::utils::string_from_bytes(&body)
+                    utils::string_from_bytes(&body)
                 );
+                return Err(Error::PushGatewayError(status.as_u16()));
             }
 
             let response = T::IncomingResponse::try_from_http_response(
@@ -179,20 +263,12 @@ pub async fn send_push_notice(
     let mut notify = None;
     let mut tweaks = Vec::new();
 
-    let power_levels: RoomPowerLevelsEventContent = db
-        .rooms
-        .room_state_get(&pdu.room_id, &EventType::RoomPowerLevels, "")?
-        .map(|ev| {
-            serde_json::from_str(ev.content.get())
-                .map_err(|_| Error::bad_database("invalid m.room.power_levels event"))
-        })
-        .transpose()?
-        .unwrap_or_default();
+    let push_context = db.rooms.push_context(&pdu.room_id)?;
 
     for action in get_actions(
         user,
         &ruleset,
-        &power_levels,
+        &push_context,
         &pdu.to_sync_room_event(),
         &pdu.room_id,
         db,
@@ -216,49 +292,135 @@ pub async fn send_push_notice(
         notify = Some(n);
     }
 
-    if notify == Some(true) {
-        send_notice(unread, pusher, tweaks, pdu, db).await?;
+    if notify != Some(true) {
+        // The event triggered no actions
+        return Ok(());
+    }
+
+    let mut senderkey = user.as_bytes().to_vec();
+    senderkey.push(0xff);
+    senderkey.extend_from_slice(pusher.pushkey.as_bytes());
+
+    match send_notice(user, unread, pusher, tweaks, pdu, db).await {
+        Ok(rejected) => {
+            db.pusher.reset_failures(&senderkey)?;
+
+            // The gateway tells us about pushkeys that are not valid anymore
+            if rejected.contains(&pusher.pushkey) {
+                info!("Push gateway rejected pushkey of {}, removing pusher", user);
+                remove_pusher(user, pusher, db)?;
+            }
+
+            Ok(())
+        }
+        // Client errors except 429 Too Many Requests
+        Err(e @ Error::PushGatewayError(400..=428))
+        | Err(e @ Error::PushGatewayError(430..=499)) => {
+            let failures = db.pusher.record_failure(&senderkey, &e)?;
+
+            if failures >= MAX_PUSHER_FAILURES {
+                warn!(
+                    "Push gateway refused {} notifications in a row for {}, removing pusher",
+                    failures, user
+                );
+                remove_pusher(user, pusher, db)?;
+            }
+
+            // Retrying won't change the answer of the gateway
+            Ok(())
+        }
+        Err(e) => {
+            db.pusher.record_failure(&senderkey, &e)?;
+            Err(e)
+        }
     }
-    // Else the event triggered no actions
+}
+
+fn remove_pusher(user: &UserId, pusher: &get_pushers::Pusher, db: &Database) -> Result<()> {
+    db.pusher.set_pusher(
+        user,
+        set_pusher::Pusher {
+            pushkey: pusher.pushkey.clone(),
+            kind: None,
+            app_id: pusher.app_id.clone(),
+            app_display_name: pusher.app_display_name.clone(),
+            device_display_name: pusher.device_display_name.clone(),
+            profile_tag: pusher.profile_tag.clone(),
+            lang: pusher.lang.clone(),
+            data: pusher.data.clone(),
+        },
+    )
+}
 
-    Ok(())
+/// The parts of the push rule evaluation context that are the same for all users in a room.
+///
+/// The power levels are used for `sender_notification_permission` conditions.
+pub struct RoomPushContext {
+    pub member_count: UInt,
+    pub power_levels: RoomPowerLevelsEventContent,
 }
 
-#[tracing::instrument(skip(user, ruleset, pdu, db))]
+#[tracing::instrument(skip(user, ruleset, push_context, pdu, db))]
 pub fn get_actions<'a>(
     user: &UserId,
     ruleset: &'a Ruleset,
-    power_levels: &RoomPowerLevelsEventContent,
+    push_context: &RoomPushContext,
     pdu: &Raw<AnySyncRoomEvent>,
     room_id: &RoomId,
     db: &Database,
 ) -> Result<&'a [Action]> {
-    let ctx = PushConditionRoomCtx {
-        room_id: room_id.clone(),
-        member_count: 10_u32.into(), // TODO: get member count efficiently
-        user_display_name: db
+    // The display name of the user in this room is used for `contains_display_name` conditions
+    let user_display_name = match db
+        .rooms
+        .room_state_get(room_id, &EventType::RoomMember, user.as_str())?
+        .and_then(|member| {
+            serde_json::from_str::<RoomMemberEventContent>(member.content.get())
+                .ok()?
+                .displayname
+        }) {
+        Some(displayname) => displayname,
+        None => db
             .users
             .displayname(user)?
             .unwrap_or_else(|| user.localpart().to_owned()),
-        users_power_levels: power_levels.users.clone(),
-        default_power_level: power_levels.users_default,
-        notification_power_levels: power_levels.notifications.clone(),
+    };
+
+    let ctx = PushConditionRoomCtx {
+        room_id: room_id.clone(),
+        member_count: push_context.member_count,
+        user_display_name,
+        users_power_levels: push_context.power_levels.users.clone(),
+        default_power_level: push_context.power_levels.users_default,
+        notification_power_levels: push_context.power_levels.notifications.clone(),
     };
 
     Ok(ruleset.get_actions(pdu, &ctx))
 }
 
-#[tracing::instrument(skip(unread, pusher, tweaks, event, db))]
+/// Sends the notification and returns the pushkeys the push gateway rejected.
+#[tracing::instrument(skip(user, unread, pusher, tweaks, event, db))]
 async fn send_notice(
+    user: &UserId,
     unread: UInt,
     pusher: &get_pushers::Pusher,
     tweaks: Vec<Tweak>,
     event: &PduEvent,
     db: &Database,
-) -> Result<()> {
-    // TODO: email
+) -> Result<Vec<String>> {
     if pusher.kind == PusherKind::Email {
-        return Ok(());
+        // Highlights are collected and sent as a digest later
+        if db.globals.email().is_some()
+            && tweaks.iter().any(|t| matches!(t, Tweak::Highlight(true)))
+        {
+            let mut senderkey = user.as_bytes().to_vec();
+            senderkey.push(0xff);
+            senderkey.extend_from_slice(pusher.pushkey.as_bytes());
+
+            db.email
+                .queue_notification(&senderkey, &event.event_id, db.globals.next_count()?)?;
+        }
+
+        return Ok(Vec::new());
     }
 
     // TODO:
@@ -270,7 +432,7 @@ async fn send_notice(
         url
     } else {
         error!("Http Pusher must have URL specified.");
-        return Ok(());
+        return Ok(Vec::new());
     };
 
     let mut device = Device::new(pusher.app_id.clone(), pusher.pushkey.clone());
@@ -301,13 +463,13 @@ async fn send_notice(
         notifi.prio = NotificationPriority::High
     }
 
-    if event_id_only {
+    let response = if event_id_only {
         send_request(
             &db.globals,
             url,
             send_event_notification::v1::Request::new(notifi),
         )
-        .await?;
+        .await?
     } else {
         notifi.sender = Some(&event.sender);
         notifi.event_type = Some(&event.kind);
@@ -339,10 +501,8 @@ async fn send_notice(
             url,
             send_event_notification::v1::Request::new(notifi),
         )
-        .await?;
-    }
-
-    // TODO: email
+        .await?
+    };
 
-    Ok(())
+    Ok(response.rejected)
 }
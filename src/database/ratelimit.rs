@@ -0,0 +1,636 @@
+use ruma::UserId;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// ## Examples:
+/// - Default limits:
+/// ```toml
+/// [global.rate_limit]
+/// enabled = true
+/// ```
+/// - Allow at most 5 messages per user and 20 messages per IP every 10 seconds:
+/// ```toml
+/// [global.rate_limit]
+/// message = { per_user = 5, per_ip = 20, window_secs = 10 }
+/// ```
+///
+/// A limit of 0 disables that limit. Appservices and admins are never rate limited.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RateLimitConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_message_rule")]
+    pub message: RateLimitRule,
+    #[serde(default = "default_registration_rule")]
+    pub registration: RateLimitRule,
+    #[serde(default = "default_login_rule")]
+    pub login: RateLimitRule,
+    #[serde(default = "default_media_upload_rule")]
+    pub media_upload: RateLimitRule,
+    #[serde(default = "default_email_token_rule")]
+    pub email_token: RateLimitRule,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            message: default_message_rule(),
+            registration: default_registration_rule(),
+            login: default_login_rule(),
+            media_upload: default_media_upload_rule(),
+            email_token: default_email_token_rule(),
+        }
+    }
+}
+
+/// How many requests a single user or IP address can make within the sliding window.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct RateLimitRule {
+    #[serde(default)]
+    pub per_user: u32,
+    #[serde(default)]
+    pub per_ip: u32,
+    pub window_secs: u64,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_message_rule() -> RateLimitRule {
+    RateLimitRule {
+        per_user: 30,
+        per_ip: 60,
+        window_secs: 10,
+    }
+}
+
+fn default_registration_rule() -> RateLimitRule {
+    RateLimitRule {
+        per_user: 0,
+        per_ip: 5,
+        window_secs: 60,
+    }
+}
+
+fn default_login_rule() -> RateLimitRule {
+    RateLimitRule {
+        per_user: 0,
+        per_ip: 10,
+        window_secs: 60,
+    }
+}
+
+fn default_media_upload_rule() -> RateLimitRule {
+    RateLimitRule {
+        per_user: 10,
+        per_ip: 20,
+        window_secs: 10,
+    }
+}
+
+fn default_email_token_rule() -> RateLimitRule {
+    RateLimitRule {
+        per_user: 0,
+        per_ip: 5,
+        window_secs: 60,
+    }
+}
+
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// The kinds of client requests that are rate limited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RateLimitedAction {
+    Message,
+    Registration,
+    Login,
+    MediaUpload,
+    EmailToken,
+}
+
+impl RateLimitedAction {
+    /// Finds the action of a request from the path of its endpoint.
+    pub fn from_path(path: &str) -> Option<Self> {
+        match path {
+            "/_matrix/client/r0/rooms/:room_id/send/:event_type/:txn_id" => Some(Self::Message),
+            "/_matrix/client/r0/register" => Some(Self::Registration),
+            "/_matrix/client/r0/login" => Some(Self::Login),
+            "/_matrix/media/r0/upload" => Some(Self::MediaUpload),
+            "/_matrix/client/r0/account/3pid/email/requestToken"
+            | "/_matrix/client/r0/account/password/email/requestToken" => Some(Self::EmailToken),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum RateLimitKey {
+    User(UserId),
+    Ip(IpAddr),
+}
+
+/// The time a client has to wait before it is allowed to retry its request. This is handed from
+/// the request guard to the catcher through the request-local cache.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RetryAfter(pub Option<Duration>);
+
+/// Sliding window rate limiter for client requests.
+///
+/// For every user and IP address the times of their recent requests are remembered. A request is
+/// rejected if there already are as many requests in the last `window_secs` as the limit allows.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    requests: Mutex<HashMap<(RateLimitedAction, RateLimitKey), VecDeque<Instant>>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            requests: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn rule(&self, action: RateLimitedAction) -> RateLimitRule {
+        match action {
+            RateLimitedAction::Message => self.config.message,
+            RateLimitedAction::Registration => self.config.registration,
+            RateLimitedAction::Login => self.config.login,
+            RateLimitedAction::MediaUpload => self.config.media_upload,
+            RateLimitedAction::EmailToken => self.config.email_token,
+        }
+    }
+
+    /// Records a request of the given user and/or IP address.
+    ///
+    /// Returns how long the client has to wait if the request exceeds a limit. Rejected requests
+    /// are not recorded.
+    pub fn check(
+        &self,
+        action: RateLimitedAction,
+        user_id: Option<&UserId>,
+        ip: Option<IpAddr>,
+    ) -> Result<(), Duration> {
+        self.check_at(action, user_id, ip, Instant::now())
+    }
+
+    /// Same as `check`, but uses the given time as the current time.
+    pub fn check_at(
+        &self,
+        action: RateLimitedAction,
+        user_id: Option<&UserId>,
+        ip: Option<IpAddr>,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+
+        let rule = self.rule(action);
+        let window = Duration::from_secs(rule.window_secs);
+
+        let keys = user_id
+            .filter(|_| rule.per_user > 0)
+            .map(|user_id| (RateLimitKey::User(user_id.clone()), rule.per_user))
+            .into_iter()
+            .chain(
+                ip.filter(|_| rule.per_ip > 0)
+                    .map(|ip| (RateLimitKey::Ip(ip), rule.per_ip)),
+            )
+            .collect::<Vec<_>>();
+
+        let mut requests = self.requests.lock().unwrap();
+
+        if requests.len() > MAX_TRACKED_CLIENTS {
+            // Forget clients that have no requests in their window anymore
+            requests.retain(|(action, _), times| {
+                let window = Duration::from_secs(self.rule(*action).window_secs);
+                times
+                    .back()
+                    .map_or(false, |&time| now.saturating_duration_since(time) < window)
+            });
+        }
+
+        let mut retry_after = None;
+        for (key, limit) in &keys {
+            let times = match requests.get_mut(&(action, key.clone())) {
+                Some(times) => times,
+                None => continue,
+            };
+
+            // Forget requests that left the window
+            while times
+                .front()
+                .map_or(false, |&time| now.saturating_duration_since(time) >= window)
+            {
+                times.pop_front();
+            }
+
+            if times.len() >= *limit as usize {
+                let oldest = *times.front().expect("limit is at least 1");
+                let wait = window - now.saturating_duration_since(oldest);
+                retry_after = Some(retry_after.map_or(wait, |r: Duration| r.max(wait)));
+            }
+        }
+
+        if let Some(retry_after) = retry_after {
+            return Err(retry_after);
+        }
+
+        for (key, _) in keys {
+            requests
+                .entry((action, key))
+                .or_insert_with(VecDeque::new)
+                .push_back(now);
+        }
+
+        Ok(())
+    }
+}
+
+/// ## Example:
+/// ```toml
+/// [global.login_lockout]
+/// delay_after = 3
+/// lockout_after = 10
+/// ip_lockout_after = 50
+/// lockout_secs = 900
+/// ```
+///
+/// After `delay_after` failed logins of a user, every further attempt has to wait twice as long
+/// as the previous one. After `lockout_after` failed logins, the user is locked out for
+/// `lockout_secs`. IP addresses are locked out after `ip_lockout_after` failed logins, no matter
+/// which users they tried.
+#[derive(Clone, Debug, Deserialize)]
+pub struct LoginLockoutConfig {
+    #[serde(default = "default_delay_after")]
+    pub delay_after: u32,
+    #[serde(default = "default_lockout_after")]
+    pub lockout_after: u32,
+    #[serde(default = "default_ip_lockout_after")]
+    pub ip_lockout_after: u32,
+    #[serde(default = "default_lockout_secs")]
+    pub lockout_secs: u64,
+}
+
+impl Default for LoginLockoutConfig {
+    fn default() -> Self {
+        Self {
+            delay_after: default_delay_after(),
+            lockout_after: default_lockout_after(),
+            ip_lockout_after: default_ip_lockout_after(),
+            lockout_secs: default_lockout_secs(),
+        }
+    }
+}
+
+fn default_delay_after() -> u32 {
+    3
+}
+
+fn default_lockout_after() -> u32 {
+    10
+}
+
+fn default_ip_lockout_after() -> u32 {
+    50
+}
+
+fn default_lockout_secs() -> u64 {
+    15 * 60
+}
+
+type FailureState = (Instant, u32); // Time of last failed login, number of failed logins
+
+/// Keeps track of failed logins per user and per IP address to slow down brute-force attacks.
+pub struct LoginLockout {
+    config: LoginLockoutConfig,
+    user_failures: Mutex<HashMap<UserId, FailureState>>,
+    ip_failures: Mutex<HashMap<IpAddr, FailureState>>,
+}
+
+impl LoginLockout {
+    pub fn new(config: LoginLockoutConfig) -> Self {
+        Self {
+            config,
+            user_failures: Mutex::new(HashMap::new()),
+            ip_failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn lockout_duration(&self) -> Duration {
+        Duration::from_secs(self.config.lockout_secs)
+    }
+
+    /// How long a client has to wait after its last failed login before it may try again.
+    fn user_wait(&self, failures: u32) -> Duration {
+        if failures >= self.config.lockout_after {
+            self.lockout_duration()
+        } else if failures >= self.config.delay_after {
+            let exponent = (failures - self.config.delay_after).min(31);
+            Duration::from_secs(1 << exponent).min(self.lockout_duration())
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+
+    /// Returns how long the user or IP address has to wait before the next login attempt, if
+    /// they have to wait at all.
+    pub fn check(&self, user_id: Option<&UserId>, ip: Option<IpAddr>) -> Result<(), Duration> {
+        self.check_at(user_id, ip, Instant::now())
+    }
+
+    /// Same as `check`, but uses the given time as the current time.
+    pub fn check_at(
+        &self,
+        user_id: Option<&UserId>,
+        ip: Option<IpAddr>,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let mut wait = Duration::from_secs(0);
+
+        if let Some((last, failures)) =
+            user_id.and_then(|user_id| self.user_failures.lock().unwrap().get(user_id).copied())
+        {
+            let elapsed = now.saturating_duration_since(last);
+            wait = wait.max(self.user_wait(failures).saturating_sub(elapsed));
+        }
+
+        if let Some((last, failures)) =
+            ip.and_then(|ip| self.ip_failures.lock().unwrap().get(&ip).copied())
+        {
+            if self.config.ip_lockout_after > 0 && failures >= self.config.ip_lockout_after {
+                let elapsed = now.saturating_duration_since(last);
+                wait = wait.max(self.lockout_duration().saturating_sub(elapsed));
+            }
+        }
+
+        if wait > Duration::from_secs(0) {
+            Err(wait)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Remembers a failed login of the user and/or IP address.
+    pub fn record_failure(&self, user_id: Option<&UserId>, ip: Option<IpAddr>) {
+        self.record_failure_at(user_id, ip, Instant::now())
+    }
+
+    /// Same as `record_failure`, but uses the given time as the current time.
+    pub fn record_failure_at(&self, user_id: Option<&UserId>, ip: Option<IpAddr>, now: Instant) {
+        let lockout_duration = self.lockout_duration();
+        let bump = |state: &mut FailureState| {
+            // Failures are forgotten after a quiet period as long as the lockout
+            if now.saturating_duration_since(state.0) >= lockout_duration {
+                state.1 = 0;
+            }
+            *state = (now, state.1 + 1);
+        };
+
+        if let Some(user_id) = user_id {
+            let mut user_failures = self.user_failures.lock().unwrap();
+            if user_failures.len() > MAX_TRACKED_CLIENTS {
+                user_failures
+                    .retain(|_, (last, _)| now.saturating_duration_since(*last) < lockout_duration);
+            }
+            bump(user_failures.entry(user_id.clone()).or_insert((now, 0)));
+        }
+
+        if let Some(ip) = ip {
+            let mut ip_failures = self.ip_failures.lock().unwrap();
+            if ip_failures.len() > MAX_TRACKED_CLIENTS {
+                ip_failures
+                    .retain(|_, (last, _)| now.saturating_duration_since(*last) < lockout_duration);
+            }
+            bump(ip_failures.entry(ip).or_insert((now, 0)));
+        }
+    }
+
+    /// Forgets all failed logins of the user, for example after a successful login.
+    ///
+    /// Returns true if the user had failed logins.
+    pub fn unlock_user(&self, user_id: &UserId) -> bool {
+        self.user_failures.lock().unwrap().remove(user_id).is_some()
+    }
+
+    /// Forgets all failed logins from the IP address.
+    ///
+    /// Returns true if the IP address had failed logins.
+    pub fn unlock_ip(&self, ip: &IpAddr) -> bool {
+        self.ip_failures.lock().unwrap().remove(ip).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        LoginLockout, LoginLockoutConfig, RateLimitConfig, RateLimitRule, RateLimitedAction,
+        RateLimiter,
+    };
+    use ruma::UserId;
+    use std::{
+        convert::TryFrom,
+        net::{IpAddr, Ipv4Addr},
+        time::{Duration, Instant},
+    };
+
+    fn limiter(per_user: u32, per_ip: u32) -> RateLimiter {
+        let rule = RateLimitRule {
+            per_user,
+            per_ip,
+            window_secs: 10,
+        };
+
+        RateLimiter::new(RateLimitConfig {
+            enabled: true,
+            message: rule,
+            registration: rule,
+            login: rule,
+            media_upload: rule,
+            email_token: rule,
+        })
+    }
+
+    #[test]
+    fn rejects_requests_over_the_limit() {
+        let limiter = limiter(2, 0);
+        let user = UserId::try_from("@alice:example.com").unwrap();
+        let start = Instant::now();
+
+        assert!(limiter
+            .check_at(RateLimitedAction::Message, Some(&user), None, start)
+            .is_ok());
+        assert!(limiter
+            .check_at(
+                RateLimitedAction::Message,
+                Some(&user),
+                None,
+                start + Duration::from_secs(1)
+            )
+            .is_ok());
+        assert_eq!(
+            limiter.check_at(
+                RateLimitedAction::Message,
+                Some(&user),
+                None,
+                start + Duration::from_secs(4)
+            ),
+            Err(Duration::from_secs(6))
+        );
+    }
+
+    #[test]
+    fn window_slides() {
+        let limiter = limiter(2, 0);
+        let user = UserId::try_from("@alice:example.com").unwrap();
+        let start = Instant::now();
+
+        for secs in &[0, 5] {
+            assert!(limiter
+                .check_at(
+                    RateLimitedAction::Message,
+                    Some(&user),
+                    None,
+                    start + Duration::from_secs(*secs)
+                )
+                .is_ok());
+        }
+
+        // The first request left the window, the second one is still in it
+        assert!(limiter
+            .check_at(
+                RateLimitedAction::Message,
+                Some(&user),
+                None,
+                start + Duration::from_secs(10)
+            )
+            .is_ok());
+        assert_eq!(
+            limiter.check_at(
+                RateLimitedAction::Message,
+                Some(&user),
+                None,
+                start + Duration::from_secs(11)
+            ),
+            Err(Duration::from_secs(4))
+        );
+    }
+
+    #[test]
+    fn users_and_ips_are_limited_separately() {
+        let limiter = limiter(1, 2);
+        let alice = UserId::try_from("@alice:example.com").unwrap();
+        let bob = UserId::try_from("@bob:example.com").unwrap();
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let now = Instant::now();
+
+        assert!(limiter
+            .check_at(RateLimitedAction::Message, Some(&alice), Some(ip), now)
+            .is_ok());
+        assert!(limiter
+            .check_at(RateLimitedAction::Message, Some(&alice), Some(ip), now)
+            .is_err());
+        assert!(limiter
+            .check_at(RateLimitedAction::Message, Some(&bob), Some(ip), now)
+            .is_ok());
+        // The IP address used up its limit, even though carol never sent anything
+        let carol = UserId::try_from("@carol:example.com").unwrap();
+        assert!(limiter
+            .check_at(RateLimitedAction::Message, Some(&carol), Some(ip), now)
+            .is_err());
+        // Other actions have their own limits
+        assert!(limiter
+            .check_at(RateLimitedAction::Login, None, Some(ip), now)
+            .is_ok());
+    }
+
+    #[test]
+    fn zero_disables_limit() {
+        let limiter = limiter(0, 0);
+        let user = UserId::try_from("@alice:example.com").unwrap();
+        let now = Instant::now();
+
+        for _ in 0..100 {
+            assert!(limiter
+                .check_at(RateLimitedAction::MediaUpload, Some(&user), None, now)
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn failed_logins_are_delayed_progressively() {
+        let lockout = LoginLockout::new(LoginLockoutConfig {
+            delay_after: 2,
+            lockout_after: 5,
+            ip_lockout_after: 0,
+            lockout_secs: 60,
+        });
+        let user = UserId::try_from("@alice:example.com").unwrap();
+        let start = Instant::now();
+
+        lockout.record_failure_at(Some(&user), None, start);
+        assert!(lockout.check_at(Some(&user), None, start).is_ok());
+
+        lockout.record_failure_at(Some(&user), None, start);
+        assert_eq!(
+            lockout.check_at(Some(&user), None, start),
+            Err(Duration::from_secs(1))
+        );
+
+        lockout.record_failure_at(Some(&user), None, start);
+        assert_eq!(
+            lockout.check_at(Some(&user), None, start + Duration::from_secs(1)),
+            Err(Duration::from_secs(1))
+        );
+        assert!(lockout
+            .check_at(Some(&user), None, start + Duration::from_secs(2))
+            .is_ok());
+
+        lockout.record_failure_at(Some(&user), None, start);
+        lockout.record_failure_at(Some(&user), None, start);
+        assert_eq!(
+            lockout.check_at(Some(&user), None, start),
+            Err(Duration::from_secs(60))
+        );
+
+        assert!(lockout.unlock_user(&user));
+        assert!(lockout.check_at(Some(&user), None, start).is_ok());
+    }
+
+    #[test]
+    fn ip_is_locked_out_across_users() {
+        let lockout = LoginLockout::new(LoginLockoutConfig {
+            delay_after: 10,
+            lockout_after: 10,
+            ip_lockout_after: 3,
+            lockout_secs: 60,
+        });
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let start = Instant::now();
+
+        for name in &["@a:example.com", "@b:example.com", "@c:example.com"] {
+            let user = UserId::try_from(*name).unwrap();
+            lockout.record_failure_at(Some(&user), Some(ip), start);
+        }
+
+        let user = UserId::try_from("@d:example.com").unwrap();
+        assert_eq!(
+            lockout.check_at(Some(&user), Some(ip), start + Duration::from_secs(10)),
+            Err(Duration::from_secs(50))
+        );
+        assert!(lockout
+            .check_at(Some(&user), None, start + Duration::from_secs(10))
+            .is_ok());
+        assert!(lockout
+            .check_at(Some(&user), Some(ip), start + Duration::from_secs(60))
+            .is_ok());
+    }
+}
@@ -0,0 +1,225 @@
+use std::sync::Arc;
+
+use crate::{utils, Error, Result};
+use ruma::{serde::CanonicalJsonObject, EventId, Int, MilliSecondsSinceUnixEpoch, RoomId, UserId};
+use serde::{Deserialize, Serialize};
+
+use super::{abstraction::Tree, globals::Globals};
+
+pub struct Reports {
+    /// ReportId -> Report
+    pub(super) reportid_report: Arc<dyn Tree>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportStatus {
+    Open,
+    Resolved,
+}
+
+/// A report of an event by a local user.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Report {
+    pub reporter: UserId,
+    pub room_id: RoomId,
+    pub event_id: EventId,
+    pub sender: UserId,
+    pub score: Int,
+    pub reason: String,
+    /// The event as it was when it was reported, in case it gets redacted later.
+    pub event: CanonicalJsonObject,
+    pub status: ReportStatus,
+    pub received_at: MilliSecondsSinceUnixEpoch,
+    pub resolved_at: Option<MilliSecondsSinceUnixEpoch>,
+    pub resolved_by: Option<UserId>,
+    /// Descriptions of the actions admins took because of this report.
+    #[serde(default)]
+    pub actions: Vec<String>,
+}
+
+impl Reports {
+    /// Stores a new open report and returns its id.
+    #[tracing::instrument(skip(self, event, globals))]
+    #[allow(clippy::too_many_arguments)]
+    pub fn create(
+        &self,
+        reporter: &UserId,
+        room_id: &RoomId,
+        event_id: &EventId,
+        sender: &UserId,
+        score: Int,
+        reason: &str,
+        event: CanonicalJsonObject,
+        globals: &Globals,
+    ) -> Result<u64> {
+        let report_id = globals.next_count()?;
+
+        self.save(
+            report_id,
+            &Report {
+                reporter: reporter.clone(),
+                room_id: room_id.clone(),
+                event_id: event_id.clone(),
+                sender: sender.clone(),
+                score,
+                reason: reason.to_owned(),
+                event,
+                status: ReportStatus::Open,
+                received_at: MilliSecondsSinceUnixEpoch::now(),
+                resolved_at: None,
+                resolved_by: None,
+                actions: Vec::new(),
+            },
+        )?;
+
+        Ok(report_id)
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn get(&self, report_id: u64) -> Result<Option<Report>> {
+        self.reportid_report
+            .get(&report_id.to_be_bytes())?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid report in db."))
+            })
+            .transpose()
+    }
+
+    /// Returns all reports, the newest first.
+    #[tracing::instrument(skip(self))]
+    pub fn all_reports<'a>(&'a self) -> impl Iterator<Item = Result<(u64, Report)>> + 'a {
+        self.reportid_report
+            .iter_from(&u64::MAX.to_be_bytes(), true)
+            .map(|(key, value)| {
+                let report_id = utils::u64_from_bytes(&key)
+                    .map_err(|_| Error::bad_database("Invalid report id in db."))?;
+                let report = serde_json::from_slice(&value)
+                    .map_err(|_| Error::bad_database("Invalid report in db."))?;
+
+                Ok((report_id, report))
+            })
+    }
+
+    /// Marks the report as resolved. Returns false if it does not exist or was already resolved.
+    #[tracing::instrument(skip(self))]
+    pub fn resolve(&self, report_id: u64, admin: &UserId) -> Result<bool> {
+        let mut report = match self.get(report_id)? {
+            Some(report) if report.status == ReportStatus::Open => report,
+            _ => return Ok(false),
+        };
+
+        report.status = ReportStatus::Resolved;
+        report.resolved_at = Some(MilliSecondsSinceUnixEpoch::now());
+        report.resolved_by = Some(admin.clone());
+        self.save(report_id, &report)?;
+
+        Ok(true)
+    }
+
+    /// Remembers that an admin acted on the report.
+    #[tracing::instrument(skip(self))]
+    pub fn add_action(&self, report_id: u64, action: String) -> Result<()> {
+        let mut report = self
+            .get(report_id)?
+            .ok_or_else(|| Error::bad_database("Tried to act on a report that does not exist."))?;
+
+        report.actions.push(action);
+        self.save(report_id, &report)
+    }
+
+    fn save(&self, report_id: u64, report: &Report) -> Result<()> {
+        self.reportid_report.insert(
+            &report_id.to_be_bytes(),
+            &serde_json::to_vec(report).expect("Report is valid JSON value"),
+        )
+    }
+}
+
+/// What admins can do about a reported event.
+#[derive(Clone, Copy, Debug)]
+pub enum ReportAction {
+    /// Redact the reported event.
+    Redact,
+    /// Kick the sender from the room of the event.
+    Kick,
+    /// Deactivate the account of the sender, only possible for local users.
+    Deactivate,
+}
+
+impl ReportAction {
+    pub fn from_name(action: &str) -> Option<Self> {
+        match action {
+            "redact" => Some(ReportAction::Redact),
+            "kick" => Some(ReportAction::Kick),
+            "deactivate" => Some(ReportAction::Deactivate),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Report, ReportStatus, Reports};
+    use crate::database::abstraction::memory::MemoryTree;
+    use ruma::{int, EventId, MilliSecondsSinceUnixEpoch, RoomId, UserId};
+    use std::{convert::TryFrom, sync::Arc};
+
+    fn reports_with_open_report() -> Reports {
+        let reports = Reports {
+            reportid_report: Arc::new(MemoryTree::default()),
+        };
+
+        reports
+            .save(
+                1,
+                &Report {
+                    reporter: UserId::try_from("@reporter:example.com").unwrap(),
+                    room_id: RoomId::try_from("!room:example.com").unwrap(),
+                    event_id: EventId::try_from("$event:example.com").unwrap(),
+                    sender: UserId::try_from("@sender:example.com").unwrap(),
+                    score: int!(-100),
+                    reason: "spam".to_owned(),
+                    event: Default::default(),
+                    status: ReportStatus::Open,
+                    received_at: MilliSecondsSinceUnixEpoch::now(),
+                    resolved_at: None,
+                    resolved_by: None,
+                    actions: Vec::new(),
+                },
+            )
+            .unwrap();
+
+        reports
+    }
+
+    #[test]
+    fn reports_are_resolved_once() {
+        let reports = reports_with_open_report();
+        let admin = UserId::try_from("@admin:example.com").unwrap();
+
+        assert!(reports.resolve(1, &admin).unwrap());
+        let report = reports.get(1).unwrap().unwrap();
+        assert_eq!(report.status, ReportStatus::Resolved);
+        assert_eq!(report.resolved_by, Some(admin.clone()));
+        assert!(report.resolved_at.is_some());
+
+        assert!(!reports.resolve(1, &admin).unwrap());
+        assert!(!reports.resolve(2, &admin).unwrap());
+    }
+
+    #[test]
+    fn actions_are_added_to_the_report() {
+        let reports = reports_with_open_report();
+
+        reports.add_action(1, "Redacted".to_owned()).unwrap();
+        reports.add_action(1, "Kicked".to_owned()).unwrap();
+        assert_eq!(
+            reports.get(1).unwrap().unwrap().actions,
+            ["Redacted", "Kicked"]
+        );
+
+        assert!(reports.add_action(2, "Redacted".to_owned()).is_err());
+    }
+}
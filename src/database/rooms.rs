@@ -1,13 +1,12 @@
 mod edus;
 
-pub use edus::RoomEdus;
+pub use edus::{receipt_thread_id, RoomEdus};
 
 use crate::{
     pdu::{EventHash, PduBuilder},
     server_server, utils, Database, Error, PduEvent, Result,
 };
 use lru_cache::LruCache;
-use regex::Regex;
 use ring::digest;
 use rocket::http::RawStr;
 use ruma::{
@@ -15,7 +14,7 @@ use ruma::{
     events::{
         direct::DirectEvent,
         ignored_user_list::IgnoredUserListEvent,
-        push_rules::PushRulesEvent,
+        push_rules::{PushRulesEvent, PushRulesEventContent},
         room::{
             create::RoomCreateEventContent,
             member::{MembershipState, RoomMemberEventContent},
@@ -28,22 +27,34 @@ use ruma::{
     push::{Action, Ruleset, Tweak},
     serde::{CanonicalJsonObject, CanonicalJsonValue, Raw},
     state_res::{self, RoomVersion, StateMap},
-    uint, EventId, RoomAliasId, RoomId, RoomVersionId, ServerName, UserId,
+    uint, EventId, MilliSecondsSinceUnixEpoch, RoomAliasId, RoomId, RoomVersionId, ServerName,
+    UInt, UserId,
 };
-use serde::Deserialize;
-use serde_json::value::to_raw_value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, value::to_raw_value};
 use std::{
     borrow::Cow,
     collections::{BTreeMap, HashMap, HashSet},
     convert::{TryFrom, TryInto},
     mem::size_of,
+    net::IpAddr,
     sync::{Arc, Mutex, RwLock},
-    time::Instant,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{MutexGuard, RwLock as TokioRwLock},
+    time::interval,
+};
+use tracing::{error, info, warn};
+
+use super::{
+    abstraction::Tree,
+    admin::AdminCommand,
+    appservice::RegistrationInfo,
+    pusher::{self, RoomPushContext},
+    reports::{ReportAction, ReportStatus},
+    server_notices,
 };
-use tokio::sync::MutexGuard;
-use tracing::{error, warn};
-
-use super::{abstraction::Tree, admin::AdminCommand, pusher};
 
 /// The unique identifier of each state group.
 ///
@@ -52,6 +63,48 @@ use super::{abstraction::Tree, admin::AdminCommand, pusher};
 pub type StateHashId = Vec<u8>;
 pub type CompressedStateEvent = [u8; 2 * size_of::<u64>()];
 
+/// How many entries of the notification log are kept for every user.
+const MAX_LOGGED_NOTIFICATIONS: usize = 1000;
+/// How often the notification logs are trimmed.
+const NOTIFICATION_LOG_TRIM_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How many different annotations are listed in the aggregations of an event, the most frequent
+/// first. All relations are counted.
+const MAX_BUNDLED_ANNOTATIONS: usize = 100;
+
+/// An event that notified a user, as stored in the notification log.
+#[derive(Deserialize, Serialize)]
+pub struct LoggedNotification {
+    pub room_id: RoomId,
+    pub event_id: EventId,
+    pub actions: Vec<Action>,
+    pub highlight: bool,
+    pub ts: MilliSecondsSinceUnixEpoch,
+}
+
+/// The `m.relates_to` field of an event that relates to another event, e.g. a reaction, an edit
+/// or a message in a thread. Replies don't have a `rel_type` and are not relations.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Relation {
+    pub rel_type: String,
+    pub event_id: EventId,
+    pub key: Option<String>,
+}
+
+impl Relation {
+    /// Returns the relation of the event or `None` if it doesn't relate to another event.
+    pub fn from_pdu(pdu: &PduEvent) -> Option<Self> {
+        #[derive(Deserialize)]
+        struct ExtractRelatesTo {
+            #[serde(rename = "m.relates_to")]
+            relates_to: Relation,
+        }
+
+        serde_json::from_str::<ExtractRelatesTo>(pdu.content.get())
+            .ok()
+            .map(|content| content.relates_to)
+    }
+}
+
 pub struct Rooms {
     pub edus: RoomEdus,
     pub(super) pduid_pdu: Arc<dyn Tree>, // PduId = ShortRoomId + Count
@@ -79,6 +132,9 @@ pub struct Rooms {
 
     pub(super) userroomid_notificationcount: Arc<dyn Tree>, // NotifyCount = u64
     pub(super) userroomid_highlightcount: Arc<dyn Tree>,    // HightlightCount = u64
+    pub(super) userroomthreadid_notificationcount: Arc<dyn Tree>, // UserRoomThreadId = UserId + RoomId + ThreadRootEventId
+    pub(super) userroomthreadid_highlightcount: Arc<dyn Tree>,
+    pub(super) useridcount_notification: Arc<dyn Tree>, // UserIdCount = UserId + PduCount
 
     /// Remember the current state hash of a room.
     pub(super) roomid_shortstatehash: Arc<dyn Tree>,
@@ -107,6 +163,13 @@ pub struct Rooms {
     /// RoomId + EventId -> Parent PDU EventId.
     pub(super) referencedevents: Arc<dyn Tree>,
 
+    /// RelatesToEventId + Count -> PduId of the relating event.
+    pub(super) relationid_pduid: Arc<dyn Tree>,
+    /// ShortRoomId + Count of the latest event in the thread -> thread root EventId.
+    pub(super) roomcount_threadroot: Arc<dyn Tree>,
+    pub(super) threadroot_roomcount: Arc<dyn Tree>,
+    pub(super) threadrootuserids: Arc<dyn Tree>, // ThreadRootUserId = ThreadRootEventId + UserId
+
     pub(super) pdu_cache: Mutex<LruCache<EventId, Arc<PduEvent>>>,
     pub(super) shorteventid_cache: Mutex<LruCache<u64, Arc<EventId>>>,
     pub(super) auth_chain_cache: Mutex<LruCache<Vec<u64>, Arc<HashSet<u64>>>>,
@@ -115,6 +178,7 @@ pub struct Rooms {
     pub(super) shortstatekey_cache: Mutex<LruCache<u64, (EventType, String)>>,
     pub(super) our_real_users_cache: RwLock<HashMap<RoomId, Arc<HashSet<UserId>>>>,
     pub(super) appservice_in_room_cache: RwLock<HashMap<RoomId, HashMap<String, bool>>>,
+    pub(super) pushcontext_cache: Mutex<LruCache<(u64, u64), Arc<RoomPushContext>>>, // (ShortStateHash, JoinedCount)
     pub(super) stateinfo_cache: Mutex<
         LruCache<
             u64,
@@ -1335,20 +1399,29 @@ impl Rooms {
         drop(insert_lock);
 
         // See if the event matches any known pushers
-        let power_levels: RoomPowerLevelsEventContent = db
-            .rooms
-            .room_state_get(&pdu.room_id, &EventType::RoomPowerLevels, "")?
-            .map(|ev| {
-                serde_json::from_str(ev.content.get())
-                    .map_err(|_| Error::bad_database("invalid m.room.power_levels event"))
-            })
-            .transpose()?
-            .unwrap_or_default();
+        let push_context = self.push_context(&pdu.room_id)?;
 
         let sync_pdu = pdu.to_sync_room_event();
 
+        let relation = Relation::from_pdu(pdu);
+
+        // Events in threads count towards the room and also towards the thread, for clients that
+        // show thread notifications separately
+        let thread_root = match relation
+            .as_ref()
+            .filter(|relation| relation.rel_type == "m.thread")
+        {
+            Some(relation) if self.is_relation_in_room(relation, &pdu.room_id)? => {
+                Some(&relation.event_id)
+            }
+            _ => None,
+        };
+
         let mut notifies = Vec::new();
         let mut highlights = Vec::new();
+        let mut thread_notifies = Vec::new();
+        let mut thread_highlights = Vec::new();
+        let mut notification_log = Vec::new();
 
         for user in self.get_our_real_users(&pdu.room_id, db)?.iter() {
             // Don't notify the user of their own events
@@ -1365,14 +1438,16 @@ impl Rooms {
             let mut highlight = false;
             let mut notify = false;
 
-            for action in pusher::get_actions(
+            let actions = pusher::get_actions(
                 user,
                 &rules_for_user,
-                &power_levels,
+                &push_context,
                 &sync_pdu,
                 &pdu.room_id,
                 db,
-            )? {
+            )?;
+
+            for action in actions {
                 match action {
                     Action::DontNotify => notify = false,
                     // TODO: Implement proper support for coalesce
@@ -1388,12 +1463,37 @@ impl Rooms {
             userroom_id.push(0xff);
             userroom_id.extend_from_slice(pdu.room_id.as_bytes());
 
+            let userroomthread_id =
+                thread_root.map(|thread_root| userroomthread_id(user, &pdu.room_id, thread_root));
+
             if notify {
                 notifies.push(userroom_id.clone());
+                if let Some(userroomthread_id) = &userroomthread_id {
+                    thread_notifies.push(userroomthread_id.clone());
+                }
+
+                let mut key = user.as_bytes().to_vec();
+                key.push(0xff);
+                key.extend_from_slice(&count2.to_be_bytes());
+
+                notification_log.push((
+                    key,
+                    serde_json::to_vec(&LoggedNotification {
+                        room_id: pdu.room_id.clone(),
+                        event_id: pdu.event_id.clone(),
+                        actions: actions.to_vec(),
+                        highlight,
+                        ts: MilliSecondsSinceUnixEpoch::now(),
+                    })
+                    .expect("LoggedNotification can be serialized"),
+                ));
             }
 
             if highlight {
                 highlights.push(userroom_id);
+                if let Some(userroomthread_id) = userroomthread_id {
+                    thread_highlights.push(userroomthread_id);
+                }
             }
 
             for senderkey in db.pusher.get_pusher_senderkeys(user) {
@@ -1405,6 +1505,26 @@ impl Rooms {
             .increment_batch(&mut notifies.into_iter())?;
         self.userroomid_highlightcount
             .increment_batch(&mut highlights.into_iter())?;
+        self.userroomthreadid_notificationcount
+            .increment_batch(&mut thread_notifies.into_iter())?;
+        self.userroomthreadid_highlightcount
+            .increment_batch(&mut thread_highlights.into_iter())?;
+        self.useridcount_notification
+            .insert_batch(&mut notification_log.into_iter())?;
+
+        if let Some(relation) = relation {
+            self.add_relation(
+                &relation,
+                &pdu.room_id,
+                shortroomid,
+                count2,
+                &pdu_id,
+                &pdu.sender,
+            )?;
+        }
+
+        // Rules of policy rooms take effect as soon as they arrive
+        db.policies.update_rule(pdu, &db.globals);
 
         match pdu.kind {
             EventType::RoomRedaction => {
@@ -1523,6 +1643,48 @@ impl Rooms {
                                         ));
                                     }
                                 }
+                                "unregister_appservice" => {
+                                    let message = if args.len() != 1 {
+                                        "Usage: unregister_appservice <appservice_id>".to_owned()
+                                    } else if db.appservice.unregister_appservice(args[0])? {
+                                        format!("Unregistered appservice {}.", args[0])
+                                    } else {
+                                        format!("Appservice {} does not exist.", args[0])
+                                    };
+
+                                    db.admin.send(AdminCommand::SendMessage(
+                                        RoomMessageEventContent::text_plain(message),
+                                    ));
+                                }
+                                "reload_appservices" => {
+                                    let summary = db.appservice.load_registration_files(
+                                        db.globals.appservice_registration_files(),
+                                    )?;
+
+                                    let mut message = format!(
+                                        "Loaded appservices: {}\nRemoved appservices: {}\n",
+                                        summary.loaded.join(", "),
+                                        summary.removed.join(", ")
+                                    );
+                                    for error in summary.errors {
+                                        message += &format!("Failed to load {}\n", error);
+                                    }
+
+                                    db.admin.send(AdminCommand::SendMessage(
+                                        RoomMessageEventContent::text_plain(message),
+                                    ));
+                                }
+                                "reload_policy_lists" => {
+                                    let count = db.policies.reload(self, &db.globals)?;
+
+                                    db.admin.send(AdminCommand::SendMessage(
+                                        RoomMessageEventContent::text_plain(format!(
+                                            "Loaded {} policy rules from {} policy rooms.",
+                                            count,
+                                            db.globals.policy_rooms().len()
+                                        )),
+                                    ));
+                                }
                                 "list_appservices" => {
                                     db.admin.send(AdminCommand::ListAppservices);
                                 }
@@ -1671,6 +1833,362 @@ impl Rooms {
                                         ));
                                     }
                                 }
+                                "unlock_login" => {
+                                    let message = if args.len() != 1 {
+                                        "Usage: unlock_login <userid|ip>".to_owned()
+                                    } else if let Ok(user_id) = UserId::try_from(args[0]) {
+                                        if db.globals.login_lockout.unlock_user(&user_id) {
+                                            format!("Unlocked logins of {}.", user_id)
+                                        } else {
+                                            format!("{} has no failed logins.", user_id)
+                                        }
+                                    } else if let Ok(ip) = args[0].parse::<IpAddr>() {
+                                        if db.globals.login_lockout.unlock_ip(&ip) {
+                                            format!("Unlocked logins from {}.", ip)
+                                        } else {
+                                            format!("{} has no failed logins.", ip)
+                                        }
+                                    } else {
+                                        "User ID or IP address could not be parsed.".to_owned()
+                                    };
+
+                                    db.admin.send(AdminCommand::SendMessage(
+                                        RoomMessageEventContent::text_plain(message),
+                                    ));
+                                }
+                                "shadow_ban" | "unshadow_ban" | "suspend" | "unsuspend" => {
+                                    let message = if args.len() != 1 {
+                                        format!("Usage: {} <userid>", command)
+                                    } else if let Ok(user_id) = UserId::try_from(args[0]) {
+                                        if user_id.server_name() != db.globals.server_name()
+                                            || !db.users.exists(&user_id)?
+                                        {
+                                            format!("{} is not a local user.", user_id)
+                                        } else {
+                                            match command {
+                                                "shadow_ban" => {
+                                                    db.users.set_shadow_banned(&user_id, true)?;
+                                                    format!("Shadow-banned {}.", user_id)
+                                                }
+                                                "unshadow_ban" => {
+                                                    db.users.set_shadow_banned(&user_id, false)?;
+                                                    format!("Lifted the shadow-ban of {}.", user_id)
+                                                }
+                                                "suspend" => {
+                                                    db.users.set_suspended(&user_id, true)?;
+                                                    format!("Suspended {}.", user_id)
+                                                }
+                                                _ => {
+                                                    db.users.set_suspended(&user_id, false)?;
+                                                    format!("Unsuspended {}.", user_id)
+                                                }
+                                            }
+                                        }
+                                    } else {
+                                        "User ID could not be parsed.".to_owned()
+                                    };
+
+                                    db.admin.send(AdminCommand::SendMessage(
+                                        RoomMessageEventContent::text_plain(message),
+                                    ));
+                                }
+                                "show_consent" => {
+                                    let message = if args.len() != 1 {
+                                        "Usage: show_consent <userid>".to_owned()
+                                    } else if let Ok(user_id) = UserId::try_from(args[0]) {
+                                        let mut message =
+                                            format!("Policies accepted by {}:\n", user_id);
+                                        for (policy_id, consent) in db.terms.consents(&user_id)? {
+                                            message += &format!(
+                                                "{} version {} at {} (ms since epoch)\n",
+                                                policy_id,
+                                                consent.version,
+                                                consent.accepted_at.get()
+                                            );
+                                        }
+                                        message
+                                    } else {
+                                        "User ID could not be parsed.".to_owned()
+                                    };
+
+                                    db.admin.send(AdminCommand::SendMessage(
+                                        RoomMessageEventContent::text_plain(message),
+                                    ));
+                                }
+                                "account_data" => {
+                                    let message = if args.len() != 1 {
+                                        "Usage: account_data <userid>".to_owned()
+                                    } else if let Ok(user_id) = UserId::try_from(args[0]) {
+                                        let mut message = format!(
+                                            "Account data of {} ({} bytes):\n",
+                                            user_id,
+                                            db.account_data.size(&user_id)?
+                                        );
+                                        for entry in db.account_data.entries(&user_id)? {
+                                            match entry.room_id {
+                                                Some(room_id) => {
+                                                    message += &format!(
+                                                        "{} in {}: {} bytes\n",
+                                                        entry.event_type, room_id, entry.size
+                                                    )
+                                                }
+                                                None => {
+                                                    message += &format!(
+                                                        "{}: {} bytes\n",
+                                                        entry.event_type, entry.size
+                                                    )
+                                                }
+                                            }
+                                        }
+                                        message
+                                    } else {
+                                        "User ID could not be parsed.".to_owned()
+                                    };
+
+                                    db.admin.send(AdminCommand::SendMessage(
+                                        RoomMessageEventContent::text_plain(message),
+                                    ));
+                                }
+                                "reset_account_data" => {
+                                    let message = if args.len() != 2 && args.len() != 3 {
+                                        "Usage: reset_account_data <userid> <type> [roomid]"
+                                            .to_owned()
+                                    } else if let (Ok(user_id), Ok(room_id)) = (
+                                        UserId::try_from(args[0]),
+                                        args.get(2).map(|r| RoomId::try_from(*r)).transpose(),
+                                    ) {
+                                        let event_type = EventType::from(args[1]);
+                                        // Push rules are reset to the defaults, everything else
+                                        // is emptied so clients notice the change on sync
+                                        if event_type == EventType::PushRules && room_id.is_none() {
+                                            db.account_data.update(
+                                                None,
+                                                &user_id,
+                                                EventType::PushRules,
+                                                &PushRulesEvent {
+                                                    content: PushRulesEventContent {
+                                                        global: Ruleset::server_default(&user_id),
+                                                    },
+                                                },
+                                                &db.globals,
+                                            )?;
+                                        } else {
+                                            db.account_data.update(
+                                                room_id.as_ref(),
+                                                &user_id,
+                                                event_type.clone(),
+                                                &serde_json::json!({
+                                                    "type": event_type,
+                                                    "content": {},
+                                                }),
+                                                &db.globals,
+                                            )?;
+                                        }
+                                        format!("Reset {} of {}.", event_type, user_id)
+                                    } else {
+                                        "User ID or room ID could not be parsed.".to_owned()
+                                    };
+
+                                    db.admin.send(AdminCommand::SendMessage(
+                                        RoomMessageEventContent::text_plain(message),
+                                    ));
+                                }
+                                "list_reports" => {
+                                    let show_all = args.first() == Some(&"all");
+                                    let mut message = String::new();
+
+                                    for report in db.reports.all_reports() {
+                                        let (report_id, report) = report?;
+                                        if !show_all && report.status != ReportStatus::Open {
+                                            continue;
+                                        }
+
+                                        message += &format!(
+                                            "{} ({:?}): {} reported {} by {} in {}: {}\n",
+                                            report_id,
+                                            report.status,
+                                            report.reporter,
+                                            report.event_id,
+                                            report.sender,
+                                            report.room_id,
+                                            report.reason
+                                        );
+                                    }
+
+                                    if message.is_empty() {
+                                        message = "There are no open reports.".to_owned();
+                                    }
+
+                                    db.admin.send(AdminCommand::SendMessage(
+                                        RoomMessageEventContent::text_plain(message),
+                                    ));
+                                }
+                                "show_report" => {
+                                    let report = match args.get(0).map(|id| id.parse::<u64>()) {
+                                        Some(Ok(report_id)) if args.len() == 1 => {
+                                            db.reports.get(report_id)?
+                                        }
+                                        _ => None,
+                                    };
+
+                                    let message = if args.len() != 1 {
+                                        RoomMessageEventContent::text_plain(
+                                            "Usage: show_report <reportid>",
+                                        )
+                                    } else if let Some(report) = report {
+                                        let mut text = format!(
+                                            "Report {} ({:?})\n\
+                                            Reported by {} at {} (ms since epoch)\n\
+                                            Event {} in {} sent by {}\n\
+                                            Score: {}\n\
+                                            Reason: {}\n",
+                                            args[0],
+                                            report.status,
+                                            report.reporter,
+                                            report.received_at.get(),
+                                            report.event_id,
+                                            report.room_id,
+                                            report.sender,
+                                            report.score,
+                                            report.reason
+                                        );
+                                        if let (Some(resolved_by), Some(resolved_at)) =
+                                            (&report.resolved_by, report.resolved_at)
+                                        {
+                                            text += &format!(
+                                                "Resolved by {} at {} (ms since epoch)\n",
+                                                resolved_by,
+                                                resolved_at.get()
+                                            );
+                                        }
+                                        for action in &report.actions {
+                                            text += &format!("Action: {}\n", action);
+                                        }
+
+                                        let json_text = serde_json::to_string_pretty(&report.event)
+                                            .expect("canonical json is valid json");
+                                        RoomMessageEventContent::text_html(
+                                            format!("{}\n```json\n{}\n```", text, json_text),
+                                            format!(
+                                                "<pre>{}</pre>\n<pre><code class=\"language-json\">{}\n</code></pre>\n",
+                                                RawStr::new(&text).html_escape(),
+                                                RawStr::new(&json_text).html_escape()
+                                            ),
+                                        )
+                                    } else {
+                                        RoomMessageEventContent::text_plain("Report not found.")
+                                    };
+
+                                    db.admin.send(AdminCommand::SendMessage(message));
+                                }
+                                "resolve_report" => {
+                                    let message = if args.len() != 1 {
+                                        "Usage: resolve_report <reportid>".to_owned()
+                                    } else if let Ok(report_id) = args[0].parse::<u64>() {
+                                        if db.reports.resolve(report_id, &pdu.sender)? {
+                                            format!("Resolved report {}.", report_id)
+                                        } else {
+                                            format!(
+                                                "Report {} does not exist or is already resolved.",
+                                                report_id
+                                            )
+                                        }
+                                    } else {
+                                        "Report ID could not be parsed.".to_owned()
+                                    };
+
+                                    db.admin.send(AdminCommand::SendMessage(
+                                        RoomMessageEventContent::text_plain(message),
+                                    ));
+                                }
+                                "act_on_report" => {
+                                    match (
+                                        args.get(0).map(|id| id.parse::<u64>()),
+                                        args.get(1).and_then(|a| ReportAction::from_name(a)),
+                                    ) {
+                                        (Some(Ok(report_id)), Some(action)) if args.len() == 2 => {
+                                            db.admin.send(AdminCommand::ActOnReport {
+                                                report_id,
+                                                action,
+                                                admin: pdu.sender.clone(),
+                                            });
+                                        }
+                                        _ => {
+                                            db.admin.send(AdminCommand::SendMessage(
+                                                RoomMessageEventContent::text_plain(
+                                                    "Usage: act_on_report <reportid> <redact|kick|deactivate>",
+                                                ),
+                                            ));
+                                        }
+                                    }
+                                }
+                                "broadcast_notice" => {
+                                    let notice = body.join("\n");
+                                    let message = if notice.trim().is_empty() {
+                                        "Usage: broadcast_notice, followed by the notice in the next lines".to_owned()
+                                    } else {
+                                        let conduit_user = UserId::try_from(format!(
+                                            "@conduit:{}",
+                                            db.globals.server_name()
+                                        ))
+                                        .expect("@conduit:server_name is valid");
+                                        let appservices = db.appservice.all()?;
+
+                                        let mut count = 0;
+                                        for user_id in db.users.iter() {
+                                            let user_id = user_id?;
+
+                                            // Bots of appservices don't read notices
+                                            if user_id == conduit_user
+                                                || db.users.is_deactivated(&user_id)?
+                                                || appservices.iter().any(|(_, registration)| {
+                                                    registration.is_appservice_user(
+                                                        &user_id,
+                                                        db.globals.server_name(),
+                                                    )
+                                                })
+                                            {
+                                                continue;
+                                            }
+
+                                            db.server_notices.send(
+                                                user_id,
+                                                server_notices::GENERIC_NOTICE_TYPE,
+                                                notice.clone(),
+                                            );
+                                            count += 1;
+                                        }
+
+                                        format!("Sending the notice to {} users.", count)
+                                    };
+
+                                    db.admin.send(AdminCommand::SendMessage(
+                                        RoomMessageEventContent::text_plain(message),
+                                    ));
+                                }
+                                "list_failing_pushers" => {
+                                    let mut message = String::new();
+
+                                    for pusher in db.pusher.failing_pushers() {
+                                        let (user_id, pushkey, failure) = pusher?;
+                                        message += &format!(
+                                            "{} {}: {} failures in a row, last at {} (ms since epoch): {}\n",
+                                            user_id,
+                                            pushkey,
+                                            failure.failures,
+                                            failure.last_failure.get(),
+                                            failure.last_error
+                                        );
+                                    }
+
+                                    if message.is_empty() {
+                                        message = "No pushers are failing.".to_owned();
+                                    }
+
+                                    db.admin.send(AdminCommand::SendMessage(
+                                        RoomMessageEventContent::text_plain(message),
+                                    ));
+                                }
                                 _ => {
                                     db.admin.send(AdminCommand::SendMessage(
                                         RoomMessageEventContent::text_plain(format!(
@@ -1701,9 +2219,118 @@ impl Rooms {
         self.userroomid_highlightcount
             .insert(&userroom_id, &0_u64.to_be_bytes())?;
 
+        // Reading the room also reads all threads
+        userroom_id.push(0xff);
+        for (key, _) in self
+            .userroomthreadid_notificationcount
+            .scan_prefix(userroom_id.clone())
+        {
+            self.userroomthreadid_notificationcount.remove(&key)?;
+        }
+        for (key, _) in self
+            .userroomthreadid_highlightcount
+            .scan_prefix(userroom_id)
+        {
+            self.userroomthreadid_highlightcount.remove(&key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Resets the notification counts of a single thread, or of the main timeline without the
+    /// threads if `thread_root` is `None`. The counts of the room still include the threads that
+    /// were not read.
+    #[tracing::instrument(skip(self))]
+    pub fn reset_thread_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        thread_root: Option<&EventId>,
+    ) -> Result<()> {
+        let mut userroom_id = user_id.as_bytes().to_vec();
+        userroom_id.push(0xff);
+        userroom_id.extend_from_slice(room_id.as_bytes());
+
+        match thread_root {
+            Some(thread_root) => {
+                let userroomthread_id = userroomthread_id(user_id, room_id, thread_root);
+
+                for (room_tree, thread_tree) in &[
+                    (
+                        &self.userroomid_notificationcount,
+                        &self.userroomthreadid_notificationcount,
+                    ),
+                    (
+                        &self.userroomid_highlightcount,
+                        &self.userroomthreadid_highlightcount,
+                    ),
+                ] {
+                    let read = stored_count(thread_tree, &userroomthread_id)?;
+                    let left = stored_count(room_tree, &userroom_id)?.saturating_sub(read);
+
+                    thread_tree.remove(&userroomthread_id)?;
+                    room_tree.insert(&userroom_id, &left.to_be_bytes())?;
+                }
+            }
+            None => {
+                let (notifications, highlights) = self
+                    .thread_notification_counts(user_id, room_id)?
+                    .values()
+                    .fold((0, 0), |(n, h), (notifications, highlights)| {
+                        (n + notifications, h + highlights)
+                    });
+
+                self.userroomid_notificationcount
+                    .insert(&userroom_id, &notifications.to_be_bytes())?;
+                self.userroomid_highlightcount
+                    .insert(&userroom_id, &highlights.to_be_bytes())?;
+            }
+        }
+
         Ok(())
     }
 
+    /// Returns the notification and highlight counts of all threads in the room that have unread
+    /// notifications.
+    #[tracing::instrument(skip(self))]
+    pub fn thread_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<BTreeMap<EventId, (u64, u64)>> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(room_id.as_bytes());
+        prefix.push(0xff);
+
+        let mut counts = BTreeMap::new();
+
+        for (tree, is_highlight) in &[
+            (&self.userroomthreadid_notificationcount, false),
+            (&self.userroomthreadid_highlightcount, true),
+        ] {
+            for (key, value) in tree.scan_prefix(prefix.clone()) {
+                let thread_root = EventId::try_from(
+                    utils::string_from_bytes(&key[prefix.len()..]).map_err(|_| {
+                        Error::bad_database("Invalid thread root bytes in notification count.")
+                    })?,
+                )
+                .map_err(|_| Error::bad_database("Invalid thread root in notification count."))?;
+                let count = utils::u64_from_bytes(&value)
+                    .map_err(|_| Error::bad_database("Invalid notification count in db."))?;
+
+                let counts: &mut (u64, u64) = counts.entry(thread_root).or_default();
+                if *is_highlight {
+                    counts.1 = count;
+                } else {
+                    counts.0 = count;
+                }
+            }
+        }
+
+        Ok(counts)
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn notification_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
         let mut userroom_id = user_id.as_bytes().to_vec();
@@ -1734,6 +2361,73 @@ impl Rooms {
             .unwrap_or(Ok(0))
     }
 
+    /// Returns an iterator over the notification log of a user, starting with the newest
+    /// notification before `until`.
+    #[tracing::instrument(skip(self))]
+    pub fn notifications_until<'a>(
+        &'a self,
+        user_id: &UserId,
+        until: u64,
+    ) -> impl Iterator<Item = Result<(u64, LoggedNotification)>> + 'a {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let mut current = prefix.clone();
+        current.extend_from_slice(&(until.saturating_sub(1)).to_be_bytes()); // -1 because we don't want the notification at `until`
+
+        self.useridcount_notification
+            .iter_from(&current, true)
+            .take_while(move |(k, _)| k.starts_with(&prefix))
+            .map(|(key, value)| {
+                let count =
+                    utils::u64_from_bytes(&key[key.len() - size_of::<u64>()..]).map_err(|_| {
+                        Error::bad_database("Invalid count in useridcount_notification.")
+                    })?;
+                let notification = serde_json::from_slice(&value)
+                    .map_err(|_| Error::bad_database("Invalid notification in db."))?;
+
+                Ok((count, notification))
+            })
+    }
+
+    /// Removes all but the newest entries of the notification log of every user. Returns how many
+    /// entries were removed.
+    #[tracing::instrument(skip(self))]
+    pub fn trim_notification_logs(&self) -> Result<usize> {
+        let outdated = outdated_log_entries(
+            self.useridcount_notification.iter().map(|(key, _)| key),
+            MAX_LOGGED_NOTIFICATIONS,
+        );
+
+        for key in &outdated {
+            self.useridcount_notification.remove(key)?;
+        }
+
+        Ok(outdated.len())
+    }
+
+    /// Trims the notification logs regularly, they would grow forever otherwise.
+    pub fn start_notification_log_handler(&self, db: Arc<TokioRwLock<Database>>) {
+        tokio::spawn(async move {
+            let mut i = interval(NOTIFICATION_LOG_TRIM_INTERVAL);
+
+            loop {
+                i.tick().await;
+
+                // New entries are always the newest of their user, so this can run next to them
+                let guard = db.read().await;
+                match guard.rooms.trim_notification_logs().and_then(|removed| {
+                    guard.flush()?;
+                    Ok(removed)
+                }) {
+                    Ok(0) => {}
+                    Ok(removed) => info!("Removed {} old notification log entries", removed),
+                    Err(e) => error!("Failed to trim notification logs: {}", e),
+                }
+            }
+        });
+    }
+
     /// Generates a new StateHash and associates it with the incoming event.
     ///
     /// This adds all current state events (not including the incoming event)
@@ -2128,49 +2822,22 @@ impl Rooms {
                 continue;
             }
 
-            if let Some(namespaces) = appservice.1.get("namespaces") {
-                let users = namespaces
-                    .get("users")
-                    .and_then(|users| users.as_sequence())
-                    .map_or_else(Vec::new, |users| {
-                        users
-                            .iter()
-                            .filter_map(|users| Regex::new(users.get("regex")?.as_str()?).ok())
-                            .collect::<Vec<_>>()
-                    });
-                let aliases = namespaces
-                    .get("aliases")
-                    .and_then(|aliases| aliases.as_sequence())
-                    .map_or_else(Vec::new, |aliases| {
-                        aliases
-                            .iter()
-                            .filter_map(|aliases| Regex::new(aliases.get("regex")?.as_str()?).ok())
-                            .collect::<Vec<_>>()
-                    });
-                let rooms = namespaces
-                    .get("rooms")
-                    .and_then(|rooms| rooms.as_sequence());
-
-                let matching_users = |users: &Regex| {
-                    users.is_match(pdu.sender.as_str())
-                        || pdu.kind == EventType::RoomMember
-                            && pdu
-                                .state_key
-                                .as_ref()
-                                .map_or(false, |state_key| users.is_match(state_key))
-                };
-                let matching_aliases = |aliases: &Regex| {
-                    self.room_aliases(room_id)
-                        .filter_map(|r| r.ok())
-                        .any(|room_alias| aliases.is_match(room_alias.as_str()))
-                };
+            let namespaces = &appservice.1.namespaces;
+            let matching_users = |user_id: &str| {
+                UserId::try_from(user_id)
+                    .map_or(false, |user_id| namespaces.is_user_match(&user_id))
+            };
 
-                if aliases.iter().any(matching_aliases)
-                    || rooms.map_or(false, |rooms| rooms.contains(&room_id.as_str().into()))
-                    || users.iter().any(matching_users)
-                {
-                    db.sending.send_pdu_appservice(&appservice.0, &pdu_id)?;
-                }
+            if self
+                .room_aliases(room_id)
+                .filter_map(|r| r.ok())
+                .any(|room_alias| namespaces.is_alias_match(&room_alias))
+                || namespaces.is_room_match(room_id.as_str())
+                || matching_users(pdu.sender.as_str())
+                || pdu.kind == EventType::RoomMember
+                    && pdu.state_key.as_deref().map_or(false, matching_users)
+            {
+                db.sending.send_pdu_appservice(&appservice.0, &pdu_id)?;
             }
         }
 
@@ -2296,6 +2963,206 @@ impl Rooms {
             }))
     }
 
+    /// Returns false if the event the relation points to is known to be in another room than
+    /// `room_id`. Such relations are ignored.
+    #[tracing::instrument(skip(self))]
+    pub fn is_relation_in_room(&self, relation: &Relation, room_id: &RoomId) -> Result<bool> {
+        Ok(self
+            .get_pdu(&relation.event_id)?
+            .map_or(true, |target| &target.room_id == room_id))
+    }
+
+    /// Indexes the relation of the pdu in `room_id` with the given count. Events in threads also
+    /// move their thread to the top of the thread list of the room.
+    #[tracing::instrument(skip(self, pdu_id))]
+    pub fn add_relation(
+        &self,
+        relation: &Relation,
+        room_id: &RoomId,
+        shortroomid: u64,
+        count: u64,
+        pdu_id: &[u8],
+        sender: &UserId,
+    ) -> Result<()> {
+        if !self.is_relation_in_room(relation, room_id)? {
+            return Ok(());
+        }
+
+        let mut relation_id = relation.event_id.as_bytes().to_vec();
+        relation_id.push(0xff);
+        relation_id.extend_from_slice(&count.to_be_bytes());
+        self.relationid_pduid.insert(&relation_id, pdu_id)?;
+
+        if relation.rel_type != "m.thread" {
+            return Ok(());
+        }
+
+        // Threads are only listed once their root is known
+        let root = match self.get_pdu(&relation.event_id)? {
+            Some(root) if &root.room_id == room_id => root,
+            _ => return Ok(()),
+        };
+        let thread_root = relation.event_id.as_bytes();
+
+        if let Some(old_roomcount) = self.threadroot_roomcount.get(thread_root)? {
+            self.roomcount_threadroot.remove(&old_roomcount)?;
+        } else {
+            // The sender of the root takes part in the thread too
+            let mut threadrootuser_id = thread_root.to_vec();
+            threadrootuser_id.push(0xff);
+            threadrootuser_id.extend_from_slice(root.sender.as_bytes());
+            self.threadrootuserids.insert(&threadrootuser_id, &[])?;
+        }
+
+        let mut roomcount = shortroomid.to_be_bytes().to_vec();
+        roomcount.extend_from_slice(&count.to_be_bytes());
+        self.roomcount_threadroot.insert(&roomcount, thread_root)?;
+        self.threadroot_roomcount.insert(thread_root, &roomcount)?;
+
+        let mut threadrootuser_id = thread_root.to_vec();
+        threadrootuser_id.push(0xff);
+        threadrootuser_id.extend_from_slice(sender.as_bytes());
+        self.threadrootuserids.insert(&threadrootuser_id, &[])?;
+
+        Ok(())
+    }
+
+    /// Indexes the threads of all relations that are already stored.
+    #[tracing::instrument(skip(self))]
+    pub fn index_threads(&self) -> Result<()> {
+        for (relation_id, pdu_id) in self.relationid_pduid.iter().collect::<Vec<_>>() {
+            let pdu = match self.get_pdu_from_id(&pdu_id)? {
+                Some(pdu) => pdu,
+                None => continue,
+            };
+
+            if let Some(relation) = Relation::from_pdu(&pdu) {
+                let shortroomid = utils::u64_from_bytes(&pdu_id[..size_of::<u64>()])
+                    .map_err(|_| Error::bad_database("Invalid pdu id in db."))?;
+                let count =
+                    utils::u64_from_bytes(&relation_id[relation_id.len() - size_of::<u64>()..])
+                        .map_err(|_| Error::bad_database("Invalid relation id in db."))?;
+                self.add_relation(
+                    &relation,
+                    &pdu.room_id,
+                    shortroomid,
+                    count,
+                    &pdu_id,
+                    &pdu.sender,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns an iterator over the thread roots of a room and the count of the latest event in
+    /// each thread, starting with the most recently active thread before `until`.
+    #[tracing::instrument(skip(self))]
+    pub fn threads_until<'a>(
+        &'a self,
+        room_id: &RoomId,
+        until: u64,
+    ) -> Result<impl Iterator<Item = Result<(u64, EventId)>> + 'a> {
+        let prefix = self
+            .get_shortroomid(room_id)?
+            .expect("room exists")
+            .to_be_bytes()
+            .to_vec();
+
+        let mut current = prefix.clone();
+        current.extend_from_slice(&until.saturating_sub(1).to_be_bytes());
+
+        Ok(self
+            .roomcount_threadroot
+            .iter_from(&current, true)
+            .take_while(move |(k, _)| k.starts_with(&prefix))
+            .map(|(roomcount, thread_root)| {
+                let count = utils::u64_from_bytes(&roomcount[size_of::<u64>()..])
+                    .map_err(|_| Error::bad_database("Invalid count in roomcount_threadroot."))?;
+                let thread_root =
+                    EventId::try_from(utils::string_from_bytes(&thread_root).map_err(|_| {
+                        Error::bad_database("Invalid thread root bytes in roomcount_threadroot.")
+                    })?)
+                    .map_err(|_| {
+                        Error::bad_database("Invalid thread root in roomcount_threadroot.")
+                    })?;
+                Ok((count, thread_root))
+            }))
+    }
+
+    /// Returns true if the user sent the thread root or an event in the thread.
+    #[tracing::instrument(skip(self))]
+    pub fn participated_in_thread(&self, user_id: &UserId, thread_root: &EventId) -> Result<bool> {
+        let mut threadrootuser_id = thread_root.as_bytes().to_vec();
+        threadrootuser_id.push(0xff);
+        threadrootuser_id.extend_from_slice(user_id.as_bytes());
+
+        Ok(self.threadrootuserids.get(&threadrootuser_id)?.is_some())
+    }
+
+    /// Returns an iterator over the events in `room_id` that relate to the event with id `target`
+    /// and their counts. It starts after the relation with count `from`, or at the oldest (newest
+    /// if `backwards` is set) relation if `from` is `None`.
+    #[tracing::instrument(skip(self))]
+    pub fn relations<'a>(
+        &'a self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        target: &EventId,
+        from: Option<u64>,
+        backwards: bool,
+    ) -> impl Iterator<Item = Result<(u64, PduEvent)>> + 'a {
+        let mut prefix = target.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let mut current = prefix.clone();
+        match (from, backwards) {
+            (Some(from), true) => current.extend_from_slice(&from.saturating_sub(1).to_be_bytes()),
+            (Some(from), false) => current.extend_from_slice(&(from + 1).to_be_bytes()),
+            (None, true) => current.extend_from_slice(&u64::MAX.to_be_bytes()),
+            (None, false) => {}
+        }
+
+        let user_id = user_id.clone();
+        let room_id = room_id.clone();
+
+        self.relationid_pduid
+            .iter_from(&current, backwards)
+            .take_while(move |(k, _)| k.starts_with(&prefix))
+            .map(move |(relation_id, pdu_id)| {
+                let count =
+                    utils::u64_from_bytes(&relation_id[relation_id.len() - size_of::<u64>()..])
+                        .map_err(|_| Error::bad_database("Invalid count in relationid_pduid."))?;
+                let mut pdu = self
+                    .get_pdu_from_id(&pdu_id)?
+                    .ok_or_else(|| Error::bad_database("Relation points to invalid PDU."))?;
+                if pdu.sender != user_id {
+                    pdu.remove_transaction_id()?;
+                }
+                Ok((count, pdu))
+            })
+            // Events can't relate to events in other rooms
+            .filter(move |r| r.as_ref().map_or(true, |(_, pdu)| pdu.room_id == room_id))
+    }
+
+    /// Adds the aggregations of the newest events that relate to this pdu to its unsigned field,
+    /// as seen by `user_id`. See [`bundle_relations`].
+    #[tracing::instrument(skip(self, pdu))]
+    pub fn add_bundled_aggregations(&self, user_id: &UserId, pdu: &mut PduEvent) -> Result<()> {
+        let related = self
+            .relations(user_id, &pdu.room_id, &pdu.event_id, None, true)
+            .map(|r| r.map(|(_, related)| related))
+            .collect::<Result<Vec<_>>>()?;
+
+        let relations = bundle_relations(user_id, pdu, related);
+        if !relations.is_empty() {
+            pdu.add_unsigned_field("m.relations", relations.into())?;
+        }
+
+        Ok(())
+    }
+
     /// Replace a PDU with the redacted form.
     #[tracing::instrument(skip(self, reason))]
     pub fn redact_pdu(&self, event_id: &EventId, reason: &PduEvent) -> Result<()> {
@@ -2607,7 +3474,7 @@ impl Rooms {
     pub fn appservice_in_room(
         &self,
         room_id: &RoomId,
-        appservice: &(String, serde_yaml::Value),
+        appservice: &(String, RegistrationInfo),
         db: &Database,
     ) -> Result<bool> {
         let maybe = self
@@ -2620,30 +3487,14 @@ impl Rooms {
 
         if let Some(b) = maybe {
             Ok(b)
-        } else if let Some(namespaces) = appservice.1.get("namespaces") {
-            let users = namespaces
-                .get("users")
-                .and_then(|users| users.as_sequence())
-                .map_or_else(Vec::new, |users| {
-                    users
-                        .iter()
-                        .filter_map(|users| Regex::new(users.get("regex")?.as_str()?).ok())
-                        .collect::<Vec<_>>()
-                });
-
-            let bridge_user_id = appservice
-                .1
-                .get("sender_localpart")
-                .and_then(|string| string.as_str())
-                .and_then(|string| {
-                    UserId::parse_with_server_name(string, db.globals.server_name()).ok()
-                });
+        } else {
+            let bridge_user_id = appservice.1.sender_user(db.globals.server_name());
 
             let in_room = bridge_user_id
                 .map_or(false, |id| self.is_joined(&id, room_id).unwrap_or(false))
                 || self.room_members(room_id).any(|userid| {
                     userid.map_or(false, |userid| {
-                        users.iter().any(|r| r.is_match(userid.as_str()))
+                        appservice.1.namespaces.is_user_match(&userid)
                     })
                 });
 
@@ -2655,8 +3506,6 @@ impl Rooms {
                 .insert(appservice.0.clone(), in_room);
 
             Ok(in_room)
-        } else {
-            Ok(false)
         }
     }
 
@@ -3134,6 +3983,57 @@ impl Rooms {
             .transpose()
     }
 
+    /// Returns the parts of the push rule evaluation context that are the same for all users in
+    /// the room. They are cached until the room state or the number of members changes.
+    #[tracing::instrument(skip(self))]
+    pub fn push_context(&self, room_id: &RoomId) -> Result<Arc<RoomPushContext>> {
+        let shortstatehash = self.current_shortstatehash(room_id)?;
+        let joined_count = self.room_joined_count(room_id)?.unwrap_or(0);
+
+        if let Some(shortstatehash) = shortstatehash {
+            if let Some(push_context) = self
+                .pushcontext_cache
+                .lock()
+                .unwrap()
+                .get_mut(&(shortstatehash, joined_count))
+            {
+                return Ok(Arc::clone(push_context));
+            }
+        }
+
+        let power_levels = match self.room_state_get(room_id, &EventType::RoomPowerLevels, "")? {
+            Some(ev) => serde_json::from_str(ev.content.get())
+                .map_err(|_| Error::bad_database("invalid m.room.power_levels event"))?,
+            None => {
+                // Without a power levels event, the creator of the room has power level 100
+                let mut power_levels = RoomPowerLevelsEventContent::default();
+
+                if let Some(create) = self.room_state_get(room_id, &EventType::RoomCreate, "")? {
+                    let content =
+                        serde_json::from_str::<RoomCreateEventContent>(create.content.get())
+                            .map_err(|_| Error::bad_database("Invalid create event in db."))?;
+                    power_levels.users.insert(content.creator, 100.into());
+                }
+
+                power_levels
+            }
+        };
+
+        let push_context = Arc::new(RoomPushContext {
+            member_count: UInt::try_from(joined_count).unwrap_or(UInt::MAX),
+            power_levels,
+        });
+
+        if let Some(shortstatehash) = shortstatehash {
+            self.pushcontext_cache
+                .lock()
+                .unwrap()
+                .insert((shortstatehash, joined_count), Arc::clone(&push_context));
+        }
+
+        Ok(push_context)
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn room_invited_count(&self, room_id: &RoomId) -> Result<Option<u64>> {
         self.roomid_invitedcount
@@ -3449,3 +4349,722 @@ impl Rooms {
         Ok(())
     }
 }
+
+/// Whether the event belongs to the thread a receipt names: `main` is the main timeline, other
+/// thread ids are thread roots. Thread roots belong to their thread and the main timeline.
+pub fn is_in_thread(pdu: &PduEvent, thread_id: &str) -> bool {
+    let thread_root = Relation::from_pdu(pdu)
+        .filter(|relation| relation.rel_type == "m.thread")
+        .map(|relation| relation.event_id);
+
+    match (thread_id, thread_root) {
+        ("main", thread_root) => thread_root.is_none(),
+        (thread_id, Some(thread_root)) => thread_root.as_str() == thread_id,
+        (thread_id, None) => pdu.event_id.as_str() == thread_id,
+    }
+}
+
+/// Reads a notification or highlight count, which is 0 if it was never set.
+fn stored_count(tree: &Arc<dyn Tree>, key: &[u8]) -> Result<u64> {
+    tree.get(key)?
+        .map(|bytes| {
+            utils::u64_from_bytes(&bytes)
+                .map_err(|_| Error::bad_database("Invalid notification count in db."))
+        })
+        .unwrap_or(Ok(0))
+}
+
+/// Builds `user_id 0xff room_id 0xff thread_root`, the key of the notification counts of a
+/// thread.
+fn userroomthread_id(user_id: &UserId, room_id: &RoomId, thread_root: &EventId) -> Vec<u8> {
+    let mut key = user_id.as_bytes().to_vec();
+    key.push(0xff);
+    key.extend_from_slice(room_id.as_bytes());
+    key.push(0xff);
+    key.extend_from_slice(thread_root.as_bytes());
+    key
+}
+
+/// Builds the aggregations of the events that relate to `pdu`, newest first, as seen by
+/// `user_id`:
+/// - The number of reactions per key, for the most frequent keys
+/// - The latest edit by the original sender
+/// - The number of events in the thread, the latest one and whether the user took part
+fn bundle_relations(
+    user_id: &UserId,
+    pdu: &PduEvent,
+    related: impl IntoIterator<Item = PduEvent>,
+) -> serde_json::Map<String, serde_json::Value> {
+    let mut annotations = BTreeMap::<(EventType, String), u64>::new();
+    let mut latest_edit = None;
+    let mut latest_thread_event = None;
+    let mut thread_count = 0_u64;
+    let mut participated = &pdu.sender == user_id;
+
+    for related in related {
+        if related.room_id != pdu.room_id {
+            continue;
+        }
+
+        // Redacted events lose their relation
+        let relation = match Relation::from_pdu(&related) {
+            Some(relation) if relation.event_id == pdu.event_id => relation,
+            _ => continue,
+        };
+
+        match &*relation.rel_type {
+            "m.annotation" => {
+                if let Some(key) = relation.key {
+                    *annotations.entry((related.kind.clone(), key)).or_default() += 1;
+                }
+            }
+            "m.replace" => {
+                if latest_edit.is_none() && related.sender == pdu.sender && related.kind == pdu.kind
+                {
+                    latest_edit = Some(related);
+                }
+            }
+            "m.thread" => {
+                thread_count += 1;
+                participated |= &related.sender == user_id;
+                if latest_thread_event.is_none() {
+                    latest_thread_event = Some(related);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut relations = serde_json::Map::new();
+
+    if !annotations.is_empty() {
+        let mut chunk: Vec<_> = annotations.into_iter().collect();
+        chunk.sort_by(|(_, a), (_, b)| b.cmp(a));
+        chunk.truncate(MAX_BUNDLED_ANNOTATIONS);
+
+        relations.insert(
+            "m.annotation".to_owned(),
+            json!({
+                "chunk": chunk
+                    .into_iter()
+                    .map(|((kind, key), count)| {
+                        json!({ "type": kind, "key": key, "count": count })
+                    })
+                    .collect::<Vec<_>>()
+            }),
+        );
+    }
+
+    if let Some(edit) = latest_edit {
+        relations.insert(
+            "m.replace".to_owned(),
+            json!({
+                "event_id": edit.event_id,
+                "origin_server_ts": edit.origin_server_ts,
+                "sender": edit.sender,
+            }),
+        );
+    }
+
+    if let Some(latest_event) = latest_thread_event {
+        relations.insert(
+            "m.thread".to_owned(),
+            json!({
+                "latest_event": latest_event.to_room_event(),
+                "count": thread_count,
+                "current_user_participated": participated,
+            }),
+        );
+    }
+
+    relations
+}
+
+/// Returns the keys of the notification log entries that are older than the newest `keep`
+/// entries of the same user. The keys have to be sorted like in the tree.
+fn outdated_log_entries(keys: impl Iterator<Item = Vec<u8>>, keep: usize) -> Vec<Vec<u8>> {
+    fn user_prefix(key: &[u8]) -> Option<&[u8]> {
+        key.len()
+            .checked_sub(size_of::<u64>())
+            .map(|len| &key[..len])
+    }
+
+    let mut outdated = Vec::new();
+    let mut user_entries: Vec<Vec<u8>> = Vec::new();
+
+    for key in keys {
+        let same_user = user_entries
+            .first()
+            .map_or(false, |first| user_prefix(first) == user_prefix(&key));
+
+        if !same_user {
+            let excess = user_entries.len().saturating_sub(keep);
+            outdated.extend(user_entries.drain(..excess));
+            user_entries.clear();
+        }
+
+        user_entries.push(key);
+    }
+
+    let excess = user_entries.len().saturating_sub(keep);
+    outdated.extend(user_entries.drain(..excess));
+
+    outdated
+}
+
+#[cfg(test)]
+impl Rooms {
+    /// Rooms that only live in memory, for tests.
+    pub fn in_memory() -> Self {
+        use super::abstraction::memory::MemoryTree;
+
+        fn tree() -> Arc<dyn Tree> {
+            Arc::new(MemoryTree::default())
+        }
+
+        Self {
+            edus: RoomEdus {
+                readreceiptid_readreceipt: tree(),
+                roomuserid_privateread: tree(),
+                roomuserid_lastprivatereadupdate: tree(),
+                roomuserthreadid_privatereceipt: tree(),
+                typingid_userid: tree(),
+                roomid_lasttypingupdate: tree(),
+                presenceid_presence: tree(),
+                userid_presenceid: tree(),
+                roompresenceids: tree(),
+                userid_lastpresenceupdate: tree(),
+            },
+            pduid_pdu: tree(),
+            eventid_pduid: tree(),
+            roomid_pduleaves: tree(),
+
+            alias_roomid: tree(),
+            aliasid_alias: tree(),
+            publicroomids: tree(),
+
+            tokenids: tree(),
+
+            roomserverids: tree(),
+            serverroomids: tree(),
+            userroomid_joined: tree(),
+            roomuserid_joined: tree(),
+            roomid_joinedcount: tree(),
+            roomid_invitedcount: tree(),
+            roomuseroncejoinedids: tree(),
+            userroomid_invitestate: tree(),
+            roomuserid_invitecount: tree(),
+            userroomid_leftstate: tree(),
+            roomuserid_leftcount: tree(),
+
+            userroomid_notificationcount: tree(),
+            userroomid_highlightcount: tree(),
+            userroomthreadid_notificationcount: tree(),
+            userroomthreadid_highlightcount: tree(),
+            useridcount_notification: tree(),
+
+            statekey_shortstatekey: tree(),
+            shortstatekey_statekey: tree(),
+
+            shorteventid_authchain: tree(),
+
+            roomid_shortroomid: tree(),
+
+            shortstatehash_statediff: tree(),
+            eventid_shorteventid: tree(),
+            shorteventid_eventid: tree(),
+            shorteventid_shortstatehash: tree(),
+            roomid_shortstatehash: tree(),
+            roomsynctoken_shortstatehash: tree(),
+            statehash_shortstatehash: tree(),
+
+            eventid_outlierpdu: tree(),
+            softfailedeventids: tree(),
+
+            referencedevents: tree(),
+            relationid_pduid: tree(),
+            roomcount_threadroot: tree(),
+            threadroot_roomcount: tree(),
+            threadrootuserids: tree(),
+            pdu_cache: Mutex::new(LruCache::new(100)),
+            auth_chain_cache: Mutex::new(LruCache::new(100)),
+            shorteventid_cache: Mutex::new(LruCache::new(100)),
+            eventidshort_cache: Mutex::new(LruCache::new(100)),
+            shortstatekey_cache: Mutex::new(LruCache::new(100)),
+            statekeyshort_cache: Mutex::new(LruCache::new(100)),
+            our_real_users_cache: RwLock::new(HashMap::new()),
+            appservice_in_room_cache: RwLock::new(HashMap::new()),
+            pushcontext_cache: Mutex::new(LruCache::new(1000)),
+            stateinfo_cache: Mutex::new(LruCache::new(1000)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        bundle_relations, is_in_thread, outdated_log_entries, userroomthread_id, Relation, Rooms,
+        MAX_BUNDLED_ANNOTATIONS,
+    };
+    use crate::PduEvent;
+    use ruma::{EventId, RoomId, UserId};
+    use serde_json::json;
+    use std::convert::TryFrom;
+
+    fn key(user: &str, count: u64) -> Vec<u8> {
+        let mut key = user.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(&count.to_be_bytes());
+        key
+    }
+
+    #[test]
+    fn only_the_newest_entries_of_every_user_are_kept() {
+        let keys = vec![
+            key("@alice:example.org", 1),
+            key("@alice:example.org", 5),
+            key("@alice:example.org", 9),
+            key("@bob:example.org", 2),
+            key("@bob:example.org", 3),
+            key("@carol:example.org", 4),
+        ];
+
+        assert_eq!(
+            outdated_log_entries(keys.into_iter(), 2),
+            vec![key("@alice:example.org", 1)]
+        );
+    }
+
+    #[test]
+    fn short_logs_are_kept() {
+        let keys = vec![key("@alice:example.org", 1), key("@bob:example.org", 2)];
+
+        assert!(outdated_log_entries(keys.into_iter(), 1).is_empty());
+    }
+
+    fn pdu(
+        event_id: &str,
+        room_id: &str,
+        sender: &str,
+        kind: &str,
+        content: serde_json::Value,
+    ) -> PduEvent {
+        serde_json::from_value(json!({
+            "event_id": event_id,
+            "room_id": room_id,
+            "sender": sender,
+            "origin_server_ts": 1,
+            "type": kind,
+            "content": content,
+            "prev_events": [],
+            "depth": 1,
+            "auth_events": [],
+            "hashes": { "sha256": "" },
+        }))
+        .unwrap()
+    }
+
+    fn relation(
+        event_id: &str,
+        room_id: &str,
+        sender: &str,
+        kind: &str,
+        relates_to: serde_json::Value,
+    ) -> PduEvent {
+        pdu(
+            event_id,
+            room_id,
+            sender,
+            kind,
+            json!({ "body": "", "m.relates_to": relates_to }),
+        )
+    }
+
+    const ROOM: &str = "!room:example.org";
+    const ALICE: &str = "@alice:example.org";
+    const BOB: &str = "@bob:example.org";
+
+    #[test]
+    fn relations_are_aggregated() {
+        let root = pdu(
+            "$root",
+            ROOM,
+            ALICE,
+            "m.room.message",
+            json!({ "body": "" }),
+        );
+        let reaction = |event_id, sender, key| {
+            relation(
+                event_id,
+                ROOM,
+                sender,
+                "m.reaction",
+                json!({ "rel_type": "m.annotation", "event_id": "$root", "key": key }),
+            )
+        };
+        let edit = |event_id, sender| {
+            relation(
+                event_id,
+                ROOM,
+                sender,
+                "m.room.message",
+                json!({ "rel_type": "m.replace", "event_id": "$root" }),
+            )
+        };
+        let thread_event = |event_id, sender| {
+            relation(
+                event_id,
+                ROOM,
+                sender,
+                "m.room.message",
+                json!({ "rel_type": "m.thread", "event_id": "$root" }),
+            )
+        };
+
+        // Newest first
+        let related = vec![
+            thread_event("$thread2", BOB),
+            edit("$edit3", BOB),
+            edit("$edit2", ALICE),
+            reaction("$reaction3", BOB, "👍"),
+            thread_event("$thread1", BOB),
+            edit("$edit1", ALICE),
+            reaction("$reaction2", ALICE, "👍"),
+            reaction("$reaction1", BOB, "🎉"),
+        ];
+
+        let bob = UserId::try_from(BOB).unwrap();
+        let relations = serde_json::Value::from(bundle_relations(&bob, &root, related));
+
+        assert_eq!(
+            relations["m.annotation"]["chunk"],
+            json!([
+                { "type": "m.reaction", "key": "👍", "count": 2 },
+                { "type": "m.reaction", "key": "🎉", "count": 1 },
+            ])
+        );
+        // Only edits by the sender count
+        assert_eq!(relations["m.replace"]["event_id"], "$edit2");
+        assert_eq!(relations["m.thread"]["count"], 2);
+        assert_eq!(
+            relations["m.thread"]["latest_event"]["event_id"],
+            "$thread2"
+        );
+        assert_eq!(relations["m.thread"]["current_user_participated"], true);
+    }
+
+    #[test]
+    fn only_the_most_frequent_annotations_are_listed() {
+        let root = pdu(
+            "$root",
+            ROOM,
+            ALICE,
+            "m.room.message",
+            json!({ "body": "" }),
+        );
+        let mut related = (0..=MAX_BUNDLED_ANNOTATIONS)
+            .map(|i| {
+                relation(
+                    &format!("$reaction{}", i),
+                    ROOM,
+                    BOB,
+                    "m.reaction",
+                    json!({ "rel_type": "m.annotation", "event_id": "$root", "key": i.to_string() }),
+                )
+            })
+            .collect::<Vec<_>>();
+        related.push(relation(
+            "$frequent",
+            ROOM,
+            ALICE,
+            "m.reaction",
+            json!({ "rel_type": "m.annotation", "event_id": "$root", "key": "0" }),
+        ));
+
+        let alice = UserId::try_from(ALICE).unwrap();
+        let relations = serde_json::Value::from(bundle_relations(&alice, &root, related));
+        let chunk = relations["m.annotation"]["chunk"].as_array().unwrap();
+
+        assert_eq!(chunk.len(), MAX_BUNDLED_ANNOTATIONS);
+        assert_eq!(
+            chunk[0],
+            json!({ "type": "m.reaction", "key": "0", "count": 2 })
+        );
+    }
+
+    #[test]
+    fn relations_from_other_rooms_are_ignored() {
+        let root = pdu(
+            "$root",
+            ROOM,
+            ALICE,
+            "m.room.message",
+            json!({ "body": "" }),
+        );
+        let related = vec![
+            relation(
+                "$reaction",
+                "!other:example.org",
+                BOB,
+                "m.reaction",
+                json!({ "rel_type": "m.annotation", "event_id": "$root", "key": "👍" }),
+            ),
+            relation(
+                "$edit",
+                "!other:example.org",
+                ALICE,
+                "m.room.message",
+                json!({ "rel_type": "m.replace", "event_id": "$root" }),
+            ),
+        ];
+
+        let alice = UserId::try_from(ALICE).unwrap();
+        assert!(bundle_relations(&alice, &root, related).is_empty());
+    }
+
+    #[test]
+    fn events_without_relation_are_ignored() {
+        let root = pdu(
+            "$root",
+            ROOM,
+            ALICE,
+            "m.room.message",
+            json!({ "body": "" }),
+        );
+        // Redacted events lose their content
+        let redacted = pdu("$redacted", ROOM, BOB, "m.reaction", json!({}));
+
+        let alice = UserId::try_from(ALICE).unwrap();
+        assert!(bundle_relations(&alice, &root, vec![redacted]).is_empty());
+    }
+
+    /// Stores the pdu like `append_pdu` and the relation index of migration 12 do.
+    fn store(rooms: &Rooms, shortroomid: u64, count: u64, pdu: &PduEvent) {
+        let mut pdu_id = shortroomid.to_be_bytes().to_vec();
+        pdu_id.extend_from_slice(&count.to_be_bytes());
+
+        rooms
+            .pduid_pdu
+            .insert(&pdu_id, &serde_json::to_vec(pdu).unwrap())
+            .unwrap();
+        rooms
+            .eventid_pduid
+            .insert(pdu.event_id.as_bytes(), &pdu_id)
+            .unwrap();
+
+        if let Some(relation) = Relation::from_pdu(pdu) {
+            let mut relation_id = relation.event_id.as_bytes().to_vec();
+            relation_id.push(0xff);
+            relation_id.extend_from_slice(&count.to_be_bytes());
+            rooms
+                .relationid_pduid
+                .insert(&relation_id, &pdu_id)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn threads_of_stored_relations_are_indexed() {
+        const OTHER_ROOM: &str = "!other:example.org";
+        const CAROL: &str = "@carol:example.org";
+
+        let rooms = Rooms::in_memory();
+        let room_id = RoomId::try_from(ROOM).unwrap();
+        let other_room_id = RoomId::try_from(OTHER_ROOM).unwrap();
+        rooms
+            .roomid_shortroomid
+            .insert(room_id.as_bytes(), &1_u64.to_be_bytes())
+            .unwrap();
+        rooms
+            .roomid_shortroomid
+            .insert(other_room_id.as_bytes(), &2_u64.to_be_bytes())
+            .unwrap();
+
+        let in_thread = |root| json!({ "rel_type": "m.thread", "event_id": root });
+        store(
+            &rooms,
+            1,
+            1,
+            &pdu(
+                "$root",
+                ROOM,
+                ALICE,
+                "m.room.message",
+                json!({ "body": "" }),
+            ),
+        );
+        store(
+            &rooms,
+            1,
+            2,
+            &relation("$t1", ROOM, BOB, "m.room.message", in_thread("$root")),
+        );
+        store(
+            &rooms,
+            1,
+            3,
+            &relation("$t2", ROOM, BOB, "m.room.message", in_thread("$root")),
+        );
+        // Events can't start threads in other rooms
+        store(
+            &rooms,
+            2,
+            4,
+            &relation(
+                "$t3",
+                OTHER_ROOM,
+                CAROL,
+                "m.room.message",
+                in_thread("$root"),
+            ),
+        );
+        // Threads with unknown roots are not listed
+        store(
+            &rooms,
+            1,
+            5,
+            &relation("$t4", ROOM, CAROL, "m.room.message", in_thread("$missing")),
+        );
+
+        rooms.index_threads().unwrap();
+
+        let threads = rooms
+            .threads_until(&room_id, u64::MAX)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(threads, vec![(3, EventId::try_from("$root").unwrap())]);
+        assert_eq!(
+            rooms
+                .threads_until(&other_room_id, u64::MAX)
+                .unwrap()
+                .count(),
+            0
+        );
+
+        let root = EventId::try_from("$root").unwrap();
+        let participated = |user: &str| {
+            rooms
+                .participated_in_thread(&UserId::try_from(user).unwrap(), &root)
+                .unwrap()
+        };
+        assert!(participated(ALICE));
+        assert!(participated(BOB));
+        assert!(!participated(CAROL));
+    }
+
+    #[test]
+    fn thread_notification_counts_are_kept_per_thread() {
+        let rooms = Rooms::in_memory();
+        let alice = UserId::try_from(ALICE).unwrap();
+        let room_id = RoomId::try_from(ROOM).unwrap();
+        let other_room_id = RoomId::try_from("!other:example.org").unwrap();
+        let thread1 = EventId::try_from("$thread1").unwrap();
+        let thread2 = EventId::try_from("$thread2").unwrap();
+
+        // Like append_pdu, events in threads count towards the room and the thread
+        let notify = |room_id: &RoomId, thread_root: Option<&EventId>, highlight: bool| {
+            let mut userroom_id = alice.as_bytes().to_vec();
+            userroom_id.push(0xff);
+            userroom_id.extend_from_slice(room_id.as_bytes());
+
+            let mut keys = vec![(
+                &rooms.userroomid_notificationcount,
+                &rooms.userroomid_highlightcount,
+                userroom_id,
+            )];
+            if let Some(thread_root) = thread_root {
+                keys.push((
+                    &rooms.userroomthreadid_notificationcount,
+                    &rooms.userroomthreadid_highlightcount,
+                    userroomthread_id(&alice, room_id, thread_root),
+                ));
+            }
+
+            for (notifications, highlights, key) in keys {
+                notifications.increment(&key).unwrap();
+                if highlight {
+                    highlights.increment(&key).unwrap();
+                }
+            }
+        };
+        let room_counts = || {
+            (
+                rooms.notification_count(&alice, &room_id).unwrap(),
+                rooms.highlight_count(&alice, &room_id).unwrap(),
+            )
+        };
+        notify(&room_id, None, false);
+        notify(&room_id, Some(&thread1), false);
+        notify(&room_id, Some(&thread1), true);
+        notify(&room_id, Some(&thread2), false);
+        notify(&other_room_id, Some(&thread1), false);
+
+        let counts = rooms.thread_notification_counts(&alice, &room_id).unwrap();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[&thread1], (2, 1));
+        assert_eq!(counts[&thread2], (1, 0));
+        assert_eq!(room_counts(), (4, 1));
+
+        // Reading a thread only resets that thread
+        rooms
+            .reset_thread_notification_counts(&alice, &room_id, Some(&thread1))
+            .unwrap();
+        let counts = rooms.thread_notification_counts(&alice, &room_id).unwrap();
+        assert_eq!(counts.keys().collect::<Vec<_>>(), vec![&thread2]);
+        assert_eq!(room_counts(), (2, 0));
+
+        // Reading the main timeline leaves the unread threads
+        rooms
+            .reset_thread_notification_counts(&alice, &room_id, None)
+            .unwrap();
+        assert_eq!(room_counts(), (1, 0));
+
+        // Reading the room resets all of its threads
+        rooms.reset_notification_counts(&alice, &room_id).unwrap();
+        assert!(rooms
+            .thread_notification_counts(&alice, &room_id)
+            .unwrap()
+            .is_empty());
+        assert_eq!(room_counts(), (0, 0));
+        assert_eq!(
+            rooms
+                .thread_notification_counts(&alice, &other_room_id)
+                .unwrap()[&thread1],
+            (1, 0)
+        );
+    }
+
+    #[test]
+    fn receipts_match_the_thread_of_the_event() {
+        let root = pdu(
+            "$root",
+            ROOM,
+            ALICE,
+            "m.room.message",
+            json!({ "body": "" }),
+        );
+        let in_thread = relation(
+            "$reply",
+            ROOM,
+            BOB,
+            "m.room.message",
+            json!({ "rel_type": "m.thread", "event_id": "$root" }),
+        );
+        let reaction = relation(
+            "$reaction",
+            ROOM,
+            BOB,
+            "m.reaction",
+            json!({ "rel_type": "m.annotation", "event_id": "$root", "key": "👍" }),
+        );
+
+        assert!(is_in_thread(&root, "main"));
+        assert!(is_in_thread(&root, "$root"));
+        assert!(is_in_thread(&reaction, "main"));
+        assert!(!is_in_thread(&reaction, "$root"));
+        assert!(is_in_thread(&in_thread, "$root"));
+        assert!(!is_in_thread(&in_thread, "main"));
+        assert!(!is_in_thread(&in_thread, "$other"));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{database::abstraction::Tree, utils, Error, Result};
+use crate::{database::abstraction::Tree, utils, Database, Error, Result};
 use ruma::{
     events::{
         presence::{PresenceEvent, PresenceEventContent},
@@ -9,30 +9,47 @@ use ruma::{
     signatures::CanonicalJsonObject,
     RoomId, UInt, UserId,
 };
+use serde::Serialize;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::HashSet,
     convert::{TryFrom, TryInto},
     mem,
     sync::Arc,
+    time::Duration,
 };
+use tokio::{sync::RwLock as TokioRwLock, time::interval};
+use tracing::error;
+
+/// Users that have been inactive for this long become unavailable.
+const PRESENCE_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Users that have been inactive for this long go offline.
+const PRESENCE_OFFLINE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// How often users are checked for inactivity.
+const PRESENCE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
 
 pub struct RoomEdus {
     pub(in super::super) readreceiptid_readreceipt: Arc<dyn Tree>, // ReadReceiptId = RoomId + Count + UserId
     pub(in super::super) roomuserid_privateread: Arc<dyn Tree>, // RoomUserId = Room + User, PrivateRead = Count
     pub(in super::super) roomuserid_lastprivatereadupdate: Arc<dyn Tree>, // LastPrivateReadUpdate = Count
+    pub(in super::super) roomuserthreadid_privatereceipt: Arc<dyn Tree>, // PrivateReceipt = Count + ReceiptEvent
     pub(in super::super) typingid_userid: Arc<dyn Tree>, // TypingId = RoomId + TimeoutTime + Count
     pub(in super::super) roomid_lasttypingupdate: Arc<dyn Tree>, // LastRoomTypingUpdate = Count
-    pub(in super::super) presenceid_presence: Arc<dyn Tree>, // PresenceId = RoomId + Count + UserId
-    pub(in super::super) userid_lastpresenceupdate: Arc<dyn Tree>, // LastPresenceUpdate = Count
+    pub(in super::super) presenceid_presence: Arc<dyn Tree>, // PresenceId = Count + UserId
+    pub(in super::super) userid_presenceid: Arc<dyn Tree>, // PresenceId = Count
+    pub(in super::super) roompresenceids: Arc<dyn Tree>, // RoomPresenceId = RoomId + Count + UserId
+    pub(in super::super) userid_lastpresenceupdate: Arc<dyn Tree>, // LastPresenceUpdate = Timestamp of last activity
 }
 
 impl RoomEdus {
     /// Adds an event which will be saved until a new event replaces it (e.g. read receipt).
-    pub fn readreceipt_update(
+    ///
+    /// Receipts in threads only replace the receipt of the user in the same thread.
+    pub fn readreceipt_update<T: Serialize>(
         &self,
         user_id: &UserId,
         room_id: &RoomId,
-        event: AnyEphemeralRoomEvent,
+        thread_id: Option<&str>,
+        event: &T,
         globals: &super::super::globals::Globals,
     ) -> Result<()> {
         let mut prefix = room_id.as_bytes().to_vec();
@@ -46,11 +63,12 @@ impl RoomEdus {
             .readreceiptid_readreceipt
             .iter_from(&last_possible_key, true)
             .take_while(|(key, _)| key.starts_with(&prefix))
-            .find(|(key, _)| {
+            .find(|(key, value)| {
                 key.rsplit(|&b| b == 0xff)
                     .next()
                     .expect("rsplit always returns an element")
                     == user_id.as_bytes()
+                    && receipt_thread_id(value).as_deref() == thread_id
             })
         {
             // This is the old room_latest
@@ -64,12 +82,71 @@ impl RoomEdus {
 
         self.readreceiptid_readreceipt.insert(
             &room_latest_id,
-            &serde_json::to_vec(&event).expect("EduEvent::to_string always works"),
+            &serde_json::to_vec(event).expect("EduEvent::to_string always works"),
         )?;
 
         Ok(())
     }
 
+    /// Replaces the private read receipt of the user in the room (or the thread). Private
+    /// receipts are only sent to the user themselves.
+    pub fn private_receipt_update<T: Serialize>(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        thread_id: Option<&str>,
+        event: &T,
+        globals: &super::super::globals::Globals,
+    ) -> Result<()> {
+        let mut key = room_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(user_id.as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(thread_id.unwrap_or_default().as_bytes());
+
+        let mut value = globals.next_count()?.to_be_bytes().to_vec();
+        value.extend_from_slice(
+            &serde_json::to_vec(event).expect("EduEvent::to_string always works"),
+        );
+
+        self.roomuserthreadid_privatereceipt.insert(&key, &value)
+    }
+
+    /// Returns the private read receipts of the user in this room that changed after `since`.
+    #[tracing::instrument(skip(self))]
+    pub fn private_receipts_since(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        since: u64,
+    ) -> Result<Vec<Raw<ruma::events::AnySyncEphemeralRoomEvent>>> {
+        let mut prefix = room_id.as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(user_id.as_bytes());
+        prefix.push(0xff);
+
+        let mut receipts = Vec::new();
+
+        for (_, value) in self.roomuserthreadid_privatereceipt.scan_prefix(prefix) {
+            let count = utils::u64_from_bytes(&value[..mem::size_of::<u64>()])
+                .map_err(|_| Error::bad_database("Invalid count in private receipt."))?;
+            if count <= since {
+                continue;
+            }
+
+            let mut json =
+                serde_json::from_slice::<CanonicalJsonObject>(&value[mem::size_of::<u64>()..])
+                    .map_err(|_| Error::bad_database("Private read receipt is invalid json."))?;
+            json.remove("room_id");
+
+            receipts.push(Raw::from_json(
+                serde_json::value::to_raw_value(&json).expect("json is valid raw value"),
+            ));
+        }
+
+        Ok(receipts)
+    }
+
     /// Returns an iterator over the most recent read_receipts in a room that happened after the event with id `since`.
     #[tracing::instrument(skip(self))]
     pub fn readreceipts_since<'a>(
@@ -137,6 +214,23 @@ impl RoomEdus {
         Ok(())
     }
 
+    /// Makes the next sync send new notification counts without moving the private read marker,
+    /// e.g. after a thread was read.
+    #[tracing::instrument(skip(self, globals))]
+    pub fn private_read_touch(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        globals: &super::super::globals::Globals,
+    ) -> Result<()> {
+        let mut key = room_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(user_id.as_bytes());
+
+        self.roomuserid_lastprivatereadupdate
+            .insert(&key, &globals.next_count()?.to_be_bytes())
+    }
+
     /// Returns the private read marker.
     #[tracing::instrument(skip(self))]
     pub fn private_read_get(&self, room_id: &RoomId, user_id: &UserId) -> Result<Option<u64>> {
@@ -325,52 +419,159 @@ impl RoomEdus {
         })
     }
 
-    /// Adds a presence event which will be saved until a new event replaces it.
-    ///
-    /// Note: This method takes a RoomId because presence updates are always bound to rooms to
-    /// make sure users outside these rooms can't see them.
+    /// Replaces the presence of a user and indexes it in `room_ids`, the rooms where the update
+    /// has to be sent. The `last_active_ago` field of the event has to contain the timestamp of
+    /// the last activity of the user in millis since the unix epoch.
     pub fn update_presence(
         &self,
         user_id: &UserId,
-        room_id: &RoomId,
         presence: PresenceEvent,
+        room_ids: &[RoomId],
         globals: &super::super::globals::Globals,
     ) -> Result<()> {
-        // TODO: Remove old entry? Or maybe just wipe completely from time to time?
-
-        let count = globals.next_count()?.to_be_bytes();
+        let count = globals.next_count()?;
+
+        // Remove old entries. Entries in rooms the user left since are skipped when reading.
+        if let Some(old_count) = self.userid_presenceid.get(user_id.as_bytes())? {
+            let mut old_presence_id = old_count.to_vec();
+            old_presence_id.push(0xff);
+            old_presence_id.extend_from_slice(user_id.as_bytes());
+            self.presenceid_presence.remove(&old_presence_id)?;
+
+            for room_id in room_ids {
+                self.roompresenceids
+                    .remove(&room_presence_id(room_id, &old_presence_id))?;
+            }
+        }
 
-        let mut presence_id = room_id.as_bytes().to_vec();
+        let mut presence_id = count.to_be_bytes().to_vec();
         presence_id.push(0xff);
-        presence_id.extend_from_slice(&count);
-        presence_id.push(0xff);
-        presence_id.extend_from_slice(presence.sender.as_bytes());
+        presence_id.extend_from_slice(user_id.as_bytes());
+
+        for room_id in room_ids {
+            self.roompresenceids
+                .insert(&room_presence_id(room_id, &presence_id), &[])?;
+        }
+
+        let last_active = presence
+            .content
+            .last_active_ago
+            .map_or_else(utils::millis_since_unix_epoch, u64::from);
 
         self.presenceid_presence.insert(
             &presence_id,
             &serde_json::to_vec(&presence).expect("PresenceEvent can be serialized"),
         )?;
+        self.userid_presenceid
+            .insert(user_id.as_bytes(), &count.to_be_bytes())?;
+        self.userid_lastpresenceupdate
+            .insert(user_id.as_bytes(), &last_active.to_be_bytes())?;
+
+        Ok(())
+    }
+
+    /// Sets the presence of a local or remote user, keeping the profile information up to date,
+    /// and sends it to interested appservices.
+    ///
+    /// `last_active` is the timestamp of the last activity of the user in millis since the unix
+    /// epoch. Does nothing if presence is disabled.
+    #[tracing::instrument(skip(db))]
+    pub fn set_presence(
+        db: &Database,
+        user_id: &UserId,
+        presence: PresenceState,
+        status_msg: Option<String>,
+        last_active: u64,
+    ) -> Result<()> {
+        if !db.globals.allow_presence() {
+            return Ok(());
+        }
+
+        // Only rooms with local users are interested in the presence
+        let room_ids = db
+            .rooms
+            .rooms_joined(user_id)
+            .filter_map(|r| r.ok())
+            .filter(|room_id| {
+                db.rooms
+                    .server_in_room(db.globals.server_name(), room_id)
+                    .unwrap_or(false)
+            })
+            .collect::<Vec<_>>();
+
+        // Nobody here can see the presence of remote users we share no room with
+        let is_local = user_id.server_name() == db.globals.server_name();
+        if !is_local && room_ids.is_empty() {
+            return Ok(());
+        }
 
-        self.userid_lastpresenceupdate.insert(
-            user_id.as_bytes(),
-            &utils::millis_since_unix_epoch().to_be_bytes(),
+        let currently_active = presence == PresenceState::Online
+            && utils::millis_since_unix_epoch().saturating_sub(last_active)
+                < PRESENCE_IDLE_TIMEOUT.as_millis() as u64;
+
+        let (displayname, avatar_url) = if is_local {
+            (
+                db.users.displayname(user_id)?,
+                db.users.avatar_url(user_id)?,
+            )
+        } else {
+            (None, None)
+        };
+
+        let event = PresenceEvent {
+            content: PresenceEventContent {
+                avatar_url,
+                currently_active: Some(currently_active),
+                displayname,
+                last_active_ago: Some(last_active.try_into().expect("time is valid")),
+                presence,
+                status_msg,
+            },
+            sender: user_id.clone(),
+        };
+
+        db.rooms
+            .edus
+            .update_presence(user_id, event.clone(), &room_ids, &db.globals)?;
+
+        db.sending.send_edu_appservices(
+            &room_ids,
+            &db.rooms.edus.presence_event_for_client(event),
+            db,
         )?;
 
         Ok(())
     }
 
-    /// Resets the presence timeout, so the user will stay in their current presence state.
-    #[tracing::instrument(skip(self))]
-    pub fn ping_presence(&self, user_id: &UserId) -> Result<()> {
-        self.userid_lastpresenceupdate.insert(
-            user_id.as_bytes(),
-            &utils::millis_since_unix_epoch().to_be_bytes(),
-        )?;
+    /// Marks a local user as active, e.g. because they are syncing. If their presence differs
+    /// from `presence`, it is changed.
+    ///
+    /// Clients that sync with `set_presence=offline` don't change the presence of their user.
+    #[tracing::instrument(skip(db))]
+    pub fn ping_presence(db: &Database, user_id: &UserId, presence: &PresenceState) -> Result<()> {
+        if !db.globals.allow_presence() || *presence == PresenceState::Offline {
+            return Ok(());
+        }
+
+        let now = utils::millis_since_unix_epoch();
+
+        match db.rooms.edus.get_presence_event(user_id)? {
+            Some(current) if current.content.presence == *presence => {
+                db.rooms
+                    .edus
+                    .userid_lastpresenceupdate
+                    .insert(user_id.as_bytes(), &now.to_be_bytes())?;
+            }
+            current => {
+                let status_msg = current.and_then(|current| current.content.status_msg);
+                Self::set_presence(db, user_id, presence.clone(), status_msg, now)?;
+            }
+        }
 
         Ok(())
     }
 
-    /// Returns the timestamp of the last presence update of this user in millis since the unix epoch.
+    /// Returns the timestamp of the last activity of this user in millis since the unix epoch.
     pub fn last_presence_update(&self, user_id: &UserId) -> Result<Option<u64>> {
         self.userid_lastpresenceupdate
             .get(user_id.as_bytes())?
@@ -382,168 +583,211 @@ impl RoomEdus {
             .transpose()
     }
 
-    pub fn get_last_presence_event(
-        &self,
-        user_id: &UserId,
-        room_id: &RoomId,
-    ) -> Result<Option<PresenceEvent>> {
-        let last_update = match self.last_presence_update(user_id)? {
-            Some(last) => last,
+    /// Returns the current presence of the user as it should be sent to clients.
+    pub fn get_presence_event(&self, user_id: &UserId) -> Result<Option<PresenceEvent>> {
+        let count = match self.userid_presenceid.get(user_id.as_bytes())? {
+            Some(count) => count,
             None => return Ok(None),
         };
 
-        let mut presence_id = room_id.as_bytes().to_vec();
-        presence_id.push(0xff);
-        presence_id.extend_from_slice(&last_update.to_be_bytes());
+        let mut presence_id = count.to_vec();
         presence_id.push(0xff);
         presence_id.extend_from_slice(user_id.as_bytes());
 
         self.presenceid_presence
             .get(&presence_id)?
             .map(|value| {
-                let mut presence: PresenceEvent = serde_json::from_slice(&value)
+                let presence = serde_json::from_slice(&value)
                     .map_err(|_| Error::bad_database("Invalid presence event in db."))?;
-                let current_timestamp: UInt = utils::millis_since_unix_epoch()
-                    .try_into()
-                    .expect("time is valid");
-
-                if presence.content.presence == PresenceState::Online {
-                    // Don't set last_active_ago when the user is online
-                    presence.content.last_active_ago = None;
-                } else {
-                    // Convert from timestamp to duration
-                    presence.content.last_active_ago = presence
-                        .content
-                        .last_active_ago
-                        .map(|timestamp| current_timestamp - timestamp);
-                }
-
-                Ok(presence)
+                Ok(self.presence_event_for_client(presence))
             })
             .transpose()
     }
 
-    /// Sets all users to offline who have been quiet for too long.
-    fn _presence_maintain(
-        &self,
-        rooms: &super::Rooms,
-        globals: &super::super::globals::Globals,
-    ) -> Result<()> {
+    /// Converts the stored timestamp of the last activity into a duration.
+    fn presence_event_for_client(&self, mut presence: PresenceEvent) -> PresenceEvent {
+        let current_timestamp: UInt = utils::millis_since_unix_epoch()
+            .try_into()
+            .expect("time is valid");
+
+        let last_active = self
+            .last_presence_update(&presence.sender)
+            .ok()
+            .flatten()
+            .and_then(|timestamp| UInt::try_from(timestamp).ok())
+            .or(presence.content.last_active_ago);
+
+        if presence.content.currently_active == Some(true) {
+            // Don't set last_active_ago when the user is currently active
+            presence.content.last_active_ago = None;
+        } else {
+            presence.content.last_active_ago =
+                last_active.map(|timestamp| current_timestamp.saturating_sub(timestamp));
+        }
+
+        presence
+    }
+
+    /// Moves users that have been inactive for too long to unavailable and then to offline.
+    ///
+    /// Remote users are only set offline, their servers take care of the idle state.
+    #[tracing::instrument(skip(db))]
+    pub fn presence_maintain(db: &Database) -> Result<()> {
         let current_timestamp = utils::millis_since_unix_epoch();
 
-        for (user_id_bytes, last_timestamp) in self
-            .userid_lastpresenceupdate
-            .iter()
-            .filter_map(|(k, bytes)| {
-                Some((
-                    k,
-                    utils::u64_from_bytes(&bytes)
-                        .map_err(|_| {
-                            Error::bad_database("Invalid timestamp in userid_lastpresenceupdate.")
-                        })
-                        .ok()?,
-                ))
-            })
-            .take_while(|(_, timestamp)| current_timestamp.saturating_sub(*timestamp) > 5 * 60_000)
-        // 5 Minutes
-        {
-            // Send new presence events to set the user offline
-            let count = globals.next_count()?.to_be_bytes();
-            let user_id = utils::string_from_bytes(&user_id_bytes)
-                .map_err(|_| {
+        let mut updates = Vec::new();
+
+        for (user_id_bytes, last_timestamp) in db.rooms.edus.userid_lastpresenceupdate.iter() {
+            let user_id =
+                UserId::try_from(utils::string_from_bytes(&user_id_bytes).map_err(|_| {
                     Error::bad_database("Invalid UserId bytes in userid_lastpresenceupdate.")
-                })?
-                .try_into()
+                })?)
                 .map_err(|_| Error::bad_database("Invalid UserId in userid_lastpresenceupdate."))?;
-            for room_id in rooms.rooms_joined(&user_id).filter_map(|r| r.ok()) {
-                let mut presence_id = room_id.as_bytes().to_vec();
-                presence_id.push(0xff);
-                presence_id.extend_from_slice(&count);
-                presence_id.push(0xff);
-                presence_id.extend_from_slice(&user_id_bytes);
-
-                self.presenceid_presence.insert(
-                    &presence_id,
-                    &serde_json::to_vec(&PresenceEvent {
-                        content: PresenceEventContent {
-                            avatar_url: None,
-                            currently_active: None,
-                            displayname: None,
-                            last_active_ago: Some(
-                                last_timestamp.try_into().expect("time is valid"),
-                            ),
-                            presence: PresenceState::Offline,
-                            status_msg: None,
-                        },
-                        sender: user_id.clone(),
-                    })
-                    .expect("PresenceEvent can be serialized"),
-                )?;
-            }
+            let last_timestamp = utils::u64_from_bytes(&last_timestamp).map_err(|_| {
+                Error::bad_database("Invalid timestamp in userid_lastpresenceupdate.")
+            })?;
+
+            let current = match db.rooms.edus.get_presence_event(&user_id)? {
+                Some(current) => current,
+                None => continue,
+            };
+
+            let inactive = current_timestamp.saturating_sub(last_timestamp);
+            let is_local = user_id.server_name() == db.globals.server_name();
+
+            let presence = if current.content.presence != PresenceState::Offline
+                && inactive > PRESENCE_OFFLINE_TIMEOUT.as_millis() as u64
+            {
+                PresenceState::Offline
+            } else if is_local
+                && current.content.presence == PresenceState::Online
+                && inactive > PRESENCE_IDLE_TIMEOUT.as_millis() as u64
+            {
+                PresenceState::Unavailable
+            } else {
+                continue;
+            };
+
+            updates.push((
+                user_id,
+                presence,
+                current.content.status_msg,
+                last_timestamp,
+            ));
+        }
 
-            self.userid_lastpresenceupdate.insert(
-                user_id.as_bytes(),
-                &utils::millis_since_unix_epoch().to_be_bytes(),
-            )?;
+        for (user_id, presence, status_msg, last_timestamp) in updates {
+            Self::set_presence(db, &user_id, presence, status_msg, last_timestamp)?;
         }
 
         Ok(())
     }
 
-    /// Returns an iterator over the most recent presence updates that happened after the event with id `since`.
-    #[tracing::instrument(skip(self, since, _rooms, _globals))]
+    /// Returns the presence updates of the members of a room that happened after the event with
+    /// id `since`, together with their count.
+    #[tracing::instrument(skip(self, since))]
     pub fn presence_since(
         &self,
         room_id: &RoomId,
         since: u64,
-        _rooms: &super::Rooms,
-        _globals: &super::super::globals::Globals,
-    ) -> Result<HashMap<UserId, PresenceEvent>> {
-        //self.presence_maintain(rooms, globals)?;
-
+    ) -> Result<Vec<(UserId, u64, PresenceEvent)>> {
         let mut prefix = room_id.as_bytes().to_vec();
         prefix.push(0xff);
 
         let mut first_possible_edu = prefix.clone();
         first_possible_edu.extend_from_slice(&(since + 1).to_be_bytes()); // +1 so we don't send the event at since
-        let mut hashmap = HashMap::new();
 
-        for (key, value) in self
-            .presenceid_presence
-            .iter_from(&*first_possible_edu, false)
+        let mut updates = Vec::new();
+        for (key, _) in self
+            .roompresenceids
+            .iter_from(&first_possible_edu, false)
             .take_while(|(key, _)| key.starts_with(&prefix))
         {
+            let presence_id = &key[prefix.len()..];
+            let count = utils::u64_from_bytes(
+                presence_id
+                    .get(..mem::size_of::<u64>())
+                    .ok_or_else(|| Error::bad_database("Invalid roompresenceid."))?,
+            )
+            .map_err(|_| Error::bad_database("Invalid count in roompresenceids."))?;
             let user_id = UserId::try_from(
                 utils::string_from_bytes(
-                    key.rsplit(|&b| b == 0xff)
-                        .next()
-                        .expect("rsplit always returns an element"),
+                    presence_id
+                        .get(mem::size_of::<u64>() + 1..)
+                        .ok_or_else(|| Error::bad_database("Invalid roompresenceid."))?,
                 )
-                .map_err(|_| Error::bad_database("Invalid UserId bytes in presenceid_presence."))?,
+                .map_err(|_| Error::bad_database("Invalid UserId bytes in roompresenceids."))?,
             )
-            .map_err(|_| Error::bad_database("Invalid UserId in presenceid_presence."))?;
-
-            let mut presence: PresenceEvent = serde_json::from_slice(&value)
-                .map_err(|_| Error::bad_database("Invalid presence event in db."))?;
+            .map_err(|_| Error::bad_database("Invalid UserId in roompresenceids."))?;
+
+            // The entry is outdated if the user left the room before the next update
+            match self.presenceid_presence.get(presence_id)? {
+                Some(presence) => {
+                    let presence = serde_json::from_slice(&presence)
+                        .map_err(|_| Error::bad_database("Invalid presence event in db."))?;
+                    updates.push((user_id, count, self.presence_event_for_client(presence)));
+                }
+                None => self.roompresenceids.remove(&key)?,
+            }
+        }
 
-            let current_timestamp: UInt = utils::millis_since_unix_epoch()
-                .try_into()
-                .expect("time is valid");
+        Ok(updates)
+    }
 
-            if presence.content.presence == PresenceState::Online {
-                // Don't set last_active_ago when the user is online
-                presence.content.last_active_ago = None;
-            } else {
-                // Convert from timestamp to duration
-                presence.content.last_active_ago = presence
-                    .content
-                    .last_active_ago
-                    .map(|timestamp| current_timestamp - timestamp);
+    /// Returns the presence of the user if it changed after the event with id `since`.
+    pub fn presence_of_user_since(
+        &self,
+        user_id: &UserId,
+        since: u64,
+    ) -> Result<Option<PresenceEvent>> {
+        match self.userid_presenceid.get(user_id.as_bytes())? {
+            Some(count)
+                if utils::u64_from_bytes(&count)
+                    .map_err(|_| Error::bad_database("Invalid count in userid_presenceid."))?
+                    > since =>
+            {
+                self.get_presence_event(user_id)
             }
-
-            hashmap.insert(user_id, presence);
+            _ => Ok(None),
         }
+    }
 
-        Ok(hashmap)
+    /// Checks for idle and offline users regularly.
+    pub fn start_presence_handler(&self, db: Arc<TokioRwLock<Database>>) {
+        tokio::spawn(async move {
+            let mut i = interval(PRESENCE_CHECK_INTERVAL);
+
+            loop {
+                i.tick().await;
+
+                let guard = db.read().await;
+                if let Err(e) = Self::presence_maintain(&guard).and_then(|()| guard.flush()) {
+                    error!("Failed to update presence: {}", e);
+                }
+            }
+        });
     }
 }
+
+/// Builds `room_id 0xff presence_id`.
+fn room_presence_id(room_id: &RoomId, presence_id: &[u8]) -> Vec<u8> {
+    let mut key = room_id.as_bytes().to_vec();
+    key.push(0xff);
+    key.extend_from_slice(presence_id);
+    key
+}
+
+/// Returns the thread of a stored read receipt, `None` if the receipt is not bound to a thread.
+pub fn receipt_thread_id(receipt: &[u8]) -> Option<String> {
+    let receipt = serde_json::from_slice::<serde_json::Value>(receipt).ok()?;
+
+    receipt
+        .get("content")?
+        .as_object()?
+        .values()
+        .filter_map(|receipts| receipts.as_object())
+        .flat_map(|receipts| receipts.values())
+        .filter_map(|users| users.as_object())
+        .flat_map(|users| users.values())
+        .find_map(|receipt| receipt.get("thread_id")?.as_str().map(ToOwned::to_owned))
+}
@@ -7,7 +7,9 @@ use std::{
 };
 
 use crate::{
-    appservice_server, database::pusher, server_server, utils, Database, Error, PduEvent, Result,
+    appservice_server,
+    database::{pusher, rooms},
+    server_server, utils, Database, Error, PduEvent, Result,
 };
 use federation::transactions::send_transaction_message;
 use ring::digest;
@@ -21,7 +23,8 @@ use ruma::{
         federation::{
             self,
             transactions::edu::{
-                DeviceListUpdateContent, Edu, ReceiptContent, ReceiptData, ReceiptMap,
+                DeviceListUpdateContent, Edu, PresenceContent, PresenceUpdate, ReceiptContent,
+                ReceiptData, ReceiptMap,
             },
         },
         OutgoingRequest,
@@ -30,15 +33,17 @@ use ruma::{
     events::{push_rules::PushRulesEvent, AnySyncEphemeralRoomEvent, EventType},
     push,
     receipt::ReceiptType,
-    uint, MilliSecondsSinceUnixEpoch, ServerName, UInt, UserId,
+    uint, MilliSecondsSinceUnixEpoch, RoomId, ServerName, UInt, UserId,
 };
+use serde::Serialize;
+use serde_json::value::RawValue as RawJsonValue;
 use tokio::{
     select,
     sync::{RwLock, Semaphore},
 };
 use tracing::{error, warn};
 
-use super::abstraction::Tree;
+use super::{abstraction::Tree, appservice::RegistrationInfo};
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum OutgoingKind {
@@ -90,6 +95,10 @@ pub struct Sending {
     pub sender: mpsc::UnboundedSender<(Vec<u8>, Vec<u8>)>,
 }
 
+/// Push notifications are only useful for a short time, so they are retried more often but given
+/// up on after a while.
+const PUSH_MAX_RETRIES: u32 = 10;
+
 enum TransactionStatus {
     Running,
     Failed(u32, Instant), // number of times failed, time of last failure
@@ -236,6 +245,12 @@ impl Sending {
     ) -> Result<Option<Vec<SendingEventType>>> {
         let mut retry = false;
         let mut allow = true;
+        let mut failed_tries = 0;
+
+        let (backoff_base, backoff_max) = match outgoing_kind {
+            OutgoingKind::Push(_, _) => (Duration::from_secs(5), Duration::from_secs(60 * 60)),
+            _ => (Duration::from_secs(30), Duration::from_secs(60 * 60 * 24)),
+        };
 
         let prefix = outgoing_kind.get_prefix();
         let entry = current_transaction_status.entry(prefix.clone());
@@ -247,15 +262,16 @@ impl Sending {
                 }
                 TransactionStatus::Failed(tries, time) => {
                     // Fail if a request has failed recently (exponential backoff)
-                    let mut min_elapsed_duration = Duration::from_secs(30) * (*tries) * (*tries);
-                    if min_elapsed_duration > Duration::from_secs(60 * 60 * 24) {
-                        min_elapsed_duration = Duration::from_secs(60 * 60 * 24);
+                    let mut min_elapsed_duration = backoff_base * (*tries) * (*tries);
+                    if min_elapsed_duration > backoff_max {
+                        min_elapsed_duration = backoff_max;
                     }
 
                     if time.elapsed() < min_elapsed_duration {
                         allow = false;
                     } else {
                         retry = true;
+                        failed_tries = *tries;
                         *e = TransactionStatus::Retrying(*tries);
                     }
                 }
@@ -266,6 +282,28 @@ impl Sending {
             return Ok(None);
         }
 
+        if retry
+            && matches!(outgoing_kind, OutgoingKind::Push(_, _))
+            && failed_tries >= PUSH_MAX_RETRIES
+        {
+            warn!(
+                "Dropping push notifications for {:?} after {} failed attempts",
+                outgoing_kind, failed_tries
+            );
+
+            for (key, _) in db
+                .sending
+                .servercurrentevent_data
+                .scan_prefix(prefix.clone())
+            {
+                db.sending.servercurrentevent_data.remove(&key)?;
+            }
+
+            // Continue with the new events
+            retry = false;
+            current_transaction_status.insert(prefix.clone(), TransactionStatus::Running);
+        }
+
         let mut events = Vec::new();
 
         if retry {
@@ -347,6 +385,9 @@ impl Sending {
                 let event: AnySyncEphemeralRoomEvent =
                     serde_json::from_str(read_receipt.json().get())
                         .map_err(|_| Error::bad_database("Invalid edu event in read_receipts."))?;
+                // Ruma doesn't know about threaded read receipts yet
+                let thread_id = rooms::receipt_thread_id(read_receipt.json().get().as_bytes());
+
                 let federation_event = match event {
                     AnySyncEphemeralRoomEvent::Receipt(r) => {
                         let mut read = BTreeMap::new();
@@ -364,7 +405,7 @@ impl Sending {
                             .expect("our read receipts always have the user here");
 
                         read.insert(
-                            user_id,
+                            user_id.clone(),
                             ReceiptData {
                                 data: receipt.clone(),
                                 event_ids: vec![event_id.clone()],
@@ -384,6 +425,15 @@ impl Sending {
                     }
                 };
 
+                let mut federation_event =
+                    serde_json::to_value(&federation_event).expect("json can be serialized");
+                if let (Some(thread_id), Some(data)) = (
+                    thread_id,
+                    receipt_data_mut(&mut federation_event, &room_id, &user_id),
+                ) {
+                    data.insert("thread_id".to_owned(), thread_id.into());
+                }
+
                 events.push(serde_json::to_vec(&federation_event).expect("json can be serialized"));
 
                 if events.len() >= 20 {
@@ -392,6 +442,39 @@ impl Sending {
             }
         }
 
+        // Look for presence updates of our users, unless the transaction is already full
+        if db.globals.allow_presence() && events.len() < 20 {
+            let mut push = Vec::new();
+            let mut pushed_users = HashSet::new();
+
+            for room_id in db.rooms.server_rooms(server) {
+                for (user_id, count, presence) in db.rooms.edus.presence_since(&room_id?, since)? {
+                    if count > max_edu_count {
+                        max_edu_count = count;
+                    }
+
+                    if user_id.server_name() != db.globals.server_name()
+                        || !pushed_users.insert(user_id.clone())
+                    {
+                        continue;
+                    }
+
+                    push.push(PresenceUpdate {
+                        user_id,
+                        presence: presence.content.presence,
+                        status_msg: presence.content.status_msg,
+                        last_active_ago: presence.content.last_active_ago.unwrap_or_default(),
+                        currently_active: presence.content.currently_active.unwrap_or(false),
+                    });
+                }
+            }
+
+            if !push.is_empty() {
+                let edu = Edu::Presence(PresenceContent { push });
+                events.push(serde_json::to_vec(&edu).expect("json can be serialized"));
+            }
+        }
+
         for user_id in device_list_changes {
             // Empty prev id forces synapse to resync: https://github.com/matrix-org/synapse/blob/98aec1cc9da2bd6b8e34ffb282c85abf9b8b42ca/synapse/handlers/device.py#L767
             // Because synapse resyncs, we can just insert dummy data
@@ -472,6 +555,69 @@ impl Sending {
         Ok(())
     }
 
+    /// Queues an ephemeral event for all appservices that receive ephemeral events (MSC2409) and
+    /// are interested in one of the rooms.
+    #[tracing::instrument(skip(self, room_ids, event, db))]
+    pub fn send_edu_appservices<T: Serialize>(
+        &self,
+        room_ids: &[RoomId],
+        event: &T,
+        db: &Database,
+    ) -> Result<()> {
+        let mut serialized = None;
+
+        for appservice in db.appservice.all()? {
+            if !appservice.1.receive_ephemeral {
+                continue;
+            }
+
+            let mut interested = false;
+            for room_id in room_ids {
+                if appservice.1.namespaces.is_room_match(room_id.as_str())
+                    || db.rooms.appservice_in_room(room_id, &appservice, db)?
+                {
+                    interested = true;
+                    break;
+                }
+            }
+
+            if !interested {
+                continue;
+            }
+
+            let serialized = serialized.get_or_insert_with(|| {
+                serde_json::to_vec(event).expect("ephemeral events can be serialized")
+            });
+
+            let mut key = b"+".to_vec();
+            key.extend_from_slice(appservice.0.as_bytes());
+            key.push(0xff);
+            key.extend_from_slice(&db.globals.next_count()?.to_be_bytes());
+            self.servernameevent_data.insert(&key, serialized)?;
+            self.sender
+                .unbounded_send((key, serialized.clone()))
+                .unwrap();
+        }
+
+        Ok(())
+    }
+
+    /// Queues the current typing users of the room for the interested appservices.
+    #[tracing::instrument(skip(self, db))]
+    pub fn send_typing_appservices(&self, room_id: &RoomId, db: &Database) -> Result<()> {
+        let typing = db.rooms.edus.typings_all(room_id)?;
+
+        self.send_edu_appservices(
+            &[room_id.clone()],
+            &serde_json::json!({
+                "type": "m.typing",
+                "room_id": room_id,
+                "content": typing.content,
+            }),
+            db,
+        )
+    }
+
     #[tracing::instrument(skip(keys))]
     fn calculate_hash(keys: &[&[u8]]) -> Vec<u8> {
         // We only hash the pdu's event ids, not the whole pdu
@@ -491,6 +637,7 @@ impl Sending {
         match &kind {
             OutgoingKind::Appservice(server) => {
                 let mut pdu_jsons = Vec::new();
+                let mut ephemeral = Vec::new();
 
                 for event in &events {
                     match event {
@@ -508,37 +655,61 @@ impl Sending {
                                 })?
                                 .to_room_event())
                         }
-                        SendingEventType::Edu(_) => {
-                            // Appservices don't need EDUs (?)
+                        SendingEventType::Edu(edu) => {
+                            match serde_json::from_slice::<Box<RawJsonValue>>(edu) {
+                                Ok(edu) => ephemeral.push(edu),
+                                Err(_) => warn!("Invalid ephemeral event queued for appservice"),
+                            }
                         }
                     }
                 }
 
                 let permit = db.sending.maximum_requests.acquire().await;
 
-                let response = appservice_server::send_request(
-                    &db.globals,
-                    db.appservice
-                        .get_registration(server.as_str())
-                        .unwrap()
-                        .unwrap(), // TODO: handle error
-                    appservice::event::push_events::v1::Request {
-                        events: &pdu_jsons,
-                        txn_id: &base64::encode_config(
-                            Self::calculate_hash(
-                                &events
-                                    .iter()
-                                    .map(|e| match e {
-                                        SendingEventType::Edu(b) | SendingEventType::Pdu(b) => &**b,
-                                    })
-                                    .collect::<Vec<_>>(),
-                            ),
-                            base64::URL_SAFE_NO_PAD,
-                        ),
-                    },
-                )
-                .await
-                .map(|_response| kind.clone())
+                let registration = db
+                    .appservice
+                    .get_registration(server.as_str())
+                    .map_err(|e| (kind.clone(), e))?
+                    .ok_or_else(|| {
+                        (
+                            kind.clone(),
+                            Error::BadServerResponse("[Appservice] Appservice is not registered."),
+                        )
+                    })?;
+                let txn_id = base64::encode_config(
+                    Self::calculate_hash(
+                        &events
+                            .iter()
+                            .map(|e| match e {
+                                SendingEventType::Edu(b) | SendingEventType::Pdu(b) => &**b,
+                            })
+                            .collect::<Vec<_>>(),
+                    ),
+                    base64::URL_SAFE_NO_PAD,
+                );
+
+                let response = if ephemeral.is_empty() {
+                    appservice_server::send_request(
+                        &db.globals,
+                        registration,
+                        appservice::event::push_events::v1::Request {
+                            events: &pdu_jsons,
+                            txn_id: &txn_id,
+                        },
+                    )
+                    .await
+                    .map(|_response| ())
+                } else {
+                    appservice_server::send_ephemeral_transaction(
+                        &db.globals,
+                        registration,
+                        &txn_id,
+                        &pdu_jsons,
+                        &ephemeral,
+                    )
+                    .await
+                }
+                .map(|()| kind.clone())
                 .map_err(|e| (kind, e));
 
                 drop(permit);
@@ -551,7 +722,8 @@ impl Sending {
                 for event in &events {
                     match event {
                         SendingEventType::Pdu(pdu_id) => {
-                            pdus.push(
+                            pdus.push((
+                                pdu_id,
                                 db.rooms
                                     .get_pdu_from_id(pdu_id)
                                     .map_err(|e| (kind.clone(), e))?
@@ -563,7 +735,7 @@ impl Sending {
                                             ),
                                         )
                                     })?,
-                            );
+                            ));
                         }
                         SendingEventType::Edu(_) => {
                             // Push gateways don't need EDUs (?)
@@ -571,13 +743,24 @@ impl Sending {
                     }
                 }
 
-                for pdu in pdus {
+                let prefix = kind.get_prefix();
+
+                for (pdu_id, pdu) in pdus {
+                    // Delivered events are removed from the transaction one by one, so that a
+                    // retry after a failure doesn't push them again
+                    let mut current_key = prefix.clone();
+                    current_key.extend_from_slice(pdu_id);
+
                     // Redacted events are not notification targets (we don't send push for them)
                     if let Some(unsigned) = &pdu.unsigned {
                         if let Ok(unsigned) =
                             serde_json::from_str::<serde_json::Value>(unsigned.get())
                         {
                             if unsigned.get("redacted_because").is_some() {
+                                db.sending
+                                    .servercurrentevent_data
+                                    .remove(&current_key)
+                                    .map_err(|e| (kind.clone(), e))?;
                                 continue;
                             }
                         }
@@ -607,7 +790,13 @@ impl Sending {
                         .map_err(|e| (OutgoingKind::Push(user.clone(), pushkey.clone()), e))?
                     {
                         Some(pusher) => pusher,
-                        None => continue,
+                        None => {
+                            db.sending
+                                .servercurrentevent_data
+                                .remove(&current_key)
+                                .map_err(|e| (kind.clone(), e))?;
+                            continue;
+                        }
                     };
 
                     let rules_for_user = db
@@ -626,7 +815,7 @@ impl Sending {
 
                     let permit = db.sending.maximum_requests.acquire().await;
 
-                    let _response = pusher::send_push_notice(
+                    let response = pusher::send_push_notice(
                         &userid,
                         unread,
                         &pusher,
@@ -634,11 +823,17 @@ impl Sending {
                         &pdu,
                         &db,
                     )
-                    .await
-                    .map(|_response| kind.clone())
-                    .map_err(|e| (kind.clone(), e));
+                    .await;
 
                     drop(permit);
+
+                    // Temporary failures are retried with the push backoff
+                    response.map_err(|e| (kind.clone(), e))?;
+
+                    db.sending
+                        .servercurrentevent_data
+                        .remove(&current_key)
+                        .map_err(|e| (kind.clone(), e))?;
                 }
                 Ok(OutgoingKind::Push(user.clone(), pushkey.clone()))
             }
@@ -804,7 +999,7 @@ impl Sending {
     pub async fn send_appservice_request<T: OutgoingRequest>(
         &self,
         globals: &crate::database::globals::Globals,
-        registration: serde_yaml::Value,
+        registration: RegistrationInfo,
         request: T,
     ) -> Result<T::IncomingResponse>
     where
@@ -817,3 +1012,17 @@ impl Sending {
         response
     }
 }
+
+/// Returns the data of the read receipt of the user in a serialized receipt EDU.
+fn receipt_data_mut<'a>(
+    edu: &'a mut serde_json::Value,
+    room_id: &RoomId,
+    user_id: &UserId,
+) -> Option<&'a mut serde_json::Map<String, serde_json::Value>> {
+    edu.get_mut("content")?
+        .get_mut(room_id.as_str())?
+        .get_mut("m.read")?
+        .get_mut(user_id.as_str())?
+        .get_mut("data")?
+        .as_object_mut()
+}
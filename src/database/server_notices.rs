@@ -0,0 +1,265 @@
+use std::{collections::BTreeMap, convert::TryFrom, sync::Arc};
+
+use crate::{pdu::PduBuilder, utils, Database, Error, Result};
+use rocket::futures::{channel::mpsc, stream::StreamExt};
+use ruma::{
+    events::{
+        room::{
+            create::RoomCreateEventContent,
+            guest_access::{GuestAccess, RoomGuestAccessEventContent},
+            history_visibility::{HistoryVisibility, RoomHistoryVisibilityEventContent},
+            join_rules::{JoinRule, RoomJoinRulesEventContent},
+            member::{MembershipState, RoomMemberEventContent},
+            name::RoomNameEventContent,
+            power_levels::RoomPowerLevelsEventContent,
+        },
+        tag::{TagEvent, TagEventContent},
+        EventType,
+    },
+    identifiers::RoomName,
+    RoomId, RoomVersionId, UserId,
+};
+use serde_json::{json, value::to_raw_value};
+use tokio::sync::RwLock;
+use tracing::warn;
+
+use super::abstraction::Tree;
+
+/// The room tag clients use to show the server notices room in a special place.
+const SERVER_NOTICE_TAG: &str = "m.server_notice";
+
+/// The `server_notice_type` of notices that are not about a specific limit, like announcements.
+pub const GENERIC_NOTICE_TYPE: &str = "m.server_notice.generic";
+
+pub struct ServerNotices {
+    /// UserId -> RoomId
+    pub(super) userid_noticeroomid: Arc<dyn Tree>,
+    pub sender: mpsc::UnboundedSender<(UserId, String, String)>,
+}
+
+impl ServerNotices {
+    pub fn start_handler(
+        &self,
+        db: Arc<RwLock<Database>>,
+        mut receiver: mpsc::UnboundedReceiver<(UserId, String, String)>,
+    ) {
+        tokio::spawn(async move {
+            while let Some((user_id, notice_type, body)) = receiver.next().await {
+                let guard = db.read().await;
+
+                if let Err(e) = send_notice(&user_id, &notice_type, &body, &guard).await {
+                    warn!("Failed to send server notice to {}: {}", user_id, e);
+                }
+            }
+        });
+    }
+
+    /// Queues a notice for a local user. It is sent by `@conduit` into the user's server notices
+    /// room, which is created when needed. `notice_type` is the `server_notice_type` clients use
+    /// to tell notices apart, e.g. `GENERIC_NOTICE_TYPE`.
+    pub fn send(&self, user_id: UserId, notice_type: &str, body: String) {
+        self.sender
+            .unbounded_send((user_id, notice_type.to_owned(), body))
+            .unwrap();
+    }
+
+    /// Returns the server notices room of the user, if there is one.
+    #[tracing::instrument(skip(self))]
+    pub fn notice_room(&self, user_id: &UserId) -> Result<Option<RoomId>> {
+        self.userid_noticeroomid
+            .get(user_id.as_bytes())?
+            .map(|bytes| {
+                RoomId::try_from(utils::string_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("Room ID in userid_noticeroomid is invalid unicode.")
+                })?)
+                .map_err(|_| Error::bad_database("Room ID in userid_noticeroomid is invalid."))
+            })
+            .transpose()
+    }
+}
+
+async fn send_notice(user_id: &UserId, notice_type: &str, body: &str, db: &Database) -> Result<()> {
+    let conduit_user = UserId::try_from(format!("@conduit:{}", db.globals.server_name()))
+        .expect("@conduit:server_name is valid");
+
+    // Users that left their notices room get a new one
+    let room_id = match db.server_notices.notice_room(user_id)? {
+        Some(room_id)
+            if db.rooms.is_joined(user_id, &room_id)?
+                || db.rooms.is_invited(user_id, &room_id)? =>
+        {
+            room_id
+        }
+        _ => {
+            let room_id = create_notice_room(user_id, &conduit_user, db).await?;
+            db.server_notices
+                .userid_noticeroomid
+                .insert(user_id.as_bytes(), room_id.as_bytes())?;
+            room_id
+        }
+    };
+
+    let mutex_state = Arc::clone(
+        db.globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.clone())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    db.rooms.build_and_append_pdu(
+        PduBuilder {
+            event_type: EventType::RoomMessage,
+            content: to_raw_value(&json!({
+                "msgtype": "m.server_notice",
+                "body": body,
+                "server_notice_type": notice_type,
+            }))
+            .expect("event is valid, we just created it"),
+            unsigned: None,
+            state_key: None,
+            redacts: None,
+        },
+        &conduit_user,
+        &room_id,
+        db,
+        &state_lock,
+    )?;
+
+    drop(state_lock);
+
+    db.flush()?;
+
+    Ok(())
+}
+
+/// Creates a room where only `@conduit` can talk, invites the user and tags the room for them.
+async fn create_notice_room(
+    user_id: &UserId,
+    conduit_user: &UserId,
+    db: &Database,
+) -> Result<RoomId> {
+    let room_id = RoomId::new(db.globals.server_name());
+
+    db.rooms.get_or_create_shortroomid(&room_id, &db.globals)?;
+
+    let mutex_state = Arc::clone(
+        db.globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.clone())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    let mut content = RoomCreateEventContent::new(conduit_user.clone());
+    content.federate = false;
+    content.predecessor = None;
+    content.room_version = RoomVersionId::Version6;
+
+    let mut users = BTreeMap::new();
+    users.insert(conduit_user.clone(), 100.into());
+
+    let member_content = |membership| RoomMemberEventContent {
+        membership,
+        displayname: None,
+        avatar_url: None,
+        is_direct: None,
+        third_party_invite: None,
+        blurhash: None,
+        reason: None,
+    };
+
+    let room_name =
+        Box::<RoomName>::try_from("Server Notices".to_owned()).expect("Room name is valid");
+
+    let events = vec![
+        (EventType::RoomCreate, to_raw_value(&content), ""),
+        (
+            EventType::RoomMember,
+            to_raw_value(&member_content(MembershipState::Join)),
+            conduit_user.as_str(),
+        ),
+        // Users can read notices, but not answer
+        (
+            EventType::RoomPowerLevels,
+            to_raw_value(&RoomPowerLevelsEventContent {
+                users,
+                events_default: 100.into(),
+                ..Default::default()
+            }),
+            "",
+        ),
+        (
+            EventType::RoomJoinRules,
+            to_raw_value(&RoomJoinRulesEventContent::new(JoinRule::Invite)),
+            "",
+        ),
+        (
+            EventType::RoomHistoryVisibility,
+            to_raw_value(&RoomHistoryVisibilityEventContent::new(
+                HistoryVisibility::Shared,
+            )),
+            "",
+        ),
+        (
+            EventType::RoomGuestAccess,
+            to_raw_value(&RoomGuestAccessEventContent::new(GuestAccess::Forbidden)),
+            "",
+        ),
+        (
+            EventType::RoomName,
+            to_raw_value(&RoomNameEventContent::new(Some(room_name))),
+            "",
+        ),
+        (
+            EventType::RoomMember,
+            to_raw_value(&member_content(MembershipState::Invite)),
+            user_id.as_str(),
+        ),
+    ];
+
+    for (event_type, content, state_key) in events {
+        db.rooms.build_and_append_pdu(
+            PduBuilder {
+                event_type,
+                content: content.expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some(state_key.to_owned()),
+                redacts: None,
+            },
+            conduit_user,
+            &room_id,
+            db,
+            &state_lock,
+        )?;
+    }
+
+    drop(state_lock);
+
+    let mut tags_event = db
+        .account_data
+        .get(Some(&room_id), user_id, EventType::Tag)?
+        .unwrap_or_else(|| TagEvent {
+            content: TagEventContent {
+                tags: BTreeMap::new(),
+            },
+        });
+    tags_event
+        .content
+        .tags
+        .insert(SERVER_NOTICE_TAG.to_owned().into(), Default::default());
+
+    db.account_data.update(
+        Some(&room_id),
+        user_id,
+        EventType::Tag,
+        &tags_event,
+        &db.globals,
+    )?;
+
+    Ok(room_id)
+}
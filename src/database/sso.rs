@@ -0,0 +1,303 @@
+use std::{
+    convert::TryFrom,
+    mem::size_of,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{utils, Error, Result};
+use ruma::UserId;
+use serde::Deserialize;
+
+use super::abstraction::Tree;
+
+/// How long a user has to finish logging in at the identity provider.
+const OIDC_STATE_LIFETIME_MS: u64 = 10 * 60 * 1000;
+/// How long a client has to redeem the login token it got after the SSO login.
+const LOGIN_TOKEN_LIFETIME_MS: u64 = 2 * 60 * 1000;
+/// How often expired states and login tokens are deleted.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+const OIDC_STATE_LENGTH: usize = 32;
+const LOGIN_TOKEN_LENGTH: usize = 64;
+
+/// ## Example:
+/// ```toml
+/// [global.oidc]
+/// authorization_endpoint = "https://idp.example.com/authorize"
+/// token_endpoint = "https://idp.example.com/token"
+/// userinfo_endpoint = "https://idp.example.com/userinfo"
+/// client_id = "conduit"
+/// client_secret = "secret"
+/// callback_url = "https://matrix.example.com/_conduit/client/oidc/callback"
+/// ```
+///
+/// `callback_url` is the public URL of Conduit's callback endpoint and needs to be allowed as a
+/// redirect URI at the identity provider.
+#[derive(Clone, Debug, Deserialize)]
+pub struct OidcConfig {
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub callback_url: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    /// The claim which is used as the localpart of new users.
+    #[serde(default = "default_localpart_claim")]
+    pub localpart_claim: String,
+    /// The claim which is used as the displayname of new users.
+    #[serde(default = "default_displayname_claim")]
+    pub displayname_claim: String,
+    /// Allows identity provider users to log in as an existing local user with the same localpart.
+    #[serde(default)]
+    pub link_existing_users: bool,
+}
+
+fn default_scopes() -> Vec<String> {
+    vec!["openid".to_owned(), "profile".to_owned()]
+}
+
+fn default_localpart_claim() -> String {
+    "preferred_username".to_owned()
+}
+
+fn default_displayname_claim() -> String {
+    "name".to_owned()
+}
+
+pub struct Sso {
+    pub(super) oidcstate_redirecturl: Arc<dyn Tree>, // RedirectUrl = ExpiresAt + RedirectUrl
+    pub(super) logintoken_userid: Arc<dyn Tree>,     // UserId = ExpiresAt + UserId
+    pub(super) oidcsubject_userid: Arc<dyn Tree>,    // OidcSubject = "sub" claim of the IdP user
+    pub(super) last_cleanup: Mutex<Instant>,
+}
+
+impl Sso {
+    /// Remembers where the client wants to be sent after the login at the identity provider.
+    ///
+    /// Returns the `state` parameter for the authorization request.
+    pub fn create_oidc_state(&self, redirect_url: &str) -> Result<String> {
+        // Anyone can start a login, so unfinished ones have to be cleaned up
+        self.remove_expired_if_due()?;
+
+        let state = utils::random_string(OIDC_STATE_LENGTH);
+
+        let mut value = (utils::millis_since_unix_epoch() + OIDC_STATE_LIFETIME_MS)
+            .to_be_bytes()
+            .to_vec();
+        value.extend_from_slice(redirect_url.as_bytes());
+
+        self.oidcstate_redirecturl
+            .insert(state.as_bytes(), &value)?;
+
+        Ok(state)
+    }
+
+    /// Returns the redirect url of the client if the state is known and not expired. Each state
+    /// can only be used once.
+    pub fn take_oidc_state(&self, state: &str) -> Result<Option<String>> {
+        let value = match self.oidcstate_redirecturl.get(state.as_bytes())? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        self.oidcstate_redirecturl.remove(state.as_bytes())?;
+
+        let (expires_at, redirect_url) = parse_expiring(&value)
+            .ok_or_else(|| Error::bad_database("Invalid entry in oidcstate_redirecturl."))?;
+
+        if expires_at < utils::millis_since_unix_epoch() {
+            return Ok(None);
+        }
+
+        utils::string_from_bytes(redirect_url)
+            .map(Some)
+            .map_err(|_| Error::bad_database("Invalid redirect url in oidcstate_redirecturl."))
+    }
+
+    /// Creates a short-lived token the client can use to log in as the user.
+    pub fn create_login_token(&self, user_id: &UserId) -> Result<String> {
+        let token = utils::random_string(LOGIN_TOKEN_LENGTH);
+
+        let mut value = (utils::millis_since_unix_epoch() + LOGIN_TOKEN_LIFETIME_MS)
+            .to_be_bytes()
+            .to_vec();
+        value.extend_from_slice(user_id.as_bytes());
+
+        self.logintoken_userid.insert(token.as_bytes(), &value)?;
+
+        Ok(token)
+    }
+
+    /// Returns the user of a login token and invalidates the token.
+    pub fn redeem_login_token(&self, token: &str) -> Result<Option<UserId>> {
+        let value = match self.logintoken_userid.get(token.as_bytes())? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        self.logintoken_userid.remove(token.as_bytes())?;
+
+        let (expires_at, user_id) = parse_expiring(&value)
+            .ok_or_else(|| Error::bad_database("Invalid entry in logintoken_userid."))?;
+
+        if expires_at < utils::millis_since_unix_epoch() {
+            return Ok(None);
+        }
+
+        UserId::try_from(
+            utils::string_from_bytes(user_id)
+                .map_err(|_| Error::bad_database("Invalid user id in logintoken_userid."))?,
+        )
+        .map(Some)
+        .map_err(|_| Error::bad_database("Invalid user id in logintoken_userid."))
+    }
+
+    /// Deletes all states and login tokens that expired.
+    ///
+    /// Returns how many were deleted.
+    pub fn remove_expired(&self) -> Result<usize> {
+        let now = utils::millis_since_unix_epoch();
+        let mut removed = 0;
+
+        for tree in &[&self.oidcstate_redirecturl, &self.logintoken_userid] {
+            let expired = tree
+                .iter()
+                .filter(|(_, value)| {
+                    parse_expiring(value).map_or(true, |(expires_at, _)| expires_at < now)
+                })
+                .map(|(key, _)| key)
+                .collect::<Vec<_>>();
+
+            for key in expired {
+                tree.remove(&key)?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    fn remove_expired_if_due(&self) -> Result<()> {
+        {
+            let mut last_cleanup = self.last_cleanup.lock().unwrap();
+            if last_cleanup.elapsed() < CLEANUP_INTERVAL {
+                return Ok(());
+            }
+            *last_cleanup = Instant::now();
+        }
+
+        self.remove_expired().map(|_| ())
+    }
+
+    /// Returns the local user the identity provider subject logged in as before.
+    pub fn user_for_subject(&self, subject: &str) -> Result<Option<UserId>> {
+        self.oidcsubject_userid
+            .get(subject.as_bytes())?
+            .map(|bytes| {
+                UserId::try_from(utils::string_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("User ID in oidcsubject_userid is invalid unicode.")
+                })?)
+                .map_err(|_| Error::bad_database("User ID in oidcsubject_userid is invalid."))
+            })
+            .transpose()
+    }
+
+    pub fn set_user_for_subject(&self, subject: &str, user_id: &UserId) -> Result<()> {
+        self.oidcsubject_userid
+            .insert(subject.as_bytes(), user_id.as_bytes())
+    }
+}
+
+fn parse_expiring(value: &[u8]) -> Option<(u64, &[u8])> {
+    if value.len() < size_of::<u64>() {
+        return None;
+    }
+
+    let (expires_at, rest) = value.split_at(size_of::<u64>());
+    Some((utils::u64_from_bytes(expires_at).ok()?, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sso, CLEANUP_INTERVAL};
+    use crate::{database::abstraction::memory::MemoryTree, utils};
+    use ruma::UserId;
+    use std::{
+        convert::TryFrom,
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+
+    fn sso() -> Sso {
+        Sso {
+            oidcstate_redirecturl: Arc::new(MemoryTree::default()),
+            logintoken_userid: Arc::new(MemoryTree::default()),
+            oidcsubject_userid: Arc::new(MemoryTree::default()),
+            last_cleanup: Mutex::new(Instant::now()),
+        }
+    }
+
+    #[test]
+    fn oidc_state_can_only_be_used_once() {
+        let sso = sso();
+        let state = sso
+            .create_oidc_state("https://client.example.org/")
+            .unwrap();
+
+        assert_eq!(
+            sso.take_oidc_state(&state).unwrap().as_deref(),
+            Some("https://client.example.org/")
+        );
+        assert_eq!(sso.take_oidc_state(&state).unwrap(), None);
+        assert_eq!(sso.take_oidc_state("unknown").unwrap(), None);
+    }
+
+    #[test]
+    fn login_token_can_only_be_redeemed_once() {
+        let sso = sso();
+        let user_id = UserId::try_from("@alice:example.org").unwrap();
+        let token = sso.create_login_token(&user_id).unwrap();
+
+        assert_eq!(sso.redeem_login_token(&token).unwrap(), Some(user_id));
+        assert_eq!(sso.redeem_login_token(&token).unwrap(), None);
+    }
+
+    #[test]
+    fn expired_states_are_rejected_and_removed() {
+        let sso = sso();
+
+        let mut value = (utils::millis_since_unix_epoch() - 1)
+            .to_be_bytes()
+            .to_vec();
+        value.extend_from_slice(b"https://client.example.org/");
+        sso.oidcstate_redirecturl
+            .insert(b"expired", &value)
+            .unwrap();
+        sso.oidcstate_redirecturl.insert(b"invalid", b"").unwrap();
+        let state = sso
+            .create_oidc_state("https://client.example.org/")
+            .unwrap();
+
+        assert_eq!(sso.remove_expired().unwrap(), 2);
+        assert_eq!(sso.take_oidc_state("expired").unwrap(), None);
+        assert!(sso.take_oidc_state(&state).unwrap().is_some());
+    }
+
+    #[test]
+    fn creating_states_cleans_up_when_due() {
+        let sso = sso();
+        let mut value = (utils::millis_since_unix_epoch() - 1)
+            .to_be_bytes()
+            .to_vec();
+        value.extend_from_slice(b"https://client.example.org/");
+        sso.oidcstate_redirecturl
+            .insert(b"expired", &value)
+            .unwrap();
+
+        *sso.last_cleanup.lock().unwrap() = Instant::now() - CLEANUP_INTERVAL;
+        sso.create_oidc_state("https://client.example.org/")
+            .unwrap();
+
+        assert_eq!(sso.oidcstate_redirecturl.get(b"expired").unwrap(), None);
+    }
+}
@@ -0,0 +1,229 @@
+use std::{collections::BTreeMap, convert::TryFrom, sync::Arc};
+
+use crate::{utils, Error, Result};
+use ruma::{MilliSecondsSinceUnixEpoch, UserId};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, value::RawValue as RawJsonValue};
+
+use super::{
+    abstraction::Tree,
+    server_notices::{ServerNotices, GENERIC_NOTICE_TYPE},
+};
+
+/// The UIAA stage in which users accept the policies.
+pub const TERMS_AUTH_TYPE: &str = "m.login.terms";
+
+const CONSENT_TOKEN_LENGTH: usize = 32;
+
+/// Policy documents new users have to accept during registration. When the version of a document
+/// changes, existing users can only read until they accept the new version through a link in
+/// their server notices room.
+///
+/// ## Example:
+/// ```toml
+/// [global.terms]
+/// public_baseurl = "https://matrix.example.com"
+///
+/// [global.terms.policies.privacy_policy]
+/// version = "1.0"
+/// name = "Privacy Policy"
+/// url = "https://example.com/privacy-1.0.html"
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+pub struct TermsConfig {
+    /// The public URL of this server, used for consent links.
+    pub public_baseurl: String,
+    pub policies: BTreeMap<String, PolicyDocument>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PolicyDocument {
+    pub version: String,
+    pub name: String,
+    pub url: String,
+    #[serde(default = "default_lang")]
+    pub lang: String,
+}
+
+fn default_lang() -> String {
+    "en".to_owned()
+}
+
+/// The acceptance of a version of a policy document.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Consent {
+    pub version: String,
+    pub accepted_at: MilliSecondsSinceUnixEpoch,
+}
+
+pub struct Terms {
+    /// UserId + PolicyId -> Consent
+    pub(super) useridpolicyid_consent: Arc<dyn Tree>,
+    /// UserId -> the policy versions the last consent notice asked for
+    pub(super) userid_consentnotice: Arc<dyn Tree>,
+    pub(super) consenttoken_userid: Arc<dyn Tree>,
+    pub(super) userid_consenttoken: Arc<dyn Tree>,
+}
+
+impl Terms {
+    /// Returns the `params` of a UIAA response with a terms stage.
+    pub fn uiaa_params(config: &TermsConfig) -> Box<RawJsonValue> {
+        let policies = config
+            .policies
+            .iter()
+            .map(|(id, document)| {
+                (
+                    id.clone(),
+                    json!({
+                        "version": document.version,
+                        document.lang.clone(): {
+                            "name": document.name,
+                            "url": document.url,
+                        },
+                    }),
+                )
+            })
+            .collect::<serde_json::Map<_, _>>();
+
+        serde_json::value::to_raw_value(&json!({
+            TERMS_AUTH_TYPE: {
+                "policies": policies,
+            },
+        }))
+        .expect("json is valid raw value")
+    }
+
+    /// Records that the user accepted the current version of every policy document.
+    #[tracing::instrument(skip(self, config))]
+    pub fn accept(&self, user_id: &UserId, config: &TermsConfig) -> Result<()> {
+        for (id, document) in &config.policies {
+            let mut key = user_id.as_bytes().to_vec();
+            key.push(0xff);
+            key.extend_from_slice(id.as_bytes());
+
+            self.useridpolicyid_consent.insert(
+                &key,
+                &serde_json::to_vec(&Consent {
+                    version: document.version.clone(),
+                    accepted_at: MilliSecondsSinceUnixEpoch::now(),
+                })
+                .expect("Consent is valid JSON value"),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns which policy documents the user accepted and when.
+    #[tracing::instrument(skip(self))]
+    pub fn consents(&self, user_id: &UserId) -> Result<Vec<(String, Consent)>> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.useridpolicyid_consent
+            .scan_prefix(prefix.clone())
+            .map(|(key, value)| {
+                let id = utils::string_from_bytes(&key[prefix.len()..]).map_err(|_| {
+                    Error::bad_database("Invalid policy id in useridpolicyid_consent.")
+                })?;
+                let consent = serde_json::from_slice(&value)
+                    .map_err(|_| Error::bad_database("Invalid Consent in db."))?;
+
+                Ok((id, consent))
+            })
+            .collect()
+    }
+
+    /// Checks if the user still has to accept the current version of a policy document.
+    #[tracing::instrument(skip(self, config))]
+    pub fn needs_consent(&self, user_id: &UserId, config: &TermsConfig) -> Result<bool> {
+        let consents = self
+            .consents(user_id)?
+            .into_iter()
+            .collect::<BTreeMap<_, _>>();
+
+        Ok(config.policies.iter().any(|(id, document)| {
+            consents
+                .get(id)
+                .map_or(true, |consent| consent.version != document.version)
+        }))
+    }
+
+    /// Sends the user a server notice with a link to accept the current policies, unless they
+    /// already got one for these versions.
+    #[tracing::instrument(skip(self, config, server_notices))]
+    pub fn notify_missing_consent(
+        &self,
+        user_id: &UserId,
+        config: &TermsConfig,
+        server_notices: &ServerNotices,
+    ) -> Result<()> {
+        let versions = config
+            .policies
+            .iter()
+            .map(|(id, document)| format!("{}={}", id, document.version))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        if self
+            .userid_consentnotice
+            .get(user_id.as_bytes())?
+            .as_deref()
+            == Some(versions.as_bytes())
+        {
+            return Ok(());
+        }
+
+        let mut notice = "Our policies have changed. Until you accept them, you can only read \
+            messages:\n"
+            .to_owned();
+        for document in config.policies.values() {
+            notice += &format!(
+                "- {} (version {}): {}\n",
+                document.name, document.version, document.url
+            );
+        }
+        notice += &format!(
+            "\nAccept them at {}/_conduit/client/consent?token={}",
+            config.public_baseurl.trim_end_matches('/'),
+            self.consent_token(user_id)?
+        );
+
+        server_notices.send(user_id.clone(), GENERIC_NOTICE_TYPE, notice);
+        self.userid_consentnotice
+            .insert(user_id.as_bytes(), versions.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Returns the token for the consent link of the user.
+    #[tracing::instrument(skip(self))]
+    pub fn consent_token(&self, user_id: &UserId) -> Result<String> {
+        if let Some(token) = self.userid_consenttoken.get(user_id.as_bytes())? {
+            return utils::string_from_bytes(&token)
+                .map_err(|_| Error::bad_database("Invalid token in userid_consenttoken."));
+        }
+
+        let token = utils::random_string(CONSENT_TOKEN_LENGTH);
+        self.userid_consenttoken
+            .insert(user_id.as_bytes(), token.as_bytes())?;
+        self.consenttoken_userid
+            .insert(token.as_bytes(), user_id.as_bytes())?;
+
+        Ok(token)
+    }
+
+    /// Returns the user the consent link belongs to.
+    #[tracing::instrument(skip(self, token))]
+    pub fn user_from_consent_token(&self, token: &str) -> Result<Option<UserId>> {
+        self.consenttoken_userid
+            .get(token.as_bytes())?
+            .map(|bytes| {
+                UserId::try_from(utils::string_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("User ID in consenttoken_userid is invalid unicode.")
+                })?)
+                .map_err(|_| Error::bad_database("User ID in consenttoken_userid is invalid."))
+            })
+            .transpose()
+    }
+}
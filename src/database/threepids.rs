@@ -0,0 +1,462 @@
+use std::{
+    convert::TryFrom,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{utils, Error, Result};
+use ruma::{MilliSecondsSinceUnixEpoch, UInt, UserId};
+use serde::{Deserialize, Serialize};
+
+use super::abstraction::Tree;
+
+pub const SID_LENGTH: usize = 32;
+const VALIDATION_TOKEN_LENGTH: usize = 32;
+/// How long the link in a validation email can be used.
+const VALIDATION_SESSION_LIFETIME_MS: u64 = 60 * 60 * 1000;
+/// How often expired validation sessions are deleted at most.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Why the user has to prove that they own an address.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidationPurpose {
+    /// The address is added to the account after the validation.
+    AddThreepid,
+    /// The user forgot their password and resets it through an address of their account.
+    PasswordReset,
+}
+
+/// A validation of an address through a link with a token.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ValidationSession {
+    pub purpose: ValidationPurpose,
+    pub client_secret: String,
+    pub medium: String,
+    pub address: String,
+    pub send_attempt: UInt,
+    pub token: String,
+    pub created_at: MilliSecondsSinceUnixEpoch,
+    pub validated_at: Option<MilliSecondsSinceUnixEpoch>,
+}
+
+impl ValidationSession {
+    pub fn is_expired(&self) -> bool {
+        utils::millis_since_unix_epoch().saturating_sub(self.created_at.get().into())
+            > VALIDATION_SESSION_LIFETIME_MS
+    }
+}
+
+/// A third party identifier that belongs to a local user.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Threepid {
+    pub medium: String,
+    pub address: String,
+    pub added_at: MilliSecondsSinceUnixEpoch,
+    pub validated_at: MilliSecondsSinceUnixEpoch,
+}
+
+pub struct Threepids {
+    /// Sid -> ValidationSession
+    pub(super) sid_validationsession: Arc<dyn Tree>,
+    /// ClientSecret + Medium + Address -> Sid
+    pub(super) clientsecretaddress_sid: Arc<dyn Tree>,
+    /// UserId + Medium + Address -> Threepid
+    pub(super) useridaddress_threepid: Arc<dyn Tree>,
+    /// Medium + Address -> UserId
+    pub(super) address_userid: Arc<dyn Tree>,
+    pub(super) last_cleanup: Mutex<Instant>,
+}
+
+impl Threepids {
+    /// Starts the validation of an address. Returns the session id and, if an email has to be
+    /// sent, the session with the token for the link.
+    ///
+    /// Clients retry with the same `send_attempt` if they don't know if their request arrived,
+    /// only a higher one sends the email again.
+    #[tracing::instrument(skip(self, client_secret))]
+    pub fn request_token(
+        &self,
+        purpose: ValidationPurpose,
+        client_secret: &str,
+        medium: &str,
+        address: &str,
+        send_attempt: UInt,
+    ) -> Result<(String, Option<ValidationSession>)> {
+        // Anyone can request links, so sessions that are never validated have to be cleaned up
+        self.remove_expired_if_due()?;
+
+        let key = address_key(client_secret.as_bytes(), medium, address);
+
+        let existing = self
+            .clientsecretaddress_sid
+            .get(&key)?
+            .map(|sid| {
+                utils::string_from_bytes(&sid)
+                    .map_err(|_| Error::bad_database("Invalid sid in clientsecretaddress_sid."))
+            })
+            .transpose()?
+            .map(|sid| Ok::<_, Error>((self.get_session(&sid)?, sid)))
+            .transpose()?;
+
+        let sid = match existing {
+            Some((Some(session), sid)) if session.purpose == purpose && !session.is_expired() => {
+                if send_attempt <= session.send_attempt {
+                    return Ok((sid, None));
+                }
+                sid
+            }
+            _ => utils::random_string(SID_LENGTH),
+        };
+
+        let session = ValidationSession {
+            purpose,
+            client_secret: client_secret.to_owned(),
+            medium: medium.to_owned(),
+            address: address.to_owned(),
+            send_attempt,
+            token: utils::random_string(VALIDATION_TOKEN_LENGTH),
+            created_at: MilliSecondsSinceUnixEpoch::now(),
+            validated_at: None,
+        };
+
+        self.save_session(&sid, &session)?;
+        self.clientsecretaddress_sid.insert(&key, sid.as_bytes())?;
+
+        Ok((sid, Some(session)))
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn get_session(&self, sid: &str) -> Result<Option<ValidationSession>> {
+        self.sid_validationsession
+            .get(sid.as_bytes())?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid ValidationSession in db."))
+            })
+            .transpose()
+    }
+
+    /// Returns the purpose of the session if the token from the link is right, without marking
+    /// the session as validated.
+    #[tracing::instrument(skip(self, client_secret, token))]
+    pub fn purpose(
+        &self,
+        sid: &str,
+        client_secret: &str,
+        token: &str,
+    ) -> Result<Option<ValidationPurpose>> {
+        Ok(self
+            .matching_session(sid, client_secret, token)?
+            .map(|session| session.purpose))
+    }
+
+    /// Marks the session as validated if the token from the link is right. Returns the purpose
+    /// of the session.
+    #[tracing::instrument(skip(self, client_secret, token))]
+    pub fn validate(
+        &self,
+        sid: &str,
+        client_secret: &str,
+        token: &str,
+    ) -> Result<Option<ValidationPurpose>> {
+        let mut session = match self.matching_session(sid, client_secret, token)? {
+            Some(session) => session,
+            None => return Ok(None),
+        };
+
+        if session.validated_at.is_none() {
+            session.validated_at = Some(MilliSecondsSinceUnixEpoch::now());
+            self.save_session(sid, &session)?;
+        }
+
+        Ok(Some(session.purpose))
+    }
+
+    /// Removes a validated session and returns it. Sessions that were not validated yet are
+    /// kept, so the user can still click the link.
+    #[tracing::instrument(skip(self, client_secret))]
+    pub fn take_validated(
+        &self,
+        sid: &str,
+        client_secret: &str,
+        purpose: ValidationPurpose,
+    ) -> Result<Option<ValidationSession>> {
+        let session = match self.get_session(sid)? {
+            Some(session)
+                if session.client_secret == client_secret
+                    && session.purpose == purpose
+                    && session.validated_at.is_some()
+                    && !session.is_expired() =>
+            {
+                session
+            }
+            _ => return Ok(None),
+        };
+
+        self.sid_validationsession.remove(sid.as_bytes())?;
+        self.clientsecretaddress_sid.remove(&address_key(
+            client_secret.as_bytes(),
+            &session.medium,
+            &session.address,
+        ))?;
+
+        Ok(Some(session))
+    }
+
+    /// Binds the address to the user.
+    #[tracing::instrument(skip(self))]
+    pub fn add(
+        &self,
+        user_id: &UserId,
+        medium: &str,
+        address: &str,
+        validated_at: MilliSecondsSinceUnixEpoch,
+    ) -> Result<()> {
+        let threepid = Threepid {
+            medium: medium.to_owned(),
+            address: address.to_owned(),
+            added_at: MilliSecondsSinceUnixEpoch::now(),
+            validated_at,
+        };
+
+        self.useridaddress_threepid.insert(
+            &address_key(user_id.as_bytes(), medium, address),
+            &serde_json::to_vec(&threepid).expect("Threepid is valid JSON value"),
+        )?;
+        self.address_userid
+            .insert(&medium_address_key(medium, address), user_id.as_bytes())
+    }
+
+    /// Unbinds the address from the user. Returns false if it was not bound to them.
+    #[tracing::instrument(skip(self))]
+    pub fn remove(&self, user_id: &UserId, medium: &str, address: &str) -> Result<bool> {
+        let key = address_key(user_id.as_bytes(), medium, address);
+        if self.useridaddress_threepid.get(&key)?.is_none() {
+            return Ok(false);
+        }
+
+        self.useridaddress_threepid.remove(&key)?;
+        self.address_userid
+            .remove(&medium_address_key(medium, address))?;
+
+        Ok(true)
+    }
+
+    /// Unbinds all addresses of the user, e.g. when the account is deactivated.
+    #[tracing::instrument(skip(self))]
+    pub fn remove_all(&self, user_id: &UserId) -> Result<()> {
+        for threepid in self.threepids(user_id).collect::<Result<Vec<_>>>()? {
+            self.remove(user_id, &threepid.medium, &threepid.address)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the user the address is bound to.
+    #[tracing::instrument(skip(self))]
+    pub fn find_user(&self, medium: &str, address: &str) -> Result<Option<UserId>> {
+        self.address_userid
+            .get(&medium_address_key(medium, address))?
+            .map(|bytes| {
+                UserId::try_from(utils::string_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("User ID in address_userid is invalid unicode.")
+                })?)
+                .map_err(|_| Error::bad_database("User ID in address_userid is invalid."))
+            })
+            .transpose()
+    }
+
+    /// Returns all addresses bound to the user.
+    #[tracing::instrument(skip(self))]
+    pub fn threepids<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> impl Iterator<Item = Result<Threepid>> + 'a {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.useridaddress_threepid
+            .scan_prefix(prefix)
+            .map(|(_, value)| {
+                serde_json::from_slice(&value)
+                    .map_err(|_| Error::bad_database("Invalid Threepid in db."))
+            })
+    }
+
+    /// Deletes all validation sessions that expired.
+    ///
+    /// Returns how many were deleted.
+    pub fn remove_expired(&self) -> Result<usize> {
+        let mut removed = 0;
+
+        let expired = self
+            .sid_validationsession
+            .iter()
+            .filter_map(
+                |(sid, bytes)| match serde_json::from_slice::<ValidationSession>(&bytes) {
+                    Ok(session) if !session.is_expired() => None,
+                    session => Some((sid, session.ok())),
+                },
+            )
+            .collect::<Vec<_>>();
+
+        for (sid, session) in expired {
+            self.sid_validationsession.remove(&sid)?;
+            if let Some(session) = session {
+                self.clientsecretaddress_sid.remove(&address_key(
+                    session.client_secret.as_bytes(),
+                    &session.medium,
+                    &session.address,
+                ))?;
+            }
+            removed += 1;
+        }
+
+        // Sessions that could not be parsed above leave entries without a session behind
+        for (key, sid) in self.clientsecretaddress_sid.iter().collect::<Vec<_>>() {
+            if self.sid_validationsession.get(&sid)?.is_none() {
+                self.clientsecretaddress_sid.remove(&key)?;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    fn remove_expired_if_due(&self) -> Result<()> {
+        {
+            let mut last_cleanup = self.last_cleanup.lock().unwrap();
+            if last_cleanup.elapsed() < CLEANUP_INTERVAL {
+                return Ok(());
+            }
+            *last_cleanup = Instant::now();
+        }
+
+        self.remove_expired().map(|_| ())
+    }
+
+    fn matching_session(
+        &self,
+        sid: &str,
+        client_secret: &str,
+        token: &str,
+    ) -> Result<Option<ValidationSession>> {
+        Ok(self.get_session(sid)?.filter(|session| {
+            session.client_secret == client_secret
+                && session.token == token
+                && !session.is_expired()
+        }))
+    }
+
+    fn save_session(&self, sid: &str, session: &ValidationSession) -> Result<()> {
+        self.sid_validationsession.insert(
+            sid.as_bytes(),
+            &serde_json::to_vec(session).expect("ValidationSession is valid JSON value"),
+        )
+    }
+}
+
+/// Builds `prefix 0xff medium 0xff address`.
+fn address_key(prefix: &[u8], medium: &str, address: &str) -> Vec<u8> {
+    let mut key = prefix.to_vec();
+    key.push(0xff);
+    key.extend_from_slice(&medium_address_key(medium, address));
+    key
+}
+
+fn medium_address_key(medium: &str, address: &str) -> Vec<u8> {
+    let mut key = medium.as_bytes().to_vec();
+    key.push(0xff);
+    key.extend_from_slice(address.as_bytes());
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{address_key, Threepids, ValidationPurpose, ValidationSession};
+    use crate::database::abstraction::memory::MemoryTree;
+    use ruma::{MilliSecondsSinceUnixEpoch, UInt};
+    use std::{
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+
+    fn threepids() -> Threepids {
+        Threepids {
+            sid_validationsession: Arc::new(MemoryTree::default()),
+            clientsecretaddress_sid: Arc::new(MemoryTree::default()),
+            useridaddress_threepid: Arc::new(MemoryTree::default()),
+            address_userid: Arc::new(MemoryTree::default()),
+            last_cleanup: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn request(threepids: &Threepids) -> (String, ValidationSession) {
+        let (sid, session) = threepids
+            .request_token(
+                ValidationPurpose::PasswordReset,
+                "secret",
+                "email",
+                "alice@example.org",
+                UInt::from(1_u32),
+            )
+            .unwrap();
+
+        (sid, session.unwrap())
+    }
+
+    #[test]
+    fn opening_the_link_does_not_validate() {
+        let threepids = threepids();
+        let (sid, session) = request(&threepids);
+
+        assert_eq!(
+            threepids.purpose(&sid, "secret", &session.token).unwrap(),
+            Some(ValidationPurpose::PasswordReset)
+        );
+        assert!(threepids
+            .take_validated(&sid, "secret", ValidationPurpose::PasswordReset)
+            .unwrap()
+            .is_none());
+
+        assert_eq!(
+            threepids.validate(&sid, "secret", &session.token).unwrap(),
+            Some(ValidationPurpose::PasswordReset)
+        );
+        assert!(threepids
+            .take_validated(&sid, "secret", ValidationPurpose::PasswordReset)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn expired_sessions_are_removed() {
+        let threepids = threepids();
+        let (sid, mut session) = request(&threepids);
+        session.created_at = MilliSecondsSinceUnixEpoch(UInt::from(0_u32));
+        threepids.save_session(&sid, &session).unwrap();
+
+        let (other_sid, _) = threepids
+            .request_token(
+                ValidationPurpose::AddThreepid,
+                "other_secret",
+                "email",
+                "bob@example.org",
+                UInt::from(1_u32),
+            )
+            .unwrap();
+        threepids
+            .sid_validationsession
+            .insert(b"invalid", b"")
+            .unwrap();
+
+        assert_eq!(threepids.remove_expired().unwrap(), 2);
+        assert!(threepids.get_session(&sid).unwrap().is_none());
+        assert!(threepids
+            .clientsecretaddress_sid
+            .get(&address_key(b"secret", "email", "alice@example.org"))
+            .unwrap()
+            .is_none());
+        assert!(threepids.get_session(&other_sid).unwrap().is_some());
+    }
+}
@@ -0,0 +1,269 @@
+use hmac::{Hmac, Mac, NewMac};
+use ruma::{SecondsSinceUnixEpoch, UserId};
+use serde::Deserialize;
+use sha1::Sha1;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime},
+};
+use tracing::warn;
+
+type HmacSha1 = Hmac<Sha1>;
+
+/// ## Examples:
+/// - Two TURN server groups, every user always gets the same group:
+/// ```toml
+/// [global.turn]
+/// selection = "per_user"
+///
+/// [[global.turn.servers]]
+/// uris = ["turn:turn1.example.com:3478?transport=udp"]
+/// secret = "first secret"
+///
+/// [[global.turn.servers]]
+/// uris = ["turn:turn2.example.com:3478?transport=udp"]
+/// secret = "second secret"
+/// ```
+///
+/// To rotate the secret of a group, configure the TURN servers to accept both the old and the new
+/// secret, change `secret` here and remove the old secret from the TURN servers after `turn_ttl`,
+/// when all credentials created with it expired.
+///
+/// The top-level `turn_uris`, `turn_secret`, `turn_username` and `turn_password` options are
+/// used as a single group if no groups are configured here.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct TurnConfig {
+    #[serde(default)]
+    pub selection: TurnSelection,
+    #[serde(default)]
+    pub servers: Vec<TurnServerGroup>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TurnSelection {
+    /// Every user always gets the same group.
+    PerUser,
+    /// Every request gets the next group.
+    RoundRobin,
+}
+
+impl Default for TurnSelection {
+    fn default() -> Self {
+        TurnSelection::PerUser
+    }
+}
+
+/// TURN servers that share their credentials. Either `secret` (the shared secret of the TURN
+/// REST API) or `username` and `password` need to be set.
+#[derive(Clone, Debug, Deserialize)]
+pub struct TurnServerGroup {
+    pub uris: Vec<String>,
+    pub secret: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl TurnServerGroup {
+    fn is_usable(&self) -> bool {
+        fn is_set(value: &Option<String>) -> bool {
+            value.as_ref().map_or(false, |value| !value.is_empty())
+        }
+
+        !self.uris.is_empty()
+            && (is_set(&self.secret) || (is_set(&self.username) && is_set(&self.password)))
+    }
+}
+
+pub struct TurnCredentials {
+    pub username: String,
+    pub password: String,
+    pub uris: Vec<String>,
+    pub ttl: Duration,
+}
+
+pub struct TurnServers {
+    selection: TurnSelection,
+    groups: Vec<TurnServerGroup>,
+    next_group: AtomicUsize,
+}
+
+impl TurnServers {
+    /// Creates the TURN server selection. `legacy` is used if `config` has no groups.
+    pub fn new(config: TurnConfig, legacy: TurnServerGroup) -> Self {
+        let groups = if config.servers.is_empty() {
+            vec![legacy]
+        } else {
+            config.servers
+        };
+
+        let groups = groups
+            .into_iter()
+            .filter(|group| {
+                let usable = group.is_usable();
+                if !usable && !group.uris.is_empty() {
+                    warn!(
+                        "Ignoring TURN servers {:?} without secret or username and password",
+                        group.uris
+                    );
+                }
+                usable
+            })
+            .collect();
+
+        Self {
+            selection: config.selection,
+            groups,
+            next_group: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns credentials for one of the configured groups or `None` if no TURN servers are
+    /// configured.
+    pub fn credentials(&self, user_id: &UserId, ttl: Duration) -> Option<TurnCredentials> {
+        if self.groups.is_empty() {
+            return None;
+        }
+
+        let index = match self.selection {
+            TurnSelection::PerUser => {
+                let mut hasher = DefaultHasher::new();
+                user_id.hash(&mut hasher);
+                hasher.finish() as usize
+            }
+            TurnSelection::RoundRobin => self.next_group.fetch_add(1, Ordering::Relaxed),
+        } % self.groups.len();
+        let group = &self.groups[index];
+
+        let secret = match group.secret.as_ref().filter(|secret| !secret.is_empty()) {
+            Some(secret) => secret,
+            None => {
+                return Some(TurnCredentials {
+                    username: group.username.clone().unwrap_or_default(),
+                    password: group.password.clone().unwrap_or_default(),
+                    uris: group.uris.clone(),
+                    ttl,
+                })
+            }
+        };
+
+        let (username, password) = rest_credentials(secret, user_id, SystemTime::now() + ttl);
+
+        Some(TurnCredentials {
+            username,
+            password,
+            uris: group.uris.clone(),
+            ttl,
+        })
+    }
+}
+
+/// Creates credentials for the TURN REST API: the username is the expiry time and the user id,
+/// the password is the base64 encoded HMAC-SHA1 of the username with the shared secret.
+fn rest_credentials(secret: &str, user_id: &UserId, expiry: SystemTime) -> (String, String) {
+    let username = format!(
+        "{}:{}",
+        SecondsSinceUnixEpoch::from_system_time(expiry)
+            .expect("time is valid")
+            .get(),
+        user_id
+    );
+
+    let mut mac =
+        HmacSha1::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(username.as_bytes());
+
+    let password = base64::encode_config(mac.finalize().into_bytes(), base64::STANDARD);
+
+    (username, password)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rest_credentials, TurnConfig, TurnServerGroup, TurnServers};
+    use ruma::UserId;
+    use std::{
+        convert::TryFrom,
+        time::{Duration, SystemTime},
+    };
+
+    fn group(
+        secret: Option<&str>,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> TurnServerGroup {
+        TurnServerGroup {
+            uris: vec!["turn:turn.example.com:3478?transport=udp".to_owned()],
+            secret: secret.map(ToOwned::to_owned),
+            username: username.map(ToOwned::to_owned),
+            password: password.map(ToOwned::to_owned),
+        }
+    }
+
+    #[test]
+    fn rest_credentials_have_the_turn_rest_api_format() {
+        let user_id = UserId::try_from("@alice:example.org").unwrap();
+        let expiry = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        assert_eq!(
+            rest_credentials("shared secret", &user_id, expiry),
+            (
+                "1700000000:@alice:example.org".to_owned(),
+                "4HGGWspK3DFgVhGObNrSlaacCgw=".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn rest_credentials_expire_after_the_ttl() {
+        let user_id = UserId::try_from("@alice:example.org").unwrap();
+        let servers = TurnServers::new(TurnConfig::default(), group(Some("secret"), None, None));
+        let ttl = Duration::from_secs(60 * 60);
+
+        let before = SystemTime::now() + ttl;
+        let credentials = servers.credentials(&user_id, ttl).unwrap();
+        let after = SystemTime::now() + ttl;
+
+        let expiry: u64 = credentials
+            .username
+            .split(':')
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let expiry = SystemTime::UNIX_EPOCH + Duration::from_secs(expiry);
+
+        assert_eq!(credentials.ttl, ttl);
+        // The username only has seconds
+        assert!(expiry + Duration::from_secs(1) > before && expiry <= after);
+    }
+
+    #[test]
+    fn static_credentials_are_returned_as_configured() {
+        let user_id = UserId::try_from("@alice:example.org").unwrap();
+        let servers = TurnServers::new(
+            TurnConfig::default(),
+            group(None, Some("user"), Some("password")),
+        );
+
+        let credentials = servers
+            .credentials(&user_id, Duration::from_secs(60))
+            .unwrap();
+
+        assert_eq!(credentials.username, "user");
+        assert_eq!(credentials.password, "password");
+    }
+
+    #[test]
+    fn groups_without_credentials_are_skipped() {
+        let user_id = UserId::try_from("@alice:example.org").unwrap();
+        // This is what the legacy options look like when they are not configured
+        let servers = TurnServers::new(TurnConfig::default(), group(Some(""), Some(""), Some("")));
+
+        assert!(servers
+            .credentials(&user_id, Duration::from_secs(60))
+            .is_none());
+    }
+}
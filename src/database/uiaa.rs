@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{net::IpAddr, sync::Arc};
 
 use crate::{client_server::SESSION_ID_LENGTH, utils, Error, Result};
 use ruma::{
@@ -52,6 +52,7 @@ impl Uiaa {
         uiaainfo: &UiaaInfo,
         users: &super::users::Users,
         globals: &super::globals::Globals,
+        client_ip: Option<IpAddr>,
     ) -> Result<(bool, UiaaInfo)> {
         let mut uiaainfo = auth
             .session()
@@ -85,18 +86,33 @@ impl Uiaa {
                             Error::BadRequest(ErrorKind::InvalidParam, "User ID is invalid.")
                         })?;
 
+                if let Err(retry_after) = globals.login_lockout.check(Some(&user_id), client_ip) {
+                    uiaainfo.auth_error = Some(ruma::api::client::error::ErrorBody {
+                        kind: ErrorKind::LimitExceeded {
+                            retry_after_ms: Some(retry_after),
+                        },
+                        message: "Too many failed login attempts.".to_owned(),
+                    });
+                    return Ok((false, uiaainfo));
+                }
+
                 // Check if password is correct
                 if let Some(hash) = users.password_hash(&user_id)? {
                     let hash_matches =
                         argon2::verify_encoded(&hash, password.as_bytes()).unwrap_or(false);
 
                     if !hash_matches {
+                        globals
+                            .login_lockout
+                            .record_failure(Some(&user_id), client_ip);
                         uiaainfo.auth_error = Some(ruma::api::client::error::ErrorBody {
                             kind: ErrorKind::Forbidden,
                             message: "Invalid username or password.".to_owned(),
                         });
                         return Ok((false, uiaainfo));
                     }
+
+                    globals.login_lockout.unlock_user(&user_id);
                 }
 
                 // Password was correct! Let's add it to `completed`
@@ -108,9 +124,44 @@ impl Uiaa {
             k => error!("type not supported: {:?}", k),
         }
 
+        self.check_flows(user_id, device_id, uiaainfo)
+    }
+
+    /// Completes a stage ruma doesn't know about yet, like `m.login.terms`.
+    pub fn complete_stage(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        session: &str,
+        stage: AuthType,
+    ) -> Result<(bool, UiaaInfo)> {
+        let mut uiaainfo = self.get_uiaa_session(user_id, device_id, session)?;
+
+        if !uiaainfo
+            .flows
+            .iter()
+            .any(|flow| flow.stages.contains(&stage))
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "This stage is not part of any flow.",
+            ));
+        }
+
+        uiaainfo.completed.push(stage);
+
+        self.check_flows(user_id, device_id, uiaainfo)
+    }
+
+    fn check_flows(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        uiaainfo: UiaaInfo,
+    ) -> Result<(bool, UiaaInfo)> {
         // Check if a flow now succeeds
         let mut completed = false;
-        'flows: for flow in &mut uiaainfo.flows {
+        'flows: for flow in &uiaainfo.flows {
             for stage in &flow.stages {
                 if !uiaainfo.completed.contains(stage) {
                     continue 'flows;
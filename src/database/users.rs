@@ -8,7 +8,7 @@ use ruma::{
     DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch, RoomAliasId, UInt,
     UserId,
 };
-use std::{collections::BTreeMap, convert::TryFrom, mem, sync::Arc};
+use std::{collections::BTreeMap, convert::TryFrom, mem, sync::Arc, time::Duration};
 use tracing::warn;
 
 use super::abstraction::Tree;
@@ -22,6 +22,11 @@ pub struct Users {
     pub(super) userdeviceid_metadata: Arc<dyn Tree>, // This is also used to check if a device exists
     pub(super) userid_devicelistversion: Arc<dyn Tree>, // DevicelistVersion = u64
     pub(super) token_userdeviceid: Arc<dyn Tree>,
+    pub(super) token_expiresat: Arc<dyn Tree>, // ExpiresAt = u64, only for refreshable tokens
+    pub(super) userdeviceid_refreshtoken: Arc<dyn Tree>,
+    pub(super) refreshtoken_userdeviceid: Arc<dyn Tree>,
+    pub(super) userdeviceid_previousrefreshtoken: Arc<dyn Tree>,
+    pub(super) previousrefreshtoken_userdeviceid: Arc<dyn Tree>,
 
     pub(super) onetimekeyid_onetimekeys: Arc<dyn Tree>, // OneTimeKeyId = UserId + DeviceKeyId
     pub(super) userid_lastonetimekeyupdate: Arc<dyn Tree>, // LastOneTimeKeyUpdate = Count
@@ -32,6 +37,9 @@ pub struct Users {
     pub(super) userid_usersigningkeyid: Arc<dyn Tree>,
 
     pub(super) todeviceid_events: Arc<dyn Tree>, // ToDeviceId = UserId + DeviceId + Count
+
+    pub(super) userid_shadowbanned: Arc<dyn Tree>,
+    pub(super) userid_suspended: Arc<dyn Tree>,
 }
 
 impl Users {
@@ -54,6 +62,40 @@ impl Users {
             .is_empty())
     }
 
+    /// Check if the events of the user are silently dropped.
+    #[tracing::instrument(skip(self, user_id))]
+    pub fn is_shadow_banned(&self, user_id: &UserId) -> Result<bool> {
+        Ok(self.userid_shadowbanned.get(user_id.as_bytes())?.is_some())
+    }
+
+    /// Shadow-bans or unbans a user. Shadow-banned users can still send messages, but they are
+    /// never stored or sent to anyone.
+    #[tracing::instrument(skip(self, user_id))]
+    pub fn set_shadow_banned(&self, user_id: &UserId, shadow_banned: bool) -> Result<()> {
+        if shadow_banned {
+            self.userid_shadowbanned.insert(user_id.as_bytes(), &[])
+        } else {
+            self.userid_shadowbanned.remove(user_id.as_bytes())
+        }
+    }
+
+    /// Check if the account is suspended.
+    #[tracing::instrument(skip(self, user_id))]
+    pub fn is_suspended(&self, user_id: &UserId) -> Result<bool> {
+        Ok(self.userid_suspended.get(user_id.as_bytes())?.is_some())
+    }
+
+    /// Suspends or unsuspends an account. Suspended users can log in and read, but all other
+    /// requests are rejected.
+    #[tracing::instrument(skip(self, user_id))]
+    pub fn set_suspended(&self, user_id: &UserId, suspended: bool) -> Result<()> {
+        if suspended {
+            self.userid_suspended.insert(user_id.as_bytes(), &[])
+        } else {
+            self.userid_suspended.remove(user_id.as_bytes())
+        }
+    }
+
     /// Check if a user is an admin
     #[tracing::instrument(skip(self, user_id, rooms, globals))]
     pub fn is_admin(
@@ -83,7 +125,8 @@ impl Users {
         Ok(self.userid_password.iter().count())
     }
 
-    /// Find out which user an access token belongs to.
+    /// Find out which user an access token belongs to. The token might have expired, see
+    /// [`token_is_expired`](Self::token_is_expired).
     #[tracing::instrument(skip(self, token))]
     pub fn find_from_token(&self, token: &str) -> Result<Option<(UserId, String)>> {
         self.token_userdeviceid
@@ -111,6 +154,49 @@ impl Users {
             })
     }
 
+    /// Returns true if the access token expired and needs to be refreshed.
+    #[tracing::instrument(skip(self, token))]
+    pub fn token_is_expired(&self, token: &str) -> Result<bool> {
+        self.token_is_expired_at(token, utils::millis_since_unix_epoch())
+    }
+
+    /// Same as `token_is_expired`, but uses the given time in milliseconds since the unix epoch as
+    /// the current time.
+    #[tracing::instrument(skip(self, token))]
+    pub fn token_is_expired_at(&self, token: &str, now: u64) -> Result<bool> {
+        self.token_expiresat
+            .get(token.as_bytes())?
+            .map_or(Ok(false), |bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map(|expires_at| expires_at < now)
+                    .map_err(|_| Error::bad_database("Invalid expiry in token_expiresat."))
+            })
+    }
+
+    /// Find out which device a refresh token belongs to.
+    #[tracing::instrument(skip(self, refresh_token))]
+    pub fn find_from_refresh_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<(UserId, Box<DeviceId>)>> {
+        self.refreshtoken_userdeviceid
+            .get(refresh_token.as_bytes())?
+            .map(|bytes| parse_userdeviceid(&bytes))
+            .transpose()
+    }
+
+    /// Find out which device a refresh token belonged to before it was rotated.
+    #[tracing::instrument(skip(self, refresh_token))]
+    pub fn find_from_previous_refresh_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<(UserId, Box<DeviceId>)>> {
+        self.previousrefreshtoken_userdeviceid
+            .get(refresh_token.as_bytes())?
+            .map(|bytes| parse_userdeviceid(&bytes))
+            .transpose()
+    }
+
     /// Returns an iterator over all users on this homeserver.
     #[tracing::instrument(skip(self))]
     pub fn iter(&self) -> impl Iterator<Item = Result<UserId>> + '_ {
@@ -278,7 +364,9 @@ impl Users {
         if let Some(old_token) = self.userdeviceid_token.get(&userdeviceid)? {
             self.userdeviceid_token.remove(&userdeviceid)?;
             self.token_userdeviceid.remove(&old_token)?;
+            self.token_expiresat.remove(&old_token)?;
         }
+        self.remove_refresh_tokens(&userdeviceid)?;
 
         // Remove todevice events
         let mut prefix = userdeviceid.clone();
@@ -331,17 +419,119 @@ impl Users {
         // All devices have metadata
         assert!(self.userdeviceid_metadata.get(&userdeviceid)?.is_some());
 
+        self.replace_token(&userdeviceid, token)?;
+
+        // Clients without refresh token support get a token that never expires
+        self.remove_refresh_tokens(&userdeviceid)?;
+
+        Ok(())
+    }
+
+    /// Replaces the access token of one device with a token that expires after `lifetime` and
+    /// starts a new refresh token chain.
+    #[tracing::instrument(skip(self, user_id, device_id, token, refresh_token))]
+    pub fn set_refreshable_token(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        token: &str,
+        refresh_token: &str,
+        lifetime: Duration,
+    ) -> Result<()> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        // All devices have metadata
+        assert!(self.userdeviceid_metadata.get(&userdeviceid)?.is_some());
+
+        self.replace_token(&userdeviceid, token)?;
+        self.set_token_expiry(token, lifetime)?;
+
+        self.remove_refresh_tokens(&userdeviceid)?;
+        self.userdeviceid_refreshtoken
+            .insert(&userdeviceid, refresh_token.as_bytes())?;
+        self.refreshtoken_userdeviceid
+            .insert(refresh_token.as_bytes(), &userdeviceid)?;
+
+        Ok(())
+    }
+
+    /// Replaces the access token and the refresh token of one device. The old refresh token is
+    /// remembered, so that it can be detected if it is used again.
+    #[tracing::instrument(skip(self, user_id, device_id, token, refresh_token))]
+    pub fn rotate_refresh_token(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        token: &str,
+        refresh_token: &str,
+        lifetime: Duration,
+    ) -> Result<()> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        self.replace_token(&userdeviceid, token)?;
+        self.set_token_expiry(token, lifetime)?;
+
+        // Only the last rotated refresh token is remembered
+        if let Some(previous) = self.userdeviceid_previousrefreshtoken.get(&userdeviceid)? {
+            self.previousrefreshtoken_userdeviceid.remove(&previous)?;
+        }
+
+        if let Some(old_refresh_token) = self.userdeviceid_refreshtoken.get(&userdeviceid)? {
+            self.refreshtoken_userdeviceid.remove(&old_refresh_token)?;
+            self.userdeviceid_previousrefreshtoken
+                .insert(&userdeviceid, &old_refresh_token)?;
+            self.previousrefreshtoken_userdeviceid
+                .insert(&old_refresh_token, &userdeviceid)?;
+        }
+
+        self.userdeviceid_refreshtoken
+            .insert(&userdeviceid, refresh_token.as_bytes())?;
+        self.refreshtoken_userdeviceid
+            .insert(refresh_token.as_bytes(), &userdeviceid)?;
+
+        Ok(())
+    }
+
+    fn replace_token(&self, userdeviceid: &[u8], token: &str) -> Result<()> {
         // Remove old token
-        if let Some(old_token) = self.userdeviceid_token.get(&userdeviceid)? {
+        if let Some(old_token) = self.userdeviceid_token.get(userdeviceid)? {
             self.token_userdeviceid.remove(&old_token)?;
+            self.token_expiresat.remove(&old_token)?;
             // It will be removed from userdeviceid_token by the insert later
         }
 
         // Assign token to user device combination
         self.userdeviceid_token
-            .insert(&userdeviceid, token.as_bytes())?;
+            .insert(userdeviceid, token.as_bytes())?;
         self.token_userdeviceid
-            .insert(token.as_bytes(), &userdeviceid)?;
+            .insert(token.as_bytes(), userdeviceid)?;
+
+        Ok(())
+    }
+
+    fn set_token_expiry(&self, token: &str, lifetime: Duration) -> Result<()> {
+        let expires_at = utils::millis_since_unix_epoch()
+            .saturating_add(u64::try_from(lifetime.as_millis()).unwrap_or(u64::MAX));
+
+        self.token_expiresat
+            .insert(token.as_bytes(), &expires_at.to_be_bytes())
+    }
+
+    fn remove_refresh_tokens(&self, userdeviceid: &[u8]) -> Result<()> {
+        if let Some(refresh_token) = self.userdeviceid_refreshtoken.get(userdeviceid)? {
+            self.userdeviceid_refreshtoken.remove(userdeviceid)?;
+            self.refreshtoken_userdeviceid.remove(&refresh_token)?;
+        }
+
+        if let Some(previous) = self.userdeviceid_previousrefreshtoken.get(userdeviceid)? {
+            self.userdeviceid_previousrefreshtoken
+                .remove(userdeviceid)?;
+            self.previousrefreshtoken_userdeviceid.remove(&previous)?;
+        }
 
         Ok(())
     }
@@ -991,3 +1181,181 @@ impl Users {
         Ok(())
     }
 }
+
+/// Parses a user id and device id separated by 0xff.
+fn parse_userdeviceid(bytes: &[u8]) -> Result<(UserId, Box<DeviceId>)> {
+    let mut parts = bytes.split(|&b| b == 0xff);
+    let user_bytes = parts
+        .next()
+        .ok_or_else(|| Error::bad_database("UserDevice ID in db is invalid."))?;
+    let device_bytes = parts
+        .next()
+        .ok_or_else(|| Error::bad_database("UserDevice ID in db is invalid."))?;
+
+    Ok((
+        UserId::try_from(
+            utils::string_from_bytes(user_bytes)
+                .map_err(|_| Error::bad_database("User ID in db is invalid unicode."))?,
+        )
+        .map_err(|_| Error::bad_database("User ID in db is invalid."))?,
+        utils::string_from_bytes(device_bytes)
+            .map_err(|_| Error::bad_database("Device ID in db is invalid unicode."))?
+            .into(),
+    ))
+}
+
+#[cfg(test)]
+impl Users {
+    /// Users that only live in memory, for tests.
+    pub fn in_memory() -> Self {
+        use super::abstraction::memory::MemoryTree;
+
+        fn tree() -> Arc<dyn Tree> {
+            Arc::new(MemoryTree::default())
+        }
+
+        Self {
+            userid_password: tree(),
+            userid_displayname: tree(),
+            userid_avatarurl: tree(),
+            userid_blurhash: tree(),
+            userdeviceid_token: tree(),
+            userdeviceid_metadata: tree(),
+            userid_devicelistversion: tree(),
+            token_userdeviceid: tree(),
+            token_expiresat: tree(),
+            userdeviceid_refreshtoken: tree(),
+            refreshtoken_userdeviceid: tree(),
+            userdeviceid_previousrefreshtoken: tree(),
+            previousrefreshtoken_userdeviceid: tree(),
+            onetimekeyid_onetimekeys: tree(),
+            userid_lastonetimekeyupdate: tree(),
+            keychangeid_userid: tree(),
+            keyid_key: tree(),
+            userid_masterkeyid: tree(),
+            userid_selfsigningkeyid: tree(),
+            userid_usersigningkeyid: tree(),
+            todeviceid_events: tree(),
+            userid_shadowbanned: tree(),
+            userid_suspended: tree(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Users;
+    use crate::utils;
+    use ruma::{DeviceId, UserId};
+    use std::{convert::TryFrom, time::Duration};
+
+    const LIFETIME: Duration = Duration::from_secs(60);
+
+    fn user_with_device() -> (Users, UserId, Box<DeviceId>) {
+        let users = Users::in_memory();
+        let user_id = UserId::try_from("@alice:example.org").unwrap();
+        let device_id = Box::<DeviceId>::from("DEVICE");
+
+        users.create(&user_id, Some("password")).unwrap();
+        users
+            .create_device(&user_id, &device_id, "token", None)
+            .unwrap();
+
+        (users, user_id, device_id)
+    }
+
+    #[test]
+    fn rotating_replaces_access_token_and_refresh_token() {
+        let (users, user_id, device_id) = user_with_device();
+        users
+            .set_refreshable_token(&user_id, &device_id, "token1", "refresh1", LIFETIME)
+            .unwrap();
+
+        users
+            .rotate_refresh_token(&user_id, &device_id, "token2", "refresh2", LIFETIME)
+            .unwrap();
+
+        assert!(users.find_from_token("token1").unwrap().is_none());
+        assert_eq!(
+            users.find_from_token("token2").unwrap(),
+            Some((user_id.clone(), "DEVICE".to_owned()))
+        );
+        assert!(users.find_from_refresh_token("refresh1").unwrap().is_none());
+        assert_eq!(
+            users.find_from_refresh_token("refresh2").unwrap(),
+            Some((user_id.clone(), device_id.clone()))
+        );
+        // The exchanged refresh token is remembered to detect reuse
+        assert_eq!(
+            users.find_from_previous_refresh_token("refresh1").unwrap(),
+            Some((user_id.clone(), device_id.clone()))
+        );
+
+        users
+            .rotate_refresh_token(&user_id, &device_id, "token3", "refresh3", LIFETIME)
+            .unwrap();
+
+        // Only the last exchanged refresh token is remembered
+        assert!(users
+            .find_from_previous_refresh_token("refresh1")
+            .unwrap()
+            .is_none());
+        assert!(users
+            .find_from_previous_refresh_token("refresh2")
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn refreshable_tokens_expire() {
+        let (users, user_id, device_id) = user_with_device();
+        users
+            .set_refreshable_token(&user_id, &device_id, "token1", "refresh1", LIFETIME)
+            .unwrap();
+        assert!(!users.token_is_expired("token1").unwrap());
+
+        users
+            .token_expiresat
+            .insert(
+                b"token1",
+                &(utils::millis_since_unix_epoch() - 1).to_be_bytes(),
+            )
+            .unwrap();
+
+        assert!(users.token_is_expired("token1").unwrap());
+        // The device is still known, so the client is told to refresh instead of logging in
+        assert!(users.find_from_token("token1").unwrap().is_some());
+    }
+
+    #[test]
+    fn tokens_without_refresh_token_never_expire() {
+        let (users, user_id, device_id) = user_with_device();
+        users
+            .set_refreshable_token(&user_id, &device_id, "token1", "refresh1", LIFETIME)
+            .unwrap();
+
+        users.set_token(&user_id, &device_id, "token2").unwrap();
+
+        assert!(!users.token_is_expired("token2").unwrap());
+        assert!(users.find_from_refresh_token("refresh1").unwrap().is_none());
+    }
+
+    #[test]
+    fn shadow_bans_and_suspensions_are_independent() {
+        let (users, user_id, _) = user_with_device();
+        assert!(!users.is_shadow_banned(&user_id).unwrap());
+        assert!(!users.is_suspended(&user_id).unwrap());
+
+        users.set_shadow_banned(&user_id, true).unwrap();
+        assert!(users.is_shadow_banned(&user_id).unwrap());
+        assert!(!users.is_suspended(&user_id).unwrap());
+
+        users.set_suspended(&user_id, true).unwrap();
+        users.set_shadow_banned(&user_id, false).unwrap();
+        assert!(!users.is_shadow_banned(&user_id).unwrap());
+        assert!(users.is_suspended(&user_id).unwrap());
+
+        users.set_suspended(&user_id, false).unwrap();
+        assert!(!users.is_suspended(&user_id).unwrap());
+    }
+}
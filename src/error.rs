@@ -64,6 +64,8 @@ pub enum Error {
     },
     #[error("{0}")]
     BadServerResponse(&'static str),
+    #[error("Push gateway returned status {0}")]
+    PushGatewayError(u16),
     #[error("{0}")]
     BadConfig(&'static str),
     #[error("{0}")]
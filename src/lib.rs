@@ -20,7 +20,7 @@ pub use database::{Config, Database};
 pub use error::{Error, Result};
 pub use pdu::PduEvent;
 pub use rocket::Config as RocketConfig;
-pub use ruma_wrapper::{ConduitResult, Ruma, RumaResponse};
+pub use ruma_wrapper::{ConduitResult, Ruma, RumaResponse, RumaResponseWithFields};
 use std::ops::Deref;
 
 pub struct State<'r, T: Send + Sync + 'static>(pub &'r T);
@@ -19,14 +19,14 @@ mod utils;
 
 use std::sync::Arc;
 
-use database::Config;
 pub use database::Database;
+use database::{ratelimit::RetryAfter, Config};
 pub use error::{Error, Result};
 use opentelemetry::trace::{FutureExt, Tracer};
 pub use pdu::PduEvent;
 pub use rocket::State;
 use ruma::api::client::error::ErrorKind;
-pub use ruma_wrapper::{ConduitResult, Ruma, RumaResponse};
+pub use ruma_wrapper::{ConduitResult, Ruma, RumaResponse, RumaResponseWithFields};
 
 use rocket::{
     catch, catchers,
@@ -50,12 +50,22 @@ fn setup_rocket(config: Figment, data: Arc<RwLock<Database>>) -> rocket::Rocket<
                 client_server::register_route,
                 client_server::get_login_types_route,
                 client_server::login_route,
+                client_server::sso_login_route,
+                client_server::oidc_callback_route,
+                client_server::refresh_route,
+                client_server::refresh_unstable_route,
                 client_server::whoami_route,
                 client_server::logout_route,
                 client_server::logout_all_route,
                 client_server::change_password_route,
                 client_server::deactivate_route,
                 client_server::third_party_route,
+                client_server::request_3pid_email_token_route,
+                client_server::request_password_email_token_route,
+                client_server::validate_email_form_route,
+                client_server::validate_email_route,
+                client_server::add_3pid_route,
+                client_server::delete_3pid_route,
                 client_server::get_capabilities_route,
                 client_server::get_pushrules_all_route,
                 client_server::set_pushrule_route,
@@ -122,6 +132,11 @@ fn setup_rocket(config: Figment, data: Arc<RwLock<Database>>) -> rocket::Rocket<
                 client_server::search_users_route,
                 client_server::get_member_events_route,
                 client_server::get_protocols_route,
+                client_server::get_protocol_route,
+                client_server::get_location_for_protocol_route,
+                client_server::get_location_for_room_alias_route,
+                client_server::get_user_for_protocol_route,
+                client_server::get_user_for_user_id_route,
                 client_server::send_message_event_route,
                 client_server::send_state_event_for_key_route,
                 client_server::send_state_event_for_empty_key_route,
@@ -130,6 +145,11 @@ fn setup_rocket(config: Figment, data: Arc<RwLock<Database>>) -> rocket::Rocket<
                 client_server::get_state_events_for_empty_key_route,
                 client_server::sync_events_route,
                 client_server::get_context_route,
+                client_server::get_relating_events_route,
+                client_server::get_relating_events_with_rel_type_route,
+                client_server::get_relating_events_with_rel_type_and_event_type_route,
+                client_server::get_threads_route,
+                client_server::get_threads_unstable_route,
                 client_server::get_message_events_route,
                 client_server::search_events_route,
                 client_server::turn_server_route,
@@ -152,11 +172,19 @@ fn setup_rocket(config: Figment, data: Arc<RwLock<Database>>) -> rocket::Rocket<
                 client_server::get_key_changes_route,
                 client_server::get_pushers_route,
                 client_server::set_pushers_route,
+                client_server::get_notifications_route,
+                client_server::email_unsubscribe_form_route,
+                client_server::email_unsubscribe_route,
+                client_server::consent_form_route,
+                client_server::consent_route,
                 // client_server::third_party_route,
                 client_server::upgrade_room_route,
                 server_server::get_server_version_route,
                 server_server::get_server_keys_route,
                 server_server::get_server_keys_deprecated_route,
+                server_server::get_remote_server_keys_route,
+                server_server::get_remote_server_keys_all_route,
+                server_server::get_remote_server_keys_batch_route,
                 server_server::get_public_rooms_route,
                 server_server::get_public_rooms_filtered_route,
                 server_server::send_transaction_message_route,
@@ -182,8 +210,12 @@ fn setup_rocket(config: Figment, data: Arc<RwLock<Database>>) -> rocket::Rocket<
                 not_found_catcher,
                 forbidden_catcher,
                 unknown_token_catcher,
+                expired_token_catcher,
                 missing_token_catcher,
-                bad_json_catcher
+                bad_json_catcher,
+                limit_exceeded_catcher,
+                suspended_catcher,
+                consent_not_given_catcher
             ],
         )
 }
@@ -294,6 +326,14 @@ fn unknown_token_catcher() -> Result<()> {
     ))
 }
 
+#[catch(585)]
+fn expired_token_catcher() -> Result<()> {
+    Err(Error::BadRequest(
+        ErrorKind::UnknownToken { soft_logout: true },
+        "Access token has expired.",
+    ))
+}
+
 #[catch(582)]
 fn missing_token_catcher() -> Result<()> {
     Err(Error::BadRequest(ErrorKind::MissingToken, "Missing token."))
@@ -304,6 +344,32 @@ fn bad_json_catcher() -> Result<()> {
     Err(Error::BadRequest(ErrorKind::BadJson, "Bad json."))
 }
 
+#[catch(584)]
+fn limit_exceeded_catcher(req: &Request<'_>) -> Result<()> {
+    let RetryAfter(retry_after_ms) = *req.local_cache(RetryAfter::default);
+
+    Err(Error::BadRequest(
+        ErrorKind::LimitExceeded { retry_after_ms },
+        "Too many requests.",
+    ))
+}
+
+#[catch(586)]
+fn suspended_catcher() -> Result<()> {
+    Err(Error::BadRequest(
+        ErrorKind::Forbidden,
+        "Your account is suspended, you can only read and log out.",
+    ))
+}
+
+#[catch(587)]
+fn consent_not_given_catcher() -> Result<()> {
+    Err(Error::BadRequest(
+        ErrorKind::Forbidden,
+        "You have to accept the new policies first, join your server notices room for the link.",
+    ))
+}
+
 fn default_config() -> rocket::Config {
     let mut config = rocket::Config::release_default();
 
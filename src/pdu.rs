@@ -102,6 +102,24 @@ impl PduEvent {
         Ok(())
     }
 
+    /// Sets a field of the unsigned data, e.g. bundled aggregations.
+    pub fn add_unsigned_field(&mut self, key: &str, value: serde_json::Value) -> crate::Result<()> {
+        let mut unsigned: BTreeMap<String, Box<RawJsonValue>> = self
+            .unsigned
+            .as_ref()
+            .map_or(Ok(BTreeMap::new()), |unsigned| {
+                serde_json::from_str(unsigned.get())
+            })
+            .map_err(|_| Error::bad_database("Invalid unsigned in pdu event"))?;
+        unsigned.insert(
+            key.to_owned(),
+            to_raw_value(&value).expect("json value is valid"),
+        );
+        self.unsigned = Some(to_raw_value(&unsigned).expect("unsigned is valid"));
+
+        Ok(())
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn to_sync_room_event(&self) -> Raw<AnySyncRoomEvent> {
         let mut json = json!({
@@ -9,7 +9,13 @@ use std::ops::Deref;
 
 #[cfg(feature = "conduit_bin")]
 use {
-    crate::server_server,
+    crate::{
+        database::{
+            ratelimit::{RateLimitedAction, RetryAfter},
+            users::Users,
+        },
+        server_server, utils,
+    },
     rocket::{
         data::{self, ByteUnit, Data, FromData},
         http::Status,
@@ -25,6 +31,40 @@ use {
     tracing::{debug, warn},
 };
 
+/// Requests that change something but are still allowed for users who can only read (suspended
+/// users and users who didn't accept the current policies), because clients need them to log out
+/// or to read.
+#[cfg(feature = "conduit_bin")]
+const ALLOWED_WHILE_READ_ONLY: &[&str] = &[
+    "/_matrix/client/r0/logout",
+    "/_matrix/client/r0/logout/all",
+    "/_matrix/client/r0/user/:user_id/filter",
+    "/_matrix/client/r0/keys/query",
+    "/_matrix/client/r0/publicRooms",
+    "/_matrix/client/r0/search",
+    "/_matrix/client/r0/user_directory/search",
+];
+
+/// Requests that are also allowed for users who didn't accept the current policies, because they
+/// have to join their server notices room to find the link to accept them.
+#[cfg(feature = "conduit_bin")]
+const ALLOWED_WITHOUT_CONSENT: &[&str] = &[
+    "/_matrix/client/r0/join/:room_id_or_alias",
+    "/_matrix/client/r0/rooms/:room_id/join",
+    "/_matrix/client/r0/rooms/:room_id/leave",
+];
+
+/// Whether users who can only read may send this request.
+#[cfg(feature = "conduit_bin")]
+fn is_allowed_while_read_only(method: &http::Method, path: &str) -> bool {
+    method == http::Method::GET || ALLOWED_WHILE_READ_ONLY.contains(&path)
+}
+
+/// Requests that need an access token in ruma, but can also be sent without one. Users who forgot
+/// their password reset it through an email address instead.
+#[cfg(feature = "conduit_bin")]
+const ACCESS_TOKEN_OPTIONAL: &[&str] = &["/_matrix/client/r0/account/password"];
+
 /// This struct converts rocket requests into ruma structs by converting them into http requests
 /// first.
 pub struct Ruma<T: Outgoing> {
@@ -35,6 +75,8 @@ pub struct Ruma<T: Outgoing> {
     // This is None when body is not a valid string
     pub json_body: Option<CanonicalJsonValue>,
     pub from_appservice: bool,
+    // The id of the appservice that sent this request
+    pub appservice_id: Option<String>,
 }
 
 #[cfg(feature = "conduit_bin")]
@@ -74,30 +116,24 @@ where
 
         let mut json_body = serde_json::from_slice::<CanonicalJsonValue>(&body).ok();
 
-        let (sender_user, sender_device, sender_servername, from_appservice) = if let Some((
-            _id,
-            registration,
-        )) = db
+        let appservice = db
             .appservice
             .all()
             .unwrap()
-            .iter()
-            .find(|(_id, registration)| {
-                registration
-                    .get("as_token")
-                    .and_then(|as_token| as_token.as_str())
-                    .map_or(false, |as_token| token.as_deref() == Some(as_token))
-            }) {
+            .into_iter()
+            .find(|(_id, registration)| token.as_deref() == Some(&*registration.as_token));
+
+        let (sender_user, sender_device, sender_servername, from_appservice) = if let Some((
+            _id,
+            registration,
+        )) = &appservice
+        {
             match metadata.authentication {
                 AuthScheme::AccessToken | AuthScheme::QueryOnlyAccessToken => {
                     let user_id = request.query_value::<String>("user_id").map_or_else(
                         || {
                             UserId::parse_with_server_name(
-                                registration
-                                    .get("sender_localpart")
-                                    .unwrap()
-                                    .as_str()
-                                    .unwrap(),
+                                registration.sender_localpart.as_str(),
                                 db.globals.server_name(),
                             )
                             .unwrap()
@@ -108,12 +144,20 @@ where
                         },
                     );
 
+                    if !registration.is_user_allowed(&user_id, db.globals.server_name()) {
+                        warn!(
+                            "Appservice tried to masquerade as {} outside of its namespace",
+                            user_id
+                        );
+                        // Forbidden
+                        return Failure((Status::new(580), ()));
+                    }
+
                     if !db.users.exists(&user_id).unwrap() {
                         // Forbidden
                         return Failure((Status::new(580), ()));
                     }
 
-                    // TODO: Check if appservice is allowed to be that user
                     (Some(user_id), None, None, true)
                 }
                 AuthScheme::ServerSignatures => (None, None, None, true),
@@ -123,16 +167,14 @@ where
             match metadata.authentication {
                 AuthScheme::AccessToken | AuthScheme::QueryOnlyAccessToken => {
                     if let Some(token) = token {
-                        match db.users.find_from_token(token).unwrap() {
-                            // Unknown Token
-                            None => return Failure((Status::new(581), ())),
-                            Some((user_id, device_id)) => (
-                                Some(user_id),
-                                Some(Box::<DeviceId>::from(device_id)),
-                                None,
-                                false,
-                            ),
+                        match find_device(&db.users, token, utils::millis_since_unix_epoch()) {
+                            Ok((user_id, device_id)) => {
+                                (Some(user_id), Some(device_id), None, false)
+                            }
+                            Err(status) => return Failure((status, ())),
                         }
+                    } else if ACCESS_TOKEN_OPTIONAL.contains(&metadata.path) {
+                        (None, None, None, false)
                     } else {
                         // Missing Token
                         return Failure((Status::new(582), ()));
@@ -279,6 +321,55 @@ where
             }
         };
 
+        if let Some(user_id) = &sender_user {
+            if let Err(status) =
+                check_suspension(&db.users, user_id, &metadata.method, metadata.path)
+            {
+                return Failure((status, ()));
+            }
+
+            if !is_allowed_while_read_only(&metadata.method, metadata.path) {
+                if let Some(terms) = db.globals.terms() {
+                    if !from_appservice
+                        && !ALLOWED_WITHOUT_CONSENT.contains(&metadata.path)
+                        && db.terms.needs_consent(user_id, terms).unwrap()
+                    {
+                        // Consent not given
+                        return Failure((Status::new(587), ()));
+                    }
+                }
+            }
+        }
+
+        if let Some(action) = RateLimitedAction::from_path(metadata.path) {
+            if !from_appservice {
+                if let Err(retry_after) = db.globals.client_ratelimiter.check(
+                    action,
+                    sender_user.as_ref(),
+                    request.client_ip(),
+                ) {
+                    let is_admin = sender_user.as_ref().map_or(false, |user_id| {
+                        db.users
+                            .is_admin(user_id, &db.rooms, &db.globals)
+                            .unwrap_or(false)
+                    });
+
+                    if !is_admin {
+                        warn!(
+                            "Rate limited {:?} request from {:?} ({:?})",
+                            action,
+                            sender_user,
+                            request.client_ip()
+                        );
+                        request.local_cache(|| RetryAfter(Some(retry_after)));
+
+                        // Limit exceeded
+                        return Failure((Status::new(584), ()));
+                    }
+                }
+            }
+        }
+
         let mut http_request = http::Request::builder()
             .uri(request.uri().to_string())
             .method(&*request.method().to_string());
@@ -324,6 +415,7 @@ where
                 sender_device,
                 sender_servername,
                 from_appservice,
+                appservice_id: appservice.map(|(id, _)| id),
                 json_body,
             }),
             Err(e) => {
@@ -335,6 +427,38 @@ where
     }
 }
 
+/// Fails with 586 if a suspended user sends a request that is not allowed while read only.
+#[cfg(feature = "conduit_bin")]
+fn check_suspension(
+    users: &Users,
+    user_id: &UserId,
+    method: &http::Method,
+    path: &str,
+) -> Result<(), Status> {
+    if !is_allowed_while_read_only(method, path) && users.is_suspended(user_id).unwrap() {
+        // Suspended
+        Err(Status::new(586))
+    } else {
+        Ok(())
+    }
+}
+
+/// Finds the device an access token belongs to, `now` being the current time in milliseconds
+/// since the unix epoch.
+///
+/// Fails with 581 if the token is unknown and with 585 if it expired, so the client knows it has
+/// to use its refresh token.
+#[cfg(feature = "conduit_bin")]
+fn find_device(users: &Users, token: &str, now: u64) -> Result<(UserId, Box<DeviceId>), Status> {
+    match users.find_from_token(token).unwrap() {
+        // Unknown Token
+        None => Err(Status::new(581)),
+        // Expired Token
+        Some(_) if users.token_is_expired_at(token, now).unwrap() => Err(Status::new(585)),
+        Some((user_id, device_id)) => Ok((user_id, Box::<DeviceId>::from(device_id))),
+    }
+}
+
 impl<T: Outgoing> Deref for Ruma<T> {
     type Target = T::Incoming;
 
@@ -352,6 +476,10 @@ pub fn response<T: OutgoingResponse>(response: RumaResponse<T>) -> response::Res
         .try_into_http_response::<Vec<u8>>()
         .map_err(|_| Status::InternalServerError)?;
 
+    rocket_response(http_response)
+}
+
+fn rocket_response(http_response: http::Response<Vec<u8>>) -> response::Result<'static> {
     let mut response = rocket::response::Response::build();
 
     let status = http_response.status();
@@ -403,3 +531,172 @@ where
         response(self)
     }
 }
+
+/// A ruma response with additional json fields, for additions to the spec that ruma doesn't know
+/// about yet. Objects in `fields` are merged into the objects of the response.
+#[derive(Clone)]
+pub struct RumaResponseWithFields<T> {
+    pub response: T,
+    pub fields: serde_json::Map<String, serde_json::Value>,
+}
+
+impl<T> From<T> for RumaResponseWithFields<T> {
+    fn from(response: T) -> Self {
+        Self {
+            response,
+            fields: serde_json::Map::new(),
+        }
+    }
+}
+
+/// Adds all fields of `source` to `target`, merging objects that exist in both.
+#[cfg(feature = "conduit_bin")]
+fn merge_json_objects(
+    target: &mut serde_json::Map<String, serde_json::Value>,
+    source: serde_json::Map<String, serde_json::Value>,
+) {
+    for (key, value) in source {
+        match (target.get_mut(&key), value) {
+            (Some(serde_json::Value::Object(target)), serde_json::Value::Object(source)) => {
+                merge_json_objects(target, source)
+            }
+            (_, value) => {
+                target.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "conduit_bin")]
+impl<'r, 'o, T> Responder<'r, 'o> for RumaResponseWithFields<T>
+where
+    'o: 'r,
+    T: OutgoingResponse,
+{
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'o> {
+        let mut http_response = self
+            .response
+            .try_into_http_response::<Vec<u8>>()
+            .map_err(|_| Status::InternalServerError)?;
+
+        if !self.fields.is_empty() {
+            let mut body = serde_json::from_slice::<serde_json::Map<_, _>>(http_response.body())
+                .map_err(|_| Status::InternalServerError)?;
+            merge_json_objects(&mut body, self.fields);
+            *http_response.body_mut() =
+                serde_json::to_vec(&body).expect("json object to bytes can't fail");
+        }
+
+        rocket_response(http_response)
+    }
+}
+
+#[cfg(all(test, feature = "conduit_bin"))]
+mod tests {
+    use super::{
+        check_suspension, find_device, is_allowed_while_read_only, ALLOWED_WITHOUT_CONSENT,
+    };
+    use crate::{database::users::Users, utils};
+    use rocket::http::Status;
+    use ruma::{DeviceId, UserId};
+    use std::{convert::TryFrom, time::Duration};
+
+    fn user_with_device(lifetime: Duration) -> (Users, UserId, Box<DeviceId>) {
+        let users = Users::in_memory();
+        let user_id = UserId::try_from("@alice:example.org").unwrap();
+        let device_id = Box::<DeviceId>::from("DEVICE");
+
+        users.create(&user_id, Some("password")).unwrap();
+        users
+            .create_device(&user_id, &device_id, "token", None)
+            .unwrap();
+        users
+            .set_refreshable_token(&user_id, &device_id, "token", "refresh", lifetime)
+            .unwrap();
+
+        (users, user_id, device_id)
+    }
+
+    #[test]
+    fn valid_access_tokens_find_the_device() {
+        let (users, user_id, device_id) = user_with_device(Duration::from_secs(60));
+
+        assert_eq!(
+            find_device(&users, "token", utils::millis_since_unix_epoch()),
+            Ok((user_id, device_id))
+        );
+    }
+
+    #[test]
+    fn unknown_access_tokens_get_581() {
+        let (users, _, _) = user_with_device(Duration::from_secs(60));
+
+        assert_eq!(
+            find_device(&users, "unknown", utils::millis_since_unix_epoch()),
+            Err(Status::new(581))
+        );
+    }
+
+    #[test]
+    fn expired_access_tokens_get_585() {
+        let (users, _, _) = user_with_device(Duration::from_secs(60));
+        let now = utils::millis_since_unix_epoch();
+
+        assert!(find_device(&users, "token", now).is_ok());
+        assert_eq!(
+            find_device(&users, "token", now + 61_000),
+            Err(Status::new(585))
+        );
+    }
+
+    #[test]
+    fn suspended_users_can_only_read() {
+        let (users, user_id, _) = user_with_device(Duration::from_secs(60));
+        let send = "/_matrix/client/r0/rooms/:room_id/send/:event_type/:txn_id";
+
+        assert_eq!(
+            check_suspension(&users, &user_id, &http::Method::PUT, send),
+            Ok(())
+        );
+
+        users.set_suspended(&user_id, true).unwrap();
+
+        assert_eq!(
+            check_suspension(&users, &user_id, &http::Method::PUT, send),
+            Err(Status::new(586))
+        );
+        assert_eq!(
+            check_suspension(
+                &users,
+                &user_id,
+                &http::Method::GET,
+                "/_matrix/client/r0/sync"
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check_suspension(
+                &users,
+                &user_id,
+                &http::Method::POST,
+                "/_matrix/client/r0/logout"
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn users_without_consent_can_join_and_leave() {
+        for path in &[
+            "/_matrix/client/r0/join/:room_id_or_alias",
+            "/_matrix/client/r0/rooms/:room_id/join",
+            "/_matrix/client/r0/rooms/:room_id/leave",
+        ] {
+            assert!(!is_allowed_while_read_only(&http::Method::POST, path));
+            assert!(ALLOWED_WITHOUT_CONSENT.contains(path));
+        }
+
+        assert!(!ALLOWED_WITHOUT_CONSENT
+            .contains(&"/_matrix/client/r0/rooms/:room_id/send/:event_type/:txn_id"));
+    }
+}
@@ -1,12 +1,14 @@
 use crate::{
     client_server::{self, claim_keys_helper, get_keys_helper},
-    database::{rooms::CompressedStateEvent, DatabaseGuard},
+    database::{
+        rooms::{self, CompressedStateEvent, RoomEdus},
+        DatabaseGuard,
+    },
     pdu::EventHash,
     utils, ConduitResult, Database, Error, PduEvent, Result, Ruma,
 };
 use get_profile_information::v1::ProfileField;
 use http::header::{HeaderValue, AUTHORIZATION};
-use regex::Regex;
 use rocket::{
     futures::{prelude::*, stream::FuturesUnordered},
     response::content::Json,
@@ -40,23 +42,24 @@ use ruma::{
     },
     directory::{IncomingFilter, IncomingRoomNetwork},
     events::{
-        receipt::{ReceiptEvent, ReceiptEventContent},
         room::{
             create::RoomCreateEventContent,
             member::{MembershipState, RoomMemberEventContent},
         },
-        AnyEphemeralRoomEvent, EventType,
+        EventType,
     },
     int,
-    receipt::ReceiptType,
     serde::JsonObject,
     signatures::{CanonicalJsonObject, CanonicalJsonValue},
     state_res::{self, RoomVersion, StateMap},
     to_device::DeviceIdOrAllDevices,
-    uint, EventId, MilliSecondsSinceUnixEpoch, RoomId, RoomVersionId, ServerName,
-    ServerSigningKeyId,
+    uint, EventId, Int, MilliSecondsSinceUnixEpoch, RoomId, RoomVersionId, ServerName,
+    ServerSigningKeyId, UInt,
+};
+use serde_json::{
+    json,
+    value::{to_raw_value, RawValue as RawJsonValue},
 };
-use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
 use std::{
     collections::{btree_map, hash_map, BTreeMap, BTreeSet, HashMap, HashSet},
     convert::{TryFrom, TryInto},
@@ -550,28 +553,9 @@ pub fn get_server_keys_route(db: DatabaseGuard) -> Json<String> {
         return Json("Federation is disabled.".to_owned());
     }
 
-    let mut verify_keys = BTreeMap::new();
-    verify_keys.insert(
-        ServerSigningKeyId::try_from(
-            format!("ed25519:{}", db.globals.keypair().version()).as_str(),
-        )
-        .expect("found invalid server signing keys in DB"),
-        VerifyKey {
-            key: base64::encode_config(db.globals.keypair().public_key(), base64::STANDARD_NO_PAD),
-        },
-    );
     let mut response = serde_json::from_slice(
         get_server_keys::v2::Response {
-            server_key: ServerSigningKeys {
-                server_name: db.globals.server_name().to_owned(),
-                verify_keys,
-                old_verify_keys: BTreeMap::new(),
-                signatures: BTreeMap::new(),
-                valid_until_ts: MilliSecondsSinceUnixEpoch::from_system_time(
-                    SystemTime::now() + Duration::from_secs(86400 * 7),
-                )
-                .expect("time is valid"),
-            },
+            server_key: own_server_keys(&db),
         }
         .try_into_http_response::<Vec<u8>>()
         .unwrap()
@@ -601,6 +585,289 @@ pub fn get_server_keys_deprecated_route(db: DatabaseGuard) -> Json<String> {
     get_server_keys_route(db)
 }
 
+/// # `GET /_matrix/key/v2/query/{serverName}/{minimumValidUntilTs}`
+///
+/// Gets the public signing keys of another server, acting as a notary server.
+///
+/// - Keys are served from the cache if they are valid long enough, otherwise they are fetched
+/// from the server
+/// - The returned keys are signed by this server
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/key/v2/query/<_>/<_>", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn get_remote_server_keys_route(
+    db: DatabaseGuard,
+    body: Ruma<get_remote_server_keys::v2::Request<'_>>,
+) -> Json<String> {
+    if !db.globals.allow_federation() {
+        // TODO: Use proper types
+        return Json("Federation is disabled.".to_owned());
+    }
+
+    let body = body.body;
+
+    notary_response(&db, vec![(body.server_name, body.minimum_valid_until_ts)]).await
+}
+
+/// # `GET /_matrix/key/v2/query/{serverName}`
+///
+/// Gets the public signing keys of another server, acting as a notary server.
+///
+/// - Keys are served from the cache if they are valid long enough, otherwise they are fetched
+/// from the server
+/// - The returned keys are signed by this server
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/key/v2/query/<server_name>?<minimum_valid_until_ts>")
+)]
+#[tracing::instrument(skip(db))]
+pub async fn get_remote_server_keys_all_route(
+    db: DatabaseGuard,
+    server_name: String,
+    minimum_valid_until_ts: Option<u64>,
+) -> Json<String> {
+    if !db.globals.allow_federation() {
+        // TODO: Use proper types
+        return Json("Federation is disabled.".to_owned());
+    }
+
+    let server_name = match Box::<ServerName>::try_from(server_name) {
+        Ok(server_name) => server_name,
+        // TODO: Use proper types
+        Err(_) => return Json("Invalid server name.".to_owned()),
+    };
+
+    let minimum_valid_until_ts = minimum_valid_until_ts
+        .and_then(UInt::new)
+        .map_or_else(MilliSecondsSinceUnixEpoch::now, MilliSecondsSinceUnixEpoch);
+
+    notary_response(&db, vec![(server_name, minimum_valid_until_ts)]).await
+}
+
+/// # `POST /_matrix/key/v2/query`
+///
+/// Gets the public signing keys of multiple servers, acting as a notary server.
+///
+/// - Keys are served from the cache if they are valid long enough, otherwise they are fetched
+/// from the servers
+/// - The returned keys are signed by this server
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/key/v2/query", data = "<body>")
+)]
+#[tracing::instrument(skip(db, body))]
+pub async fn get_remote_server_keys_batch_route(
+    db: DatabaseGuard,
+    body: Ruma<get_remote_server_keys_batch::v2::Request>,
+) -> Json<String> {
+    if !db.globals.allow_federation() {
+        // TODO: Use proper types
+        return Json("Federation is disabled.".to_owned());
+    }
+
+    let queries = body
+        .server_keys
+        .iter()
+        .map(|(server_name, criteria)| {
+            // The strictest criteria of all requested keys applies to the whole server
+            let minimum_valid_until_ts = criteria
+                .values()
+                .filter_map(|c| c.minimum_valid_until_ts)
+                .max()
+                .unwrap_or(body.minimum_valid_until_ts);
+
+            (server_name.clone(), minimum_valid_until_ts)
+        })
+        .collect();
+
+    notary_response(&db, queries).await
+}
+
+/// Builds the signed response of the notary endpoints. Servers for which no keys could be found
+/// are left out.
+async fn notary_response(
+    db: &Database,
+    queries: Vec<(Box<ServerName>, MilliSecondsSinceUnixEpoch)>,
+) -> Json<String> {
+    let mut server_keys = Vec::new();
+
+    for (origin, minimum_valid_until_ts) in queries {
+        match notary_server_keys(db, &origin, minimum_valid_until_ts).await {
+            Ok(Some(keys)) => server_keys.push(keys),
+            Ok(None) => debug!("No signing keys found for {}", origin),
+            Err(e) => warn!("Failed to get signing keys for {}: {}", origin, e),
+        }
+    }
+
+    let mut response = BTreeMap::new();
+    response.insert("server_keys", server_keys);
+
+    Json(serde_json::to_string(&response).expect("JSON is canonical"))
+}
+
+/// Returns the signing keys of `origin` with a signature of this server added.
+///
+/// The keys are served as `origin` signed them. The cached response is used if it is valid at
+/// `minimum_valid_until_ts`, otherwise the keys are fetched from `origin`. If that fails, we fall
+/// back to the cached response.
+#[tracing::instrument(skip(db))]
+async fn notary_server_keys(
+    db: &Database,
+    origin: &ServerName,
+    minimum_valid_until_ts: MilliSecondsSinceUnixEpoch,
+) -> Result<Option<CanonicalJsonObject>> {
+    let mut server_keys = if origin == db.globals.server_name() {
+        utils::to_canonical_object(own_server_keys(db))
+            .map_err(|_| Error::bad_database("Invalid own signing keys."))?
+    } else {
+        let cached_keys = db.globals.signed_server_keys(origin)?;
+
+        match cached_keys {
+            Some(server_keys)
+                if keys_valid_until(&server_keys)
+                    .map_or(false, |ts| ts >= minimum_valid_until_ts.get().into()) =>
+            {
+                server_keys
+            }
+            cached_keys => {
+                debug!("Fetching signing keys for {} over federation", origin);
+
+                match fetch_self_signed_keys(db, origin).await {
+                    Ok(server_keys) => server_keys,
+                    Err(e) => {
+                        debug!("Failed to fetch signing keys of {}: {}", origin, e);
+                        match cached_keys {
+                            Some(server_keys) => server_keys,
+                            None => return Ok(None),
+                        }
+                    }
+                }
+            }
+        }
+    };
+
+    ruma::signatures::sign_json(
+        db.globals.server_name().as_str(),
+        db.globals.keypair(),
+        &mut server_keys,
+    )
+    .map_err(|_| Error::BadServerResponse("Failed to sign signing keys."))?;
+
+    Ok(Some(server_keys))
+}
+
+/// The key endpoint with the response body kept as it was sent, because the signature also
+/// covers fields that `ServerSigningKeys` does not model.
+pub mod get_server_keys_raw {
+    use ruma::{
+        api::{federation::discovery::ServerSigningKeys, ruma_api},
+        serde::Raw,
+    };
+
+    ruma_api! {
+        metadata: {
+            description: "Gets the homeserver's published signing keys.",
+            method: GET,
+            name: "get_server_keys_raw",
+            path: "/_matrix/key/v2/server",
+            rate_limited: false,
+            authentication: None,
+        }
+
+        request: {}
+
+        response: {
+            #[ruma_api(body)]
+            pub server_key: Raw<ServerSigningKeys>,
+        }
+    }
+}
+
+/// Fetches the signing keys of `origin` and stores them if they are signed by one of their own
+/// verify keys.
+async fn fetch_self_signed_keys(db: &Database, origin: &ServerName) -> Result<CanonicalJsonObject> {
+    let response = db
+        .sending
+        .send_federation_request(&db.globals, origin, get_server_keys_raw::Request::new())
+        .await?;
+
+    let server_keys = serde_json::from_str::<CanonicalJsonObject>(response.server_key.json().get())
+        .map_err(|_| Error::BadServerResponse("Invalid signing keys."))?;
+    let server_key = response
+        .server_key
+        .deserialize()
+        .map_err(|_| Error::BadServerResponse("Invalid signing keys."))?;
+
+    if &*server_key.server_name != origin {
+        return Err(Error::BadServerResponse(
+            "Server returned signing keys of another server.",
+        ));
+    }
+
+    verify_self_signed_keys(origin, &server_key.verify_keys, &server_keys)?;
+
+    db.globals.add_signing_key(origin, server_key)?;
+    db.globals.add_signed_server_keys(origin, &server_keys)?;
+
+    Ok(server_keys)
+}
+
+/// Checks that a key response carries a valid signature of one of its current verify keys.
+fn verify_self_signed_keys(
+    origin: &ServerName,
+    verify_keys: &BTreeMap<ServerSigningKeyId, VerifyKey>,
+    server_keys: &CanonicalJsonObject,
+) -> Result<()> {
+    let mut pub_key_map = BTreeMap::new();
+    pub_key_map.insert(
+        origin.as_str().to_owned(),
+        verify_keys
+            .iter()
+            .map(|(key_id, key)| (key_id.to_string(), key.key.clone()))
+            .collect::<BTreeMap<_, _>>(),
+    );
+
+    ruma::signatures::verify_json(&pub_key_map, server_keys).map_err(|e| {
+        warn!("Signing keys of {} have no valid signature: {}", origin, e);
+        Error::BadServerResponse("Signing keys are not signed by the server.")
+    })
+}
+
+/// Returns the `valid_until_ts` of a key response.
+fn keys_valid_until(server_keys: &CanonicalJsonObject) -> Option<Int> {
+    match server_keys.get("valid_until_ts") {
+        Some(CanonicalJsonValue::Integer(ts)) => Some(*ts),
+        _ => None,
+    }
+}
+
+/// Returns the current signing keys of this server.
+fn own_server_keys(db: &Database) -> ServerSigningKeys {
+    let mut verify_keys = BTreeMap::new();
+    verify_keys.insert(
+        ServerSigningKeyId::try_from(
+            format!("ed25519:{}", db.globals.keypair().version()).as_str(),
+        )
+        .expect("found invalid server signing keys in DB"),
+        VerifyKey {
+            key: base64::encode_config(db.globals.keypair().public_key(), base64::STANDARD_NO_PAD),
+        },
+    );
+
+    ServerSigningKeys {
+        server_name: db.globals.server_name().to_owned(),
+        verify_keys,
+        old_verify_keys: BTreeMap::new(),
+        signatures: BTreeMap::new(),
+        valid_until_ts: MilliSecondsSinceUnixEpoch::from_system_time(
+            SystemTime::now() + Duration::from_secs(86400 * 7),
+        )
+        .expect("time is valid"),
+    }
+}
+
 /// # `POST /_matrix/federation/v1/publicRooms`
 ///
 /// Lists the public rooms on this server.
@@ -789,41 +1056,77 @@ pub async fn send_transaction_message_route(
         }
     }
 
-    for edu in body
+    for (edu, raw_edu) in body
         .edus
         .iter()
-        .filter_map(|edu| serde_json::from_str::<Edu>(edu.json().get()).ok())
+        .filter_map(|edu| Some((serde_json::from_str::<Edu>(edu.json().get()).ok()?, edu)))
     {
         match edu {
-            Edu::Presence(_) => {}
+            Edu::Presence(presence) => {
+                for update in presence.push {
+                    // Servers can only send presence for their own users
+                    if update.user_id.server_name() != body.origin {
+                        continue;
+                    }
+
+                    RoomEdus::set_presence(
+                        &db,
+                        &update.user_id,
+                        update.presence,
+                        update.status_msg,
+                        utils::millis_since_unix_epoch()
+                            .saturating_sub(update.last_active_ago.into()),
+                    )?;
+                }
+            }
             Edu::Receipt(receipt) => {
+                // Ruma doesn't know about threaded read receipts yet
+                let raw_edu = serde_json::from_str::<serde_json::Value>(raw_edu.json().get())
+                    .expect("EDU was parsed before");
+
                 for (room_id, room_updates) in receipt.receipts {
                     for (user_id, user_updates) in room_updates.read {
+                        let thread_id = receipt_thread_id(&raw_edu, &room_id, &user_id);
+
                         if let Some((event_id, _)) = user_updates
                             .event_ids
                             .iter()
+                            .filter(|id| {
+                                db.rooms.get_pdu(id).ok().flatten().map_or(false, |pdu| {
+                                    pdu.room_id == room_id
+                                        && thread_id.map_or(true, |thread_id| {
+                                            rooms::is_in_thread(&pdu, thread_id)
+                                        })
+                                })
+                            })
                             .filter_map(|id| {
                                 db.rooms.get_pdu_count(id).ok().flatten().map(|r| (id, r))
                             })
                             .max_by_key(|(_, count)| *count)
                         {
-                            let mut user_receipts = BTreeMap::new();
-                            user_receipts.insert(user_id.clone(), user_updates.data);
-
-                            let mut receipts = BTreeMap::new();
-                            receipts.insert(ReceiptType::Read, user_receipts);
-
-                            let mut receipt_content = BTreeMap::new();
-                            receipt_content.insert(event_id.to_owned(), receipts);
+                            let mut receipt = json!({ "ts": user_updates.data.ts });
+                            if let Some(thread_id) = thread_id {
+                                receipt["thread_id"] = json!(thread_id);
+                            }
 
-                            let event = AnyEphemeralRoomEvent::Receipt(ReceiptEvent {
-                                content: ReceiptEventContent(receipt_content),
-                                room_id: room_id.clone(),
+                            let event = json!({
+                                "type": "m.receipt",
+                                "room_id": room_id,
+                                "content": {
+                                    event_id.as_str(): {
+                                        "m.read": {
+                                            user_id.as_str(): receipt,
+                                        },
+                                    },
+                                },
                             });
+                            db.sending
+                                .send_edu_appservices(&[room_id.clone()], &event, &db)?;
                             db.rooms.edus.readreceipt_update(
                                 &user_id,
                                 &room_id,
-                                event,
+                                thread_id,
+                                &event,
                                 &db.globals,
                             )?;
                         } else {
@@ -846,6 +1149,8 @@ pub async fn send_transaction_message_route(
                         .edus
                         .typing_remove(&typing.user_id, &typing.room_id, &db.globals)?;
                 }
+
+                db.sending.send_typing_appservices(&typing.room_id, &db)?;
             }
             Edu::DeviceListUpdate(DeviceListUpdateContent { user_id, .. }) => {
                 db.users
@@ -1640,7 +1945,12 @@ async fn upgrade_outlier_to_timeline_pdu(
         None::<PduEvent>,
         |k, s| auth_events.get(&(k.clone(), s.to_owned())),
     )
-    .map_err(|_e| "Auth check failed.".to_owned())?;
+    .map_err(|_e| "Auth check failed.".to_owned())?
+        // Events of rooms, users and servers that our policy lists ban stay in the room graph,
+        // but clients don't see them
+        || db.policies.is_room_banned(room_id)
+        || db.policies.is_server_banned(origin)
+        || db.policies.is_user_banned(&incoming_pdu.sender);
 
     if soft_fail {
         append_incoming_pdu(
@@ -2165,50 +2475,22 @@ fn append_incoming_pdu(
             continue;
         }
 
-        if let Some(namespaces) = appservice.1.get("namespaces") {
-            let users = namespaces
-                .get("users")
-                .and_then(|users| users.as_sequence())
-                .map_or_else(Vec::new, |users| {
-                    users
-                        .iter()
-                        .filter_map(|users| Regex::new(users.get("regex")?.as_str()?).ok())
-                        .collect::<Vec<_>>()
-                });
-            let aliases = namespaces
-                .get("aliases")
-                .and_then(|aliases| aliases.as_sequence())
-                .map_or_else(Vec::new, |aliases| {
-                    aliases
-                        .iter()
-                        .filter_map(|aliases| Regex::new(aliases.get("regex")?.as_str()?).ok())
-                        .collect::<Vec<_>>()
-                });
-            let rooms = namespaces
-                .get("rooms")
-                .and_then(|rooms| rooms.as_sequence());
-
-            let matching_users = |users: &Regex| {
-                users.is_match(pdu.sender.as_str())
-                    || pdu.kind == EventType::RoomMember
-                        && pdu
-                            .state_key
-                            .as_ref()
-                            .map_or(false, |state_key| users.is_match(state_key))
-            };
-            let matching_aliases = |aliases: &Regex| {
-                db.rooms
-                    .room_aliases(&pdu.room_id)
-                    .filter_map(|r| r.ok())
-                    .any(|room_alias| aliases.is_match(room_alias.as_str()))
-            };
+        let namespaces = &appservice.1.namespaces;
+        let matching_users = |user_id: &str| {
+            UserId::try_from(user_id).map_or(false, |user_id| namespaces.is_user_match(&user_id))
+        };
 
-            if aliases.iter().any(matching_aliases)
-                || rooms.map_or(false, |rooms| rooms.contains(&pdu.room_id.as_str().into()))
-                || users.iter().any(matching_users)
-            {
-                db.sending.send_pdu_appservice(&appservice.0, &pdu_id)?;
-            }
+        if db
+            .rooms
+            .room_aliases(&pdu.room_id)
+            .filter_map(|r| r.ok())
+            .any(|room_alias| namespaces.is_alias_match(&room_alias))
+            || namespaces.is_room_match(pdu.room_id.as_str())
+            || matching_users(pdu.sender.as_str())
+            || pdu.kind == EventType::RoomMember
+                && pdu.state_key.as_deref().map_or(false, matching_users)
+        {
+            db.sending.send_pdu_appservice(&appservice.0, &pdu_id)?;
         }
     }
 
@@ -2996,6 +3278,16 @@ pub async fn create_invite_route(
     )
     .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "state_key is not a user id."))?;
 
+    if db.policies.is_user_banned(&sender)
+        || db.policies.is_user_banned(&invited_user)
+        || db.policies.is_room_banned(&body.room_id)
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This invite is blocked by a policy list of this server.",
+        ));
+    }
+
     let mut invite_state = body.invite_room_state.clone();
 
     let mut event: JsonObject = serde_json::from_str(body.event.get())
@@ -3454,9 +3746,32 @@ pub(crate) async fn fetch_join_signing_keys(
     Ok(())
 }
 
+/// Returns the thread of the read receipt of the user in a receipt EDU.
+fn receipt_thread_id<'a>(
+    edu: &'a serde_json::Value,
+    room_id: &RoomId,
+    user_id: &UserId,
+) -> Option<&'a str> {
+    edu.get("content")?
+        .get(room_id.as_str())?
+        .get("m.read")?
+        .get(user_id.as_str())?
+        .get("data")?
+        .get("thread_id")?
+        .as_str()
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{add_port_to_hostname, get_ip_with_port, FedDest};
+    use super::{
+        add_port_to_hostname, get_ip_with_port, keys_valid_until, verify_self_signed_keys, FedDest,
+    };
+    use ruma::{
+        api::federation::discovery::VerifyKey,
+        signatures::{CanonicalJsonValue, Ed25519KeyPair},
+        ServerName, ServerSigningKeyId,
+    };
+    use std::{collections::BTreeMap, convert::TryFrom};
 
     #[test]
     fn ips_get_default_ports() {
@@ -3497,4 +3812,72 @@ mod tests {
             FedDest::Named(String::from("example.com"), String::from(":1337"))
         )
     }
+
+    fn signed_server_keys(
+        keypair: &Ed25519KeyPair,
+    ) -> (
+        BTreeMap<ServerSigningKeyId, VerifyKey>,
+        ruma::signatures::CanonicalJsonObject,
+    ) {
+        let mut verify_keys = BTreeMap::new();
+        verify_keys.insert(
+            ServerSigningKeyId::try_from(format!("ed25519:{}", keypair.version()).as_str())
+                .unwrap(),
+            VerifyKey::new(base64::encode_config(
+                keypair.public_key(),
+                base64::STANDARD_NO_PAD,
+            )),
+        );
+
+        let mut server_keys = ruma::signatures::CanonicalJsonObject::new();
+        server_keys.insert(
+            "server_name".to_owned(),
+            CanonicalJsonValue::String("example.org".to_owned()),
+        );
+        server_keys.insert(
+            "valid_until_ts".to_owned(),
+            CanonicalJsonValue::Integer(1_000.into()),
+        );
+
+        (verify_keys, server_keys)
+    }
+
+    fn generate_keypair(version: &str) -> Ed25519KeyPair {
+        Ed25519KeyPair::from_der(&Ed25519KeyPair::generate().unwrap(), version.to_owned()).unwrap()
+    }
+
+    #[test]
+    fn self_signed_keys_are_accepted() {
+        let origin = Box::<ServerName>::try_from("example.org").unwrap();
+        let keypair = generate_keypair("a");
+        let (verify_keys, mut server_keys) = signed_server_keys(&keypair);
+        ruma::signatures::sign_json("example.org", &keypair, &mut server_keys).unwrap();
+
+        assert!(verify_self_signed_keys(&origin, &verify_keys, &server_keys).is_ok());
+        assert_eq!(keys_valid_until(&server_keys), Some(1_000.into()));
+    }
+
+    #[test]
+    fn keys_without_valid_origin_signature_are_rejected() {
+        let origin = Box::<ServerName>::try_from("example.org").unwrap();
+        let keypair = generate_keypair("a");
+        let (verify_keys, mut server_keys) = signed_server_keys(&keypair);
+
+        // Not signed at all
+        assert!(verify_self_signed_keys(&origin, &verify_keys, &server_keys).is_err());
+
+        // Signed by a key that is not one of the verify keys
+        let other_keypair = generate_keypair("a");
+        let mut forged = server_keys.clone();
+        ruma::signatures::sign_json("example.org", &other_keypair, &mut forged).unwrap();
+        assert!(verify_self_signed_keys(&origin, &verify_keys, &forged).is_err());
+
+        // Changed after signing
+        ruma::signatures::sign_json("example.org", &keypair, &mut server_keys).unwrap();
+        server_keys.insert(
+            "valid_until_ts".to_owned(),
+            CanonicalJsonValue::Integer(2_000.into()),
+        );
+        assert!(verify_self_signed_keys(&origin, &verify_keys, &server_keys).is_err());
+    }
 }
@@ -1,10 +1,13 @@
 use argon2::{Config, Variant};
 use cmp::Ordering;
 use rand::prelude::*;
-use ruma::serde::{try_from_json_map, CanonicalJsonError, CanonicalJsonObject};
+use ruma::{
+    serde::{try_from_json_map, CanonicalJsonError, CanonicalJsonObject},
+    EventId,
+};
 use std::{
     cmp,
-    convert::TryInto,
+    convert::{TryFrom, TryInto},
     str::FromStr,
     time::{SystemTime, UNIX_EPOCH},
 };
@@ -72,6 +75,13 @@ pub fn random_string(length: usize) -> String {
         .collect()
 }
 
+/// Generates an event id that looks like a real one, e.g. for the events of shadow-banned users
+/// that are thrown away.
+pub fn random_event_id() -> EventId {
+    EventId::try_from(format!("${}", random_string(43)))
+        .expect("random strings are valid event ids")
+}
+
 /// Calculate a new hash for the given password
 #[tracing::instrument(skip(password))]
 pub fn calculate_hash(password: &str) -> Result<String, argon2::Error> {